@@ -0,0 +1,200 @@
+//! Benchmarks for locking and sourcing a config with many plugins.
+//!
+//! Run these using `cargo bench`. The plugins are local, or downloaded from a
+//! local server, so that the results don't depend on the network.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The number of plugins in the config.
+const PLUGINS: usize = 25;
+
+/// The number of extra files in each plugin of the large config.
+const FILES: usize = 200;
+
+/// The number of times to run each benchmark.
+const ITERATIONS: u32 = 20;
+
+/// A temporary home directory containing a config with [`PLUGINS`] plugins.
+struct Setup {
+    home: tempfile::TempDir,
+}
+
+impl Setup {
+    /// Create a config where each plugin has the given number of extra files
+    /// that are all matched.
+    fn new(files: usize) -> Self {
+        let home = tempfile::tempdir().expect("create temporary directory");
+        let mut config = String::new();
+        for i in 0..PLUGINS {
+            let dir = home.path().join(format!("plugins/plugin-{}", i));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(
+                dir.join(format!("plugin-{}.plugin.zsh", i)),
+                format!("echo {}\n", i),
+            )
+            .unwrap();
+            for j in 0..files {
+                fs::write(
+                    dir.join(format!("plugin-{}-{}.zsh", i, j)),
+                    format!("echo {}\n", j),
+                )
+                .unwrap();
+            }
+            config.push_str(&format!(
+                "[plugins.plugin-{}]\nlocal = \"{}\"\n",
+                i,
+                dir.display()
+            ));
+            if files > 0 {
+                config.push_str("use = [\"*.zsh\"]\n");
+            }
+            config.push('\n');
+        }
+        fs::create_dir_all(home.path().join("config")).unwrap();
+        fs::create_dir_all(home.path().join("data")).unwrap();
+        fs::write(home.path().join("config/plugins.toml"), config).unwrap();
+        Self { home }
+    }
+
+    fn path(&self) -> &Path {
+        self.home.path()
+    }
+}
+
+/// A local HTTP server that serves a small plugin file for every request.
+///
+/// Connections are kept alive, so the number of connections shows how many of
+/// them were reused.
+struct Server {
+    port: u16,
+    connections: Arc<AtomicUsize>,
+}
+
+impl Server {
+    fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                        // Respond once the request head has been read.
+                        if line == "\r\n" {
+                            let body = "echo remote\n";
+                            let response = format!(
+                                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                                body.len(),
+                                body
+                            );
+                            if stream.write_all(response.as_bytes()).is_err() {
+                                break;
+                            }
+                        }
+                        line.clear();
+                    }
+                });
+            }
+        });
+        Self { port, connections }
+    }
+
+    /// Returns the number of connections that have been accepted.
+    fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+/// Create a config with [`PLUGINS`] remote plugins that are downloaded from the
+/// given server.
+fn setup_remote(server: &Server) -> tempfile::TempDir {
+    let home = tempfile::tempdir().expect("create temporary directory");
+    let mut config = String::new();
+    for i in 0..PLUGINS {
+        config.push_str(&format!(
+            "[plugins.remote-{}]\nremote = \"http://127.0.0.1:{}/remote-{}.zsh\"\n\n",
+            i, server.port, i
+        ));
+    }
+    fs::create_dir_all(home.path().join("config")).unwrap();
+    fs::create_dir_all(home.path().join("data")).unwrap();
+    fs::write(home.path().join("config/plugins.toml"), config).unwrap();
+    home
+}
+
+/// Run `sheldon` with the given arguments in the given home directory.
+fn sheldon(home: &Path, args: &[&str]) {
+    let bin = PathBuf::from(env!("CARGO_BIN_EXE_sheldon"));
+    let status = Command::new(bin)
+        .args(args)
+        .env("HOME", home)
+        .env("SHELDON_CONFIG_DIR", home.join("config"))
+        .env("SHELDON_DATA_DIR", home.join("data"))
+        .env_remove("SHELDON_JOBS")
+        .env_remove("SHELDON_MATCH_JOBS")
+        .output()
+        .expect("run sheldon")
+        .status;
+    assert!(status.success(), "sheldon {:?} failed", args);
+}
+
+/// Run the given function [`ITERATIONS`] times and print the mean duration.
+fn bench_function<F>(name: &str, mut f: F)
+where
+    F: FnMut(),
+{
+    // Warm up.
+    f();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let mean: Duration = start.elapsed() / ITERATIONS;
+    println!("{:<28} {:>12.3?}", name, mean);
+}
+
+fn main() {
+    let setup = Setup::new(0);
+    let home = setup.path();
+
+    bench_function("lock --jobs 1", || sheldon(home, &["--jobs", "1", "lock"]));
+    bench_function("lock", || sheldon(home, &["lock"]));
+    bench_function("source", || sheldon(home, &["source"]));
+
+    // Matching dominates when the plugins have many files.
+    let setup = Setup::new(FILES);
+    let home = setup.path();
+
+    bench_function("lock large --match-jobs 1", || {
+        sheldon(home, &["--match-jobs", "1", "lock"])
+    });
+    bench_function("lock large", || sheldon(home, &["lock"]));
+
+    // Every download reuses a connection from the shared pool of handles,
+    // instead of connecting to the server again.
+    let server = Server::start();
+    let home = setup_remote(&server);
+    let home = home.path();
+
+    bench_function("lock remote --reinstall", || {
+        sheldon(home, &["lock", "--reinstall"])
+    });
+    println!(
+        "{:<28} {:>12}",
+        "  connections per lock",
+        server.connections() / (ITERATIONS as usize + 1)
+    );
+}
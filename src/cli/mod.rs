@@ -10,15 +10,16 @@ use std::env;
 use std::io;
 use std::path::PathBuf;
 use std::process;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use clap::{IntoApp, Parser};
 use clap_complete as complete;
 
 use crate::cli::raw::{Add, RawCommand, RawOpt};
-use crate::config::{EditPlugin, GitReference, RawPlugin, Shell};
+use crate::config::{EditPlugin, GitReference, RawApply, RawPlugin, Shell};
 use crate::context::{log_error, Color, Context, Output, Verbosity};
-use crate::lock::LockMode;
+use crate::lock::{LockMode, OnError};
 use crate::util::build;
 
 /// Parse the command line arguments.
@@ -47,15 +48,60 @@ pub enum Command {
     Add {
         name: String,
         plugin: Box<EditPlugin>,
+        verify: bool,
     },
     /// Open up the config file in the default editor.
     Edit,
     /// Remove a plugin from the config file.
     Remove { name: String },
+    /// List the plugins in the config file.
+    List { tags: Vec<String> },
+    /// Explain why a plugin is or is not in the generated script.
+    Why { name: String },
     /// Install the plugins sources and generate the lock file.
-    Lock,
+    Lock {
+        dry_run: bool,
+        clean: bool,
+        backup: bool,
+        print: bool,
+        install_only: bool,
+        diff: bool,
+        summary: bool,
+        dump_resolved: Option<PathBuf>,
+        prune: bool,
+        restore: bool,
+    },
     /// Generate and print out the script.
-    Source,
+    Source {
+        shells: Vec<Shell>,
+        output_dir: Option<PathBuf>,
+        measure: bool,
+        apply: Option<Vec<String>>,
+        no_lock: bool,
+        tags: Vec<String>,
+        eval_safe: bool,
+    },
+    /// Remove the unused files and directories in the data directory.
+    Clean {
+        since: Option<Duration>,
+        orphans_only: bool,
+    },
+    /// Permanently remove the files and directories in the trash.
+    Gc,
+    /// Check that the plugins in the lock file are installed.
+    Verify { checksums: bool },
+    /// Check that the external tools the config relies on are installed.
+    Health,
+    /// Export the config with every plugin pinned to its locked version.
+    Export { output: Option<PathBuf> },
+    /// Replace the config file with a snapshot and install it.
+    Import { path: PathBuf },
+    /// Write a tar bundle of the installed plugin sources to stdout.
+    ExportBundle,
+    /// Unpack a tar bundle of plugin sources from stdin.
+    ImportBundle,
+    /// Print out the resolved config and data paths.
+    Paths { json: bool },
 }
 
 impl Opt {
@@ -63,7 +109,9 @@ impl Opt {
         let RawOpt {
             quiet,
             verbose,
+            trace,
             color,
+            progress_format,
             home,
             data_dir,
             config_dir,
@@ -72,34 +120,107 @@ impl Opt {
             clone_dir,
             download_dir,
             profile,
+            max_download_size,
+            jobs,
+            match_jobs,
+            on_error,
+            keep_going,
+            strict,
+            credential_helper,
             command,
         } = raw_opt;
 
+        let on_error = if keep_going { OnError::Skip } else { on_error };
+
         let mut lock_mode = None;
+        let mut update_max_age = None;
 
         let command = match command {
             RawCommand::Init { shell } => Command::Init { shell },
             RawCommand::Add(add) => {
+                let verify = !add.no_verify;
                 let (name, plugin) = EditPlugin::from_add(*add);
                 Command::Add {
                     name,
                     plugin: Box::new(plugin),
+                    verify,
                 }
             }
             RawCommand::Edit => Command::Edit,
             RawCommand::Remove { name } => Command::Remove { name },
-            RawCommand::Lock { update, reinstall } => {
+            RawCommand::List { tag } => Command::List { tags: tag },
+            RawCommand::Why { name } => Command::Why { name },
+            RawCommand::Lock {
+                update,
+                reinstall,
+                update_max_age: max_age,
+                dry_run,
+                clean: _,
+                no_clean,
+                backup,
+                print,
+                install_only,
+                diff,
+                summary,
+                dump_resolved,
+                prune_lockfile,
+                restore,
+            } => {
                 lock_mode = LockMode::from_lock_flags(update, reinstall);
-                Command::Lock
+                update_max_age = max_age;
+                Command::Lock {
+                    dry_run,
+                    clean: !no_clean && !print && !install_only,
+                    backup,
+                    print,
+                    install_only,
+                    diff,
+                    summary,
+                    dump_resolved,
+                    prune: prune_lockfile,
+                    restore,
+                }
             }
             RawCommand::Source {
                 relock,
                 update,
                 reinstall,
+                update_max_age: max_age,
+                shell,
+                output_dir,
+                measure,
+                apply,
+                no_lock,
+                tag,
+                eval_safe,
             } => {
                 lock_mode = LockMode::from_source_flags(relock, update, reinstall);
-                Command::Source
+                update_max_age = max_age;
+                Command::Source {
+                    shells: shell,
+                    output_dir,
+                    measure,
+                    apply,
+                    no_lock,
+                    tags: tag,
+                    eval_safe,
+                }
             }
+            RawCommand::Clean {
+                since,
+                orphans_only,
+            } => Command::Clean {
+                since,
+                orphans_only,
+            },
+            RawCommand::Gc => Command::Gc,
+            RawCommand::Verify { checksums } => Command::Verify { checksums },
+            RawCommand::Health => Command::Health,
+            RawCommand::Export { output } => Command::Export { output },
+            RawCommand::Import { path } => Command::Import { path },
+            RawCommand::ExportBundle => Command::ExportBundle,
+            RawCommand::ImportBundle => Command::ImportBundle,
+            RawCommand::Paths { json } => Command::Paths { json },
             RawCommand::Completions { shell } => {
                 let mut app = RawOpt::into_app();
                 let shell = complete::Shell::from(shell);
@@ -114,6 +235,8 @@ impl Opt {
 
         let verbosity = if quiet {
             Verbosity::Quiet
+        } else if trace {
+            Verbosity::Trace
         } else if verbose {
             Verbosity::Verbose
         } else {
@@ -123,6 +246,7 @@ impl Opt {
         let output = Output {
             verbosity,
             no_color: color.is_no_color(),
+            progress_format,
         };
 
         let home = match home.or_else(home::home_dir).ok_or_else(|| {
@@ -184,6 +308,14 @@ impl Opt {
             profile,
             output,
             lock_mode,
+            max_download_size,
+            jobs,
+            match_jobs,
+            on_error,
+            strict,
+            credential_helper,
+            update_max_age,
+            gnupg_home: None,
         };
 
         Self { ctx, command }
@@ -207,6 +339,7 @@ impl EditPlugin {
             uses,
             apply,
             profiles,
+            no_verify: _,
         } = add;
 
         let reference = match (branch, rev, tag) {
@@ -230,11 +363,29 @@ impl EditPlugin {
                 inline: None,
                 proto,
                 reference,
+                before: None,
+                archive: None,
+                clone_as: None,
+                no_history: None,
+                verify_signature: None,
+                trusted_keys: None,
+                checksum: None,
+                filename: None,
+                version: None,
                 dir,
                 uses,
-                apply,
+                apply: apply.map(RawApply::Names),
+                apply_matching: None,
+                templates: None,
                 profiles,
+                shell: None,
+                follow_symlinks: None,
+                follow_dependencies: None,
+                version_from: None,
+                match_shebang: None,
+                order: None,
                 rest: None,
+                tags: None,
             }),
         )
     }
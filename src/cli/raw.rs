@@ -1,4 +1,5 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
 use clap::{AppSettings, ArgGroup, Parser};
 use clap_complete as complete;
@@ -6,6 +7,8 @@ use url::Url;
 
 use crate::cli::color_choice::ColorChoice;
 use crate::config::{GistRepository, GitHubRepository, GitProtocol, Shell};
+use crate::context::ProgressFormat;
+use crate::lock::{parse_duration, OnError};
 use crate::util::build;
 
 #[derive(Debug, PartialEq, Eq, Parser)]
@@ -29,10 +32,18 @@ pub struct RawOpt {
     #[clap(long, short)]
     pub verbose: bool,
 
+    /// Log every Git operation and filesystem change, more than `--verbose`.
+    #[clap(long)]
+    pub trace: bool,
+
     /// Output coloring: always, auto, or never.
     #[clap(long, value_name = "WHEN", default_value_t)]
     pub color: ColorChoice,
 
+    /// Also report progress as JSON lines on stderr: human or json.
+    #[clap(long, value_name = "FORMAT", default_value_t)]
+    pub progress_format: ProgressFormat,
+
     /// The home directory.
     #[clap(long, value_name = "PATH", hide(true))]
     pub home: Option<PathBuf>,
@@ -65,6 +76,34 @@ pub struct RawOpt {
     #[clap(long, value_name = "PROFILE", env = "SHELDON_PROFILE")]
     pub profile: Option<String>,
 
+    /// The maximum size of remote downloads in bytes.
+    #[clap(long, value_name = "BYTES", env = "SHELDON_MAX_DOWNLOAD_SIZE")]
+    pub max_download_size: Option<u64>,
+
+    /// The number of plugin sources to install in parallel.
+    #[clap(long, value_name = "N", env = "SHELDON_JOBS")]
+    pub jobs: Option<usize>,
+
+    /// The number of plugins to match files for in parallel.
+    #[clap(long, value_name = "N", env = "SHELDON_MATCH_JOBS")]
+    pub match_jobs: Option<usize>,
+
+    /// What to do when a plugin fails to install: skip or abort.
+    #[clap(long, value_name = "POLICY", env = "SHELDON_ON_ERROR", default_value_t)]
+    pub on_error: OnError,
+
+    /// Continue past plugins that fail to install, overriding `--on-error`.
+    #[clap(short = 'k', long)]
+    pub keep_going: bool,
+
+    /// Treat warnings about the locked plugins as errors.
+    #[clap(long)]
+    pub strict: bool,
+
+    /// The Git credential helper to use for private repositories.
+    #[clap(long, value_name = "HELPER", env = "SHELDON_CREDENTIAL_HELPER")]
+    pub credential_helper: Option<String>,
+
     /// The subcommand to run.
     #[clap(subcommand)]
     pub command: RawCommand,
@@ -92,6 +131,20 @@ pub enum RawCommand {
         name: String,
     },
 
+    /// List the plugins in the config file.
+    List {
+        /// Only list the plugins with this tag. Can be given more than once.
+        #[clap(long, value_name = "TAG", multiple_occurrences(true))]
+        tag: Vec<String>,
+    },
+
+    /// Explain why a plugin is or is not in the generated script.
+    Why {
+        /// The name of the plugin.
+        #[clap(value_name = "NAME")]
+        name: String,
+    },
+
     /// Install the plugins sources and generate the lock file.
     Lock {
         /// Update all plugin sources.
@@ -101,6 +154,75 @@ pub enum RawCommand {
         /// Reinstall all plugin sources.
         #[clap(long, conflicts_with = "update")]
         reinstall: bool,
+
+        /// Only update Git sources to commits at least this old, for example
+        /// `7d`.
+        #[clap(
+            long,
+            value_name = "DURATION",
+            requires = "update",
+            parse(try_from_str = parse_duration)
+        )]
+        update_max_age: Option<Duration>,
+
+        /// Only show what would be done, without installing anything.
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Remove unused files and directories after locking (the default).
+        #[clap(long)]
+        clean: bool,
+
+        /// Do not remove unused files and directories after locking.
+        #[clap(long, conflicts_with = "clean")]
+        no_clean: bool,
+
+        /// Copy the previous lock file to a backup before overwriting it.
+        #[clap(long)]
+        backup: bool,
+
+        /// Print the lock file to stdout instead of writing it, without cleaning.
+        #[clap(long, conflicts_with_all = &["dry-run", "clean", "backup"])]
+        print: bool,
+
+        /// Only install the plugin sources, without rendering the plugins or
+        /// writing the lock file.
+        #[clap(
+            long,
+            conflicts_with_all = &["dry-run", "clean", "no-clean", "backup", "print"]
+        )]
+        install_only: bool,
+
+        /// Show how the lock file would change, and only write it if confirmed.
+        #[clap(long, conflicts_with_all = &["dry-run", "print", "install-only"])]
+        diff: bool,
+
+        /// Show a summary of what was locked afterwards.
+        #[clap(long, conflicts_with_all = &["dry-run", "install-only"])]
+        summary: bool,
+
+        /// Also write the resolved config that is locked to this file.
+        #[clap(
+            long,
+            value_name = "PATH",
+            conflicts_with_all = &["install-only"]
+        )]
+        dump_resolved: Option<PathBuf>,
+
+        /// Remove plugins no longer in the config from the lock file, without
+        /// installing anything.
+        #[clap(
+            long,
+            conflicts_with_all = &["update", "reinstall", "dry-run", "print", "install-only", "diff", "summary", "dump-resolved"]
+        )]
+        prune_lockfile: bool,
+
+        /// Swap the lock file with its backup, without locking anything.
+        #[clap(
+            long,
+            conflicts_with_all = &["update", "reinstall", "dry-run", "clean", "no-clean", "backup", "print", "install-only", "diff", "summary", "dump-resolved", "prune-lockfile"]
+        )]
+        restore: bool,
     },
 
     /// Generate and print out the script.
@@ -116,6 +238,102 @@ pub enum RawCommand {
         /// Reinstall all plugin sources (implies --relock).
         #[clap(long, conflicts_with = "update")]
         reinstall: bool,
+
+        /// Only update Git sources to commits at least this old, for example
+        /// `7d`.
+        #[clap(
+            long,
+            value_name = "DURATION",
+            requires = "update",
+            parse(try_from_str = parse_duration)
+        )]
+        update_max_age: Option<Duration>,
+
+        /// Render for this shell instead, accepted values are: bash, zsh. Can be
+        /// given more than once with --output-dir.
+        #[clap(long, value_name = "SHELL", multiple_occurrences(true))]
+        shell: Vec<Shell>,
+
+        /// Write the script for each --shell to `<shell>.sh` in this directory.
+        #[clap(long, value_name = "DIR", requires = "shell")]
+        output_dir: Option<PathBuf>,
+
+        /// Print the size in bytes of each plugin in the script, instead of the
+        /// script.
+        #[clap(long, conflicts_with = "output-dir")]
+        measure: bool,
+
+        /// Apply these templates to every plugin instead of the configured
+        /// ones, for example `source,fpath`.
+        #[clap(long, value_name = "TEMPLATE", use_value_delimiter(true))]
+        apply: Option<Vec<String>>,
+
+        /// Lock the plugins in memory only, without writing the lock file.
+        #[clap(long, conflicts_with = "relock")]
+        no_lock: bool,
+
+        /// Only render the plugins with this tag. Can be given more than once.
+        #[clap(long, value_name = "TAG", multiple_occurrences(true))]
+        tag: Vec<String>,
+
+        /// Check that the script parses using `bash -n` or `zsh -n` before
+        /// printing it.
+        #[clap(long, conflicts_with = "measure")]
+        eval_safe: bool,
+    },
+
+    /// Remove the unused files and directories in the data directory.
+    Clean {
+        /// Only remove downloads that have not been modified for this long, for
+        /// example `30d`.
+        #[clap(long, value_name = "DURATION", parse(try_from_str = parse_duration))]
+        since: Option<Duration>,
+
+        /// Only remove directories that are not used by any plugin, leaving
+        /// stray files alone.
+        #[clap(long)]
+        orphans_only: bool,
+    },
+
+    /// Permanently remove the files and directories in the trash.
+    Gc,
+
+    /// Check that the plugins in the lock file are installed.
+    Verify {
+        /// Also check the downloaded files against their checksums in the lock
+        /// file.
+        #[clap(long)]
+        checksums: bool,
+    },
+
+    /// Check that the external tools the config relies on are installed.
+    Health,
+
+    /// Export the config with every plugin pinned to its locked version.
+    Export {
+        /// Write the snapshot to this file instead of stdout.
+        #[clap(long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+
+    /// Replace the config file with a snapshot and install it.
+    Import {
+        /// The snapshot file to import.
+        #[clap(value_name = "PATH")]
+        path: PathBuf,
+    },
+
+    /// Write a tar bundle of the installed plugin sources to stdout.
+    ExportBundle,
+
+    /// Unpack a tar bundle of plugin sources from stdin.
+    ImportBundle,
+
+    /// Print out the resolved config and data paths.
+    Paths {
+        /// Print the paths as a JSON object.
+        #[clap(long)]
+        json: bool,
     },
 
     /// Generate completions for the given shell.
@@ -190,6 +408,10 @@ pub struct Add {
     /// Only use this plugin under one of the given profiles
     #[clap(long, value_name = "PROFILES", multiple_values(true))]
     pub profiles: Option<Vec<String>>,
+
+    /// Don't check that the repository and reference exist
+    #[clap(long)]
+    pub no_verify: bool,
 }
 
 impl From<Shell> for complete::Shell {
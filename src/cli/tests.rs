@@ -1,11 +1,14 @@
 use super::*;
 
 use std::iter;
+use std::path::Path;
 
 use pretty_assertions::assert_eq;
 use serde::Serialize;
 
 use crate::cli::color_choice::ColorChoice;
+use crate::context::ProgressFormat;
+use crate::lock::OnError;
 
 fn setup() {
     for (k, _) in env::vars() {
@@ -74,7 +77,9 @@ fn raw_opt_no_options() {
         RawOpt {
             quiet: false,
             verbose: false,
+            trace: false,
             color: Default::default(),
+            progress_format: Default::default(),
             home: None,
             config_dir: None,
             data_dir: None,
@@ -83,9 +88,28 @@ fn raw_opt_no_options() {
             clone_dir: None,
             download_dir: None,
             profile: None,
+            max_download_size: None,
+            jobs: None,
+            match_jobs: None,
+            on_error: OnError::Skip,
+            keep_going: false,
+            strict: false,
+            credential_helper: None,
             command: RawCommand::Lock {
                 update: false,
-                reinstall: false
+                reinstall: false,
+                update_max_age: None,
+                dry_run: false,
+                clean: false,
+                no_clean: false,
+                backup: false,
+                print: false,
+                install_only: false,
+                diff: false,
+                summary: false,
+                dump_resolved: None,
+                prune_lockfile: false,
+                restore: false,
             },
         }
     );
@@ -98,8 +122,11 @@ fn raw_opt_options() {
         raw_opt(&[
             "--quiet",
             "--verbose",
+            "--trace",
             "--color",
             "never",
+            "--progress-format",
+            "json",
             "--home",
             "/",
             "--config-dir",
@@ -116,12 +143,25 @@ fn raw_opt_options() {
             "/downloads",
             "--profile",
             "profile",
+            "--max-download-size",
+            "1024",
+            "--jobs",
+            "4",
+            "--match-jobs",
+            "2",
+            "--on-error",
+            "abort",
+            "--strict",
+            "--credential-helper",
+            "store",
             "lock",
         ]),
         RawOpt {
             quiet: true,
             verbose: true,
+            trace: true,
             color: ColorChoice::Never,
+            progress_format: ProgressFormat::Json,
             home: Some("/".into()),
             config_dir: Some("/test".into()),
             data_dir: Some("/test".into()),
@@ -130,9 +170,28 @@ fn raw_opt_options() {
             clone_dir: Some("/repos".into()),
             download_dir: Some("/downloads".into()),
             profile: Some("profile".into()),
+            max_download_size: Some(1024),
+            jobs: Some(4),
+            match_jobs: Some(2),
+            on_error: OnError::Abort,
+            keep_going: false,
+            strict: true,
+            credential_helper: Some("store".into()),
             command: RawCommand::Lock {
                 update: false,
-                reinstall: false
+                reinstall: false,
+                update_max_age: None,
+                dry_run: false,
+                clean: false,
+                no_clean: false,
+                backup: false,
+                print: false,
+                install_only: false,
+                diff: false,
+                summary: false,
+                dump_resolved: None,
+                prune_lockfile: false,
+                restore: false,
             },
         }
     );
@@ -220,6 +279,7 @@ fn raw_opt_add_git_with_options() {
             uses: Some(vec_into!["{name}.sh", "*.zsh"]),
             apply: Some(vec_into!["something", "another-thing"]),
             profiles: None,
+            no_verify: false,
         }))
     );
 }
@@ -262,6 +322,7 @@ fn raw_opt_add_gist_options() {
             uses: Some(vec_into!["{name}.sh", "*.zsh"]),
             apply: Some(vec_into!["something", "another-thing"]),
             profiles: None,
+            no_verify: false,
         }))
     );
 }
@@ -304,6 +365,7 @@ fn raw_opt_add_github_options() {
             uses: Some(vec_into!["{name}.sh", "*.zsh"]),
             apply: Some(vec_into!["something", "another-thing"]),
             profiles: None,
+            no_verify: false,
         }))
     );
 }
@@ -340,6 +402,7 @@ fn raw_opt_add_remote_options() {
             uses: Some(vec_into!["{name}.sh", "*.zsh"]),
             apply: Some(vec_into!["something", "another-thing"]),
             profiles: None,
+            no_verify: false,
         }))
     );
 }
@@ -376,6 +439,7 @@ fn raw_opt_add_local_options() {
             uses: Some(vec_into!["{name}.sh", "*.zsh"]),
             apply: Some(vec_into!["something", "another-thing"]),
             profiles: None,
+            no_verify: false,
         }))
     );
 }
@@ -499,6 +563,43 @@ fn raw_opt_lock_with_update_and_reinstall_expect_conflict() {
     );
 }
 
+#[test]
+fn raw_opt_lock_with_clean_and_no_clean_expect_conflict() {
+    setup();
+    assert_eq!(
+        raw_opt_err(&["lock", "--clean", "--no-clean"]).kind,
+        clap::ErrorKind::ArgumentConflict
+    );
+}
+
+#[test]
+fn opt_lock_clean() {
+    setup();
+    for (args, expected) in [
+        (&["lock"][..], true),
+        (&["lock", "--clean"][..], true),
+        (&["lock", "--no-clean"][..], false),
+        (&["lock", "--print"][..], false),
+    ] {
+        let opt = Opt::from_raw_opt(raw_opt(args));
+        assert!(matches!(opt.command, Command::Lock { clean, .. } if clean == expected));
+    }
+}
+
+#[test]
+fn opt_on_error_and_keep_going() {
+    setup();
+    for (args, expected) in [
+        (&["lock"][..], OnError::Skip),
+        (&["--on-error", "abort", "lock"][..], OnError::Abort),
+        (&["--keep-going", "lock"][..], OnError::Skip),
+        (&["--on-error", "abort", "-k", "lock"][..], OnError::Skip),
+    ] {
+        let opt = Opt::from_raw_opt(raw_opt(args));
+        assert_eq!(opt.ctx.on_error(), expected);
+    }
+}
+
 #[test]
 fn raw_opt_source_help() {
     setup();
@@ -515,3 +616,176 @@ fn raw_opt_source_with_update_and_reinstall_expect_conflict() {
         clap::ErrorKind::ArgumentConflict
     );
 }
+
+#[test]
+fn opt_source_shell() {
+    setup();
+    let opt = Opt::from_raw_opt(raw_opt(&["source", "--shell", "bash"]));
+    assert!(matches!(
+        opt.command,
+        Command::Source {
+            shells,
+            output_dir: None,
+            measure: false,
+            apply: None,
+            no_lock: false,
+            tags,
+            eval_safe: false,
+        } if shells == [Shell::Bash] && tags.is_empty()
+    ));
+    let opt = Opt::from_raw_opt(raw_opt(&["source"]));
+    assert!(matches!(
+        opt.command,
+        Command::Source {
+            shells,
+            output_dir: None,
+            measure: false,
+            apply: None,
+            no_lock: false,
+            tags,
+            eval_safe: false,
+        } if shells.is_empty() && tags.is_empty()
+    ));
+}
+
+#[test]
+fn opt_source_and_list_tag() {
+    setup();
+    let opt = Opt::from_raw_opt(raw_opt(&["source", "--tag", "prompt", "--tag", "slow"]));
+    assert!(matches!(
+        opt.command,
+        Command::Source { tags, .. } if tags == ["prompt", "slow"]
+    ));
+    let opt = Opt::from_raw_opt(raw_opt(&["list", "--tag", "slow"]));
+    assert!(matches!(opt.command, Command::List { tags } if tags == ["slow"]));
+}
+
+#[test]
+fn opt_why() {
+    setup();
+    let opt = Opt::from_raw_opt(raw_opt(&["why", "example"]));
+    assert!(matches!(opt.command, Command::Why { name } if name == "example"));
+}
+
+#[test]
+fn opt_lock_and_source_update_max_age() {
+    setup();
+    let opt = Opt::from_raw_opt(raw_opt(&["lock", "--update", "--update-max-age", "7d"]));
+    assert_eq!(opt.ctx.lock_mode, Some(LockMode::Update));
+    assert_eq!(
+        opt.ctx.update_max_age,
+        Some(Duration::from_secs(7 * 24 * 60 * 60))
+    );
+    let opt = Opt::from_raw_opt(raw_opt(&["source", "--update", "--update-max-age", "12h"]));
+    assert_eq!(
+        opt.ctx.update_max_age,
+        Some(Duration::from_secs(12 * 60 * 60))
+    );
+    assert_eq!(
+        raw_opt_err(&["lock", "--update-max-age", "7d"]).kind,
+        clap::ErrorKind::MissingRequiredArgument
+    );
+}
+
+#[test]
+fn opt_source_eval_safe() {
+    setup();
+    let opt = Opt::from_raw_opt(raw_opt(&["source", "--eval-safe"]));
+    assert!(matches!(
+        opt.command,
+        Command::Source {
+            eval_safe: true,
+            ..
+        }
+    ));
+    assert_eq!(
+        raw_opt_err(&["source", "--eval-safe", "--measure"]).kind,
+        clap::ErrorKind::ArgumentConflict
+    );
+}
+
+#[test]
+fn raw_opt_source_with_no_lock_and_relock_expect_conflict() {
+    setup();
+    assert_eq!(
+        raw_opt_err(&["source", "--no-lock", "--relock"]).kind,
+        clap::ErrorKind::ArgumentConflict
+    );
+}
+
+#[test]
+fn opt_source_apply() {
+    setup();
+    let opt = Opt::from_raw_opt(raw_opt(&["source", "--apply", "source,fpath"]));
+    assert!(matches!(
+        opt.command,
+        Command::Source { apply: Some(apply), .. } if apply == ["source", "fpath"]
+    ));
+}
+
+#[test]
+fn raw_opt_lock_with_restore_and_update_expect_conflict() {
+    setup();
+    assert_eq!(
+        raw_opt_err(&["lock", "--restore", "--update"]).kind,
+        clap::ErrorKind::ArgumentConflict
+    );
+    assert_eq!(
+        raw_opt_err(&["lock", "--restore", "--backup"]).kind,
+        clap::ErrorKind::ArgumentConflict
+    );
+}
+
+#[test]
+fn raw_opt_lock_with_install_only_expect_conflict() {
+    setup();
+    for flag in ["--dry-run", "--print", "--backup", "--restore"] {
+        assert_eq!(
+            raw_opt_err(&["lock", "--install-only", flag]).kind,
+            clap::ErrorKind::ArgumentConflict
+        );
+    }
+}
+
+#[test]
+fn raw_opt_lock_with_prune_lockfile_expect_conflict() {
+    setup();
+    for flag in ["--update", "--dry-run", "--print", "--diff", "--restore"] {
+        assert_eq!(
+            raw_opt_err(&["lock", "--prune-lockfile", flag]).kind,
+            clap::ErrorKind::ArgumentConflict
+        );
+    }
+}
+
+#[test]
+fn opt_lock_dump_resolved() {
+    setup();
+    let opt = Opt::from_raw_opt(raw_opt(&["lock", "--dump-resolved", "resolved.toml"]));
+    assert!(matches!(
+        opt.command,
+        Command::Lock { dump_resolved: Some(path), .. } if path == Path::new("resolved.toml")
+    ));
+    for flag in ["--install-only", "--prune-lockfile", "--restore"] {
+        assert_eq!(
+            raw_opt_err(&["lock", "--dump-resolved", "resolved.toml", flag]).kind,
+            clap::ErrorKind::ArgumentConflict
+        );
+    }
+}
+
+#[test]
+fn opt_lock_backup_and_restore() {
+    setup();
+    for (args, expected) in [
+        (&["lock"][..], (false, false)),
+        (&["lock", "--backup"][..], (true, false)),
+        (&["lock", "--restore"][..], (false, true)),
+    ] {
+        let opt = Opt::from_raw_opt(raw_opt(args));
+        assert!(matches!(
+            opt.command,
+            Command::Lock { backup, restore, .. } if (backup, restore) == expected
+        ));
+    }
+}
@@ -0,0 +1,68 @@
+//! Deprecated config keys that are still accepted.
+//!
+//! Renamed keys are migrated to their new names before the config is
+//! deserialized, with a warning pointing at the new name.
+
+use anyhow::{anyhow, Error};
+use toml::value::Table;
+
+/// Plugin config keys that have been renamed, as pairs of the old and new
+/// name.
+const PLUGIN_KEYS: &[(&str, &str)] = &[("protocol", "proto")];
+
+/// Rename any deprecated keys in the given config file contents, returning
+/// whether any were renamed.
+///
+/// The plugins in groups are also migrated. A deprecated key is left alone if
+/// the new key is also set, so that it is reported as unused.
+pub fn migrate(value: &mut toml::Value, warnings: &mut Vec<Error>) -> bool {
+    let count = warnings.len();
+    let table = match value.as_table_mut() {
+        Some(table) => table,
+        None => return false,
+    };
+    migrate_plugins(table, "plugins", warnings);
+    if let Some(groups) = table.get_mut("groups").and_then(toml::Value::as_table_mut) {
+        for (group, value) in groups.iter_mut() {
+            if let Some(table) = value.as_table_mut() {
+                migrate_plugins(table, &format!("groups.{}.plugins", group), warnings);
+            }
+        }
+    }
+    warnings.len() > count
+}
+
+fn migrate_plugins(table: &mut Table, prefix: &str, warnings: &mut Vec<Error>) {
+    let plugins = match table.get_mut("plugins").and_then(toml::Value::as_table_mut) {
+        Some(plugins) => plugins,
+        None => return,
+    };
+    for (name, plugin) in plugins.iter_mut() {
+        if let Some(plugin) = plugin.as_table_mut() {
+            rename_keys(
+                plugin,
+                PLUGIN_KEYS,
+                &format!("{}.{}", prefix, name),
+                warnings,
+            );
+        }
+    }
+}
+
+fn rename_keys(table: &mut Table, keys: &[(&str, &str)], prefix: &str, warnings: &mut Vec<Error>) {
+    for (old, new) in keys {
+        if table.contains_key(*new) {
+            continue;
+        }
+        if let Some(value) = table.remove(*old) {
+            warnings.push(anyhow!(
+                "use of deprecated config key: `{prefix}.{old}`, please use `{prefix}.{new}` \
+                 instead",
+                prefix = prefix,
+                old = old,
+                new = new,
+            ));
+            table.insert(new.to_string(), value);
+        }
+    }
+}
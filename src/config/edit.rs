@@ -6,7 +6,8 @@ use std::path::Path;
 
 use anyhow::{bail, Context as ResultExt, Result};
 
-use crate::config::{RawPlugin, Shell};
+use crate::config::normalize::{gist_url, github_url};
+use crate::config::{RawPlugin, Shell, Source};
 
 /// An editable plugin.
 #[derive(Debug)]
@@ -27,6 +28,37 @@ impl From<RawPlugin> for EditPlugin {
     }
 }
 
+impl EditPlugin {
+    /// Returns the Git source of this plugin, if it has one.
+    pub fn git_source(&self) -> Result<Option<Source>> {
+        let RawPlugin {
+            git,
+            gist,
+            github,
+            proto,
+            reference,
+            archive,
+            clone_as,
+            no_history,
+            ..
+        } = &self.inner;
+        let url = match (git, gist, github) {
+            (Some(url), None, None) => url.clone(),
+            (None, Some(repository), None) => gist_url(repository, *proto)?,
+            (None, None, Some(repository)) => github_url(repository, *proto)?,
+            _ => return Ok(None),
+        };
+        Ok(Some(Source::Git {
+            url,
+            reference: reference.clone(),
+            archive: archive.unwrap_or(false),
+            clone_as: clone_as.clone(),
+            no_history: no_history.unwrap_or(false),
+            verify_signature: None,
+        }))
+    }
+}
+
 impl fmt::Display for EditConfig {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.doc)
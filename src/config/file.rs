@@ -14,10 +14,10 @@ use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use thiserror::Error;
 use url::Url;
 
-use crate::config::{GitReference, Shell, Template};
+use crate::config::{Condition, GitReference, Manifest, Shell, Template};
 
 /// The contents of the configuration file.
-#[derive(Debug, Default, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(default)]
 pub struct RawConfig {
     /// What type of shell is being used.
@@ -29,6 +29,71 @@ pub struct RawConfig {
     pub apply: Option<Vec<String>>,
     /// A map of name to template string.
     pub templates: IndexMap<String, Template>,
+    /// The built-in templates to remove.
+    pub remove_templates: Option<Vec<String>>,
+    /// Which files and directories in the clone and download directories
+    /// should never be removed when cleaning.
+    pub clean_ignore: Option<Vec<String>>,
+    /// A raw snippet to append to the end of the generated script.
+    pub footer: Option<String>,
+    /// Whether to accumulate the `PATH`, `path`, and `fpath` templates into a
+    /// single deduplicated assignment at the end of the generated script.
+    pub dedupe_paths: Option<bool>,
+    /// Whether to remove the zsh completion dump when the lock file is newer
+    /// than it.
+    pub invalidate_compdump: Option<bool>,
+    /// Whether to wrap the generated script in a guard so that it is only
+    /// sourced once.
+    pub guard: Option<bool>,
+    /// Whether to wrap each plugin in the generated script with measurements
+    /// that print how long it took to load.
+    pub profile_startup: Option<bool>,
+    /// Whether to move cleaned files and directories to the trash directory
+    /// instead of removing them.
+    pub trash: Option<bool>,
+    /// Whether to fail locking if the working tree of a Git source has
+    /// uncommitted changes.
+    pub require_clean: Option<bool>,
+    /// Whether to rewrite matched files of Git and Remote sources that have
+    /// CRLF line endings with LF line endings.
+    pub normalize_line_endings: Option<bool>,
+    /// An environment variable that lists the plugins in the generated script.
+    pub manifest: Option<Manifest>,
+    /// The name of a function to wrap the generated script in, so that it is
+    /// only loaded when the function is called.
+    pub wrap_in_function: Option<String>,
+    /// A template for the directory in the clone directory that Git sources
+    /// are cloned to.
+    pub clone_dir_layout: Option<String>,
+    /// The profiles that are active when no profile is given.
+    pub active_profiles: Option<Vec<String>>,
+    /// Other config files to load plugins from.
+    pub imports: Option<Vec<PathBuf>>,
+    /// A directory of config file fragments to load plugins from.
+    pub fragments: Option<PathBuf>,
+    /// A map of name to plugin.
+    pub plugins: IndexMap<String, RawPlugin>,
+    /// A map of name to group of plugins.
+    pub groups: IndexMap<String, RawGroup>,
+    /// Any extra keys,
+    #[serde(flatten, deserialize_with = "deserialize_rest_toml_value")]
+    pub rest: Option<toml::Value>,
+}
+
+/// A config file fragment, which only defines plugins.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RawFragment {
+    /// A map of name to plugin.
+    pub plugins: IndexMap<String, RawPlugin>,
+}
+
+/// A named group of plugins that are enabled or disabled together.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RawGroup {
+    /// Whether the plugins in this group are used. This defaults to `true`.
+    pub enabled: Option<bool>,
     /// A map of name to plugin.
     pub plugins: IndexMap<String, RawPlugin>,
     /// Any extra keys,
@@ -57,6 +122,30 @@ pub struct RawPlugin {
     /// The Git reference to checkout.
     #[serde(flatten)]
     pub reference: Option<GitReference>,
+    /// Only checkout commits on the branch from before this date.
+    pub before: Option<String>,
+    /// Whether to remove the `.git` directory after cloning. This defaults to
+    /// `false`.
+    pub archive: Option<bool>,
+    /// The name of the directory in the clone directory to clone to, instead of
+    /// one based on the URL.
+    pub clone_as: Option<String>,
+    /// Whether to fetch only the tree at the reference using `git archive`,
+    /// instead of cloning the history. This defaults to `false`.
+    pub no_history: Option<bool>,
+    /// Whether to verify the GPG signature of the checked out tag or commit.
+    /// This defaults to `false`.
+    pub verify_signature: Option<bool>,
+    /// The IDs or fingerprints of the keys that the checked out tag or commit
+    /// must be signed by.
+    pub trusted_keys: Option<Vec<String>>,
+    /// The expected checksum of a downloaded file.
+    pub checksum: Option<String>,
+    /// The file name to save a downloaded file as, instead of one based on the
+    /// URL.
+    pub filename: Option<String>,
+    /// The version to render into the `remote` URL, using `{{ version }}`.
+    pub version: Option<String>,
     /// Which directory to use in this plugin.
     ///
     /// This directory can contain template parameters.
@@ -67,17 +156,56 @@ pub struct RawPlugin {
     /// These files can contain template parameters.
     #[serde(rename = "use")]
     pub uses: Option<Vec<String>>,
-    /// What templates to apply to each matched file. If this is `None` then the
-    /// default templates will be applied.
-    pub apply: Option<Vec<String>>,
+    /// What templates to apply to each matched file, or a map of template name
+    /// to a glob pattern of the files to apply it to. If this is `None` then
+    /// the default templates will be applied.
+    pub apply: Option<RawApply>,
+    /// A map of glob pattern to the templates to apply to the matched files
+    /// that match that pattern, instead of the `apply` templates.
+    pub apply_matching: Option<IndexMap<String, Vec<String>>>,
+    /// Templates that are only available to this plugin. These take precedence
+    /// over the global templates with the same name.
+    pub templates: Option<IndexMap<String, Template>>,
     /// If configured, only installs this plugin if one of the given profiles is
     /// set in the SHELDON_PROFILE environment variable.
     pub profiles: Option<Vec<String>>,
+    /// If configured, only installs this plugin when generating the script for
+    /// this shell.
+    pub shell: Option<Shell>,
+    /// Whether to follow symlinked directories when matching files. This
+    /// defaults to `false`.
+    pub follow_symlinks: Option<bool>,
+    /// Whether to also lock the plugins declared in this plugin's
+    /// `sheldon.plugins.toml` file. This defaults to `false`.
+    pub follow_dependencies: Option<bool>,
+    /// A file, relative to the plugin directory, to read the plugin's version
+    /// from. A dotted key can be given after a `#` to read the version from a
+    /// JSON file.
+    pub version_from: Option<String>,
+    /// Also use the files in the plugin directory whose first line starts with
+    /// this prefix, for example `#compdef`.
+    pub match_shebang: Option<String>,
+    /// Files, relative to the plugin directory, to source first in the given
+    /// order. The other matched files follow in the order they were matched.
+    pub order: Option<Vec<String>>,
+    /// Labels for selecting this plugin with the `--tag` option, unlike
+    /// profiles these do not affect whether the plugin is installed.
+    pub tags: Option<Vec<String>>,
     /// Any extra keys,
     #[serde(flatten, deserialize_with = "deserialize_rest_toml_value")]
     pub rest: Option<toml::Value>,
 }
 
+/// The templates to apply to a plugin's files.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum RawApply {
+    /// The names of the templates to apply to each matched file.
+    Names(Vec<String>),
+    /// A map of template name to a glob pattern of the files to apply it to.
+    Globs(IndexMap<String, String>),
+}
+
 /// A Gist repository identifier.
 #[derive(Debug, PartialEq, Eq)]
 pub struct GistRepository {
@@ -172,12 +300,6 @@ impl_serialize_as_str! { GitHubRepository }
 // Deserialization implementations
 ////////////////////////////////////////////////////////////////////////////////
 
-impl Default for Shell {
-    fn default() -> Self {
-        Self::Zsh
-    }
-}
-
 /// Produced when we fail to parse the shell type.
 #[derive(Debug, Error)]
 #[error("expected one of `bash` or `zsh`, got `{}`", self.0)]
@@ -205,13 +327,28 @@ mod template {
     #[derive(Deserialize)]
     struct TemplateAux {
         value: String,
+        #[serde(default)]
         each: bool,
+        #[serde(default)]
+        requires: Vec<String>,
+        #[serde(default)]
+        when: Option<Condition>,
     }
 
     impl From<TemplateAux> for Template {
         fn from(aux: TemplateAux) -> Self {
-            let TemplateAux { value, each } = aux;
-            Self { value, each }
+            let TemplateAux {
+                value,
+                each,
+                requires,
+                when,
+            } = aux;
+            Self {
+                value,
+                each,
+                requires,
+                when,
+            }
         }
     }
 
@@ -220,6 +357,8 @@ mod template {
             Self {
                 value: s.to_string(),
                 each: false,
+                requires: Vec::new(),
+                when: None,
             }
         }
     }
@@ -450,7 +589,9 @@ mod tests {
             test.t,
             Template {
                 value: "test".to_string(),
-                each: false
+                each: false,
+                requires: Vec::new(),
+                when: None,
             }
         );
     }
@@ -462,7 +603,24 @@ mod tests {
             test.t,
             Template {
                 value: "test".to_string(),
-                each: true
+                each: true,
+                requires: Vec::new(),
+                when: None,
+            }
+        );
+    }
+
+    #[test]
+    fn template_deserialize_with_requires() {
+        let test: TemplateTest =
+            toml::from_str("t = { value = 'test {{ dir }}', requires = ['dir'] }").unwrap();
+        assert_eq!(
+            test.t,
+            Template {
+                value: "test {{ dir }}".to_string(),
+                each: false,
+                requires: vec_into!["dir"],
+                when: None,
             }
         );
     }
@@ -500,6 +658,19 @@ mod tests {
         assert_eq!(test.g, GitReference::Rev(String::from("cd65e828")));
     }
 
+    #[test]
+    fn git_reference_deserialize_fallback() {
+        let test: TestGitReference =
+            toml::from_str("reference = [{ tag = 'v0.5.1' }, { branch = 'master' }]").unwrap();
+        assert_eq!(
+            test.g,
+            GitReference::Fallback(vec![
+                GitReference::Tag(String::from("v0.5.1")),
+                GitReference::Branch(String::from("master"))
+            ])
+        );
+    }
+
     #[derive(Debug, Deserialize)]
     struct TestGistRepository {
         g: GistRepository,
@@ -1,22 +1,29 @@
 //! The user configuration.
 
+mod deprecated;
 mod edit;
 mod file;
 mod normalize;
 mod profile;
+mod snapshot;
 
+use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::str;
 
-use anyhow::{Context as ResultExt, Error, Result};
+use anyhow::{bail, Context as ResultExt, Error, Result};
 use indexmap::IndexMap;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
 pub use crate::config::edit::{EditConfig, EditPlugin};
-pub use crate::config::file::{GistRepository, GitHubRepository, GitProtocol, RawPlugin};
-pub use crate::config::profile::MatchesProfile;
+pub use crate::config::file::{GistRepository, GitHubRepository, GitProtocol, RawApply, RawPlugin};
+use crate::config::file::{RawConfig, RawFragment};
+pub use crate::config::profile::{active_profiles, MatchesProfile};
+pub use crate::config::snapshot::snapshot;
+use crate::context::Context;
 
 /// The user configuration.
 #[derive(Debug)]
@@ -29,17 +36,106 @@ pub struct Config {
     pub apply: Option<Vec<String>>,
     /// A map of name to template string.
     pub templates: IndexMap<String, Template>,
+    /// The built-in templates to remove.
+    pub remove_templates: Option<Vec<String>>,
+    /// Which files and directories in the clone and download directories
+    /// should never be removed when cleaning.
+    pub clean_ignore: Option<Vec<String>>,
+    /// A raw snippet to append to the end of the generated script.
+    pub footer: Option<String>,
+    /// Whether to accumulate the `PATH`, `path`, and `fpath` templates into a
+    /// single deduplicated assignment at the end of the generated script.
+    pub dedupe_paths: Option<bool>,
+    /// Whether to remove the zsh completion dump when the lock file is newer
+    /// than it.
+    pub invalidate_compdump: Option<bool>,
+    /// Whether to wrap the generated script in a guard so that it is only
+    /// sourced once.
+    pub guard: Option<bool>,
+    /// Whether to wrap each plugin in the generated script with measurements
+    /// that print how long it took to load.
+    pub profile_startup: Option<bool>,
+    /// Whether to move cleaned files and directories to the trash directory
+    /// instead of removing them.
+    pub trash: Option<bool>,
+    /// Whether to fail locking if the working tree of a Git source has
+    /// uncommitted changes.
+    pub require_clean: Option<bool>,
+    /// Whether to rewrite matched files of Git and Remote sources that have
+    /// CRLF line endings with LF line endings.
+    pub normalize_line_endings: Option<bool>,
+    /// An environment variable that lists the plugins in the generated script.
+    pub manifest: Option<Manifest>,
+    /// The name of a function to wrap the generated script in, so that it is
+    /// only loaded when the function is called.
+    pub wrap_in_function: Option<String>,
+    /// A template for the directory in the clone directory that Git sources
+    /// are cloned to.
+    pub clone_dir_layout: Option<String>,
+    /// The profiles that are active when no profile is given.
+    pub active_profiles: Option<Vec<String>>,
+    /// The other config files that plugins were loaded from.
+    pub imports: Vec<PathBuf>,
     /// Each configured plugin.
     pub plugins: Vec<Plugin>,
+    /// The configured plugins that are never locked, and why.
+    pub skipped: IndexMap<String, SkipReason>,
+}
+
+/// Why a configured plugin is not locked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The plugin is in a group that is not enabled.
+    Group(String),
+    /// None of the plugin's profiles are active.
+    Profiles(Vec<String>),
+    /// The plugin is only for another shell.
+    Shell(Shell),
 }
 
 /// The type of shell that we are using.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
 pub enum Shell {
     Bash,
+    #[default]
     Zsh,
 }
 
+/// An environment variable that lists the plugins in the generated script.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+    /// The name of the environment variable.
+    pub variable: String,
+    /// The separator between each entry.
+    pub separator: String,
+    /// Whether to list the plugin files instead of the plugin names.
+    pub files: bool,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self {
+            variable: "SHELDON_PLUGINS".into(),
+            separator: ":".into(),
+            files: false,
+        }
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Group(group) => write!(f, "it is in the disabled group `{}`", group),
+            Self::Profiles(profiles) => {
+                let profiles: Vec<_> = profiles.iter().map(|p| format!("`{}`", p)).collect();
+                write!(f, "none of its profiles {} are active", profiles.join(", "))
+            }
+            Self::Shell(shell) => write!(f, "it is only for the `{}` shell", shell),
+        }
+    }
+}
+
 /// A wrapper around a template string.
 #[derive(Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct Template {
@@ -47,9 +143,44 @@ pub struct Template {
     pub value: String,
     /// Whether this template should be applied to each file.
     pub each: bool,
+    /// The variables that must be available when rendering this template.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub requires: Vec<String>,
+    /// The conditions under which this template is applied.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub when: Option<Condition>,
+}
+
+/// The conditions under which a template is applied.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    /// Only apply the template when generating the script for this shell.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shell: Option<Shell>,
+    /// Only apply the template on this operating system, for example `linux`
+    /// or `macos`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+}
+
+impl Condition {
+    /// Whether the condition holds for the given shell and operating system.
+    pub fn matches(&self, shell: Shell, os: &str) -> bool {
+        self.shell.is_none_or(|s| s == shell) && self.os.as_ref().is_none_or(|o| o == os)
+    }
+}
+
+/// Whether a plugin with the given tags is selected by any of the `wanted`
+/// tags.
+///
+/// Every plugin is selected if no tags are wanted.
+pub fn is_tagged(tags: &[String], wanted: &[String]) -> bool {
+    wanted.is_empty() || tags.iter().any(|tag| wanted.contains(tag))
 }
 
 /// A configured plugin.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, PartialEq, Eq)]
 pub enum Plugin {
     External(ExternalPlugin),
@@ -69,8 +200,28 @@ pub struct ExternalPlugin {
     pub uses: Option<Vec<String>>,
     /// What templates to apply to each matched file.
     pub apply: Option<Vec<String>>,
+    /// What templates to apply to the matched files that match each pattern.
+    pub apply_matching: Vec<(String, Vec<String>)>,
+    /// The templates to apply to the files that match each of their patterns.
+    pub apply_globs: Vec<(String, String)>,
+    /// The templates that are only available to this plugin.
+    pub templates: IndexMap<String, Template>,
     /// Only use this plugin under one of the given profiles
     pub profiles: Option<Vec<String>>,
+    /// Only use this plugin when generating the script for this shell.
+    pub shell: Option<Shell>,
+    /// Whether to follow symlinked directories when matching files.
+    pub follow_symlinks: bool,
+    /// Whether to also lock the plugins declared in this plugin's source.
+    pub follow_dependencies: bool,
+    /// A file to read the plugin's version from.
+    pub version_from: Option<String>,
+    /// Also use the files whose first line starts with this prefix.
+    pub match_shebang: Option<String>,
+    /// The files to put before the other matched files, in this order.
+    pub order: Vec<String>,
+    /// Labels for selecting this plugin.
+    pub tags: Vec<String>,
 }
 
 /// The source for a [`Plugin`].
@@ -80,9 +231,29 @@ pub enum Source {
     Git {
         url: Url,
         reference: Option<GitReference>,
+        /// Whether to only keep the working tree, without the `.git` directory.
+        archive: bool,
+        /// The name of the directory in the clone directory to clone to,
+        /// instead of one based on the URL.
+        clone_as: Option<String>,
+        /// Whether to try fetching only the tree using `git archive` instead of
+        /// cloning, the result is the same as `archive`.
+        no_history: bool,
+        /// Whether to verify the signature of the checked out tag or commit,
+        /// and the keys that it must be signed by. If there are no keys then
+        /// any key that Git trusts is accepted.
+        verify_signature: Option<Vec<String>>,
     },
     /// A remote file.
-    Remote { url: Url },
+    Remote {
+        url: Url,
+        /// The version to render into the URL.
+        version: Option<String>,
+        /// The expected checksum of the file, for example `sha256:<digest>`.
+        checksum: Option<String>,
+        /// The file name to save the file as, instead of one based on the URL.
+        filename: Option<String>,
+    },
     /// A local directory.
     Local { dir: PathBuf },
 }
@@ -97,6 +268,16 @@ pub enum GitReference {
     Rev(String),
     /// From a tag.
     Tag(String),
+    /// From the first of the given references that exists.
+    #[serde(rename = "reference")]
+    Fallback(Vec<GitReference>),
+    /// From the newest commit on a branch, or the default branch, that was
+    /// committed before a date.
+    #[serde(skip)]
+    Before {
+        branch: Option<String>,
+        date: String,
+    },
 }
 
 /// An inline configured plugin.
@@ -108,16 +289,448 @@ pub struct InlinePlugin {
     pub raw: String,
     /// Only use this plugin under one of the given profiles
     pub profiles: Option<Vec<String>>,
+    /// Only use this plugin when generating the script for this shell.
+    pub shell: Option<Shell>,
+    /// Labels for selecting this plugin.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
 }
 
 /// Load a [`Config`] from the given path.
-pub fn from_path<P>(path: P, warnings: &mut Vec<Error>) -> Result<Config>
+///
+/// The plugins from any imported config files are merged into the config.
+pub fn from_path<P>(ctx: &Context, path: P, warnings: &mut Vec<Error>) -> Result<Config>
 where
     P: AsRef<Path>,
 {
-    let path = path.as_ref();
-    let bytes = fs::read(&path).with_context(s!("failed to read from `{}`", path.display()))?;
+    let raw_config = raw_from_path(path.as_ref(), warnings)?;
+    let raw_config = merge_imports(ctx, path.as_ref(), raw_config, warnings)?;
+    normalize::normalize(raw_config, warnings)
+}
+
+/// Merge any imported config files and fragments into the [`RawConfig`] read
+/// from the given path.
+fn merge_imports(
+    ctx: &Context,
+    path: &Path,
+    mut raw_config: RawConfig,
+    warnings: &mut Vec<Error>,
+) -> Result<RawConfig> {
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut resolved = Vec::new();
+    if let Some(imports) = raw_config.imports.take() {
+        for import in imports {
+            let import = dir.join(ctx.expand_tilde(import));
+            let imported = raw_from_path(&import, warnings)
+                .with_context(s!("failed to import `{}`", import.display()))?;
+            merge_import(&mut raw_config, imported)
+                .with_context(s!("failed to import `{}`", import.display()))?;
+            resolved.push(import);
+        }
+    }
+    if let Some(fragments) = raw_config.fragments.take() {
+        let fragments = dir.join(ctx.expand_tilde(fragments));
+        // The directory itself is tracked so that adding or removing a fragment
+        // also causes a relock.
+        resolved.push(fragments.clone());
+        for fragment in fragment_paths(&fragments)? {
+            merge_fragment(&mut raw_config, &fragment, warnings)
+                .with_context(s!("failed to import fragment `{}`", fragment.display()))?;
+            resolved.push(fragment);
+        }
+    }
+    if !resolved.is_empty() {
+        raw_config.imports = Some(resolved);
+    }
+    Ok(raw_config)
+}
+
+/// Returns the `.toml` files in the given fragment directory, sorted by name.
+fn fragment_paths(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).with_context(s!("failed to read `{}`", dir.display()))? {
+        let path = entry
+            .with_context(s!("failed to read `{}`", dir.display()))?
+            .path();
+        if path.extension().is_some_and(|ext| ext == "toml") && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Merge the plugins from a config file fragment into the primary config.
+fn merge_fragment(
+    raw_config: &mut RawConfig,
+    path: &Path,
+    warnings: &mut Vec<Error>,
+) -> Result<()> {
+    let bytes = fs::read(path).with_context(s!("failed to read from `{}`", path.display()))?;
     let contents = String::from_utf8(bytes).context("config file contents are not valid UTF-8")?;
-    let raw_config = toml::from_str(&contents).context("failed to deserialize contents as TOML")?;
+    let RawFragment { plugins } = parse(&contents, warnings)?;
+    for (name, plugin) in plugins {
+        if raw_config.plugins.contains_key(&name) {
+            bail!("plugin `{}` is already defined", name);
+        }
+        raw_config.plugins.insert(name, plugin);
+    }
+    Ok(())
+}
+
+/// Read a [`RawConfig`] from the given path.
+fn raw_from_path(path: &Path, warnings: &mut Vec<Error>) -> Result<RawConfig> {
+    let bytes = fs::read(path).with_context(s!("failed to read from `{}`", path.display()))?;
+    let contents = String::from_utf8(bytes).context("config file contents are not valid UTF-8")?;
+    parse(&contents, warnings)
+}
+
+/// Deserialize config file contents, renaming any deprecated keys.
+fn parse<T>(contents: &str, warnings: &mut Vec<Error>) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    let mut value: toml::Value =
+        toml::from_str(contents).context("failed to deserialize contents as TOML")?;
+    if deprecated::migrate(&mut value, warnings) {
+        value
+            .try_into()
+            .context("failed to deserialize contents as TOML")
+    } else {
+        // Deserialize the contents directly, so that errors include the
+        // location in the file.
+        toml::from_str(contents).context("failed to deserialize contents as TOML")
+    }
+}
+
+/// Merge an imported [`RawConfig`] into the primary one.
+///
+/// The plugins, groups, and templates are added to the primary config. Global
+/// settings are only used if they are not set in the primary config.
+fn merge_import(raw_config: &mut RawConfig, imported: RawConfig) -> Result<()> {
+    let RawConfig {
+        shell,
+        matches,
+        apply,
+        templates,
+        remove_templates,
+        clean_ignore,
+        footer,
+        dedupe_paths,
+        invalidate_compdump,
+        guard,
+        profile_startup,
+        trash,
+        require_clean,
+        normalize_line_endings,
+        manifest,
+        wrap_in_function,
+        clone_dir_layout,
+        active_profiles,
+        imports,
+        fragments,
+        plugins,
+        groups,
+        rest: _,
+    } = imported;
+
+    if imports.is_some() {
+        bail!("imported config files cannot have imports");
+    }
+    if fragments.is_some() {
+        bail!("imported config files cannot have fragments");
+    }
+
+    for (name, plugin) in plugins {
+        if raw_config.plugins.contains_key(&name) {
+            bail!("plugin `{}` is already defined", name);
+        }
+        raw_config.plugins.insert(name, plugin);
+    }
+    for (name, group) in groups {
+        if raw_config.groups.contains_key(&name) {
+            bail!("group `{}` is already defined", name);
+        }
+        raw_config.groups.insert(name, group);
+    }
+    for (name, template) in templates {
+        raw_config.templates.entry(name).or_insert(template);
+    }
+
+    fn merge<T>(primary: &mut Option<T>, imported: Option<T>) {
+        if primary.is_none() {
+            *primary = imported;
+        }
+    }
+    merge(&mut raw_config.shell, shell);
+    merge(&mut raw_config.matches, matches);
+    merge(&mut raw_config.apply, apply);
+    merge(&mut raw_config.remove_templates, remove_templates);
+    merge(&mut raw_config.clean_ignore, clean_ignore);
+    merge(&mut raw_config.footer, footer);
+    merge(&mut raw_config.dedupe_paths, dedupe_paths);
+    merge(&mut raw_config.invalidate_compdump, invalidate_compdump);
+    merge(&mut raw_config.guard, guard);
+    merge(&mut raw_config.profile_startup, profile_startup);
+    merge(&mut raw_config.trash, trash);
+    merge(&mut raw_config.require_clean, require_clean);
+    merge(
+        &mut raw_config.normalize_line_endings,
+        normalize_line_endings,
+    );
+    merge(&mut raw_config.manifest, manifest);
+    merge(&mut raw_config.wrap_in_function, wrap_in_function);
+    merge(&mut raw_config.clone_dir_layout, clone_dir_layout);
+    merge(&mut raw_config.active_profiles, active_profiles);
+    Ok(())
+}
+
+/// Load a [`Config`] from the given string, as if it were the contents of the
+/// config file at the given path.
+///
+/// Like [`from_path`], the plugins from any imported config files are merged
+/// into the config.
+pub fn from_str_at<P>(
+    ctx: &Context,
+    path: P,
+    contents: &str,
+    warnings: &mut Vec<Error>,
+) -> Result<Config>
+where
+    P: AsRef<Path>,
+{
+    let raw_config = parse(contents, warnings)?;
+    let raw_config = merge_imports(ctx, path.as_ref(), raw_config, warnings)?;
     normalize::normalize(raw_config, warnings)
 }
+
+////////////////////////////////////////////////////////////////////////////////
+// Unit tests
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin_names(config: &Config) -> Vec<&str> {
+        config
+            .plugins
+            .iter()
+            .map(|plugin| match plugin {
+                Plugin::External(plugin) => plugin.name.as_str(),
+                Plugin::Inline(plugin) => plugin.name.as_str(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn from_path_with_imports() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let ctx = Context::testing(dir);
+        fs::write(
+            dir.join("plugins.toml"),
+            r#"
+shell = "zsh"
+imports = ["work.toml"]
+
+[plugins.first]
+inline = "echo first"
+"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("work.toml"),
+            r#"
+shell = "bash"
+footer = "echo footer"
+
+[plugins.second]
+inline = "echo second"
+
+[plugins.third]
+inline = "echo third"
+"#,
+        )
+        .unwrap();
+
+        let config = from_path(&ctx, dir.join("plugins.toml"), &mut Vec::new()).unwrap();
+
+        assert_eq!(plugin_names(&config), ["first", "second", "third"]);
+        assert_eq!(config.shell, Shell::Zsh);
+        assert_eq!(config.footer.as_deref(), Some("echo footer"));
+        assert_eq!(config.imports, vec![dir.join("work.toml")]);
+    }
+
+    #[test]
+    fn from_path_with_imports_colliding_plugin() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let ctx = Context::testing(dir);
+        fs::write(
+            dir.join("plugins.toml"),
+            r#"
+imports = ["work.toml"]
+
+[plugins.first]
+inline = "echo first"
+"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("work.toml"),
+            r#"
+[plugins.first]
+inline = "echo other"
+"#,
+        )
+        .unwrap();
+
+        let err = from_path(&ctx, dir.join("plugins.toml"), &mut Vec::new()).unwrap_err();
+
+        assert_eq!(
+            format!("{:#}", err),
+            format!(
+                "failed to import `{}`: plugin `first` is already defined",
+                dir.join("work.toml").display()
+            )
+        );
+    }
+
+    #[test]
+    fn from_path_with_fragments() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let ctx = Context::testing(dir);
+        fs::write(
+            dir.join("plugins.toml"),
+            r#"
+fragments = "plugins.d"
+
+[plugins.first]
+inline = "echo first"
+"#,
+        )
+        .unwrap();
+        let fragments = dir.join("plugins.d");
+        fs::create_dir(&fragments).unwrap();
+        fs::write(
+            fragments.join("b.toml"),
+            r#"
+[plugins.third]
+inline = "echo third"
+"#,
+        )
+        .unwrap();
+        fs::write(
+            fragments.join("a.toml"),
+            r#"
+[plugins.second]
+inline = "echo second"
+"#,
+        )
+        .unwrap();
+        fs::write(fragments.join("README.md"), "").unwrap();
+
+        let config = from_path(&ctx, dir.join("plugins.toml"), &mut Vec::new()).unwrap();
+
+        assert_eq!(plugin_names(&config), ["first", "second", "third"]);
+        assert_eq!(
+            config.imports,
+            vec![
+                fragments.clone(),
+                fragments.join("a.toml"),
+                fragments.join("b.toml")
+            ]
+        );
+    }
+
+    #[test]
+    fn from_path_with_fragments_colliding_plugin() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let ctx = Context::testing(dir);
+        fs::write(dir.join("plugins.toml"), "fragments = \"plugins.d\"\n").unwrap();
+        let fragments = dir.join("plugins.d");
+        fs::create_dir(&fragments).unwrap();
+        for name in ["a.toml", "b.toml"] {
+            fs::write(
+                fragments.join(name),
+                "[plugins.first]\ninline = \"echo first\"\n",
+            )
+            .unwrap();
+        }
+
+        let err = from_path(&ctx, dir.join("plugins.toml"), &mut Vec::new()).unwrap_err();
+
+        assert_eq!(
+            format!("{:#}", err),
+            format!(
+                "failed to import fragment `{}`: plugin `first` is already defined",
+                fragments.join("b.toml").display()
+            )
+        );
+    }
+
+    #[test]
+    fn from_str_at_with_deprecated_key() {
+        let ctx = Context::testing(Path::new(""));
+        let mut warnings = Vec::new();
+        let config = from_str_at(
+            &ctx,
+            "plugins.toml",
+            r#"
+[plugins.test]
+github = "owner/repo"
+protocol = "ssh"
+
+[groups.work.plugins.other]
+github = "owner/other"
+protocol = "ssh"
+"#,
+            &mut warnings,
+        )
+        .unwrap();
+
+        let warnings: Vec<_> = warnings.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            warnings,
+            [
+                "use of deprecated config key: `plugins.test.protocol`, please use \
+                 `plugins.test.proto` instead",
+                "use of deprecated config key: `groups.work.plugins.other.protocol`, please use \
+                 `groups.work.plugins.other.proto` instead",
+            ]
+        );
+        for plugin in &config.plugins {
+            match plugin {
+                Plugin::External(ExternalPlugin {
+                    source: Source::Git { url, .. },
+                    ..
+                }) => assert_eq!(url.scheme(), "ssh"),
+                plugin => panic!("unexpected plugin {:?}", plugin),
+            }
+        }
+    }
+
+    #[test]
+    fn from_str_at_with_deprecated_and_new_key() {
+        let ctx = Context::testing(Path::new(""));
+        let mut warnings = Vec::new();
+        from_str_at(
+            &ctx,
+            "plugins.toml",
+            r#"
+[plugins.test]
+github = "owner/repo"
+proto = "https"
+protocol = "ssh"
+"#,
+            &mut warnings,
+        )
+        .unwrap();
+
+        let warnings: Vec<_> = warnings.iter().map(ToString::to_string).collect();
+        assert_eq!(warnings, ["unused config key: `plugins.test.protocol`"]);
+    }
+}
@@ -1,14 +1,23 @@
 //! Normalize a raw config from the file into a [`Config`].
 
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::slice;
 use std::str;
-use std::str::FromStr;
 
 use anyhow::{anyhow, bail, Context as ResultExt, Error, Result};
 use indexmap::IndexMap;
+use regex_macro::regex;
 use url::Url;
 
-use crate::config::file::{GitProtocol, RawConfig, RawPlugin};
-use crate::config::{Config, ExternalPlugin, InlinePlugin, Plugin, Shell, Source, Template};
+use crate::config::file::{
+    GistRepository, GitHubRepository, GitProtocol, RawApply, RawConfig, RawGroup, RawPlugin,
+};
+use crate::config::{
+    Config, ExternalPlugin, GitReference, InlinePlugin, Plugin, Shell, SkipReason, Source, Template,
+};
+use crate::lock::{render_clone_dir, render_remote_url};
+use crate::util::git;
 
 /// The Gist domain host.
 const GIST_HOST: &str = "gist.github.com";
@@ -23,7 +32,25 @@ pub fn normalize(raw_config: RawConfig, warnings: &mut Vec<Error>) -> Result<Con
         matches,
         apply,
         templates,
+        remove_templates,
+        clean_ignore,
+        footer,
+        dedupe_paths,
+        invalidate_compdump,
+        guard,
+        profile_startup,
+        trash,
+        require_clean,
+        normalize_line_endings,
+        manifest,
+        wrap_in_function,
+        clone_dir_layout,
+        active_profiles,
+        imports,
+        // Fragments are merged in when the config file is read.
+        fragments: _,
         plugins,
+        groups,
         rest,
     } = raw_config;
 
@@ -37,9 +64,83 @@ pub fn normalize(raw_config: RawConfig, warnings: &mut Vec<Error>) -> Result<Con
             .with_context(s!("failed to compile template `{}`", name))?;
     }
 
+    if let Some(patterns) = &clean_ignore {
+        // Check that the patterns are valid globs, built the same way as when
+        // cleaning.
+        for pattern in patterns {
+            globset::GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .with_context(s!("failed to parse `clean_ignore` pattern `{}`", pattern))?;
+        }
+    }
+
     let shell = shell.unwrap_or_default();
 
-    validate_template_names(shell, &apply, &templates)?;
+    if shell != Shell::Zsh && invalidate_compdump.is_some() {
+        bail!("the `invalidate_compdump` field is only supported by zsh");
+    }
+
+    if let Some(remove_templates) = &remove_templates {
+        for name in remove_templates {
+            if !shell.default_templates().contains_key(name) {
+                bail!(
+                    "cannot remove template `{}`, it is not a built-in template",
+                    name
+                );
+            }
+        }
+    }
+
+    if let Some(name) = &wrap_in_function {
+        if !regex!(r"^[A-Za-z_][A-Za-z0-9_]*$").is_match(name) {
+            bail!(
+                "the `wrap_in_function` field must be a valid function name, got `{}`",
+                name
+            );
+        }
+    }
+
+    if let Some(layout) = &clone_dir_layout {
+        handlebars::Template::compile(layout)
+            .context("failed to compile the `clone_dir_layout` template")?;
+    }
+
+    validate_template_names(shell, apply.as_deref(), &templates)?;
+
+    // Flatten the plugins in each enabled group, in order, after the other
+    // plugins.
+    let mut names: HashSet<_> = plugins.keys().cloned().collect();
+    let mut plugins: Vec<_> = plugins.into_iter().collect();
+    let mut skipped = IndexMap::new();
+    for (group_name, group) in groups {
+        let RawGroup {
+            enabled,
+            plugins: group_plugins,
+            rest,
+        } = group;
+        check_extra_toml(rest, |key| {
+            warnings.push(anyhow!(
+                "unused config key: `groups.{}.{}`",
+                group_name,
+                key
+            ))
+        });
+        for (name, plugin) in group_plugins {
+            if !names.insert(name.clone()) {
+                bail!(
+                    "plugin `{}` in group `{}` is already defined",
+                    name,
+                    group_name
+                );
+            }
+            if enabled.unwrap_or(true) {
+                plugins.push((name, plugin));
+            } else {
+                skipped.insert(name, SkipReason::Group(group_name.clone()));
+            }
+        }
+    }
 
     // Normalize the plugins.
     let mut normalized_plugins = Vec::with_capacity(plugins.len());
@@ -51,15 +152,63 @@ pub fn normalize(raw_config: RawConfig, warnings: &mut Vec<Error>) -> Result<Con
         );
     }
 
+    check_clone_as(&normalized_plugins)?;
+    if let Some(layout) = &clone_dir_layout {
+        check_clone_dir_layout(&normalized_plugins, layout)?;
+    }
+
+    // The built-in `defer` template requires `zsh-defer` to be loaded first.
+    if shell == Shell::Zsh && !templates.contains_key("defer") {
+        check_defer_order(&normalized_plugins, &apply, warnings);
+    }
+
     Ok(Config {
         shell,
         matches,
         apply,
         templates,
+        remove_templates,
+        clean_ignore,
+        footer,
+        dedupe_paths,
+        invalidate_compdump,
+        guard,
+        profile_startup,
+        trash,
+        require_clean,
+        normalize_line_endings,
+        manifest,
+        wrap_in_function,
+        clone_dir_layout,
+        active_profiles,
+        imports: imports.unwrap_or_default(),
         plugins: normalized_plugins,
+        skipped,
     })
 }
 
+/// Construct the URL of a Gist repository.
+pub fn gist_url(repository: &GistRepository, proto: Option<GitProtocol>) -> Result<Url> {
+    let url_str = format!(
+        "{}{}/{}",
+        proto.unwrap_or(GitProtocol::Https).prefix(),
+        GIST_HOST,
+        repository
+    );
+    Url::parse(&url_str).with_context(s!("failed to construct Gist URL using `{}`", repository))
+}
+
+/// Construct the URL of a GitHub repository.
+pub fn github_url(repository: &GitHubRepository, proto: Option<GitProtocol>) -> Result<Url> {
+    let url_str = format!(
+        "{}{}/{}",
+        proto.unwrap_or(GitProtocol::Https).prefix(),
+        GITHUB_HOST,
+        repository
+    );
+    Url::parse(&url_str).with_context(s!("failed to construct GitHub URL using `{}`", repository))
+}
+
 /// Normalize a raw plugin from the file into a [`Plugin`] which is simpler and
 /// easier to handle.
 ///
@@ -83,65 +232,162 @@ fn normalize_plugin(
         remote,
         local,
         inline,
-        mut proto,
+        proto,
         reference,
+        before,
+        archive,
+        clone_as,
+        no_history,
+        verify_signature,
+        trusted_keys,
+        checksum,
+        filename,
+        version,
         dir,
         uses,
         apply,
+        apply_matching,
+        templates: plugin_templates,
         profiles,
-        mut rest,
+        shell: plugin_shell,
+        follow_symlinks,
+        follow_dependencies,
+        version_from,
+        match_shebang,
+        order,
+        tags,
+        rest,
     } = raw_plugin;
 
-    let is_reference_some = reference.is_some();
-    let is_gist_or_github = gist.is_some() || github.is_some();
+    // The plugin's templates are validated against the shell that it is for.
+    let shell = plugin_shell.unwrap_or(shell);
 
-    // Handle some deprecated items :/
-    if proto.is_none() {
-        if let Some(protocol) = try_pop_toml_value(&mut rest, "protocol") {
-            warnings.push(anyhow!(
-                "use of deprecated config key: `plugins.{name}.protocol`, please use \
-                 `plugins.{name}.proto` instead",
-                name = name,
-            ));
-            proto = Some(protocol);
+    let reference = match (reference, before) {
+        (reference, None) => reference,
+        (None, Some(date)) => Some(GitReference::Before { branch: None, date }),
+        (Some(GitReference::Branch(branch)), Some(date)) => Some(GitReference::Before {
+            branch: Some(branch),
+            date,
+        }),
+        (Some(_), Some(_)) => {
+            bail!("the `before` field is only supported with the `branch` field");
         }
+    };
+    if let Some(GitReference::Before { date, .. }) = &reference {
+        git::parse_date(date).with_context(s!("failed to parse `before` date `{}`", date))?;
     }
 
+    let is_reference_some = reference.is_some();
+    if let Some(GitReference::Fallback(references)) = &reference {
+        if references.is_empty() {
+            bail!("the `reference` field must contain at least one candidate");
+        } else if references
+            .iter()
+            .any(|r| matches!(r, GitReference::Fallback(_)))
+        {
+            bail!("the `reference` field candidates must be a `branch`, `tag`, or `rev`");
+        }
+    }
+    let is_gist_or_github = gist.is_some() || github.is_some();
+
     check_extra_toml(rest, |key| {
         warnings.push(anyhow!("unused config key: `plugins.{}.{}`", name, key))
     });
 
+    if let Some(dir) = &clone_as {
+        let mut components = Path::new(dir).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            bail!(
+                "the `clone_as` field must be a single directory name, got `{}`",
+                dir
+            );
+        }
+    }
+
+    let verify = match (verify_signature, trusted_keys.as_deref()) {
+        (Some(true), keys) => Some(
+            keys.unwrap_or_default()
+                .iter()
+                .map(|key| normalize_key(key))
+                .collect::<Result<Vec<_>>>()?,
+        ),
+        (_, Some(_)) => bail!("the `trusted_keys` field requires `verify_signature = true`"),
+        (_, None) => None,
+    };
+
+    // Only one source field can be given, report all of the conflicting ones.
+    let source_fields: Vec<_> = [
+        ("git", git.is_some()),
+        ("gist", gist.is_some()),
+        ("github", github.is_some()),
+        ("remote", remote.is_some()),
+        ("local", local.is_some()),
+        ("inline", inline.is_some()),
+    ]
+    .iter()
+    .filter(|(_, is_some)| *is_some)
+    .map(|(field, _)| format!("`{}`", field))
+    .collect();
+    if source_fields.len() > 1 {
+        let (last, rest) = source_fields.split_last().unwrap();
+        bail!(
+            "plugin `{}` has multiple source fields {} and {}, only one is allowed",
+            name,
+            rest.join(", "),
+            last
+        );
+    }
+
     let raw_source = match (git, gist, github, remote, local, inline) {
         // `git` type
-        (Some(url), None, None, None, None, None) => {
-            TempSource::External(Source::Git { url, reference })
-        }
+        (Some(url), None, None, None, None, None) => TempSource::External(Source::Git {
+            url,
+            reference,
+            archive: archive.unwrap_or(false),
+            clone_as: clone_as.clone(),
+            no_history: no_history.unwrap_or(false),
+            verify_signature: verify.clone(),
+        }),
         // `gist` type
-        (None, Some(repository), None, None, None, None) => {
-            let url_str = format!(
-                "{}{}/{}",
-                proto.unwrap_or(GitProtocol::Https).prefix(),
-                GIST_HOST,
-                repository
-            );
-            let url = Url::parse(&url_str)
-                .with_context(s!("failed to construct Gist URL using `{}`", repository))?;
-            TempSource::External(Source::Git { url, reference })
-        }
+        (None, Some(repository), None, None, None, None) => TempSource::External(Source::Git {
+            url: gist_url(&repository, proto)?,
+            reference,
+            archive: archive.unwrap_or(false),
+            clone_as: clone_as.clone(),
+            no_history: no_history.unwrap_or(false),
+            verify_signature: verify.clone(),
+        }),
         // `github` type
-        (None, None, Some(repository), None, None, None) => {
-            let url_str = format!(
-                "{}{}/{}",
-                proto.unwrap_or(GitProtocol::Https).prefix(),
-                GITHUB_HOST,
-                repository
-            );
-            let url = Url::parse(&url_str)
-                .with_context(s!("failed to construct GitHub URL using `{}`", repository))?;
-            TempSource::External(Source::Git { url, reference })
-        }
+        (None, None, Some(repository), None, None, None) => TempSource::External(Source::Git {
+            url: github_url(&repository, proto)?,
+            reference,
+            archive: archive.unwrap_or(false),
+            clone_as: clone_as.clone(),
+            no_history: no_history.unwrap_or(false),
+            verify_signature: verify.clone(),
+        }),
         // `remote` type
-        (None, None, None, Some(url), None, None) => TempSource::External(Source::Remote { url }),
+        (None, None, None, Some(url), None, None) => {
+            let checksum = checksum.as_deref().map(normalize_checksum).transpose()?;
+            let filename = filename.as_deref().map(validate_filename).transpose()?;
+            // Check that the version renders into the URL.
+            let rendered = render_remote_url(&url, version.as_deref())?;
+            if version.is_some() && rendered == url {
+                bail!(
+                    "the `version` field requires a `{{{{ version }}}}` template in the `remote` \
+                     URL"
+                );
+            }
+            TempSource::External(Source::Remote {
+                url,
+                version: version.clone(),
+                checksum,
+                filename,
+            })
+        }
         // `local` type
         (None, None, None, None, Some(dir), None) => TempSource::External(Source::Local { dir }),
         // `inline` type
@@ -149,22 +395,82 @@ fn normalize_plugin(
         (None, None, None, None, None, None) => {
             bail!("plugin `{}` has no source fields", name);
         }
-        _ => {
-            bail!("plugin `{}` has multiple source fields", name);
-        }
+        // Multiple source fields were rejected above.
+        _ => unreachable!(),
     };
 
     match raw_source {
         TempSource::External(source) => {
             if !source.is_git() && is_reference_some {
                 bail!(
-                    "the `branch`, `tag`, and `rev` fields are not supported by this plugin type"
+                    "the `branch`, `tag`, `rev`, and `reference` fields are not supported by this \
+                     plugin type"
                 );
             } else if proto.is_some() && !is_gist_or_github {
                 bail!("the `proto` field is not supported by this plugin type");
+            } else if archive.is_some() && !source.is_git() {
+                bail!("the `archive` field is not supported by this plugin type");
+            } else if clone_as.is_some() && !source.is_git() {
+                bail!("the `clone_as` field is not supported by this plugin type");
+            } else if no_history.is_some() && !source.is_git() {
+                bail!("the `no_history` field is not supported by this plugin type");
+            } else if verify_signature.is_some() && !source.is_git() {
+                bail!("the `verify_signature` field is not supported by this plugin type");
+            } else if trusted_keys.is_some() && !source.is_git() {
+                bail!("the `trusted_keys` field is not supported by this plugin type");
+            } else if verify.is_some() && (archive == Some(true) || no_history == Some(true)) {
+                bail!(
+                    "the `verify_signature` field is not supported with the `archive` or \
+                     `no_history` fields"
+                );
+            } else if checksum.is_some() && !matches!(source, Source::Remote { .. }) {
+                bail!("the `checksum` field is not supported by this plugin type");
+            } else if filename.is_some() && !matches!(source, Source::Remote { .. }) {
+                bail!("the `filename` field is not supported by this plugin type");
+            } else if version.is_some() && !matches!(source, Source::Remote { .. }) {
+                bail!("the `version` field is not supported by this plugin type");
             }
 
-            validate_template_names(shell, &apply, templates)?;
+            // The plugin's own templates take precedence over the global
+            // templates, but are not available to any other plugin.
+            let plugin_templates = plugin_templates.unwrap_or_default();
+            for (name, template) in &plugin_templates {
+                handlebars::Template::compile(&template.value)
+                    .with_context(s!("failed to compile template `{}`", name))?;
+            }
+            let merged;
+            let templates = if plugin_templates.is_empty() {
+                templates
+            } else {
+                merged = templates
+                    .iter()
+                    .chain(&plugin_templates)
+                    .map(|(name, template)| (name.clone(), template.clone()))
+                    .collect();
+                &merged
+            };
+
+            // A map of template name to pattern applies only the templates
+            // whose patterns match each file.
+            let (apply, apply_globs) = match apply {
+                Some(RawApply::Names(apply)) => (Some(apply), Vec::new()),
+                Some(RawApply::Globs(globs)) => (Some(Vec::new()), globs.into_iter().collect()),
+                None => (None, Vec::new()),
+            };
+            validate_template_names(shell, apply.as_deref(), templates)?;
+            for (name, pattern) in &apply_globs {
+                globset::Glob::new(pattern)
+                    .with_context(s!("failed to parse `apply` pattern `{}`", pattern))?;
+                validate_template_names(shell, Some(slice::from_ref(name)), templates)?;
+            }
+
+            let apply_matching: Vec<_> = apply_matching.unwrap_or_default().into_iter().collect();
+            for (pattern, apply) in &apply_matching {
+                // Check that the patterns are valid globs.
+                globset::Glob::new(pattern)
+                    .with_context(s!("failed to parse `apply_matching` pattern `{}`", pattern))?;
+                validate_template_names(shell, Some(apply), templates)?;
+            }
 
             Ok(Plugin::External(ExternalPlugin {
                 name,
@@ -172,16 +478,47 @@ fn normalize_plugin(
                 dir,
                 uses,
                 apply,
+                apply_matching,
+                apply_globs,
+                templates: plugin_templates,
                 profiles,
+                shell: plugin_shell,
+                follow_symlinks: follow_symlinks.unwrap_or(false),
+                follow_dependencies: follow_dependencies.unwrap_or(false),
+                version_from,
+                match_shebang,
+                order: order.unwrap_or_default(),
+                tags: tags.unwrap_or_default(),
             }))
         }
         TempSource::Inline(raw) => {
             let unsupported = [
                 ("`proto` field is", proto.is_some()),
-                ("`branch`, `tag`, and `rev` fields are", is_reference_some),
+                (
+                    "`branch`, `tag`, `rev`, and `reference` fields are",
+                    is_reference_some,
+                ),
+                ("`archive` field is", archive.is_some()),
+                ("`clone_as` field is", clone_as.is_some()),
+                ("`no_history` field is", no_history.is_some()),
+                ("`verify_signature` field is", verify_signature.is_some()),
+                ("`trusted_keys` field is", trusted_keys.is_some()),
+                ("`checksum` field is", checksum.is_some()),
+                ("`filename` field is", filename.is_some()),
+                ("`version` field is", version.is_some()),
                 ("`dir` field is", dir.is_some()),
                 ("`use` field is", uses.is_some()),
                 ("`apply` field is", apply.is_some()),
+                ("`apply_matching` field is", apply_matching.is_some()),
+                ("`templates` field is", plugin_templates.is_some()),
+                ("`follow_symlinks` field is", follow_symlinks.is_some()),
+                (
+                    "`follow_dependencies` field is",
+                    follow_dependencies.is_some(),
+                ),
+                ("`version_from` field is", version_from.is_some()),
+                ("`match_shebang` field is", match_shebang.is_some()),
+                ("`order` field is", order.is_some()),
             ];
             for (field, is_some) in &unsupported {
                 if *is_some {
@@ -192,6 +529,8 @@ fn normalize_plugin(
                 name,
                 raw,
                 profiles,
+                shell: plugin_shell,
+                tags: tags.unwrap_or_default(),
             }))
         }
     }
@@ -214,21 +553,54 @@ impl Source {
     }
 }
 
-/// Try and pop the TOML value from the table.
-fn try_pop_toml_value<T>(rest: &mut Option<toml::Value>, key: &str) -> Option<T>
-where
-    T: FromStr,
-{
-    if let Some(toml::Value::Table(table)) = rest {
-        if let Some(toml::Value::String(s)) = table.get(key) {
-            let result = s.parse().ok();
-            if result.is_some() {
-                table.remove(key);
-            }
-            return result;
+/// Validate a checksum and convert it to lowercase.
+///
+/// Only SHA-256 checksums are supported, in the form `sha256:<digest>`.
+fn normalize_checksum(checksum: &str) -> Result<String> {
+    match checksum.split_once(':') {
+        Some(("sha256", digest))
+            if digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()) =>
+        {
+            Ok(format!("sha256:{}", digest.to_ascii_lowercase()))
+        }
+        _ => bail!(
+            "invalid checksum `{}`, expected `sha256:` followed by 64 hexadecimal digits",
+            checksum
+        ),
+    }
+}
+
+/// Validate a trusted key and convert it to uppercase.
+///
+/// Keys are given as a long key ID or a full fingerprint, optionally prefixed
+/// with `0x`.
+fn normalize_key(key: &str) -> Result<String> {
+    let hex = key.strip_prefix("0x").unwrap_or(key);
+    if matches!(hex.len(), 16 | 40) && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(hex.to_ascii_uppercase())
+    } else {
+        bail!(
+            "invalid trusted key `{}`, expected a 16 digit key ID or a 40 digit fingerprint",
+            key
+        )
+    }
+}
+
+/// Check that a remote `filename` is a plain file name, so that the download
+/// stays inside its directory.
+fn validate_filename(filename: &str) -> Result<String> {
+    let mut components = Path::new(filename).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None)
+            if name == filename && !filename.contains(['/', '\\']) =>
+        {
+            Ok(filename.to_string())
         }
+        _ => bail!(
+            "the `filename` field must be a plain file name, got `{}`",
+            filename
+        ),
     }
-    None
 }
 
 /// Call the given function on all extra TOML keys.
@@ -243,10 +615,135 @@ where
     }
 }
 
+/// Check that different Git sources are not cloned to the same `clone_as`
+/// directory.
+fn check_clone_as(plugins: &[Plugin]) -> Result<()> {
+    let mut seen: HashMap<&str, (&str, &Source)> = HashMap::new();
+    for plugin in plugins {
+        if let Plugin::External(ExternalPlugin {
+            name,
+            source:
+                source @ Source::Git {
+                    clone_as: Some(dir),
+                    ..
+                },
+            ..
+        }) = plugin
+        {
+            match seen.get(dir.as_str()) {
+                Some((other, other_source)) if *other_source != source => {
+                    bail!(
+                        "plugins `{}` and `{}` have different sources but the same `clone_as` \
+                         directory `{}`",
+                        other,
+                        name,
+                        dir
+                    );
+                }
+                Some(_) => {}
+                None => {
+                    seen.insert(dir, (name, source));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that the `clone_dir_layout` does not render the same directory for Git
+/// sources with different URLs, or a directory that is already used by another
+/// source's `clone_as`.
+fn check_clone_dir_layout(plugins: &[Plugin], layout: &str) -> Result<()> {
+    let mut seen: HashMap<PathBuf, (&str, &Url)> = HashMap::new();
+    for plugin in plugins {
+        if let Plugin::External(ExternalPlugin {
+            name,
+            source: Source::Git { url, clone_as, .. },
+            ..
+        }) = plugin
+        {
+            // A directory that fails to render is reported when locking.
+            let dir = match clone_as {
+                Some(dir) => PathBuf::from(dir),
+                None => match render_clone_dir(layout, url) {
+                    Ok(dir) => dir,
+                    Err(_) => continue,
+                },
+            };
+            match seen.get(&dir) {
+                Some((other, other_url)) if *other_url != url => {
+                    bail!(
+                        "plugins `{}` and `{}` have different sources but the same clone \
+                         directory `{}`, change the `clone_dir_layout`",
+                        other,
+                        name,
+                        dir.display()
+                    );
+                }
+                Some(_) => {}
+                None => {
+                    seen.insert(dir, (name, url));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that a `zsh-defer` plugin is defined before any plugin that applies
+/// the built-in `defer` template.
+fn check_defer_order(plugins: &[Plugin], apply: &Option<Vec<String>>, warnings: &mut Vec<Error>) {
+    let applies_defer = |apply: &Option<Vec<String>>| {
+        apply
+            .as_ref()
+            .is_some_and(|apply| apply.iter().any(|name| name == "defer"))
+    };
+    let mut seen = false;
+    for plugin in plugins {
+        match plugin {
+            Plugin::External(plugin) => {
+                if plugin.is_zsh_defer() {
+                    seen = true;
+                } else if !seen
+                    && applies_defer(if plugin.apply.is_some() {
+                        &plugin.apply
+                    } else {
+                        apply
+                    })
+                {
+                    warnings.push(anyhow!(
+                        "plugin `{}` applies the `defer` template but no `zsh-defer` plugin is \
+                         defined before it",
+                        plugin.name
+                    ));
+                }
+            }
+            Plugin::Inline(plugin) => {
+                if plugin.name == "zsh-defer" {
+                    seen = true;
+                }
+            }
+        }
+    }
+}
+
+impl ExternalPlugin {
+    /// Whether this plugin looks like it provides `zsh-defer`.
+    fn is_zsh_defer(&self) -> bool {
+        match &self.source {
+            Source::Git { url, .. } => {
+                self.name == "zsh-defer"
+                    || url.path().trim_end_matches(".git").ends_with("/zsh-defer")
+            }
+            _ => self.name == "zsh-defer",
+        }
+    }
+}
+
 /// Check whether the specifed templates actually exist.
 fn validate_template_names(
     shell: Shell,
-    apply: &Option<Vec<String>>,
+    apply: Option<&[String]>,
     templates: &IndexMap<String, Template>,
 ) -> Result<()> {
     if let Some(apply) = apply {
@@ -267,7 +764,9 @@ fn validate_template_names(
 mod tests {
     use super::*;
 
-    use crate::config::{GitHubRepository, GitReference};
+    use indexmap::indexmap;
+
+    use crate::config::GitReference;
 
     use pretty_assertions::assert_eq;
 
@@ -297,9 +796,55 @@ mod tests {
                     &mut Vec::new(),
                 )
                 .unwrap_err();
-                assert_eq!(err.to_string(), "plugin `test` has multiple source fields")
+                let (first, second) = if sources.iter().position(|(s, _)| s == a)
+                    < sources.iter().position(|(s, _)| s == b)
+                {
+                    (a, b)
+                } else {
+                    (b, a)
+                };
+                assert_eq!(
+                    err.to_string(),
+                    format!(
+                        "plugin `test` has multiple source fields `{}` and `{}`, only one is \
+                         allowed",
+                        first, second
+                    )
+                );
             }
+
+            // Each source field on its own is valid.
+            let text = format!("{} = '{}'", a, example_a);
+            let raw = toml::from_str::<RawPlugin>(&text).unwrap();
+            normalize_plugin(
+                raw,
+                "test".to_string(),
+                Shell::default(),
+                &IndexMap::new(),
+                &mut Vec::new(),
+            )
+            .unwrap();
         }
+
+        let raw = toml::from_str::<RawPlugin>(
+            "git = 'https://github.com/rossmacarthur/sheldon-test'\n\
+             github = 'rossmacarthur/sheldon-test'\n\
+             local = '~/plugins'",
+        )
+        .unwrap();
+        let err = normalize_plugin(
+            raw,
+            "test".to_string(),
+            Shell::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "plugin `test` has multiple source fields `git`, `github` and `local`, only one is \
+             allowed"
+        );
     }
 
     #[test]
@@ -311,11 +856,25 @@ mod tests {
             source: Source::Git {
                 url: url.clone(),
                 reference: None,
+                archive: false,
+                clone_as: None,
+                no_history: false,
+                verify_signature: None,
             },
             dir: None,
             uses: None,
             apply: None,
+            apply_matching: Vec::new(),
+            apply_globs: Vec::new(),
+            templates: IndexMap::new(),
             profiles: None,
+            shell: None,
+            follow_symlinks: false,
+            follow_dependencies: false,
+            version_from: None,
+            match_shebang: None,
+            order: Vec::new(),
+            tags: Vec::new(),
         });
         let raw_plugin = RawPlugin {
             git: Some(url),
@@ -343,11 +902,25 @@ mod tests {
                 )
                 .unwrap(),
                 reference: None,
+                archive: false,
+                clone_as: None,
+                no_history: false,
+                verify_signature: None,
             },
             dir: None,
             uses: None,
             apply: None,
+            apply_matching: Vec::new(),
+            apply_globs: Vec::new(),
+            templates: IndexMap::new(),
             profiles: None,
+            shell: None,
+            follow_symlinks: false,
+            follow_dependencies: false,
+            version_from: None,
+            match_shebang: None,
+            order: Vec::new(),
+            tags: Vec::new(),
         });
         let raw_plugin = RawPlugin {
             gist: Some(
@@ -378,11 +951,25 @@ mod tests {
                 url: Url::parse("https://gist.github.com/579d02802b1cc17baed07753d09f5009")
                     .unwrap(),
                 reference: None,
+                archive: false,
+                clone_as: None,
+                no_history: false,
+                verify_signature: None,
             },
             dir: None,
             uses: None,
             apply: None,
+            apply_matching: Vec::new(),
+            apply_globs: Vec::new(),
+            templates: IndexMap::new(),
             profiles: None,
+            shell: None,
+            follow_symlinks: false,
+            follow_dependencies: false,
+            version_from: None,
+            match_shebang: None,
+            order: Vec::new(),
+            tags: Vec::new(),
         });
         let raw_plugin = RawPlugin {
             gist: Some("579d02802b1cc17baed07753d09f5009".parse().unwrap()),
@@ -410,11 +997,25 @@ mod tests {
                 )
                 .unwrap(),
                 reference: None,
+                archive: false,
+                clone_as: None,
+                no_history: false,
+                verify_signature: None,
             },
             dir: None,
             uses: None,
             apply: None,
+            apply_matching: Vec::new(),
+            apply_globs: Vec::new(),
+            templates: IndexMap::new(),
             profiles: None,
+            shell: None,
+            follow_symlinks: false,
+            follow_dependencies: false,
+            version_from: None,
+            match_shebang: None,
+            order: Vec::new(),
+            tags: Vec::new(),
         });
         let raw_plugin = RawPlugin {
             gist: Some(
@@ -444,11 +1045,25 @@ mod tests {
             source: Source::Git {
                 url: Url::parse("git://github.com/rossmacarthur/sheldon-test").unwrap(),
                 reference: None,
+                archive: false,
+                clone_as: None,
+                no_history: false,
+                verify_signature: None,
             },
             dir: None,
             uses: None,
             apply: None,
+            apply_matching: Vec::new(),
+            apply_globs: Vec::new(),
+            templates: IndexMap::new(),
             profiles: None,
+            shell: None,
+            follow_symlinks: false,
+            follow_dependencies: false,
+            version_from: None,
+            match_shebang: None,
+            order: Vec::new(),
+            tags: Vec::new(),
         });
         let raw_plugin = RawPlugin {
             github: Some(GitHubRepository {
@@ -477,11 +1092,25 @@ mod tests {
             source: Source::Git {
                 url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
                 reference: None,
+                archive: false,
+                clone_as: None,
+                no_history: false,
+                verify_signature: None,
             },
             dir: None,
             uses: None,
             apply: None,
+            apply_matching: Vec::new(),
+            apply_globs: Vec::new(),
+            templates: IndexMap::new(),
             profiles: None,
+            shell: None,
+            follow_symlinks: false,
+            follow_dependencies: false,
+            version_from: None,
+            match_shebang: None,
+            order: Vec::new(),
+            tags: Vec::new(),
         });
         let raw_plugin = RawPlugin {
             github: Some(GitHubRepository {
@@ -509,11 +1138,25 @@ mod tests {
             source: Source::Git {
                 url: Url::parse("ssh://git@github.com/rossmacarthur/sheldon-test").unwrap(),
                 reference: None,
+                archive: false,
+                clone_as: None,
+                no_history: false,
+                verify_signature: None,
             },
             dir: None,
             uses: None,
             apply: None,
+            apply_matching: Vec::new(),
+            apply_globs: Vec::new(),
+            templates: IndexMap::new(),
             profiles: None,
+            shell: None,
+            follow_symlinks: false,
+            follow_dependencies: false,
+            version_from: None,
+            match_shebang: None,
+            order: Vec::new(),
+            tags: Vec::new(),
         });
         let raw_plugin = RawPlugin {
             github: Some(GitHubRepository {
@@ -542,11 +1185,26 @@ mod tests {
                 .unwrap();
         let expected = Plugin::External(ExternalPlugin {
             name: name.clone(),
-            source: Source::Remote { url: url.clone() },
+            source: Source::Remote {
+                url: url.clone(),
+                version: None,
+                checksum: None,
+                filename: None,
+            },
             dir: None,
             uses: None,
             apply: None,
+            apply_matching: Vec::new(),
+            apply_globs: Vec::new(),
+            templates: IndexMap::new(),
             profiles: None,
+            shell: None,
+            follow_symlinks: false,
+            follow_dependencies: false,
+            version_from: None,
+            match_shebang: None,
+            order: Vec::new(),
+            tags: Vec::new(),
         });
         let raw_plugin = RawPlugin {
             remote: Some(url),
@@ -585,7 +1243,316 @@ mod tests {
         .unwrap_err();
         assert_eq!(
             err.to_string(),
-            "the `branch`, `tag`, and `rev` fields are not supported by this plugin type"
+            "the `branch`, `tag`, `rev`, and `reference` fields are not supported by this plugin \
+             type"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_remote_with_archive() {
+        let raw_plugin = RawPlugin {
+            remote: Some(
+                Url::parse(
+                    "https://github.com/rossmacarthur/sheldon-test/blob/master/test.plugin.zsh",
+                )
+                .unwrap(),
+            ),
+            archive: Some(true),
+            ..Default::default()
+        };
+        let err = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the `archive` field is not supported by this plugin type"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_remote_with_filename_invalid() {
+        for filename in ["", ".", "..", "../test.zsh", "dir/test.zsh", "/test.zsh"] {
+            let raw_plugin = RawPlugin {
+                remote: Some(Url::parse("https://example.com/plugin").unwrap()),
+                filename: Some(filename.into()),
+                ..Default::default()
+            };
+            let err = normalize_plugin(
+                raw_plugin,
+                "test".to_string(),
+                Shell::default(),
+                &IndexMap::new(),
+                &mut Vec::new(),
+            )
+            .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "the `filename` field must be a plain file name, got `{}`",
+                    filename
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn normalize_plugin_git_with_verify_signature() {
+        let normalize = |verify_signature, trusted_keys: Option<Vec<&str>>| {
+            let raw_plugin = RawPlugin {
+                git: Some(Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap()),
+                verify_signature,
+                trusted_keys: trusted_keys.map(|keys| keys.into_iter().map(Into::into).collect()),
+                ..Default::default()
+            };
+            normalize_plugin(
+                raw_plugin,
+                "test".to_string(),
+                Shell::default(),
+                &IndexMap::new(),
+                &mut Vec::new(),
+            )
+        };
+        let verify = |plugin| match plugin {
+            Plugin::External(ExternalPlugin {
+                source: Source::Git {
+                    verify_signature, ..
+                },
+                ..
+            }) => verify_signature,
+            plugin => panic!("unexpected plugin {:?}", plugin),
+        };
+
+        assert_eq!(verify(normalize(Some(false), None).unwrap()), None);
+        assert_eq!(
+            verify(normalize(Some(true), None).unwrap()),
+            Some(Vec::new())
+        );
+        assert_eq!(
+            verify(normalize(Some(true), Some(vec!["0x0123456789abcdef"])).unwrap()),
+            Some(vec_into!["0123456789ABCDEF"])
+        );
+        assert_eq!(
+            normalize(None, Some(vec!["0123456789ABCDEF"]))
+                .unwrap_err()
+                .to_string(),
+            "the `trusted_keys` field requires `verify_signature = true`"
+        );
+        assert_eq!(
+            normalize(Some(true), Some(vec!["ABCDEF"]))
+                .unwrap_err()
+                .to_string(),
+            "invalid trusted key `ABCDEF`, expected a 16 digit key ID or a 40 digit fingerprint"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_local_with_no_history() {
+        let raw_plugin = RawPlugin {
+            local: Some("/plugins/test".into()),
+            no_history: Some(true),
+            ..Default::default()
+        };
+        let err = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the `no_history` field is not supported by this plugin type"
+        );
+    }
+
+    #[test]
+    fn normalize_invalidate_compdump_bash() {
+        let raw_config = RawConfig {
+            shell: Some(Shell::Bash),
+            invalidate_compdump: Some(true),
+            ..Default::default()
+        };
+        let err = normalize(raw_config, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the `invalidate_compdump` field is only supported by zsh"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_remote_with_checksum() {
+        let raw_plugin = RawPlugin {
+            remote: Some(
+                Url::parse(
+                    "https://github.com/rossmacarthur/sheldon-test/blob/master/test.plugin.zsh",
+                )
+                .unwrap(),
+            ),
+            checksum: Some(format!("sha256:{}", "AB".repeat(32))),
+            ..Default::default()
+        };
+        let plugin = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        match plugin {
+            Plugin::External(ExternalPlugin {
+                source: Source::Remote { checksum, .. },
+                ..
+            }) => assert_eq!(checksum, Some(format!("sha256:{}", "ab".repeat(32)))),
+            plugin => panic!("unexpected plugin {:?}", plugin),
+        }
+    }
+
+    #[test]
+    fn normalize_plugin_remote_with_invalid_checksum() {
+        let raw_plugin = RawPlugin {
+            remote: Some(
+                Url::parse(
+                    "https://github.com/rossmacarthur/sheldon-test/blob/master/test.plugin.zsh",
+                )
+                .unwrap(),
+            ),
+            checksum: Some("md5:abc".to_string()),
+            ..Default::default()
+        };
+        let err = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid checksum `md5:abc`, expected `sha256:` followed by 64 hexadecimal digits"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_remote_with_version() {
+        let raw_plugin = RawPlugin {
+            remote: Some(Url::parse("https://example.com/{{ version }}/test.plugin.zsh").unwrap()),
+            version: Some("1.2.3".to_string()),
+            ..Default::default()
+        };
+        let plugin = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        match plugin {
+            Plugin::External(ExternalPlugin {
+                source: Source::Remote { version, .. },
+                ..
+            }) => assert_eq!(version.as_deref(), Some("1.2.3")),
+            plugin => panic!("unexpected plugin {:?}", plugin),
+        }
+    }
+
+    #[test]
+    fn normalize_plugin_remote_with_version_without_template() {
+        let raw_plugin = RawPlugin {
+            remote: Some(Url::parse("https://example.com/test.plugin.zsh").unwrap()),
+            version: Some("1.2.3".to_string()),
+            ..Default::default()
+        };
+        let err = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the `version` field requires a `{{ version }}` template in the `remote` URL"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_github_with_before() {
+        let raw_plugin = RawPlugin {
+            github: Some("rossmacarthur/sheldon-test".parse().unwrap()),
+            reference: Some(GitReference::Branch("main".to_string())),
+            before: Some("2023-01-01".to_string()),
+            ..Default::default()
+        };
+        let plugin = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        match plugin {
+            Plugin::External(ExternalPlugin {
+                source: Source::Git { reference, .. },
+                ..
+            }) => assert_eq!(
+                reference,
+                Some(GitReference::Before {
+                    branch: Some("main".to_string()),
+                    date: "2023-01-01".to_string()
+                })
+            ),
+            plugin => panic!("unexpected plugin {:?}", plugin),
+        }
+
+        let raw_plugin = RawPlugin {
+            github: Some("rossmacarthur/sheldon-test".parse().unwrap()),
+            reference: Some(GitReference::Tag("v0.1.0".to_string())),
+            before: Some("2023-01-01".to_string()),
+            ..Default::default()
+        };
+        let err = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the `before` field is only supported with the `branch` field"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_github_with_empty_fallback() {
+        let raw_plugin = RawPlugin {
+            github: Some("rossmacarthur/sheldon-test".parse().unwrap()),
+            reference: Some(GitReference::Fallback(Vec::new())),
+            ..Default::default()
+        };
+        let err = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the `reference` field must contain at least one candidate"
         );
     }
 
@@ -626,7 +1593,17 @@ mod tests {
             dir: None,
             uses: None,
             apply: None,
+            apply_matching: Vec::new(),
+            apply_globs: Vec::new(),
+            templates: IndexMap::new(),
             profiles: None,
+            shell: None,
+            follow_symlinks: false,
+            follow_dependencies: false,
+            version_from: None,
+            match_shebang: None,
+            order: Vec::new(),
+            tags: Vec::new(),
         });
         let raw_plugin = RawPlugin {
             local: Some("/home/temp".into()),
@@ -650,6 +1627,8 @@ mod tests {
             name: name.clone(),
             raw: "echo 'this is a test'\n".to_string(),
             profiles: None,
+            shell: None,
+            tags: Vec::new(),
         });
         let raw_plugin = RawPlugin {
             inline: Some("echo 'this is a test'\n".to_string()),
@@ -670,7 +1649,7 @@ mod tests {
     fn normalize_plugin_inline_apply() {
         let raw_plugin = RawPlugin {
             inline: Some("echo 'this is a test'\n".to_string()),
-            apply: Some(vec_into!["test"]),
+            apply: Some(RawApply::Names(vec_into!["test"])),
             ..Default::default()
         };
         let err = normalize_plugin(
@@ -694,7 +1673,7 @@ mod tests {
                 owner: "rossmacarthur".to_string(),
                 name: "sheldon-test".to_string(),
             }),
-            apply: Some(vec_into!["test"]),
+            apply: Some(RawApply::Names(vec_into!["test"])),
             ..Default::default()
         };
         let err = normalize_plugin(
@@ -707,4 +1686,360 @@ mod tests {
         .unwrap_err();
         assert_eq!(err.to_string(), "unknown template `test`");
     }
+
+    #[test]
+    fn normalize_plugin_shell_templates() {
+        let raw_plugin = || RawPlugin {
+            github: Some("rossmacarthur/sheldon-test".parse().unwrap()),
+            apply: Some(RawApply::Names(vec_into!["fpath"])),
+            shell: Some(Shell::Zsh),
+            ..Default::default()
+        };
+        let plugin = normalize_plugin(
+            raw_plugin(),
+            "test".to_string(),
+            Shell::Bash,
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert!(matches!(plugin, Plugin::External(p) if p.shell == Some(Shell::Zsh)));
+
+        let err = normalize_plugin(
+            RawPlugin {
+                shell: None,
+                ..raw_plugin()
+            },
+            "test".to_string(),
+            Shell::Bash,
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "unknown template `fpath`");
+    }
+
+    #[test]
+    fn normalize_plugin_templates() {
+        let raw_config: RawConfig = toml::from_str(
+            r#"
+[plugins.first]
+github = "rossmacarthur/sheldon-test"
+apply = ["hello"]
+templates.hello = "echo hello {{ name }}"
+"#,
+        )
+        .unwrap();
+        let config = normalize(raw_config, &mut Vec::new()).unwrap();
+        match &config.plugins[0] {
+            Plugin::External(plugin) => {
+                assert_eq!(
+                    plugin.templates,
+                    indexmap::indexmap! {
+                        "hello".to_string() => Template::from("echo hello {{ name }}"),
+                    }
+                );
+            }
+            plugin => panic!("unexpected plugin {:?}", plugin),
+        }
+
+        // The template is not available to other plugins.
+        let raw_config: RawConfig = toml::from_str(
+            r#"
+[plugins.first]
+github = "rossmacarthur/sheldon-test"
+apply = ["hello"]
+templates.hello = "echo hello {{ name }}"
+
+[plugins.second]
+github = "rossmacarthur/sheldon-test"
+apply = ["hello"]
+"#,
+        )
+        .unwrap();
+        let err = normalize(raw_config, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "failed to normalize plugin `second`: unknown template `hello`"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_apply_globs() {
+        let raw_config: RawConfig = toml::from_str(
+            r#"
+[plugins.test]
+github = "rossmacarthur/sheldon-test"
+apply = { source = "*.zsh", fpath = "completions/*" }
+"#,
+        )
+        .unwrap();
+        let config = normalize(raw_config, &mut Vec::new()).unwrap();
+        match &config.plugins[0] {
+            Plugin::External(plugin) => {
+                assert_eq!(plugin.apply, Some(Vec::new()));
+                assert_eq!(
+                    plugin.apply_globs,
+                    vec![
+                        ("source".into(), "*.zsh".into()),
+                        ("fpath".into(), "completions/*".into())
+                    ]
+                );
+            }
+            plugin => panic!("unexpected plugin {:?}", plugin),
+        }
+
+        let raw_config: RawConfig = toml::from_str(
+            r#"
+[plugins.test]
+github = "rossmacarthur/sheldon-test"
+apply = { test = "*.zsh" }
+"#,
+        )
+        .unwrap();
+        let err = normalize(raw_config, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "failed to normalize plugin `test`: unknown template `test`"
+        );
+    }
+
+    #[test]
+    fn normalize_clone_as_conflict() {
+        let raw_config: RawConfig = toml::from_str(
+            r#"
+[plugins.a]
+github = "owner/a"
+clone_as = "short"
+
+[plugins.b]
+github = "owner/a"
+clone_as = "short"
+
+[plugins.c]
+github = "owner/c"
+clone_as = "short"
+"#,
+        )
+        .unwrap();
+        let err = normalize(raw_config, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "plugins `a` and `c` have different sources but the same `clone_as` directory `short`"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_clone_as_invalid() {
+        for (clone_as, local) in [("a/b", false), ("..", false), ("short", true)] {
+            let raw_plugin = RawPlugin {
+                github: (!local).then(|| "owner/repo".parse().unwrap()),
+                local: local.then(|| "/plugins".into()),
+                clone_as: Some(clone_as.into()),
+                ..Default::default()
+            };
+            let err = normalize_plugin(
+                raw_plugin,
+                "test".to_string(),
+                Shell::default(),
+                &IndexMap::new(),
+                &mut Vec::new(),
+            )
+            .unwrap_err();
+            let expected = if local {
+                "the `clone_as` field is not supported by this plugin type".to_string()
+            } else {
+                format!(
+                    "the `clone_as` field must be a single directory name, got `{}`",
+                    clone_as
+                )
+            };
+            assert_eq!(err.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn normalize_wrap_in_function_invalid() {
+        let raw_config: RawConfig = toml::from_str("wrap_in_function = \"sheldon load\"").unwrap();
+        let err = normalize(raw_config, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the `wrap_in_function` field must be a valid function name, got `sheldon load`"
+        );
+    }
+
+    #[test]
+    fn normalize_clone_dir_layout_invalid() {
+        let raw_config: RawConfig = toml::from_str("clone_dir_layout = \"{{ owner\"").unwrap();
+        let err = normalize(raw_config, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to compile the `clone_dir_layout` template"
+        );
+    }
+
+    #[test]
+    fn normalize_clone_dir_layout_collision() {
+        let raw_config: RawConfig = toml::from_str(
+            r#"
+clone_dir_layout = "{{ repo }}"
+
+[plugins.a]
+github = "owner/a"
+
+[plugins.b]
+github = "owner/a"
+tag = "v0.1.0"
+
+[plugins.c]
+github = "other/a"
+"#,
+        )
+        .unwrap();
+        let err = normalize(raw_config, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "plugins `a` and `c` have different sources but the same clone directory `a`, change \
+             the `clone_dir_layout`"
+        );
+    }
+
+    #[test]
+    fn normalize_defer_without_zsh_defer() {
+        let raw_config: RawConfig = toml::from_str(
+            r#"
+[plugins.zsh-syntax-highlighting]
+github = "zsh-users/zsh-syntax-highlighting"
+apply = ["defer"]
+
+[plugins.zsh-defer]
+github = "romkatv/zsh-defer"
+"#,
+        )
+        .unwrap();
+        let mut warnings = Vec::new();
+        normalize(raw_config, &mut warnings).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].to_string(),
+            "plugin `zsh-syntax-highlighting` applies the `defer` template but no `zsh-defer` \
+             plugin is defined before it"
+        );
+    }
+
+    #[test]
+    fn normalize_defer_with_zsh_defer() {
+        let raw_config: RawConfig = toml::from_str(
+            r#"
+[plugins.defer]
+github = "romkatv/zsh-defer"
+
+[plugins.zsh-syntax-highlighting]
+github = "zsh-users/zsh-syntax-highlighting"
+apply = ["defer"]
+"#,
+        )
+        .unwrap();
+        let mut warnings = Vec::new();
+        normalize(raw_config, &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn normalize_groups_order() {
+        let raw_config: RawConfig = toml::from_str(
+            r#"
+[plugins.first]
+local = "~/first"
+
+[groups.prompt.plugins.second]
+local = "~/second"
+
+[groups.prompt.plugins.third]
+local = "~/third"
+
+[groups.completions.plugins.fourth]
+local = "~/fourth"
+"#,
+        )
+        .unwrap();
+        let config = normalize(raw_config, &mut Vec::new()).unwrap();
+        let names: Vec<_> = config
+            .plugins
+            .iter()
+            .map(|plugin| match plugin {
+                Plugin::External(plugin) => plugin.name.as_str(),
+                Plugin::Inline(plugin) => plugin.name.as_str(),
+            })
+            .collect();
+        assert_eq!(names, ["first", "second", "third", "fourth"]);
+    }
+
+    #[test]
+    fn normalize_groups_disabled() {
+        let raw_config: RawConfig = toml::from_str(
+            r#"
+[plugins.first]
+local = "~/first"
+
+[groups.prompt]
+enabled = false
+
+[groups.prompt.plugins.second]
+local = "~/second"
+
+[groups.prompt.plugins.third]
+inline = "echo third"
+"#,
+        )
+        .unwrap();
+        let config = normalize(raw_config, &mut Vec::new()).unwrap();
+        assert_eq!(config.plugins.len(), 1);
+        assert!(matches!(&config.plugins[0], Plugin::External(p) if p.name == "first"));
+        assert_eq!(
+            config.skipped,
+            indexmap_into! {
+                "second" => SkipReason::Group("prompt".to_string()),
+                "third" => SkipReason::Group("prompt".to_string())
+            }
+        );
+    }
+
+    #[test]
+    fn normalize_groups_duplicate_plugin() {
+        let raw_config: RawConfig = toml::from_str(
+            r#"
+[plugins.first]
+local = "~/first"
+
+[groups.prompt]
+enabled = false
+
+[groups.prompt.plugins.first]
+local = "~/other"
+"#,
+        )
+        .unwrap();
+        let err = normalize(raw_config, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "plugin `first` in group `prompt` is already defined"
+        );
+    }
+
+    #[test]
+    fn normalize_remove_templates_not_built_in() {
+        let raw_config: RawConfig = toml::from_str(
+            r#"
+shell = "bash"
+remove_templates = ["PATH", "fpath"]
+"#,
+        )
+        .unwrap();
+        let err = normalize(raw_config, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cannot remove template `fpath`, it is not a built-in template"
+        );
+    }
 }
@@ -6,13 +6,10 @@ use crate::Context;
 pub trait MatchesProfile {
     fn profiles(&self) -> Option<&[String]>;
 
-    fn matches_profile(&self, ctx: &Context) -> bool {
+    fn matches_profile(&self, active: &[String]) -> bool {
         match self.profiles() {
             None => true,
-            Some(profiles) => match &ctx.profile {
-                None => false,
-                Some(profile) => profiles.contains(profile),
-            },
+            Some(profiles) => profiles.iter().any(|profile| active.contains(profile)),
         }
     }
 }
@@ -28,3 +25,14 @@ impl MatchesProfile for &InlinePlugin {
         self.profiles.as_deref()
     }
 }
+
+/// Returns the active profiles.
+///
+/// A profile given on the command line takes precedence over the default
+/// active profiles in the config file.
+pub fn active_profiles(ctx: &Context, defaults: Option<Vec<String>>) -> Vec<String> {
+    match ctx.profile() {
+        Some(profile) => vec![profile.to_string()],
+        None => defaults.unwrap_or_default(),
+    }
+}
@@ -0,0 +1,148 @@
+//! Export a self-contained snapshot of the config.
+
+use std::path::Path;
+
+use anyhow::{bail, Context as ResultExt, Result};
+
+use crate::config::file::{RawConfig, RawPlugin};
+use crate::config::{merge_imports, raw_from_path, GitReference};
+use crate::context::Context;
+use crate::lock::{LockedConfig, LockedExternalPlugin, LockedPlugin};
+use crate::util::sha256;
+
+/// Export a snapshot of the config file at the given path.
+///
+/// The snapshot is a config file that contains the plugins from any imported
+/// config files, with each Git plugin pinned to the commit and each remote
+/// plugin pinned to the checksum in the given locked config. Plugins that are
+/// not in the locked config, for example because of profiles, are left as is.
+pub fn snapshot(ctx: &Context, path: &Path, locked: &LockedConfig) -> Result<String> {
+    let mut warnings = Vec::new();
+    let raw_config = raw_from_path(path, &mut warnings)?;
+    let mut raw_config = merge_imports(ctx, path, raw_config, &mut warnings)?;
+    raw_config.imports = None;
+    raw_config.fragments = None;
+
+    let RawConfig {
+        plugins, groups, ..
+    } = &mut raw_config;
+    let plugins = plugins
+        .iter_mut()
+        .chain(groups.values_mut().flat_map(|g| g.plugins.iter_mut()));
+    for (name, plugin) in plugins {
+        if let Some(LockedPlugin::External(locked)) = locked.plugin(name) {
+            pin(plugin, locked).with_context(s!("failed to pin plugin `{}`", name))?;
+        }
+    }
+
+    let mut value = toml::Value::try_from(&raw_config).context("failed to serialize snapshot")?;
+    if let Some(table) = value.as_table_mut() {
+        for key in ["templates", "groups"] {
+            if table
+                .get(key)
+                .and_then(toml::Value::as_table)
+                .is_some_and(|t| t.is_empty())
+            {
+                table.remove(key);
+            }
+        }
+    }
+    toml::to_string_pretty(&value).context("failed to serialize snapshot")
+}
+
+/// Pin the plugin to the exact version that was locked.
+fn pin(plugin: &mut RawPlugin, locked: &LockedExternalPlugin) -> Result<()> {
+    if plugin.git.is_some() || plugin.gist.is_some() || plugin.github.is_some() {
+        if plugin.archive == Some(true) || plugin.no_history == Some(true) {
+            bail!("the source was exported without Git history");
+        }
+        let commit = locked
+            .revision()
+            .context("failed to find the checked out commit")?;
+        plugin.reference = Some(GitReference::Rev(commit));
+        plugin.before = None;
+    } else if plugin.remote.is_some() {
+        let file = &locked.files[0];
+        let digest = sha256::digest_file(file)
+            .with_context(s!("failed to read from `{}`", file.display()))?;
+        plugin.checksum = Some(format!("sha256:{}", digest));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use url::Url;
+
+    use crate::config;
+    use crate::lock;
+    use crate::util::{git, testing};
+
+    fn locked_head(locked: &LockedConfig) -> Vec<(String, git2::Oid)> {
+        locked
+            .plugins
+            .iter()
+            .map(|plugin| match plugin {
+                LockedPlugin::External(plugin) => {
+                    let repo = git::open(&plugin.source_dir).unwrap();
+                    let oid = repo.head().unwrap().target().unwrap();
+                    (plugin.name.clone(), oid)
+                }
+                LockedPlugin::Inline(plugin) => (plugin.name.clone(), git2::Oid::zero()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn snapshot_export_import_reproduces_locked_plugins() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let origin = temp.path().join("origin");
+        let oid = testing::git_commit(&origin, "test.plugin.zsh", "echo 'first'\n");
+        let url = Url::from_directory_path(&origin).unwrap();
+
+        // Seed the clone directory so that locking does not need the network.
+        let export_dir = temp.path().join("export");
+        let ctx = Context::testing(&export_dir);
+        git2::Repository::clone(url.as_str(), ctx.clone_dir().join("example.com/test")).unwrap();
+        fs::write(
+            ctx.config_file(),
+            "shell = \"zsh\"\n\n\
+             [plugins.test]\n\
+             git = \"https://example.com/test\"\n\n\
+             [plugins.inline-test]\n\
+             inline = \"echo 'inline'\"\n",
+        )
+        .unwrap();
+        let mut warnings = Vec::new();
+        let config = config::from_path(&ctx, ctx.config_file(), &mut warnings).unwrap();
+        let locked = lock::config(&ctx, config).unwrap();
+        assert!(locked.errors.is_empty());
+        let snapshot = snapshot(&ctx, ctx.config_file(), &locked).unwrap();
+        assert!(snapshot.contains(&format!("rev = '{}'", oid)));
+
+        // The snapshot keeps the exported commit even after upstream moves on.
+        testing::git_commit(&origin, "test.plugin.zsh", "echo 'second'\n");
+
+        let import_dir = temp.path().join("import");
+        let ctx = Context::testing(&import_dir);
+        git2::Repository::clone(url.as_str(), ctx.clone_dir().join("example.com/test")).unwrap();
+        fs::write(ctx.config_file(), &snapshot).unwrap();
+        let config = config::from_path(&ctx, ctx.config_file(), &mut warnings).unwrap();
+        let imported = lock::config(&ctx, config).unwrap();
+        assert!(imported.errors.is_empty());
+        assert!(warnings.is_empty());
+
+        assert_eq!(locked_head(&imported), locked_head(&locked));
+        assert_eq!(
+            locked_head(&imported),
+            vec![
+                ("test".to_string(), oid),
+                ("inline-test".to_string(), git2::Oid::zero())
+            ]
+        );
+    }
+}
@@ -2,12 +2,17 @@
 
 use std::fmt;
 use std::path::{Path, PathBuf};
+use std::result;
+use std::str::FromStr;
+use std::time::Duration;
 
 pub use ansi_term::Color;
 use anyhow::Error;
+use indexmap::{indexmap, IndexMap};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-use crate::lock::LockMode;
+use crate::lock::{LockMode, OnError};
 use crate::util::PathExt;
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
@@ -25,6 +30,22 @@ pub struct Context {
     pub output: Output,
     #[serde(skip)]
     pub lock_mode: Option<LockMode>,
+    #[serde(skip)]
+    pub max_download_size: Option<u64>,
+    #[serde(skip)]
+    pub jobs: Option<usize>,
+    #[serde(skip)]
+    pub match_jobs: Option<usize>,
+    #[serde(skip)]
+    pub on_error: OnError,
+    #[serde(skip)]
+    pub strict: bool,
+    #[serde(skip)]
+    pub credential_helper: Option<String>,
+    #[serde(skip)]
+    pub update_max_age: Option<Duration>,
+    #[serde(skip)]
+    pub gnupg_home: Option<PathBuf>,
 }
 
 /// The output style.
@@ -34,14 +55,40 @@ pub struct Output {
     pub verbosity: Verbosity,
     /// Whether to not use ANSI color codes.
     pub no_color: bool,
+    /// How progress events are reported.
+    pub progress_format: ProgressFormat,
+}
+
+/// How progress events are reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProgressFormat {
+    /// Only report progress using the human readable output.
+    #[default]
+    Human,
+    /// Also report each progress event as a line of JSON.
+    Json,
+}
+
+/// A progress event while installing a source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    /// The source started installing.
+    Started,
+    /// The source and its plugins finished installing.
+    Finished,
+    /// The source or one of its plugins failed to install.
+    Failed,
 }
 
 /// The requested verbosity of output.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd)]
 pub enum Verbosity {
     Quiet,
+    #[default]
     Normal,
     Verbose,
+    Trace,
 }
 
 /// A message that can be logged.
@@ -57,12 +104,6 @@ pub trait ToMessage {
     fn to_message(&self, ctx: &Context) -> Message<'_>;
 }
 
-impl Default for Verbosity {
-    fn default() -> Self {
-        Self::Normal
-    }
-}
-
 impl Context {
     /// The location of the home directory.
     pub fn home(&self) -> &Path {
@@ -99,6 +140,25 @@ impl Context {
         &self.download_dir
     }
 
+    /// Each of the resolved paths with its name.
+    pub fn paths(&self) -> IndexMap<&'static str, &Path> {
+        indexmap! {
+            "home" => self.home(),
+            "config_dir" => self.config_dir(),
+            "data_dir" => self.data_dir(),
+            "config_file" => self.config_file(),
+            "lock_file" => self.lock_file(),
+            "clone_dir" => self.clone_dir(),
+            "download_dir" => self.download_dir(),
+        }
+    }
+
+    /// The directory that cleaned files and directories are moved to when the
+    /// `trash` option is set.
+    pub fn trash_dir(&self) -> PathBuf {
+        self.data_dir().join("trash")
+    }
+
     /// The profile used for conditional plugins.
     pub fn profile(&self) -> Option<&str> {
         self.profile.as_deref()
@@ -118,6 +178,47 @@ impl Context {
         path.as_ref().replace_home(self.home())
     }
 
+    /// The maximum size in bytes of a remote source download.
+    pub fn max_download_size(&self) -> Option<u64> {
+        self.max_download_size
+    }
+
+    /// The number of plugin sources to install in parallel.
+    pub fn jobs(&self) -> Option<usize> {
+        self.jobs
+    }
+
+    /// The number of plugins to match files for in parallel.
+    pub fn match_jobs(&self) -> Option<usize> {
+        self.match_jobs
+    }
+
+    /// What to do when a source or plugin fails to lock.
+    pub fn on_error(&self) -> OnError {
+        self.on_error
+    }
+
+    /// Whether warnings about the locked plugins should be errors instead.
+    pub fn strict(&self) -> bool {
+        self.strict
+    }
+
+    /// The Git credential helper to use for private repositories.
+    pub fn credential_helper(&self) -> Option<&str> {
+        self.credential_helper.as_deref()
+    }
+
+    /// When updating, only advance Git sources to commits at least this old.
+    pub fn update_max_age(&self) -> Option<Duration> {
+        self.update_max_age
+    }
+
+    /// The GnuPG home directory to verify signatures with, instead of the
+    /// default keyring.
+    pub fn gnupg_home(&self) -> Option<&Path> {
+        self.gnupg_home.as_deref()
+    }
+
     pub fn lock_mode(&self) -> LockMode {
         self.lock_mode.unwrap_or(LockMode::Normal)
     }
@@ -147,6 +248,57 @@ impl Context {
     pub fn log_error(&self, color: Color, prefix: &str, err: &Error) {
         log_error(self.output.no_color, color, prefix, err);
     }
+
+    /// Report a progress event for the given source.
+    ///
+    /// This does nothing unless JSON progress events were requested, in which
+    /// case the event is written to stderr as a single line of JSON regardless
+    /// of the verbosity.
+    pub fn log_event(&self, event: Event, source: &str, err: Option<&Error>) {
+        if self.output.progress_format != ProgressFormat::Json {
+            return;
+        }
+        #[derive(Serialize)]
+        struct Line<'a> {
+            event: Event,
+            source: &'a str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            error: Option<String>,
+        }
+        let line = Line {
+            event,
+            source,
+            error: err.map(|err| format!("{:#}", err)),
+        };
+        // Serializing this struct cannot fail.
+        eprintln!("{}", serde_json::to_string(&line).unwrap());
+    }
+}
+
+impl fmt::Display for ProgressFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Human => f.write_str("human"),
+            Self::Json => f.write_str("json"),
+        }
+    }
+}
+
+/// Produced when we fail to parse a progress format.
+#[derive(Debug, Error)]
+#[error("expected `human` or `json`, got `{}`", self.0)]
+pub struct ParseProgressFormatError(String);
+
+impl FromStr for ProgressFormat {
+    type Err = ParseProgressFormatError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            s => Err(ParseProgressFormatError(s.to_string())),
+        }
+    }
 }
 
 impl<'a> fmt::Display for Message<'a> {
@@ -7,10 +7,11 @@ use std::process::{self, Command};
 use std::result;
 use std::str;
 
-use anyhow::{anyhow, bail, Context as ResultExt, Result};
+use anyhow::{anyhow, bail, Context as ResultExt, Error, Result};
 use thiserror::Error;
 
-use crate::config::EditConfig;
+use crate::config::{self, EditConfig};
+use crate::context::Context;
 use crate::util::TempPath;
 
 /// Possible environment variables.
@@ -94,7 +95,7 @@ impl Editor {
     }
 
     /// Open a file for editing with initial contents.
-    pub fn edit(self, path: &Path, contents: &str) -> Result<Child> {
+    pub fn edit(&self, path: &Path, contents: &str) -> Result<Child> {
         let (overwrite, temp) = match TempPath::new(path) {
             Ok(temp) => (true, temp),
             Err(path) => {
@@ -117,35 +118,184 @@ impl Editor {
                 }
             }
         };
-        let Self { bin, args } = self;
         if overwrite {
-            fs::write(temp.path(), &contents).context("failed to write to temporary file")?;
+            fs::write(temp.path(), contents).context("failed to write to temporary file")?;
         }
-        let child = Command::new(bin)
-            .args(args)
+        let child = Command::new(&self.bin)
+            .args(&self.args)
             .arg(temp.path())
             .spawn()
             .context("failed to spawn editor subprocess")?;
         Ok(Child { child, temp })
     }
+
+    /// Open the config file for editing until the edited config is valid.
+    ///
+    /// If the edited config is invalid then `reopen` is called with the error
+    /// to decide whether to reopen the editor with the edited contents. If not,
+    /// the error is returned. The config file itself is never modified.
+    pub fn edit_until_valid<F>(
+        &self,
+        ctx: &Context,
+        path: &Path,
+        original_contents: &str,
+        mut reopen: F,
+    ) -> Result<EditConfig>
+    where
+        F: FnMut(&Error) -> bool,
+    {
+        let mut contents = original_contents.to_string();
+        loop {
+            let edited = self.edit(path, &contents)?.wait()?;
+            if edited == original_contents {
+                bail!("aborted, no changes!");
+            }
+            match validate(ctx, path, &edited) {
+                Ok(config) => return Ok(config),
+                Err(err) if reopen(&err) => contents = edited,
+                Err(err) => {
+                    return Err(err.context("edited config is invalid, not updating config file"))
+                }
+            }
+        }
+    }
+}
+
+/// Returns the path that the previous config file is backed up to.
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".bak");
+    path.with_file_name(file_name)
+}
+
+/// Validate the edited contents of the config file at the given path.
+///
+/// The config is validated together with any config files and fragments that
+/// it imports.
+fn validate(ctx: &Context, path: &Path, contents: &str) -> Result<EditConfig> {
+    let edit_config = EditConfig::from_str(contents)?;
+    config::from_str_at(ctx, path, contents, &mut Vec::new())?;
+    Ok(edit_config)
 }
 
 impl Child {
-    /// Wait for the child process to exit and then update the config file.
-    pub fn wait_and_update(self, original_contents: &str) -> Result<EditConfig> {
+    /// Wait for the child process to exit and then return the edited contents.
+    fn wait(self) -> Result<String> {
         let Self { mut child, temp } = self;
         let exit_status = child.wait()?;
         if exit_status.success() {
-            let contents =
-                fs::read_to_string(temp.path()).context("failed to read from temporary file")?;
-            if contents == original_contents {
-                bail!("aborted, no changes!");
-            } else {
-                EditConfig::from_str(&contents)
-                    .context("edited config is invalid, not updating config file")
-            }
+            fs::read_to_string(temp.path()).context("failed to read from temporary file")
         } else {
             bail!("editor terminated with {}", exit_status)
         }
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////
+// Unit tests
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+
+    /// An editor that runs the given shell script with the file path as `$0`.
+    fn script_editor(script: &str) -> Editor {
+        Editor {
+            bin: "sh".into(),
+            args: vec!["-c".to_string(), script.to_string()],
+        }
+    }
+
+    #[test]
+    fn backup_path_appends_extension() {
+        assert_eq!(
+            backup_path(Path::new("/sheldon/plugins.toml")),
+            Path::new("/sheldon/plugins.toml.bak")
+        );
+    }
+
+    #[test]
+    fn editor_edit_until_valid_invalid_toml() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let path = temp.path().join("plugins.toml");
+        let original = "[plugins.test]\ninline = 'echo test'\n";
+        fs::write(&path, original).unwrap();
+
+        let editor = script_editor("printf 'plugins = ' > \"$0\"");
+        let mut reopened = 0;
+        let err = editor
+            .edit_until_valid(&ctx, &path, original, |_| {
+                reopened += 1;
+                reopened < 2
+            })
+            .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "edited config is invalid, not updating config file"
+        );
+        assert_eq!(reopened, 2);
+        assert_eq!(fs::read_to_string(&path).unwrap(), original);
+        assert!(!temp.path().join("~plugins.toml").exists());
+    }
+
+    #[test]
+    fn editor_edit_until_valid_reopens_edited_contents() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let path = temp.path().join("plugins.toml");
+        let original = "[plugins.test]\ninline = 'echo test'\n";
+        fs::write(&path, original).unwrap();
+
+        // The first edit adds an unknown template, the second edit fixes it.
+        let editor = script_editor(
+            "if grep -q unknown \"$0\"; then \
+                 sed -i.orig 's/unknown/source/' \"$0\"; \
+             else \
+                 printf \"[plugins.test]\\nlocal = '.'\\napply = ['unknown']\\n\" > \"$0\"; \
+             fi",
+        );
+        let config = editor
+            .edit_until_valid(&ctx, &path, original, |err| {
+                assert_eq!(err.root_cause().to_string(), "unknown template `unknown`");
+                true
+            })
+            .unwrap();
+
+        assert_eq!(
+            config.to_string(),
+            "[plugins.test]\nlocal = '.'\napply = ['source']\n"
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), original);
+    }
+
+    #[test]
+    fn editor_edit_until_valid_validates_imports() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let path = temp.path().join("plugins.toml");
+        let original = "[plugins.test]\ninline = 'echo test'\n";
+        fs::write(&path, original).unwrap();
+        fs::write(
+            temp.path().join("work.toml"),
+            "[plugins.work]\nlocal = '.'\napply = ['unknown']\n",
+        )
+        .unwrap();
+
+        let editor = script_editor("printf \"imports = ['work.toml']\\n\" > \"$0\"");
+        let err = editor
+            .edit_until_valid(&ctx, &path, original, |err| {
+                assert_eq!(err.root_cause().to_string(), "unknown template `unknown`");
+                false
+            })
+            .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "edited config is invalid, not updating config file"
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), original);
+    }
+}
@@ -0,0 +1,244 @@
+//! Check that the external tools the config relies on are installed.
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use indexmap::IndexMap;
+
+use crate::config::{Config, Plugin, Shell, Source};
+use crate::context::Context;
+use crate::util::keyring;
+
+/// An external tool that is required by the config.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Tool {
+    /// The name of the program.
+    pub name: &'static str,
+    /// Why the program is required.
+    pub reason: String,
+    /// Where the program was found, if it was found.
+    pub path: Option<PathBuf>,
+}
+
+/// Find the external tools that the config relies on, and look each of them up
+/// in the given search path.
+///
+/// Git sources do not need Git to be installed because it is built into
+/// Sheldon, so Git is only reported when it is run directly, for example to
+/// verify signatures or to fetch a tree without its history.
+pub fn check(ctx: &Context, config: &Config, search_path: Option<&OsStr>, cwd: &Path) -> Vec<Tool> {
+    let mut required = IndexMap::new();
+
+    let mut shells = vec![config.shell];
+    shells.extend(config.plugins.iter().filter_map(|plugin| match plugin {
+        Plugin::External(plugin) => plugin.shell,
+        Plugin::Inline(plugin) => plugin.shell,
+    }));
+    for shell in shells {
+        let name = match shell {
+            Shell::Bash => "bash",
+            Shell::Zsh => "zsh",
+        };
+        required
+            .entry(name)
+            .or_insert_with(|| format!("to source the script generated for `{}`", shell));
+    }
+
+    let plugin_templates = config.plugins.iter().flat_map(|plugin| match plugin {
+        Plugin::External(plugin) => Some(&plugin.templates),
+        Plugin::Inline(_) => None,
+    });
+    let secret = config
+        .templates
+        .iter()
+        .chain(plugin_templates.flatten())
+        .find(|(_, t)| t.uses_secret());
+    if let Some((template, _)) = secret {
+        if let Some(program) = keyring::program() {
+            required
+                .entry(program)
+                .or_insert_with(|| format!("to look up secrets for the `{}` template", template));
+        }
+    }
+
+    let verified = config.plugins.iter().find_map(|plugin| match plugin {
+        Plugin::External(plugin) => match &plugin.source {
+            Source::Git {
+                verify_signature: Some(_),
+                ..
+            } => Some(&plugin.name),
+            _ => None,
+        },
+        Plugin::Inline(_) => None,
+    });
+    if let Some(name) = verified {
+        for program in ["git", "gpg"] {
+            required
+                .entry(program)
+                .or_insert_with(|| format!("to verify the signature of the `{}` plugin", name));
+        }
+    }
+
+    let archived = config.plugins.iter().find_map(|plugin| match plugin {
+        Plugin::External(plugin) => match &plugin.source {
+            Source::Git {
+                no_history: true, ..
+            } => Some(&plugin.name),
+            _ => None,
+        },
+        Plugin::Inline(_) => None,
+    });
+    if let Some(name) = archived {
+        for program in ["git", "tar"] {
+            required
+                .entry(program)
+                .or_insert_with(|| format!("to fetch the `{}` plugin without its history", name));
+        }
+    }
+
+    if ctx.credential_helper().is_some() {
+        required
+            .entry("sh")
+            .or_insert_with(|| "to run the credential helper".to_string());
+    }
+
+    required
+        .into_iter()
+        .map(|(name, reason)| Tool {
+            name,
+            reason,
+            path: which::which_in(name, search_path, cwd).ok(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::config;
+
+    fn check_config(dir: &Path, contents: &str, search_path: &Path) -> Vec<Tool> {
+        let ctx = Context::testing(dir);
+        fs::write(ctx.config_file(), contents).unwrap();
+        let mut warnings = Vec::new();
+        let config = config::from_path(&ctx, ctx.config_file(), &mut warnings).unwrap();
+        check(&ctx, &config, Some(search_path.as_os_str()), dir)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_reports_present_and_missing_tools() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let bin = temp.path().join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        let zsh = bin.join("zsh");
+        fs::write(&zsh, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&zsh, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let tools = check_config(
+            temp.path(),
+            "shell = \"zsh\"\n\n\
+             [plugins.inline-test]\n\
+             inline = \"echo 'inline'\"\n\
+             shell = \"bash\"\n\n\
+             [templates]\n\
+             token = \"export TOKEN={{ secret \\\"token\\\" }}\"\n",
+            &bin,
+        );
+
+        let found: Vec<_> = tools
+            .iter()
+            .map(|tool| (tool.name, tool.path.is_some()))
+            .collect();
+        let mut expected = vec![("zsh", true), ("bash", false)];
+        if let Some(program) = keyring::program() {
+            expected.push((program, false));
+        }
+        assert_eq!(found, expected);
+        assert_eq!(tools[0].path.as_deref(), Some(zsh.as_path()));
+        assert_eq!(tools[1].reason, "to source the script generated for `bash`");
+    }
+
+    #[test]
+    fn check_verify_signature_requires_git_and_gpg() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let tools = check_config(
+            temp.path(),
+            "shell = \"bash\"\n\n\
+             [plugins.test]\n\
+             git = \"https://github.com/rossmacarthur/sheldon-test\"\n\
+             tag = \"v0.1.0\"\n\
+             verify_signature = true\n",
+            temp.path(),
+        );
+        let found: Vec<_> = tools.iter().map(|tool| tool.name).collect();
+        assert_eq!(found, ["bash", "git", "gpg"]);
+        assert_eq!(
+            tools[2].reason,
+            "to verify the signature of the `test` plugin"
+        );
+    }
+
+    #[test]
+    fn check_no_history_requires_git_and_tar() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let tools = check_config(
+            temp.path(),
+            "shell = \"bash\"\n\n\
+             [plugins.test]\n\
+             git = \"https://example.com/test.git\"\n\
+             no_history = true\n",
+            temp.path(),
+        );
+        let found: Vec<_> = tools.iter().map(|tool| tool.name).collect();
+        assert_eq!(found, ["bash", "git", "tar"]);
+        assert_eq!(
+            tools[2].reason,
+            "to fetch the `test` plugin without its history"
+        );
+    }
+
+    #[test]
+    fn check_plugin_secret_requires_keyring() {
+        let program = match keyring::program() {
+            Some(program) => program,
+            None => return,
+        };
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let tools = check_config(
+            temp.path(),
+            "shell = \"bash\"\n\n\
+             [plugins.test]\n\
+             local = \"~\"\n\
+             apply = [\"token\"]\n\n\
+             [plugins.test.templates]\n\
+             token = \"export TOKEN={{ secret \\\"token\\\" }}\"\n",
+            temp.path(),
+        );
+        let found: Vec<_> = tools.iter().map(|tool| tool.name).collect();
+        assert_eq!(found, ["bash", program]);
+        assert_eq!(
+            tools[1].reason,
+            "to look up secrets for the `token` template"
+        );
+    }
+
+    #[test]
+    fn check_without_secrets_does_not_require_keyring() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let tools = check_config(temp.path(), "shell = \"bash\"\n", temp.path());
+        assert_eq!(
+            tools,
+            vec![Tool {
+                name: "bash",
+                reason: "to source the script generated for `bash`".to_string(),
+                path: None,
+            }]
+        );
+    }
+}
@@ -0,0 +1,125 @@
+//! Bundles of the installed sources, for provisioning machines without network
+//! access.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context as ResultExt, Result};
+
+use crate::context::Context;
+use crate::util::TempPath;
+
+/// Returns the clone and download directories along with their paths in a
+/// bundle, which are relative to the data directory.
+fn bundle_dirs(ctx: &Context) -> Result<Vec<(PathBuf, &Path)>> {
+    [ctx.clone_dir(), ctx.download_dir()]
+        .iter()
+        .map(|dir| {
+            let rel = dir
+                .strip_prefix(ctx.data_dir())
+                .ok()
+                .filter(|rel| rel.components().next().is_some())
+                .with_context(s!(
+                    "`{}` must be inside the data directory `{}` to be bundled",
+                    ctx.replace_home(dir).display(),
+                    ctx.replace_home(ctx.data_dir()).display()
+                ))?;
+            Ok((rel.to_path_buf(), *dir))
+        })
+        .collect()
+}
+
+/// Write a tar bundle of the clone and download directories to stdout.
+pub fn export_bundle(ctx: &Context) -> Result<()> {
+    let mut tar = Command::new("tar");
+    tar.arg("-cf").arg("-").arg("-C").arg(ctx.data_dir());
+    let mut args = Vec::new();
+    for (rel, dir) in bundle_dirs(ctx)? {
+        if dir.is_dir() {
+            args.push(rel);
+        }
+    }
+    if args.is_empty() {
+        bail!("there are no installed sources to bundle");
+    }
+    trace!(
+        ctx,
+        "Running",
+        &format!(
+            "tar -cf - -C {} {}",
+            ctx.replace_home(ctx.data_dir()).display(),
+            args.iter()
+                .map(|rel| rel.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        )
+    );
+    let status = tar
+        .args(&args)
+        .stdout(Stdio::inherit())
+        .status()
+        .context("failed to run `tar`")?;
+    if !status.success() {
+        bail!("failed to create bundle, `tar` exited with {}", status);
+    }
+    header!(ctx, "Bundled", ctx.data_dir());
+    Ok(())
+}
+
+/// Unpack a tar bundle from stdin into the clone and download directories.
+///
+/// Each directory in the bundle replaces the existing one.
+pub fn import_bundle(ctx: &Context) -> Result<()> {
+    let dirs = bundle_dirs(ctx)?;
+    let data_dir = ctx.data_dir();
+    fs::create_dir_all(data_dir)
+        .with_context(s!("failed to create directory `{}`", data_dir.display()))?;
+    let temp_dir = TempPath::new_force(&data_dir.join("bundle"))
+        .context("failed to prepare temporary bundle directory")?;
+    fs::create_dir(temp_dir.path()).with_context(s!(
+        "failed to create directory `{}`",
+        temp_dir.path().display()
+    ))?;
+
+    trace!(
+        ctx,
+        "Running",
+        &format!(
+            "tar -xf - -C {}",
+            ctx.replace_home(temp_dir.path()).display()
+        )
+    );
+    let status = Command::new("tar")
+        .arg("-xf")
+        .arg("-")
+        .arg("-C")
+        .arg(temp_dir.path())
+        .stdin(Stdio::inherit())
+        .status()
+        .context("failed to run `tar`")?;
+    if !status.success() {
+        bail!("failed to unpack bundle, `tar` exited with {}", status);
+    }
+
+    let mut imported = false;
+    for (rel, dir) in dirs {
+        let unpacked = temp_dir.path().join(rel);
+        if !unpacked.is_dir() {
+            continue;
+        }
+        if let Some(parent) = dir.parent() {
+            fs::create_dir_all(parent)
+                .with_context(s!("failed to create directory `{}`", parent.display()))?;
+        }
+        TempPath::new_unchecked(unpacked)
+            .rename(dir)
+            .with_context(s!("failed to replace `{}`", dir.display()))?;
+        status!(ctx, "Imported", &ctx.replace_home(dir).display());
+        imported = true;
+    }
+    if !imported {
+        bail!("the bundle does not contain any sources");
+    }
+    Ok(())
+}
@@ -1,8 +1,12 @@
 use std::collections::HashSet;
-use std::path::Path;
-use std::{fs, result};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use std::{fs, io, result};
 
-use anyhow::{Context as ResultExt, Error, Result};
+use anyhow::{anyhow, Context as ResultExt, Error, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use once_cell::unsync::OnceCell;
+use regex_macro::regex;
 use walkdir::WalkDir;
 
 use crate::context::Context;
@@ -11,7 +15,19 @@ use crate::lock::LockedConfig;
 
 impl LockedConfig {
     /// Clean the clone and download directories.
-    pub fn clean(&self, ctx: &Context, warnings: &mut Vec<Error>) {
+    ///
+    /// If `since` is set then only the paths in the download directory that
+    /// have not been modified for at least that long are removed. If
+    /// `orphans_only` is set then only the directories that are not used by any
+    /// plugin are removed, stray files are left alone. If the `trash` option is
+    /// set then paths are moved to the trash directory instead of removed.
+    pub fn clean(
+        &self,
+        ctx: &Context,
+        since: Option<Duration>,
+        orphans_only: bool,
+        warnings: &mut Vec<Error>,
+    ) {
         let clean_clone_dir = self.ctx.clone_dir().starts_with(self.ctx.data_dir());
         let clean_download_dir = self.ctx.download_dir().starts_with(self.ctx.data_dir());
 
@@ -19,14 +35,18 @@ impl LockedConfig {
             return;
         }
 
-        // Track the source directories, all the plugin directory parents, and all the
-        // plugin files.
+        // Track the source directories, the local source directories, all the
+        // plugin directory parents, and all the plugin files.
         let mut source_dirs = HashSet::new();
+        let mut local_dirs = HashSet::new();
         let mut parent_dirs = HashSet::new();
         let mut files = HashSet::new();
         for plugin in &self.plugins {
             if let LockedPlugin::External(locked) = plugin {
                 source_dirs.insert(locked.source_dir.as_path());
+                if locked.local {
+                    local_dirs.insert(locked.source_dir.as_path());
+                }
                 parent_dirs.extend(locked.dir().ancestors());
                 files.extend(locked.files.iter().filter_map(|f| {
                     // `files` is only used when filtering the download directory
@@ -41,29 +61,85 @@ impl LockedConfig {
         parent_dirs.insert(self.ctx.clone_dir());
         parent_dirs.insert(self.ctx.download_dir());
 
+        // Track any paths protected by the `clean_ignore` patterns. Their
+        // ancestors must also be preserved.
+        let protected = match self.clean_ignore_set() {
+            Ok(set) => {
+                let mut protected = HashSet::new();
+                for dir in [self.ctx.clone_dir(), self.ctx.download_dir()] {
+                    protected.extend(find_protected(&set, dir));
+                }
+                protected
+            }
+            Err(err) => {
+                // Not cleaning anything is the safest thing to do.
+                warnings.push(err);
+                return;
+            }
+        };
+        let protected_parents: HashSet<_> = protected
+            .iter()
+            .flat_map(|p| p.ancestors().skip(1))
+            .map(Path::to_path_buf)
+            .collect();
+        let is_protected = |p: &Path| protected.contains(p) || protected_parents.contains(p);
+        let is_candidate = |e: &walkdir::DirEntry| !orphans_only || e.file_type().is_dir();
+
+        // Each clean moves paths into its own directory in the trash, so that
+        // paths cleaned at different times do not collide. It is only created
+        // once the first path is moved.
+        let trash_dir = self.ctx.trash_dir();
+        let trash = OnceCell::new();
+        let remove = |path: &Path| {
+            if !self.trash {
+                return remove_path(ctx, path);
+            }
+            // The walk still yields the contents of directories that have
+            // already been moved to the trash.
+            if fs::symlink_metadata(path).is_err() {
+                return Ok(());
+            }
+            let trash = trash.get_or_try_init(|| create_trash(&trash_dir))?;
+            trash_path(ctx, self.ctx.data_dir(), trash, path)
+        };
+
         if clean_clone_dir {
             for entry in WalkDir::new(self.ctx.clone_dir())
                 .into_iter()
-                .filter_entry(|e| !source_dirs.contains(e.path()))
+                .filter_entry(|e| {
+                    let p = e.path();
+                    !source_dirs.contains(p) && !protected.contains(p) && p != trash_dir
+                })
                 .filter_map(result::Result::ok)
-                .filter(|e| !parent_dirs.contains(e.path()))
+                .filter(|e| !parent_dirs.contains(e.path()) && !is_protected(e.path()))
+                .filter(is_candidate)
             {
-                if let Err(err) = remove_path(ctx, entry.path()) {
+                if let Err(err) = remove(entry.path()) {
                     warnings.push(err);
                 }
             }
         }
 
         if clean_download_dir {
+            let cutoff = since.and_then(|since| SystemTime::now().checked_sub(since));
             for entry in WalkDir::new(self.ctx.download_dir())
                 .into_iter()
+                .filter_entry(|e| {
+                    let p = e.path();
+                    !local_dirs.contains(p) && !protected.contains(p) && p != trash_dir
+                })
                 .filter_map(result::Result::ok)
                 .filter(|e| {
                     let p = e.path();
-                    !files.contains(p) && !parent_dirs.contains(p)
+                    !files.contains(p) && !parent_dirs.contains(p) && !is_protected(p)
                 })
+                .filter(|e| match cutoff {
+                    Some(cutoff) => is_older_than(e.path(), cutoff),
+                    None => true,
+                })
+                .filter(is_candidate)
             {
-                if let Err(err) = remove_path(ctx, entry.path()) {
+                if let Err(err) = remove(entry.path()) {
                     warnings.push(err);
                 }
             }
@@ -71,6 +147,190 @@ impl LockedConfig {
     }
 }
 
+impl LockedConfig {
+    /// Compile the `clean_ignore` patterns.
+    fn clean_ignore_set(&self) -> Result<GlobSet> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &self.clean_ignore {
+            builder.add(
+                GlobBuilder::new(pattern)
+                    .literal_separator(true)
+                    .build()
+                    .with_context(s!("failed to parse `clean_ignore` pattern `{}`", pattern))?,
+            );
+        }
+        builder
+            .build()
+            .context("failed to build `clean_ignore` patterns")
+    }
+}
+
+/// Find all the paths in the given directory that match the given patterns.
+///
+/// The patterns are matched against the path relative to the directory.
+fn find_protected(set: &GlobSet, dir: &Path) -> Vec<PathBuf> {
+    if set.is_empty() {
+        return Vec::new();
+    }
+    let mut protected = Vec::new();
+    let mut it = WalkDir::new(dir).min_depth(1).into_iter();
+    while let Some(entry) = it.next() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        if set.is_match(entry.path().strip_prefix(dir).unwrap()) {
+            if entry.file_type().is_dir() {
+                it.skip_current_dir();
+            }
+            protected.push(entry.into_path());
+        }
+    }
+    protected
+}
+
+/// Whether the path, and everything inside it if it is a directory, was last
+/// modified before the given time.
+fn is_older_than(path: &Path, cutoff: SystemTime) -> bool {
+    WalkDir::new(path).into_iter().all(|entry| {
+        entry
+            .ok()
+            .and_then(|e| e.metadata().ok())
+            .and_then(|m| m.modified().ok())
+            .is_some_and(|modified| modified < cutoff)
+    })
+}
+
+/// Parse a duration like `30d`, the supported units are `s`, `m`, `h`, `d`,
+/// and `w`.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let err = || {
+        anyhow!(
+            "invalid duration `{}`, expected a number followed by one of `s`, `m`, `h`, `d`, or \
+             `w`",
+            s
+        )
+    };
+    let captures = regex!(r"^(\d+)([smhdw])$").captures(s).ok_or_else(err)?;
+    let value: u64 = captures[1].parse().map_err(|_| err())?;
+    let unit = match &captures[2] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => unreachable!(),
+    };
+    value
+        .checked_mul(unit)
+        .map(Duration::from_secs)
+        .ok_or_else(err)
+}
+
+/// Create a new directory in the trash for a single clean.
+///
+/// The directory is named after the current time, with a numeric suffix if a
+/// directory with that name already exists.
+fn create_trash(trash_dir: &Path) -> Result<PathBuf> {
+    fs::create_dir_all(trash_dir)
+        .with_context(s!("failed to create directory `{}`", trash_dir.display()))?;
+    let secs = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let mut suffix = 0;
+    loop {
+        let name = match suffix {
+            0 => secs.to_string(),
+            n => format!("{}-{}", secs, n),
+        };
+        let dir = trash_dir.join(name);
+        match fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => suffix += 1,
+            Err(err) => {
+                return Err(err).with_context(s!("failed to create directory `{}`", dir.display()))
+            }
+        }
+    }
+}
+
+/// Move a path into the trash, keeping its path relative to the data directory.
+///
+/// If the path can not be renamed, for example because the trash is on another
+/// device, then it is copied to the trash and removed instead.
+fn trash_path(ctx: &Context, data_dir: &Path, trash: &Path, path: &Path) -> Result<()> {
+    let path_replace_home = ctx.replace_home(path);
+    let path_display = &path_replace_home.display();
+    let dest = trash.join(path.strip_prefix(data_dir).unwrap_or(path));
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)
+            .with_context(s!("failed to create directory `{}`", parent.display()))?;
+    }
+    if let Err(err) = fs::rename(path, &dest) {
+        if copy_path(path, &dest).is_err() {
+            // Leave the original path as the only copy.
+            nuke_path(&dest).ok();
+            return Err(err).with_context(s!("failed to move `{}` to the trash", path_display));
+        }
+        // The copy is kept even if this fails, in case some of the original
+        // path was already removed.
+        nuke_path(path).with_context(s!("failed to move `{}` to the trash", path_display))?;
+    }
+    warning_v!(ctx, "Trashed", path_display);
+    Ok(())
+}
+
+/// Copy a file or directory, including the contents of the directory.
+fn copy_path(from: &Path, to: &Path) -> io::Result<()> {
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let dest = to.join(entry.path().strip_prefix(from).unwrap());
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            fs::create_dir_all(&dest)?;
+        } else if file_type.is_symlink() {
+            copy_symlink(entry.path(), &dest)?;
+        } else {
+            fs::copy(entry.path(), &dest)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &Path, to: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(from)?, to)
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(from: &Path, to: &Path) -> io::Result<()> {
+    fs::copy(from, to).map(drop)
+}
+
+/// Remove a file or directory, without following symlinks.
+fn nuke_path(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Permanently remove everything in the trash directory.
+///
+/// Returns whether there was anything to remove.
+pub fn empty_trash(ctx: &Context) -> Result<bool> {
+    let trash_dir = ctx.trash_dir();
+    if !trash_dir.exists() {
+        return Ok(false);
+    }
+    fs::remove_dir_all(&trash_dir).with_context(s!(
+        "failed to remove directory `{}`",
+        ctx.replace_home(&trash_dir).display()
+    ))?;
+    Ok(true)
+}
+
 fn remove_path(ctx: &Context, path: &Path) -> Result<()> {
     let path_replace_home = ctx.replace_home(path);
     let path_display = &path_replace_home.display();
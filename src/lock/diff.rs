@@ -0,0 +1,141 @@
+//! Comparing a new lock against the existing one.
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+use crate::context::Context;
+use crate::lock::file::{LockedConfig, LockedPlugin};
+
+/// The commit that each Git source is checked out at, by source directory.
+pub type Revisions = HashMap<PathBuf, String>;
+
+/// Returns the commit that the Git source of each external plugin is checked
+/// out at.
+///
+/// This must be read before the sources are updated by a new lock, since lock
+/// files written by older versions do not record commits.
+pub fn revisions(locked: &LockedConfig) -> Revisions {
+    let mut revisions = Revisions::new();
+    for plugin in &locked.plugins {
+        if let LockedPlugin::External(plugin) = plugin {
+            if revisions.contains_key(&plugin.source_dir) {
+                continue;
+            }
+            if let Some(revision) = plugin.revision() {
+                revisions.insert(plugin.source_dir.clone(), revision);
+            }
+        }
+    }
+    revisions
+}
+
+fn short(revision: &str) -> &str {
+    &revision[..revision.len().min(7)]
+}
+
+fn display(ctx: &Context, path: &Path) -> String {
+    ctx.replace_home(path).display().to_string()
+}
+
+/// Describe how the plugins in `new` differ from the ones in `old`, one line
+/// per change.
+///
+/// Added plugins are prefixed with `+`, removed plugins with `-`, and changes
+/// to existing plugins with `~`. The `old_revisions` are the commits the Git
+/// sources were checked out at before `new` was locked.
+pub fn diff(
+    ctx: &Context,
+    old: Option<&LockedConfig>,
+    old_revisions: &Revisions,
+    new: &LockedConfig,
+) -> Vec<String> {
+    let new_revisions = revisions(new);
+    let old_plugins: Vec<&LockedPlugin> =
+        old.map(|o| o.plugins.iter().collect()).unwrap_or_default();
+    let mut lines = Vec::new();
+
+    for plugin in &new.plugins {
+        let name = plugin.name();
+        let previous = match old_plugins.iter().find(|p| p.name() == name) {
+            Some(previous) => previous,
+            None => {
+                lines.push(format!("+ {}", name));
+                continue;
+            }
+        };
+        match (previous, plugin) {
+            (LockedPlugin::External(a), LockedPlugin::External(b)) => {
+                // Clones that are migrated are only moved when the lock file is
+                // written.
+                let source_dir = new
+                    .migrations
+                    .iter()
+                    .find(|(from, _)| *from == b.source_dir)
+                    .map_or(&b.source_dir, |(_, to)| to);
+                if a.source_dir != *source_dir {
+                    lines.push(format!(
+                        "~ {}: source {} -> {}",
+                        name,
+                        display(ctx, &a.source_dir),
+                        display(ctx, source_dir)
+                    ));
+                }
+                match (
+                    old_revisions.get(&a.source_dir),
+                    new_revisions.get(&b.source_dir),
+                ) {
+                    (Some(x), Some(y)) if x != y => {
+                        lines.push(format!("~ {}: revision {} -> {}", name, short(x), short(y)))
+                    }
+                    (None, Some(y)) => lines.push(format!("~ {}: revision {}", name, short(y))),
+                    _ => {}
+                }
+                if a.version != b.version {
+                    lines.push(format!(
+                        "~ {}: version {} -> {}",
+                        name,
+                        a.version.as_deref().unwrap_or("none"),
+                        b.version.as_deref().unwrap_or("none")
+                    ));
+                }
+                if a.checksum != b.checksum {
+                    lines.push(format!("~ {}: checksum changed", name));
+                }
+                let files_a: BTreeSet<_> = a.files.iter().collect();
+                let files_b: BTreeSet<_> = b.files.iter().collect();
+                for file in files_b.difference(&files_a) {
+                    lines.push(format!("~ {}: + {}", name, display(ctx, file)));
+                }
+                for file in files_a.difference(&files_b) {
+                    lines.push(format!("~ {}: - {}", name, display(ctx, file)));
+                }
+                if a.apply != b.apply {
+                    lines.push(format!(
+                        "~ {}: apply [{}] -> [{}]",
+                        name,
+                        a.apply.join(", "),
+                        b.apply.join(", ")
+                    ));
+                }
+                if a.templates != b.templates {
+                    lines.push(format!("~ {}: templates changed", name));
+                }
+            }
+            (LockedPlugin::Inline(a), LockedPlugin::Inline(b)) => {
+                if a != b {
+                    lines.push(format!("~ {}: inline source changed", name));
+                }
+            }
+            _ => lines.push(format!("~ {}: plugin type changed", name)),
+        }
+    }
+
+    for plugin in old_plugins {
+        let name = plugin.name();
+        if !new.plugins.iter().any(|p| p.name() == name) {
+            lines.push(format!("- {}", name));
+        }
+    }
+
+    lines
+}
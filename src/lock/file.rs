@@ -6,9 +6,11 @@ use std::path::{Path, PathBuf};
 use anyhow::{Context as ResultExt, Error, Result};
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
+use url::Url;
 
-use crate::config::{InlinePlugin, Template};
+use crate::config::{InlinePlugin, Manifest, Shell, Template};
 use crate::context::Context;
+use crate::lock::source::Fetched;
 
 /// A locked `Config`.
 #[derive(Debug, Deserialize, Serialize)]
@@ -16,6 +18,44 @@ pub struct LockedConfig {
     /// The global context that was used to generated this `LockedConfig`.
     #[serde(flatten)]
     pub ctx: Context,
+    /// What type of shell is being used.
+    #[serde(default)]
+    pub shell: Shell,
+    /// Which files and directories in the clone and download directories
+    /// should never be removed when cleaning.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub clean_ignore: Vec<String>,
+    /// A raw snippet to append to the end of the generated script.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub footer: Option<String>,
+    /// Whether to accumulate the `PATH`, `path`, and `fpath` templates into a
+    /// single deduplicated assignment at the end of the generated script.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub dedupe_paths: bool,
+    /// Whether to remove the zsh completion dump when the lock file is newer
+    /// than it.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub invalidate_compdump: bool,
+    /// Whether to wrap the generated script in a guard so that it is only
+    /// sourced once.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub guard: bool,
+    /// Whether to wrap each plugin with measurements that print how long it
+    /// took to load.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub profile_startup: bool,
+    /// Whether to move cleaned files and directories to the trash directory.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub trash: bool,
+    /// The other config files that plugins were loaded from.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub imports: Vec<PathBuf>,
+    /// An environment variable that lists the plugins in the generated script.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<Manifest>,
+    /// The name of a function to wrap the generated script in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wrap_in_function: Option<String>,
     /// Each locked plugin.
     pub plugins: Vec<LockedPlugin>,
     /// A map of name to template.
@@ -26,9 +66,26 @@ pub struct LockedConfig {
     /// Any errors that occurred while generating this `LockedConfig`.
     #[serde(skip)]
     pub errors: Vec<Error>,
+    /// Any warnings about the plugins in this `LockedConfig`.
+    #[serde(skip)]
+    pub warnings: Vec<Error>,
+    /// The clones that were locked where they are, and the locations that they
+    /// are moved to before the lock file is written.
+    #[serde(skip)]
+    pub migrations: Vec<(PathBuf, PathBuf)>,
+    /// The directories of the sources that were installed while generating
+    /// this `LockedConfig`.
+    #[serde(skip)]
+    pub installed: Vec<PathBuf>,
+    /// The directories of the Git sources that were moved to another commit
+    /// than the previous lock file records while generating this
+    /// `LockedConfig`.
+    #[serde(skip)]
+    pub updated: Vec<PathBuf>,
 }
 
 /// A locked `Plugin`.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum LockedPlugin {
@@ -49,20 +106,98 @@ pub struct LockedExternalPlugin {
     pub files: Vec<PathBuf>,
     /// What templates to apply to each file.
     pub apply: Vec<String>,
+    /// Whether this plugin has a local source.
+    ///
+    /// Local sources are not owned by Sheldon and are never cleaned.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub local: bool,
+    /// The file that a remote plugin's source was downloaded to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    /// The verified checksum of a remote plugin's downloaded file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// The URL that a remote plugin with a `version` was downloaded from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<Url>,
+    /// The commit that a Git plugin's source was checked out at.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    /// The branch, tag, or revision that a Git plugin's source was resolved
+    /// from.
+    ///
+    /// For the default branch this is the name of the remote's default branch,
+    /// and for fallback references it is the candidate that was used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    /// How the tree of a Git plugin's source was fetched, if it was exported
+    /// without its history.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fetched: Option<Fetched>,
+    /// The version of this plugin, read from the `version_from` file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Labels for selecting this plugin.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// The templates that are only available to this plugin.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub templates: IndexMap<String, Template>,
+    /// The files that have different templates applied to them.
+    ///
+    /// Note: this field must come last in the struct for it to serialize
+    /// properly.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<LockedFileGroup>,
+}
+
+/// A group of a [`LockedExternalPlugin`]'s files that have different templates
+/// applied to them.
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LockedFileGroup {
+    /// The files in this group.
+    pub files: Vec<PathBuf>,
+    /// What templates to apply to the files in this group.
+    pub apply: Vec<String>,
+}
+
+pub fn is_false(b: &bool) -> bool {
+    !b
 }
 
 impl LockedConfig {
+    /// Serialize a `LockedConfig` exactly as it would be written to the given
+    /// path.
+    ///
+    /// The config is serialized as JSON if the path has a `.json` extension,
+    /// otherwise it is serialized as TOML. The output never contains
+    /// timestamps or locale dependent formatting, so locking the same config
+    /// produces the same output on any machine.
+    pub fn to_string_for(&self, path: &Path) -> Result<String> {
+        if is_json(path) {
+            serde_json::to_string_pretty(&self).context("failed to serialize locked config")
+        } else {
+            toml::to_string(&self).context("failed to serialize locked config")
+        }
+    }
+
     /// Write a `LockedConfig` config to the given path.
+    ///
+    /// The config is written as JSON if the path has a `.json` extension,
+    /// otherwise it is written as TOML.
     pub fn to_path<P>(&self, path: P) -> Result<()>
     where
         P: AsRef<Path>,
     {
         let path = path.as_ref();
-        fs::write(
-            path,
-            &toml::to_string(&self).context("failed to serialize locked config")?,
-        )
-        .with_context(s!("failed to write locked config to `{}`", path.display()))?;
+        let contents = self.to_string_for(path)?;
+        fs::write(path, &contents)
+            .with_context(s!("failed to write locked config to `{}`", path.display()))?;
         Ok(())
     }
 }
+
+/// Whether the lock file at the given path uses the JSON format.
+pub fn is_json(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
@@ -1,22 +1,53 @@
+mod bundle;
 mod clean;
+mod diff;
 mod file;
 mod plugin;
+mod resolved;
 mod script;
 mod source;
 
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
 use std::fs;
-use std::path::Path;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fmt, result};
 
-use anyhow::{Context as ResultExt, Result};
+use anyhow::{anyhow, bail, Context as ResultExt, Error, Result};
 use indexmap::{indexmap, IndexMap};
 use itertools::{Either, Itertools};
 use once_cell::sync::Lazy;
 use rayon::prelude::*;
+use regex_macro::regex;
+use thiserror::Error;
 
-use crate::config::{Config, MatchesProfile, Plugin, Shell, Template};
-use crate::context::Context;
-pub use crate::lock::file::LockedConfig;
-use crate::lock::file::{LockedExternalPlugin, LockedPlugin};
+use crate::config::{
+    Config, ExternalPlugin, InlinePlugin, Manifest, MatchesProfile, Plugin, Shell, SkipReason,
+    Source, Template,
+};
+use crate::context::{Context, Event};
+use crate::editor::backup_path;
+pub use crate::lock::bundle::{export_bundle, import_bundle};
+pub use crate::lock::clean::{empty_trash, parse_duration};
+pub use crate::lock::diff::{diff, revisions};
+pub use crate::lock::file::{LockedConfig, LockedExternalPlugin, LockedPlugin};
+pub use crate::lock::script::{check_syntax, Measurement};
+pub use crate::lock::source::check as check_source;
+pub use crate::lock::source::render_clone_dir;
+pub use crate::lock::source::render_url as render_remote_url;
+use crate::lock::source::{LockedSource, Session};
+use crate::util::{git, sha256, TempPath};
+
+/// The first line of the script cache, followed by the lock file digest.
+const CACHE_HEADER: &str = "# sheldon cache ";
+
+/// The file in a plugin's source directory that declares its dependencies.
+const DEPENDENCIES_FILE: &str = "sheldon.plugins.toml";
+
+/// The maximum depth of nested plugin dependencies.
+const MAX_DEPENDENCY_DEPTH: usize = 8;
 
 /// Behaviour when locking a config file.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -29,36 +60,342 @@ pub enum LockMode {
     Reinstall,
 }
 
+/// What to do when a source or plugin fails to lock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnError {
+    /// Record the error and continue, producing a partial lock.
+    #[default]
+    Skip,
+    /// Stop at the first error.
+    Abort,
+}
+
+impl fmt::Display for OnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Skip => f.write_str("skip"),
+            Self::Abort => f.write_str("abort"),
+        }
+    }
+}
+
+/// Produced when we fail to parse an error policy.
+#[derive(Debug, Error)]
+#[error("expected `skip` or `abort`, got `{}`", self.0)]
+pub struct ParseOnErrorError(String);
+
+impl FromStr for OnError {
+    type Err = ParseOnErrorError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "abort" => Ok(Self::Abort),
+            s => Err(ParseOnErrorError(s.to_string())),
+        }
+    }
+}
+
 /// Read a [`LockedConfig`] from the given path.
+///
+/// The config is read as JSON if the path has a `.json` extension, otherwise
+/// it is read as TOML.
 pub fn from_path<P>(path: P) -> Result<LockedConfig>
 where
     P: AsRef<Path>,
 {
     let path = path.as_ref();
-    let locked: LockedConfig = toml::from_str(&String::from_utf8_lossy(
-        &fs::read(&path)
-            .with_context(s!("failed to read locked config from `{}`", path.display()))?,
-    ))
-    .context("failed to deserialize locked config")?;
+    let bytes = fs::read(path)
+        .with_context(s!("failed to read locked config from `{}`", path.display()))?;
+    // Invalid UTF-8 means the lock file is corrupt so we never lossily convert it.
+    let contents =
+        String::from_utf8(bytes).context("locked config contents are not valid UTF-8")?;
+    let locked: LockedConfig = if file::is_json(path) {
+        serde_json::from_str(&contents).context("failed to deserialize locked config")?
+    } else {
+        toml::from_str(&contents).context("failed to deserialize locked config")?
+    };
     Ok(locked)
 }
 
+/// Copy the lock file at the given path to its backup path, `<path>.bak`.
+///
+/// Returns the backup path, or `None` if there is no lock file to back up.
+pub fn backup(path: &Path) -> Result<Option<PathBuf>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let backup = backup_path(path);
+    fs::copy(path, &backup).with_context(s!("failed to back up `{}`", path.display()))?;
+    Ok(Some(backup))
+}
+
+/// Swap the lock file at the given path with its backup.
+///
+/// The backup must be a valid lock file. The lock file that it replaces, if
+/// any, becomes the new backup so that restoring again undoes the restore.
+pub fn restore(path: &Path) -> Result<()> {
+    let backup = backup_path(path);
+    if !backup.exists() {
+        bail!("no backup of the lock file found at `{}`", backup.display());
+    }
+    from_path(&backup).context("failed to read the lock file backup")?;
+
+    let temp = TempPath::new_force(path)?;
+    let moved = if path.exists() {
+        fs::rename(path, temp.path())
+            .with_context(s!("failed to move `{}` aside", path.display()))?;
+        true
+    } else {
+        false
+    };
+    if let Err(err) = fs::rename(&backup, path) {
+        if moved {
+            fs::rename(temp.path(), path).ok();
+        }
+        return Err(err).with_context(s!("failed to restore `{}`", backup.display()));
+    }
+    if moved {
+        temp.rename(&backup)
+            .with_context(s!("failed to back up `{}`", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Returns the path of the script cache for the lock file at the given path,
+/// `<path>.cache`.
+fn cache_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".cache");
+    path.with_file_name(file_name)
+}
+
+/// Returns the cached script for the lock file at the given path.
+///
+/// Returns `None` if there is no cached script, or if the lock file has changed
+/// since the script was cached.
+pub fn cached_script(path: &Path) -> Option<String> {
+    let digest = sha256::digest_file(path).ok()?;
+    let contents = fs::read_to_string(cache_path(path)).ok()?;
+    let (header, script) = contents.split_once('\n')?;
+    let cached = header.strip_prefix(CACHE_HEADER)?;
+    (cached == digest).then(|| script.to_string())
+}
+
+/// Cache the script generated for the lock file at the given path.
+pub fn cache_script(path: &Path, script: &str) -> Result<()> {
+    let digest =
+        sha256::digest_file(path).with_context(s!("failed to read `{}`", path.display()))?;
+    let cache = cache_path(path);
+    let temp = TempPath::new_force(&cache)?;
+    fs::write(
+        temp.path(),
+        format!("{}{}\n{}", CACHE_HEADER, digest, script),
+    )
+    .with_context(s!("failed to write `{}`", cache.display()))?;
+    temp.rename(&cache)
+        .with_context(s!("failed to write `{}`", cache.display()))?;
+    Ok(())
+}
+
+/// Counts of what locking did, for displaying a summary afterwards.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// The number of plugins that were locked.
+    pub plugins: usize,
+    /// The number of sources that were installed or reinstalled.
+    pub installed: usize,
+    /// The number of sources that were updated.
+    pub updated: usize,
+    /// The number of errors that occurred.
+    pub errors: usize,
+}
+
+impl Summary {
+    /// Count what locking actually did.
+    pub fn new(locked: &LockedConfig) -> Self {
+        Self {
+            plugins: locked.plugins.len(),
+            installed: locked.installed.len(),
+            updated: locked.updated.len(),
+            errors: locked.errors.len(),
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        write!(
+            f,
+            "{} plugin{} locked, {} source{} installed, {} updated, {} error{}",
+            self.plugins,
+            plural(self.plugins),
+            self.installed,
+            plural(self.installed),
+            self.updated,
+            self.errors,
+            plural(self.errors)
+        )
+    }
+}
+
+/// The planned work for converting a [`Config`] into a [`LockedConfig`].
+#[derive(Debug)]
+pub struct Plan {
+    /// What type of shell is being used.
+    shell: Shell,
+    /// The default templates merged with the configured templates.
+    templates: IndexMap<String, Template>,
+    /// The global files to match on.
+    matches: Vec<String>,
+    /// The global templates to apply.
+    apply: Vec<String>,
+    /// Which files and directories should never be removed when cleaning.
+    clean_ignore: Vec<String>,
+    /// A raw snippet to append to the end of the generated script.
+    footer: Option<String>,
+    /// Whether to accumulate path templates into a single assignment.
+    dedupe_paths: bool,
+    /// Whether to remove the zsh completion dump when the lock file changes.
+    invalidate_compdump: bool,
+    /// Whether to wrap the generated script in a guard.
+    guard: bool,
+    /// Whether to measure how long each plugin takes to load.
+    profile_startup: bool,
+    /// Whether to move cleaned paths to the trash directory.
+    trash: bool,
+    /// Whether to fail if a Git source has uncommitted changes.
+    require_clean: bool,
+    /// Whether to rewrite matched files with CRLF line endings.
+    normalize_line_endings: bool,
+    /// An environment variable that lists the plugins.
+    manifest: Option<Manifest>,
+    /// The name of a function to wrap the generated script in.
+    wrap_in_function: Option<String>,
+    /// A template for the directory that Git sources are cloned to.
+    clone_dir_layout: Option<String>,
+    /// The other config files that plugins were loaded from.
+    imports: Vec<PathBuf>,
+    /// Each unique source and the plugins, with their original position, that
+    /// use it in the current profile.
+    sources: IndexMap<Source, Vec<(usize, ExternalPlugin)>>,
+    /// The inline plugins, with their original position, in the current
+    /// profile.
+    inlines: Vec<(usize, InlinePlugin)>,
+    /// The configured plugins that are not locked, and why.
+    skipped: IndexMap<String, SkipReason>,
+}
+
+/// The commits that the Git sources of a [`Plan`] were checked out at before
+/// it was executed.
+#[derive(Debug)]
+pub struct Checkpoint {
+    /// Each Git source with its directory and commit.
+    sources: Vec<(Source, PathBuf, String)>,
+}
+
+/// A plugin whose source still needs to be installed.
+struct Queued {
+    /// The position that the plugin is sorted by.
+    ///
+    /// This is the index of the plugin, preceded by the indexes of the plugins
+    /// that declared it as a dependency and followed by `usize::MAX`, so that
+    /// dependencies sort before the plugin that declared them.
+    key: Vec<usize>,
+    /// The plugin to lock.
+    plugin: ExternalPlugin,
+    /// The sources of the plugins that declared this plugin as a dependency.
+    ancestors: Vec<Source>,
+    /// Whether the source must not have uncommitted changes.
+    require_clean: bool,
+    /// Whether to normalize the line endings of the matched files.
+    normalize_line_endings: bool,
+}
+
+/// A plugin whose source has been installed.
+enum Installed {
+    /// A plugin that has been locked, or failed to lock.
+    Locked(Result<Box<LockedPlugin>>),
+    /// A plugin whose files still need to be matched, the name of its source,
+    /// and whether to normalize their line endings.
+    Pending(String, LockedSource, Box<ExternalPlugin>, bool),
+}
+
+/// Run the given function using a thread pool with the given number of jobs,
+/// otherwise the current thread pool is used.
+fn with_jobs<T, F>(jobs: Option<usize>, f: F) -> Result<T>
+where
+    T: Send,
+    F: FnOnce() -> Result<T> + Send,
+{
+    match jobs {
+        Some(jobs) => rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build()
+            .context("failed to build thread pool")?
+            .install(f),
+        None => f(),
+    }
+}
+
 /// Consume the [`Config`] and convert it to a [`LockedConfig`].
 ///
 /// This method installs all necessary remote dependencies of plugins,
 /// validates that local plugins are present, and checks that templates
 /// can compile.
 pub fn config(ctx: &Context, config: Config) -> Result<LockedConfig> {
+    plan(ctx, config).execute(ctx)
+}
+
+/// Consume the [`Config`] and convert it to a [`LockedConfig`] for the given
+/// shell instead of the configured one.
+///
+/// The default matches and templates of the given shell are used, so this fails
+/// if any template the config applies is not available for that shell.
+pub fn config_for_shell(ctx: &Context, mut config: Config, shell: Shell) -> Result<LockedConfig> {
+    config.shell = shell;
+    let plan = plan(ctx, config);
+    plan.check_templates()?;
+    plan.execute(ctx)
+}
+
+/// Consume the [`Config`] and plan the work to convert it to a
+/// [`LockedConfig`].
+///
+/// This method does not perform any IO.
+pub fn plan(ctx: &Context, config: Config) -> Plan {
     let Config {
         shell,
         matches,
         apply,
         templates,
+        remove_templates,
+        clean_ignore,
+        footer,
+        dedupe_paths,
+        invalidate_compdump,
+        guard,
+        profile_startup,
+        trash,
+        require_clean,
+        normalize_line_endings,
+        manifest,
+        wrap_in_function,
+        clone_dir_layout,
+        active_profiles,
+        imports,
         plugins,
+        mut skipped,
     } = config;
 
+    let active_profiles = crate::config::active_profiles(ctx, active_profiles);
+
     let templates = {
         let mut map = shell.default_templates().clone();
+        for name in remove_templates.unwrap_or_default() {
+            map.shift_remove(&name);
+        }
         for (name, template) in templates {
             map.insert(name, template);
         }
@@ -76,98 +413,746 @@ pub fn config(ctx: &Context, config: Config) -> Result<LockedConfig> {
             });
     let inlines = inlines
         .into_iter()
-        .filter(|(_, p)| p.matches_profile(ctx))
-        .map(|(i, p)| (i, LockedPlugin::Inline(p)));
+        .filter(
+            |(_, p)| match skip_reason(p, p.shell, &active_profiles, shell) {
+                Some(reason) => {
+                    skipped.insert(p.name.clone(), reason);
+                    false
+                }
+                None => true,
+            },
+        )
+        .collect();
 
     // Create a map of unique `Source` to `Vec<Plugin>`
-    let mut map = IndexMap::new();
+    let mut sources = IndexMap::new();
     for (index, plugin) in externals {
-        map.entry(plugin.source.clone())
-            .or_insert_with(|| Vec::with_capacity(1))
-            .push((index, plugin));
+        let plugins = sources
+            .entry(plugin.source.clone())
+            .or_insert_with(|| Vec::with_capacity(1));
+        match skip_reason(&plugin, plugin.shell, &active_profiles, shell) {
+            Some(reason) => {
+                skipped.insert(plugin.name.clone(), reason);
+            }
+            None => plugins.push((index, plugin)),
+        }
     }
 
-    let matches = &matches.as_ref().unwrap_or_else(|| shell.default_matches());
-    #[allow(clippy::redundant_closure)]
-    let apply = apply.as_ref().unwrap_or_else(|| Shell::default_apply());
-    let count = map.len();
-    let mut errors = Vec::new();
+    Plan {
+        shell,
+        matches: matches.unwrap_or_else(|| shell.default_matches().clone()),
+        apply: apply.unwrap_or_else(|| Shell::default_apply().clone()),
+        templates,
+        clean_ignore: clean_ignore.unwrap_or_default(),
+        footer,
+        dedupe_paths: dedupe_paths.unwrap_or(false),
+        invalidate_compdump: invalidate_compdump.unwrap_or(false),
+        guard: guard.unwrap_or(false),
+        profile_startup: profile_startup.unwrap_or(false),
+        trash: trash.unwrap_or(false),
+        require_clean: require_clean.unwrap_or(false),
+        normalize_line_endings: normalize_line_endings.unwrap_or(false),
+        manifest,
+        wrap_in_function,
+        clone_dir_layout,
+        imports,
+        sources,
+        inlines,
+        skipped,
+    }
+}
 
-    let plugins = if count == 0 {
-        inlines
-            .into_iter()
-            .map(|(_, locked)| locked)
-            .collect::<Vec<_>>()
+/// Returns why a plugin for the given shell is not locked, if it is not.
+fn skip_reason<P: MatchesProfile>(
+    plugin: P,
+    plugin_shell: Option<Shell>,
+    active_profiles: &[String],
+    shell: Shell,
+) -> Option<SkipReason> {
+    if !plugin.matches_profile(active_profiles) {
+        Some(SkipReason::Profiles(plugin.profiles()?.to_vec()))
+    } else if plugin_shell.is_some_and(|s| s != shell) {
+        plugin_shell.map(SkipReason::Shell)
     } else {
-        // Install the sources in parallel.
-        map.into_par_iter()
+        None
+    }
+}
+
+impl Plan {
+    /// Returns why the plugin with the given name is not locked, or `None` if
+    /// it is locked.
+    ///
+    /// Fails if there is no plugin with the given name in the config.
+    pub fn skip_reason(&self, name: &str) -> Result<Option<&SkipReason>> {
+        if let Some(reason) = self.skipped.get(name) {
+            Ok(Some(reason))
+        } else if self.plugin_names().contains(&name) {
+            Ok(None)
+        } else {
+            bail!("plugin `{}` is not defined", name)
+        }
+    }
+
+    /// Returns the names of the plugins that would be locked, in the order
+    /// they would be rendered.
+    pub fn plugin_names(&self) -> Vec<&str> {
+        self.sources
+            .values()
+            .flatten()
+            .map(|(index, plugin)| (*index, plugin.name.as_str()))
+            .chain(
+                self.inlines
+                    .iter()
+                    .map(|(index, plugin)| (*index, plugin.name.as_str())),
+            )
+            .sorted_by_key(|(index, _)| *index)
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    /// Check that every template that is applied is available.
+    ///
+    /// A plugin's own templates are only available to that plugin.
+    fn check_templates(&self) -> Result<()> {
+        let applied = self.apply.iter().map(|name| (name, None)).chain(
+            self.sources.values().flatten().flat_map(|(_, plugin)| {
+                plugin
+                    .apply
+                    .iter()
+                    .flatten()
+                    .chain(plugin.apply_matching.iter().flat_map(|(_, names)| names))
+                    .map(move |name| (name, Some(&plugin.templates)))
+            }),
+        );
+        for (name, local) in applied {
+            if !self.templates.contains_key(name)
+                && !local.is_some_and(|local| local.contains_key(name))
+            {
+                bail!(
+                    "template `{}` is not available for shell `{}`",
+                    name,
+                    self.shell
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the commits that the Git sources are checked out at, so that
+    /// they can be restored if the lock is not written.
+    ///
+    /// The `revisions` are read from the existing lock file.
+    pub fn checkpoint(&self, ctx: &Context, revisions: &diff::Revisions) -> Checkpoint {
+        let migrations = self.migrations(ctx, &previous_sources(ctx));
+        let sources = self
+            .sources
+            .keys()
+            .filter_map(|src| {
+                let dir = source::location(ctx, src, self.clone_dir_layout.as_deref())
+                    .ok()??
+                    .dir;
+                // Clones that are migrated are locked where they are.
+                let dir = migrations
+                    .iter()
+                    .find(|(_, to)| *to == dir)
+                    .map_or(dir, |(from, _)| from.clone());
+                let revision = revisions.get(&dir)?.clone();
+                Some((src.clone(), dir, revision))
+            })
+            .collect();
+        Checkpoint { sources }
+    }
+
+    /// Log the planned operations without performing them.
+    pub fn print(&self, ctx: &Context) -> Result<()> {
+        for (source, plugins) in &self.sources {
+            if plugins.is_empty() {
+                status!(ctx, "Skipped", &source);
+            } else {
+                let operation = source::plan(ctx, source, self.clone_dir_layout.as_deref())
+                    .with_context(s!("failed to plan source `{}`", source))?;
+                status!(ctx, operation.as_str(), &source);
+            }
+        }
+        for name in self.plugin_names() {
+            status!(ctx, "Render", &name);
+        }
+        Ok(())
+    }
+
+    /// Consume the [`Plan`] and convert it to a [`LockedConfig`].
+    ///
+    /// If a number of jobs is configured then the sources are installed using
+    /// a thread pool with that many threads, otherwise the global thread pool
+    /// is used. Once the sources are installed the files of each plugin are
+    /// matched in parallel, using the configured number of match jobs if any.
+    pub fn execute(self, ctx: &Context) -> Result<LockedConfig> {
+        let previous = previous_sources(ctx);
+        let session = Session {
+            migrations: self.migrations(ctx, &previous),
+            previous,
+            ..Default::default()
+        };
+        self.execute_in(ctx, &session)
+    }
+
+    /// Consume the [`Plan`] and convert it to a [`LockedConfig`], sharing the
+    /// given session between the sources.
+    fn execute_in(self, ctx: &Context, session: &Session) -> Result<LockedConfig> {
+        let mut locked = with_jobs(ctx.jobs(), || self.execute_with(ctx, session))?;
+        locked.migrations = session.migrations.clone();
+        locked.check_duplicates(ctx);
+        Ok(locked)
+    }
+
+    /// Returns the existing clones of sources that are not at their location,
+    /// for example because the clone directory layout changed, and where they
+    /// should be moved to instead of cloning them again.
+    fn migrations(&self, ctx: &Context, previous: &[LockedSource]) -> Vec<(PathBuf, PathBuf)> {
+        let previous: Vec<_> = previous.iter().map(|p| p.dir.clone()).collect();
+        source::migrations(
+            ctx,
+            self.sources.keys(),
+            self.clone_dir_layout.as_deref(),
+            &previous,
+        )
+    }
+
+    /// Consume the [`Plan`] and only install the sources that are used by
+    /// plugins, without locking the plugins.
+    ///
+    /// No templates are rendered and the dependencies of plugins are not
+    /// followed. Returns the errors for the sources that failed to install,
+    /// unless the configured policy is to abort.
+    pub fn install(self, ctx: &Context) -> Result<Vec<Error>> {
+        let previous = previous_sources(ctx);
+        let session = Session {
+            migrations: self.migrations(ctx, &previous),
+            previous,
+            ..Default::default()
+        };
+        let Self {
+            sources,
+            require_clean,
+            clone_dir_layout,
+            ..
+        } = self;
+        let abort = ctx.on_error() == OnError::Abort;
+        with_jobs(ctx.jobs(), || {
+            let results = sources
+                .into_par_iter()
+                .filter(|(_, plugins)| !plugins.is_empty())
+                .map(|(source, _)| {
+                    let source_name = source.to_string();
+                    ctx.log_event(Event::Started, &source_name, None);
+                    let result = source::lock(
+                        ctx,
+                        &session,
+                        source,
+                        clone_dir_layout.as_deref(),
+                        require_clean,
+                    )
+                    .map(drop)
+                    .with_context(s!("failed to install source `{}`", source_name));
+                    match &result {
+                        Ok(()) => ctx.log_event(Event::Finished, &source_name, None),
+                        Err(err) => ctx.log_event(Event::Failed, &source_name, Some(err)),
+                    }
+                    result
+                });
+            if abort {
+                results.collect::<Result<()>>()?;
+                Ok(Vec::new())
+            } else {
+                Ok(results.filter_map(Result::err).collect())
+            }
+        })
+    }
+
+    /// Consume the [`Plan`] and convert it to a [`LockedConfig`].
+    ///
+    /// The dependencies that plugins declare are added to the plugins that are
+    /// locked, see [`dependencies`].
+    fn execute_with(self, ctx: &Context, session: &Session) -> Result<LockedConfig> {
+        let Self {
+            shell,
+            templates,
+            matches,
+            apply,
+            clean_ignore,
+            footer,
+            dedupe_paths,
+            invalidate_compdump,
+            guard,
+            profile_startup,
+            trash,
+            require_clean,
+            normalize_line_endings,
+            manifest,
+            wrap_in_function,
+            clone_dir_layout,
+            imports,
+            sources,
+            inlines,
+            ..
+        } = self;
+
+        let abort = ctx.on_error() == OnError::Abort;
+        let mut errors = Vec::new();
+        // The source of each plugin by name, inline plugins have no source.
+        let mut names: HashMap<String, Option<Source>> = HashMap::new();
+        for (source, plugins) in &sources {
+            for (_, plugin) in plugins {
+                names.insert(plugin.name.clone(), Some(source.clone()));
+            }
+        }
+        for (_, plugin) in &inlines {
+            names.insert(plugin.name.clone(), None);
+        }
+        let mut installed: Vec<_> = inlines
+            .into_iter()
+            .map(|(i, p)| {
+                let locked = Installed::Locked(Ok(Box::new(LockedPlugin::Inline(p))));
+                (vec![i, usize::MAX], locked)
+            })
+            .collect();
+        let mut queue: IndexMap<Source, Vec<Queued>> = sources
+            .into_iter()
             .map(|(source, plugins)| {
-                let source_name = source.to_string();
-                let plugins: Vec<_> = plugins
+                let plugins = plugins
                     .into_iter()
-                    .filter(|(_, p)| p.matches_profile(ctx))
+                    .map(|(i, plugin)| Queued {
+                        key: vec![i, usize::MAX],
+                        plugin,
+                        ancestors: Vec::new(),
+                        require_clean,
+                        normalize_line_endings,
+                    })
                     .collect();
+                (source, plugins)
+            })
+            .collect();
+        let mut installed_sources: HashMap<Source, LockedSource> = HashMap::new();
+        let mut installed_dirs = Vec::new();
+        let mut updated_dirs = Vec::new();
 
-                if plugins.is_empty() {
-                    status!(ctx, "Skipped", &source_name);
-                    Ok(vec![])
-                } else {
-                    let source = source::lock(ctx, source)
-                        .with_context(s!("failed to install source `{}`", source_name))?;
-
-                    let mut locked = Vec::with_capacity(plugins.len());
-                    for (index, plugin) in plugins {
-                        let name = plugin.name.clone();
-                        let plugin =
-                            plugin::lock(ctx, &templates, source.clone(), matches, apply, plugin)
-                                .with_context(s!("failed to install plugin `{}`", name));
-                        locked.push((index, plugin));
+        // Install the sources in parallel. Then install the sources of the
+        // dependencies that the plugins declare, one level at a time, so that
+        // each source is only installed once.
+        while !queue.is_empty() {
+            let results = mem::take(&mut queue)
+                .into_par_iter()
+                .map(|(source, plugins)| {
+                    let source_name = source.to_string();
+                    if plugins.is_empty() {
+                        status!(ctx, "Skipped", &source_name);
+                        return Ok(None);
                     }
-                    Ok(locked)
+                    if let Some(locked_source) = installed_sources.get(&source) {
+                        return Ok(Some((source, locked_source.clone(), plugins, false)));
+                    }
+                    // Whether the source is installed, as opposed to checked or
+                    // updated, is decided before it is locked.
+                    let operation = source::plan(ctx, &source, clone_dir_layout.as_deref()).ok();
+                    ctx.log_event(Event::Started, &source_name, None);
+                    let require_clean = plugins.iter().any(|queued| queued.require_clean);
+                    let result = source::lock(
+                        ctx,
+                        session,
+                        source.clone(),
+                        clone_dir_layout.as_deref(),
+                        require_clean,
+                    )
+                    .with_context(s!("failed to install source `{}`", source_name));
+                    // A source is only finished once the files of its plugins
+                    // are matched, see below.
+                    if let Err(err) = &result {
+                        ctx.log_event(Event::Failed, &source_name, Some(err));
+                    }
+                    result.map(|locked_source| {
+                        // A migrated clone is only moved, not installed.
+                        let newly_installed = matches!(
+                            operation,
+                            Some(source::Operation::Install | source::Operation::Reinstall)
+                        ) && !session
+                            .migrations
+                            .iter()
+                            .any(|(from, _)| *from == locked_source.dir);
+                        Some((source, locked_source, plugins, newly_installed))
+                    })
+                });
+            // When aborting, stop installing the remaining sources as soon as
+            // any source fails.
+            let results: Vec<_> = if abort {
+                results
+                    .collect::<Result<Vec<_>>>()?
+                    .into_iter()
+                    .map(Ok)
+                    .collect()
+            } else {
+                results.collect()
+            };
+            // Record the errors that occurred while installing the sources.
+            let results: Vec<_> = results
+                .into_iter()
+                .filter_map(|result| match result {
+                    Ok(ok) => ok,
+                    Err(err) => {
+                        errors.push(err);
+                        None
+                    }
+                })
+                .collect();
+            for (source, locked_source, _, newly_installed) in &results {
+                if installed_sources
+                    .insert(source.clone(), locked_source.clone())
+                    .is_some()
+                {
+                    continue;
                 }
-            })
-            // The result of this is basically an `Iter<Result<Vec<(usize, Result)>, _>>`
-            // The first thing we need to do is to filter out the failures and record the
-            // errors that occurred while installing the source in our `errors` list.
-            // Finally, we flatten the sub lists into a single iterator.
-            .collect::<Vec<_>>()
-            .into_iter()
-            .filter_map(|result| match result {
-                Ok(ok) => Some(ok),
-                Err(err) => {
-                    errors.push(err);
-                    None
+                if *newly_installed {
+                    installed_dirs.push(locked_source.dir.clone());
+                } else if is_updated(session, locked_source) {
+                    updated_dirs.push(locked_source.dir.clone());
                 }
-            })
-            .flatten()
-            // The result of this is basically a `Iter<(usize, Result<LockedExternalPlugin>)`.
-            // Similar to the above, we filter out the failures that
-            // occurred during locking of individual plugins and record the
-            // errors. Next, we combine this with the inline plugins which
-            // didn't have to be installed. Finally we sort by the original index
-            // to end up wih an iterator of `LockedPlugin`s which we can collect into a
-            // `Vec<_>`.
-            .collect::<Vec<_>>()
+            }
+            // Queue the dependencies of the plugins, they are installed in the
+            // next iteration.
+            for (source, locked_source, plugins, _) in results {
+                for queued in plugins {
+                    if queued.plugin.follow_dependencies {
+                        let ancestors: Vec<_> =
+                            queued.ancestors.iter().chain([&source]).cloned().collect();
+                        let result = dependencies(ctx, shell, &locked_source.dir, &ancestors)
+                            .and_then(|plan| {
+                                queue_dependencies(
+                                    plan,
+                                    &queued,
+                                    ancestors,
+                                    &mut names,
+                                    &mut queue,
+                                    &mut installed,
+                                )
+                            });
+                        if let Err(err) = result {
+                            let err = err.context(format!(
+                                "failed to install dependencies of plugin `{}`",
+                                queued.plugin.name
+                            ));
+                            if abort {
+                                return Err(err);
+                            }
+                            installed.push((queued.key.clone(), Installed::Locked(Err(err))));
+                        }
+                    }
+                    let pending = Installed::Pending(
+                        source.to_string(),
+                        locked_source.clone(),
+                        Box::new(queued.plugin),
+                        queued.normalize_line_endings,
+                    );
+                    installed.push((queued.key, pending));
+                }
+            }
+        }
+
+        // Match the files of a single plugin in an installed source.
+        let match_plugin =
+            |locked_source: LockedSource, plugin: ExternalPlugin, normalize_line_endings: bool| {
+                let name = plugin.name.clone();
+                plugin::lock(
+                    ctx,
+                    shell,
+                    &templates,
+                    locked_source,
+                    &matches,
+                    &apply,
+                    plugin,
+                )
+                .and_then(|locked| {
+                    plugin::check_line_endings(ctx, &locked, normalize_line_endings)?;
+                    Ok(locked)
+                })
+                .map(LockedPlugin::External)
+                .with_context(s!("failed to install plugin `{}`", name))
+            };
+        // Now that all the sources are installed, match the files of each
+        // plugin in parallel.
+        let results = with_jobs(ctx.match_jobs(), || {
+            let results = installed
+                .into_par_iter()
+                .map(|(key, installed)| match installed {
+                    Installed::Locked(result) => (key, None, result.map(|plugin| *plugin)),
+                    Installed::Pending(
+                        source_name,
+                        locked_source,
+                        plugin,
+                        normalize_line_endings,
+                    ) => (
+                        key,
+                        Some(source_name),
+                        match_plugin(locked_source, *plugin, normalize_line_endings),
+                    ),
+                });
+            // When aborting, stop matching the remaining plugins as soon as
+            // any plugin fails.
+            if abort {
+                results
+                    .map(|(key, source_name, result)| match result {
+                        Ok(plugin) => Ok((key, source_name, Ok(plugin))),
+                        Err(err) => {
+                            if let Some(source_name) = &source_name {
+                                ctx.log_event(Event::Failed, source_name, Some(&err));
+                            }
+                            Err(err)
+                        }
+                    })
+                    .collect::<Result<Vec<_>>>()
+            } else {
+                Ok(results.collect::<Vec<_>>())
+            }
+        })?;
+        // Report each installed source as finished, or as failed with the
+        // first error of its plugins.
+        let mut outcomes: IndexMap<&str, Option<&Error>> = IndexMap::new();
+        for (_, source_name, result) in &results {
+            if let Some(source_name) = source_name {
+                let outcome = outcomes.entry(source_name.as_str()).or_default();
+                if let Err(err) = result {
+                    outcome.get_or_insert(err);
+                }
+            }
+        }
+        for (source_name, err) in outcomes {
+            match err {
+                Some(err) => ctx.log_event(Event::Failed, source_name, Some(err)),
+                None => ctx.log_event(Event::Finished, source_name, None),
+            }
+        }
+        // Record the errors that occurred while locking individual plugins,
+        // and sort the rest to end up with the plugins in their original order,
+        // with dependencies before the plugins that declared them.
+        let plugins = results
             .into_iter()
-            .filter_map(|(index, result)| match result {
-                Ok(plugin) => Some((index, LockedPlugin::External(plugin))),
+            .filter_map(|(key, _, result)| match result {
+                Ok(plugin) => Some((key, plugin)),
                 Err(err) => {
                     errors.push(err);
                     None
                 }
             })
-            .chain(inlines)
-            .sorted_by_key(|(index, _)| *index)
+            .sorted_by(|(a, _), (b, _)| a.cmp(b))
             .map(|(_, locked)| locked)
-            .collect::<Vec<_>>()
+            .collect::<Vec<_>>();
+
+        Ok(LockedConfig {
+            ctx: ctx.clone(),
+            shell,
+            clean_ignore,
+            footer,
+            dedupe_paths,
+            invalidate_compdump,
+            guard,
+            profile_startup,
+            trash,
+            imports,
+            manifest,
+            wrap_in_function,
+            templates,
+            errors,
+            warnings: Vec::new(),
+            migrations: Vec::new(),
+            installed: installed_dirs,
+            updated: updated_dirs,
+            plugins,
+        })
+    }
+}
+
+impl Checkpoint {
+    /// Check out the Git sources that the given lock moved to another commit
+    /// at their recorded commit again.
+    pub fn restore(self, ctx: &Context, locked: &LockedConfig) -> Result<()> {
+        let revisions = revisions(locked);
+        for (src, dir, revision) in self.sources {
+            if revisions.get(&dir) != Some(&revision) {
+                source::restore(ctx, &src, dir, &revision)
+                    .with_context(s!("failed to restore source `{}`", src))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether a Git source was locked at another commit than the one that the
+/// existing lock file records for it.
+fn is_updated(session: &Session, locked_source: &LockedSource) -> bool {
+    session
+        .previous
+        .iter()
+        .find(|previous| previous.dir == locked_source.dir)
+        .is_some_and(|previous| {
+            previous.revision.is_some() && previous.revision != locked_source.revision
+        })
+}
+
+/// Returns the sources of the external plugins in the existing lock file, as
+/// they were recorded when it was written.
+fn previous_sources(ctx: &Context) -> Vec<LockedSource> {
+    from_path(ctx.lock_file())
+        .map(|locked| {
+            locked
+                .plugins
+                .into_iter()
+                .filter_map(|plugin| match plugin {
+                    LockedPlugin::External(plugin) if !plugin.local => Some(LockedSource {
+                        dir: plugin.source_dir,
+                        file: None,
+                        revision: plugin.revision,
+                        reference: plugin.reference,
+                        fetched: plugin.fetched,
+                    }),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Plan the plugins declared in the dependencies file in a plugin's source
+/// directory.
+///
+/// The `ancestors` are the sources of the plugins whose dependencies are being
+/// planned, including the given source directory's, this is used to detect
+/// dependency cycles. Returns `None` if there is no dependencies file.
+fn dependencies(
+    ctx: &Context,
+    shell: Shell,
+    dir: &Path,
+    ancestors: &[Source],
+) -> Result<Option<Plan>> {
+    if ancestors.len() > MAX_DEPENDENCY_DEPTH {
+        bail!(
+            "exceeded the maximum dependency depth of {}",
+            MAX_DEPENDENCY_DEPTH
+        );
+    }
+    let path = dir.join(DEPENDENCIES_FILE);
+    let mut warnings = Vec::new();
+    let mut config = if path.exists() {
+        crate::config::from_path(ctx, &path, &mut warnings)?
+    } else {
+        return Ok(None);
     };
+    for warning in &warnings {
+        error_w!(ctx, warning);
+    }
+    config.shell = shell;
+    let plan = plan(ctx, config);
+    if let Some(source) = plan.sources.keys().find(|s| ancestors.contains(s)) {
+        bail!("dependency cycle detected for source `{}`", source);
+    }
+    Ok(Some(plan))
+}
 
-    Ok(LockedConfig {
-        ctx: ctx.clone(),
-        templates,
-        errors,
-        plugins,
-    })
+/// Queue the plugins of a dependency plan to be locked before the plugin that
+/// declared them.
+///
+/// The dependencies use the same shell, templates, matches, and applied
+/// templates as the plugin that declared them, and are also required to be
+/// clean and normalized if that plugin is. A dependency that has the same name
+/// and source as a plugin that is already locked is only locked once. Fails
+/// without queueing anything if a dependency has the same name as another
+/// plugin otherwise.
+fn queue_dependencies(
+    plan: Option<Plan>,
+    parent: &Queued,
+    ancestors: Vec<Source>,
+    names: &mut HashMap<String, Option<Source>>,
+    queue: &mut IndexMap<Source, Vec<Queued>>,
+    installed: &mut Vec<(Vec<usize>, Installed)>,
+) -> Result<()> {
+    let Plan {
+        sources,
+        inlines,
+        require_clean,
+        normalize_line_endings,
+        ..
+    } = match plan {
+        Some(plan) => plan,
+        None => return Ok(()),
+    };
+    let declared = sources
+        .iter()
+        .flat_map(|(source, plugins)| plugins.iter().map(move |(_, p)| (&p.name, Some(source))))
+        .chain(inlines.iter().map(|(_, p)| (&p.name, None)));
+    for (name, source) in declared {
+        match names.get(name) {
+            Some(existing) if source.is_none() || existing.as_ref() != source => {
+                bail!("dependency `{}` has the same name as another plugin", name)
+            }
+            _ => {}
+        }
+    }
+
+    let key = |index: usize| {
+        let mut key = parent.key[..parent.key.len() - 1].to_vec();
+        key.extend([index, usize::MAX]);
+        key
+    };
+    for (index, plugin) in inlines {
+        names.insert(plugin.name.clone(), None);
+        installed.push((
+            key(index),
+            Installed::Locked(Ok(Box::new(LockedPlugin::Inline(plugin)))),
+        ));
+    }
+    for (source, plugins) in sources {
+        for (index, plugin) in plugins {
+            if names
+                .insert(plugin.name.clone(), Some(source.clone()))
+                .is_some()
+            {
+                continue;
+            }
+            queue.entry(source.clone()).or_default().push(Queued {
+                key: key(index),
+                plugin,
+                ancestors: ancestors.clone(),
+                require_clean: require_clean || parent.require_clean,
+                normalize_line_endings: normalize_line_endings || parent.normalize_line_endings,
+            });
+        }
+    }
+    Ok(())
+}
+
+impl LockedConfig {
+    /// A `LockedConfig` with no plugins.
+    #[cfg(test)]
+    fn empty(ctx: &Context, shell: Shell) -> Self {
+        Self {
+            ctx: ctx.clone(),
+            shell,
+            clean_ignore: Vec::new(),
+            footer: None,
+            dedupe_paths: false,
+            invalidate_compdump: false,
+            guard: false,
+            profile_startup: false,
+            trash: false,
+            imports: Vec::new(),
+            manifest: None,
+            wrap_in_function: None,
+            plugins: Vec::new(),
+            templates: IndexMap::new(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            migrations: Vec::new(),
+            installed: Vec::new(),
+            updated: Vec::new(),
+        }
+    }
 }
 
 impl Shell {
@@ -207,16 +1192,20 @@ impl Shell {
     pub fn default_templates(&self) -> &IndexMap<String, Template> {
         static DEFAULT_TEMPLATES_BASH: Lazy<IndexMap<String, Template>> = Lazy::new(|| {
             indexmap_into! {
-                "PATH" => "export PATH=\"{{ dir }}:$PATH\"",
-                "source" => Template::from("source \"{{ file }}\"").each(true)
+                "PATH" => "export PATH={{ quote dir }}:\"$PATH\"",
+                "source" => Template::from("source {{ quote file }}").each(true)
             }
         });
         static DEFAULT_TEMPLATES_ZSH: Lazy<IndexMap<String, Template>> = Lazy::new(|| {
             indexmap_into! {
-                "PATH" => "export PATH=\"{{ dir }}:$PATH\"",
-                "path" => "path=( \"{{ dir }}\" $path )",
-                "fpath" => "fpath=( \"{{ dir }}\" $fpath )",
-                "source" => Template::from("source \"{{ file }}\"").each(true)
+                "PATH" => "export PATH={{ quote dir }}:\"$PATH\"",
+                "path" => "path=( {{ quote dir }} $path )",
+                "fpath" => "fpath=( {{ quote dir }} $fpath )",
+                "source" => Template::from("source {{ quote file }}").each(true),
+                "defer" => Template::from(
+                    "if (( $+functions[zsh-defer] )); then zsh-defer source {{ quote file }}; \
+                     else source {{ quote file }}; fi"
+                ).each(true)
             }
         });
         match self {
@@ -238,30 +1227,137 @@ impl Template {
         self.each = each;
         self
     }
+
+    /// Whether this template uses the `secret` helper.
+    pub fn uses_secret(&self) -> bool {
+        regex!(r"(\{\{~?|\()\s*secret\s").is_match(&self.value)
+    }
 }
 
 impl LockedConfig {
     /// Verify that the `LockedConfig` is okay.
     pub fn verify(&self, ctx: &Context) -> bool {
-        if !is_context_equal(&self.ctx, ctx) {
-            return false;
+        self.is_current(ctx)
+            && self.plugins.iter().all(|plugin| match plugin {
+                LockedPlugin::External(plugin) => plugin.missing().is_empty(),
+                LockedPlugin::Inline(_) => true,
+            })
+    }
+
+    /// Whether the `LockedConfig` was generated using the given context.
+    pub fn is_current(&self, ctx: &Context) -> bool {
+        is_context_equal(&self.ctx, ctx)
+    }
+
+    /// Whether the generated script can be cached.
+    ///
+    /// Scripts that contain secrets are never written to disk.
+    pub fn is_cacheable(&self) -> bool {
+        !self.uses_secret()
+    }
+
+    /// Whether any of the global or plugin templates use the `secret` helper.
+    pub fn uses_secret(&self) -> bool {
+        self.templates.values().any(Template::uses_secret)
+            || self.plugins.iter().any(|plugin| match plugin {
+                LockedPlugin::External(plugin) => {
+                    plugin.templates.values().any(Template::uses_secret)
+                }
+                LockedPlugin::Inline(_) => false,
+            })
+    }
+
+    /// Move the clones that were locked where they are to their location, and
+    /// update the paths of the plugins that use them.
+    ///
+    /// This is done just before the lock file is written, so that an existing
+    /// lock file keeps pointing at the clones otherwise. A clone that fails to
+    /// move is left where it is.
+    pub fn migrate(&mut self, ctx: &Context) {
+        for (from, to) in mem::take(&mut self.migrations) {
+            if let Err(err) = source::move_clone(&from, &to) {
+                error_w!(ctx, &err);
+                continue;
+            }
+            status!(
+                ctx,
+                "Migrated",
+                &format!(
+                    "{} to {}",
+                    ctx.replace_home(&from).display(),
+                    ctx.replace_home(&to).display()
+                )
+            );
+            for plugin in &mut self.plugins {
+                if let LockedPlugin::External(plugin) = plugin {
+                    plugin.relocate(&from, &to);
+                }
+            }
+        }
+    }
+
+    /// Returns the locked plugin with the given name.
+    pub fn plugin(&self, name: &str) -> Option<&LockedPlugin> {
+        self.plugins.iter().find(|plugin| plugin.name() == name)
+    }
+
+    /// Remove the plugins whose names are not in `names`, leaving the rest
+    /// untouched.
+    ///
+    /// Returns the names of the removed plugins.
+    pub fn prune(&mut self, names: &[&str]) -> Vec<String> {
+        let mut pruned = Vec::new();
+        self.plugins.retain(|plugin| {
+            let keep = names.contains(&plugin.name());
+            if !keep {
+                pruned.push(plugin.name().to_string());
+            }
+            keep
+        });
+        pruned
+    }
+
+    /// Report the file names that are matched by more than one plugin, as
+    /// errors in strict mode and as warnings otherwise.
+    pub fn check_duplicates(&mut self, ctx: &Context) {
+        let duplicates = self.duplicate_basenames();
+        if ctx.strict() {
+            self.errors.extend(duplicates);
+        } else {
+            self.warnings = duplicates;
         }
+    }
+
+    /// Returns a warning for each file name that is matched by more than one
+    /// plugin, since sourcing them can shadow or conflict with each other.
+    fn duplicate_basenames(&self) -> Vec<Error> {
+        let mut basenames: IndexMap<&OsStr, Vec<(&str, &Path)>> = IndexMap::new();
         for plugin in &self.plugins {
-            match plugin {
-                LockedPlugin::External(plugin) => {
-                    if !plugin.dir().exists() {
-                        return false;
-                    }
-                    for file in &plugin.files {
-                        if !file.exists() {
-                            return false;
-                        }
+            for file in plugin.files() {
+                if let Some(basename) = file.file_name() {
+                    let entries = basenames.entry(basename).or_default();
+                    // Plugins that share a source can match the same file.
+                    if !entries.iter().any(|(_, f)| *f == file) {
+                        entries.push((plugin.name(), file));
                     }
                 }
-                LockedPlugin::Inline(_) => {}
             }
         }
-        true
+        basenames
+            .into_iter()
+            .filter(|(_, entries)| entries.iter().map(|(name, _)| name).unique().count() > 1)
+            .map(|(basename, entries)| {
+                let conflicts = entries
+                    .iter()
+                    .map(|(name, file)| format!("`{}` ({})", name, file.display()))
+                    .join(", ");
+                anyhow!(
+                    "file name `{}` is matched by more than one plugin: {}",
+                    basename.to_string_lossy(),
+                    conflicts
+                )
+            })
+            .collect()
     }
 }
 
@@ -277,29 +1373,170 @@ fn is_context_equal(left: &Context, right: &Context) -> bool {
         && left.profile == right.profile
 }
 
+/// Some of a plugin's files and the templates to apply to them.
+struct Bucket<'a> {
+    /// The directories that templates without `each` set are applied to.
+    dirs: Vec<&'a Path>,
+    /// The files that templates with `each` set are applied to.
+    files: Vec<&'a Path>,
+    /// The names of the templates to apply.
+    apply: &'a [String],
+}
+
+impl LockedPlugin {
+    /// The name of this plugin.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::External(plugin) => &plugin.name,
+            Self::Inline(plugin) => &plugin.name,
+        }
+    }
+
+    /// The files that this plugin uses, an inline plugin has none.
+    pub fn files(&self) -> &[PathBuf] {
+        match self {
+            Self::External(plugin) => &plugin.files,
+            Self::Inline(_) => &[],
+        }
+    }
+
+    /// The labels for selecting this plugin.
+    fn tags(&self) -> &[String] {
+        match self {
+            Self::External(plugin) => &plugin.tags,
+            Self::Inline(plugin) => &plugin.tags,
+        }
+    }
+}
+
 impl LockedExternalPlugin {
     /// Return a reference to the plugin directory.
     fn dir(&self) -> &Path {
         self.plugin_dir.as_ref().unwrap_or(&self.source_dir)
     }
-}
-
-////////////////////////////////////////////////////////////////////////////////
-// Unit tests
-////////////////////////////////////////////////////////////////////////////////
 
-#[cfg(test)]
-mod tests {
+    /// Replace the `from` prefix of the paths of this plugin with `to`.
+    fn relocate(&mut self, from: &Path, to: &Path) {
+        let relocate = |path: &mut PathBuf| {
+            if let Ok(rest) = path.strip_prefix(from) {
+                *path = to.join(rest);
+            }
+        };
+        relocate(&mut self.source_dir);
+        self.plugin_dir.iter_mut().for_each(relocate);
+        self.file.iter_mut().for_each(relocate);
+        self.files.iter_mut().for_each(relocate);
+        for group in &mut self.groups {
+            group.files.iter_mut().for_each(relocate);
+        }
+    }
 
-    use url::Url;
+    /// Returns the commit that this plugin's Git source is checked out at.
+    ///
+    /// This is the commit recorded in the lock file. Lock files written by
+    /// older versions do not record commits, so then it is read from the clone.
+    /// Local sources and sources without Git history have no commit.
+    pub fn revision(&self) -> Option<String> {
+        if self.local {
+            return None;
+        }
+        if let Some(revision) = &self.revision {
+            return Some(revision.clone());
+        }
+        let oid = git::open(&self.source_dir).ok()?.head().ok()?.target()?;
+        Some(oid.to_string())
+    }
+
+    /// Returns this plugin's files split up by the templates to apply to them,
+    /// in the order they should be rendered.
+    ///
+    /// Each group comes first, with templates without `each` set applied to the
+    /// directories containing the group's files. The remaining files come last
+    /// and use the plugin directory.
+    fn buckets(&self) -> Vec<Bucket<'_>> {
+        let mut buckets: Vec<_> = self
+            .groups
+            .iter()
+            .map(|group| Bucket {
+                dirs: group
+                    .files
+                    .iter()
+                    .filter_map(|f| f.parent())
+                    .unique()
+                    .collect(),
+                files: group.files.iter().map(PathBuf::as_path).collect(),
+                apply: &group.apply,
+            })
+            .collect();
+        let grouped: HashSet<_> = self.groups.iter().flat_map(|g| &g.files).collect();
+        buckets.push(Bucket {
+            dirs: vec![self.dir()],
+            files: self
+                .files
+                .iter()
+                .filter(|file| !grouped.contains(file))
+                .map(PathBuf::as_path)
+                .collect(),
+            apply: &self.apply,
+        });
+        buckets
+    }
+
+    /// Hash the downloaded file of a remote plugin again, returning the actual
+    /// checksum if it does not match the one in the lock file.
+    ///
+    /// Plugins without a checksum in the lock file are not checked.
+    pub fn checksum_mismatch(&self) -> Result<Option<String>> {
+        let expected = match &self.checksum {
+            Some(expected) => expected,
+            None => return Ok(None),
+        };
+        let file = self.file.as_ref().with_context(s!(
+            "lock file does not record the downloaded file of `{}`, try running `sheldon lock`",
+            self.name
+        ))?;
+        let actual = format!(
+            "sha256:{}",
+            sha256::digest_file(file).with_context(s!("failed to read `{}`", file.display()))?
+        );
+        Ok((actual != *expected).then_some(actual))
+    }
+
+    /// Returns the paths required by this plugin that do not exist.
+    pub fn missing(&self) -> Vec<&Path> {
+        // Local sources are only required to be an existing directory, they
+        // don't need to have any Git layout.
+        if self.local && !self.source_dir.is_dir() {
+            return vec![&self.source_dir];
+        }
+        if !self.dir().exists() {
+            return vec![self.dir()];
+        }
+        self.files
+            .iter()
+            .filter(|file| !file.exists())
+            .map(PathBuf::as_path)
+            .collect()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Unit tests
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+
+    use url::Url;
 
     use super::*;
 
     use std::io::prelude::*;
+    use std::time;
 
-    use crate::config::{ExternalPlugin, Source};
+    use crate::config::{ExternalPlugin, GitReference, Source};
     use crate::context::Output;
-    use crate::util::build;
+    use crate::util::{build, testing};
 
     impl Context {
         pub fn testing(root: &Path) -> Self {
@@ -316,8 +1553,17 @@ mod tests {
                 output: Output {
                     verbosity: crate::context::Verbosity::Quiet,
                     no_color: true,
+                    progress_format: Default::default(),
                 },
                 lock_mode: None,
+                max_download_size: None,
+                jobs: None,
+                match_jobs: None,
+                on_error: OnError::Skip,
+                strict: false,
+                credential_helper: None,
+                update_max_age: None,
+                gnupg_home: None,
             }
         }
     }
@@ -332,7 +1578,23 @@ mod tests {
             matches: None,
             apply: None,
             templates: IndexMap::new(),
+            remove_templates: None,
+            clean_ignore: None,
+            footer: None,
+            dedupe_paths: None,
+            invalidate_compdump: None,
+            guard: None,
+            profile_startup: None,
+            trash: None,
+            require_clean: None,
+            normalize_line_endings: None,
+            manifest: None,
+            wrap_in_function: None,
+            clone_dir_layout: None,
+            active_profiles: None,
+            imports: Vec::new(),
             plugins: Vec::new(),
+            skipped: IndexMap::new(),
         };
 
         let locked = config(&ctx, cfg).unwrap();
@@ -346,6 +1608,185 @@ mod tests {
         assert_eq!(locked.errors.len(), 0);
     }
 
+    #[test]
+    fn plan_does_not_install() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let git = Source::Git {
+            url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
+            reference: None,
+            archive: false,
+            clone_as: None,
+            no_history: false,
+            verify_signature: None,
+        };
+        let remote = Source::Remote {
+            url: Url::parse("https://github.com/rossmacarthur/sheldon/raw/0.3.0/LICENSE-MIT")
+                .unwrap(),
+            version: None,
+            checksum: None,
+            filename: None,
+        };
+        let external = |name: &str, source: &Source| {
+            Plugin::External(ExternalPlugin {
+                name: name.to_string(),
+                source: source.clone(),
+                dir: None,
+                uses: None,
+                apply: None,
+                apply_matching: Vec::new(),
+                apply_globs: Vec::new(),
+                templates: IndexMap::new(),
+                profiles: None,
+                shell: None,
+                follow_symlinks: false,
+                follow_dependencies: false,
+                version_from: None,
+                match_shebang: None,
+                order: Vec::new(),
+                tags: Vec::new(),
+            })
+        };
+        let cfg = Config {
+            shell: Shell::Zsh,
+            matches: None,
+            apply: None,
+            templates: IndexMap::new(),
+            remove_templates: None,
+            clean_ignore: None,
+            footer: None,
+            dedupe_paths: None,
+            invalidate_compdump: None,
+            guard: None,
+            profile_startup: None,
+            trash: None,
+            require_clean: None,
+            normalize_line_endings: None,
+            manifest: None,
+            wrap_in_function: None,
+            clone_dir_layout: None,
+            active_profiles: None,
+            imports: Vec::new(),
+            plugins: vec![
+                external("first", &git),
+                Plugin::Inline(InlinePlugin {
+                    name: "second".to_string(),
+                    raw: "echo 'second'".to_string(),
+                    profiles: None,
+                    shell: None,
+                    tags: Vec::new(),
+                }),
+                external("third", &remote),
+                external("fourth", &git),
+            ],
+            skipped: IndexMap::new(),
+        };
+
+        let plan = plan(&ctx, cfg);
+        assert_eq!(plan.sources.keys().collect::<Vec<_>>(), vec![&git, &remote]);
+        assert_eq!(
+            plan.plugin_names(),
+            vec!["first", "second", "third", "fourth"]
+        );
+        for src in plan.sources.keys() {
+            assert_eq!(
+                source::plan(&ctx, src, None).unwrap(),
+                source::Operation::Install
+            );
+        }
+        plan.print(&ctx).unwrap();
+        assert!(!ctx.clone_dir().exists());
+        assert!(!ctx.download_dir().exists());
+    }
+
+    fn profiles_config() -> Config {
+        let inline = |name: &str, profiles: Option<Vec<String>>| {
+            Plugin::Inline(InlinePlugin {
+                name: name.to_string(),
+                raw: format!("echo '{}'", name),
+                profiles,
+                shell: None,
+                tags: Vec::new(),
+            })
+        };
+        Config {
+            shell: Shell::Zsh,
+            matches: None,
+            apply: None,
+            templates: IndexMap::new(),
+            remove_templates: None,
+            clean_ignore: None,
+            footer: None,
+            dedupe_paths: None,
+            invalidate_compdump: None,
+            guard: None,
+            profile_startup: None,
+            trash: None,
+            require_clean: None,
+            normalize_line_endings: None,
+            manifest: None,
+            wrap_in_function: None,
+            clone_dir_layout: None,
+            active_profiles: Some(vec_into!["base"]),
+            imports: Vec::new(),
+            plugins: vec![
+                inline("always", None),
+                inline("base", Some(vec_into!["base"])),
+                inline("work", Some(vec_into!["work"])),
+            ],
+            skipped: IndexMap::new(),
+        }
+    }
+
+    #[test]
+    fn plan_active_profiles_default() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let mut ctx = Context::testing(temp.path());
+        ctx.profile = None;
+        let plan = plan(&ctx, profiles_config());
+        assert_eq!(plan.plugin_names(), vec!["always", "base"]);
+    }
+
+    #[test]
+    fn plan_active_profiles_overridden() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let mut ctx = Context::testing(temp.path());
+        ctx.profile = Some("work".to_string());
+        let plan = plan(&ctx, profiles_config());
+        assert_eq!(plan.plugin_names(), vec!["always", "work"]);
+    }
+
+    #[test]
+    fn plan_skip_reason() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let mut ctx = Context::testing(temp.path());
+        ctx.profile = None;
+        let mut cfg = profiles_config();
+        if let Plugin::Inline(plugin) = &mut cfg.plugins[1] {
+            plugin.shell = Some(Shell::Bash);
+        }
+        cfg.skipped
+            .insert("extra".to_string(), SkipReason::Group("extras".to_string()));
+        let plan = plan(&ctx, cfg);
+        assert_eq!(plan.skip_reason("always").unwrap(), None);
+        assert_eq!(
+            plan.skip_reason("base").unwrap(),
+            Some(&SkipReason::Shell(Shell::Bash))
+        );
+        assert_eq!(
+            plan.skip_reason("work").unwrap(),
+            Some(&SkipReason::Profiles(vec_into!["work"]))
+        );
+        assert_eq!(
+            plan.skip_reason("extra").unwrap(),
+            Some(&SkipReason::Group("extras".to_string()))
+        );
+        assert_eq!(
+            plan.skip_reason("missing").unwrap_err().to_string(),
+            "plugin `missing` is not defined"
+        );
+    }
+
     #[test]
     fn locked_config_clean() {
         let temp = tempfile::tempdir().expect("create temporary directory");
@@ -355,17 +1796,47 @@ mod tests {
             matches: None,
             apply: None,
             templates: IndexMap::new(),
+            remove_templates: None,
+            clean_ignore: None,
+            footer: None,
+            dedupe_paths: None,
+            invalidate_compdump: None,
+            guard: None,
+            profile_startup: None,
+            trash: None,
+            require_clean: None,
+            normalize_line_endings: None,
+            manifest: None,
+            wrap_in_function: None,
+            clone_dir_layout: None,
+            active_profiles: None,
+            imports: Vec::new(),
             plugins: vec![Plugin::External(ExternalPlugin {
                 name: "test".to_string(),
                 source: Source::Git {
                     url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
                     reference: None,
+                    archive: false,
+                    clone_as: None,
+                    no_history: false,
+                    verify_signature: None,
                 },
                 dir: None,
                 uses: None,
                 apply: None,
+                apply_matching: Vec::new(),
+                apply_globs: Vec::new(),
+                templates: IndexMap::new(),
                 profiles: None,
+                shell: None,
+                follow_symlinks: false,
+                follow_dependencies: false,
+                version_from: None,
+                match_shebang: None,
+                order: Vec::new(),
+                tags: Vec::new(),
             })],
+            skipped: IndexMap::new(),
         };
         let locked = config(&ctx, cfg).unwrap();
         let test_dir = ctx.clone_dir().join("github.com/rossmacarthur/another-dir");
@@ -374,13 +1845,14 @@ mod tests {
         {
             fs::OpenOptions::new()
                 .create(true)
+                .truncate(true)
                 .write(true)
                 .open(&test_file)
                 .unwrap();
         }
 
         let mut warnings = Vec::new();
-        locked.clean(&ctx, &mut warnings);
+        locked.clean(&ctx, None, false, &mut warnings);
         assert!(warnings.is_empty());
         assert!(ctx
             .clone_dir()
@@ -395,25 +1867,1459 @@ mod tests {
     }
 
     #[test]
-    fn locked_config_to_and_from_path() {
-        let mut temp = tempfile::NamedTempFile::new().unwrap();
-        let content = r#"version = "<version>"
-home = "<home>"
-config_dir = "<config>"
-data_dir = "<data>"
-config_file = "<config>/plugins.toml"
-lock_file = "<data>/plugins.lock"
-clone_dir = "<data>/repos"
-download_dir = "<data>/downloads"
-plugins = []
+    fn locked_config_clean_ignore() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let locked = LockedConfig {
+            ctx: ctx.clone(),
+            shell: Shell::Zsh,
+            clean_ignore: vec_into!["manual/repo*"],
+            footer: None,
+            dedupe_paths: false,
+            invalidate_compdump: false,
+            guard: false,
+            profile_startup: false,
+            trash: false,
+            imports: Vec::new(),
+            manifest: None,
+            wrap_in_function: None,
+            plugins: Vec::new(),
+            templates: IndexMap::new(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            migrations: Vec::new(),
+            installed: Vec::new(),
+            updated: Vec::new(),
+        };
+        let protected_dir = ctx.clone_dir().join("manual/repo");
+        let stale_dir = ctx.clone_dir().join("manual/stale");
+        let other_dir = ctx.clone_dir().join("github.com/rossmacarthur/another-dir");
+        for dir in [&protected_dir, &stale_dir, &other_dir] {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(dir.join("test.txt"), "").unwrap();
+        }
 
-[templates]
-"#;
-        temp.write_all(content.as_bytes()).unwrap();
-        let locked_config = from_path(temp.into_temp_path()).unwrap();
-        let temp = tempfile::NamedTempFile::new().unwrap();
-        let path = temp.into_temp_path();
-        locked_config.to_path(&path).unwrap();
-        assert_eq!(fs::read_to_string(&path).unwrap(), content);
+        let mut warnings = Vec::new();
+        locked.clean(&ctx, None, false, &mut warnings);
+        assert!(warnings.is_empty());
+        assert!(protected_dir.join("test.txt").exists());
+        assert!(!other_dir.exists());
+        assert!(!stale_dir.exists());
+    }
+
+    #[test]
+    fn locked_external_plugin_checksum_mismatch() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let download_dir = temp.path().join("downloads/example.com");
+        let file = download_dir.join("plugin.zsh");
+        fs::create_dir_all(&download_dir).unwrap();
+        fs::write(&file, "echo 'hello world'\n").unwrap();
+        let checksum = format!("sha256:{}", sha256::digest_file(&file).unwrap());
+        let plugin = LockedExternalPlugin {
+            name: "test".to_string(),
+            source_dir: download_dir.clone(),
+            plugin_dir: None,
+            files: vec![file.clone()],
+            apply: vec_into!["source"],
+            local: false,
+            file: Some(file.clone()),
+            checksum: Some(checksum),
+            url: None,
+            revision: None,
+            reference: None,
+            fetched: None,
+            version: None,
+            templates: IndexMap::new(),
+            groups: Vec::new(),
+            tags: Vec::new(),
+        };
+        assert_eq!(plugin.checksum_mismatch().unwrap(), None);
+
+        // The cached file is corrupted after it was locked.
+        fs::write(&file, "echo 'hello w0rld'\n").unwrap();
+        let actual = format!("sha256:{}", sha256::digest_file(&file).unwrap());
+        assert_eq!(plugin.checksum_mismatch().unwrap(), Some(actual));
+
+        // The downloaded file is checked, not the files that the plugin uses.
+        let plugin = LockedExternalPlugin {
+            files: Vec::new(),
+            ..plugin
+        };
+        assert!(plugin.checksum_mismatch().unwrap().is_some());
+
+        // A lock file that does not record the downloaded file is not trusted.
+        let plugin = LockedExternalPlugin {
+            file: None,
+            ..plugin
+        };
+        assert_eq!(
+            plugin.checksum_mismatch().unwrap_err().to_string(),
+            "lock file does not record the downloaded file of `test`, try running `sheldon lock`"
+        );
+
+        // Plugins without a checksum are not checked.
+        let plugin = LockedExternalPlugin {
+            checksum: None,
+            ..plugin
+        };
+        assert_eq!(plugin.checksum_mismatch().unwrap(), None);
+    }
+
+    #[test]
+    fn locked_config_clean_since() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let download_dir = ctx.download_dir().join("example.com");
+        let locked_file = download_dir.join("locked.zsh");
+        let old_file = download_dir.join("old.zsh");
+        let new_file = download_dir.join("new.zsh");
+        let locked = LockedConfig {
+            plugins: vec![LockedPlugin::External(LockedExternalPlugin {
+                name: "locked".to_string(),
+                source_dir: download_dir.clone(),
+                plugin_dir: None,
+                files: vec![locked_file.clone()],
+                apply: vec_into!["source"],
+                local: false,
+                file: None,
+                checksum: None,
+                url: None,
+                revision: None,
+                reference: None,
+                fetched: None,
+                version: None,
+                templates: IndexMap::new(),
+                groups: Vec::new(),
+                tags: Vec::new(),
+            })],
+            ..LockedConfig::empty(&ctx, Shell::Zsh)
+        };
+        fs::create_dir_all(&download_dir).unwrap();
+        let old = time::SystemTime::now() - time::Duration::from_secs(60 * 60 * 24 * 31);
+        for file in [&locked_file, &old_file, &new_file] {
+            let f = fs::File::create(file).unwrap();
+            if file != &new_file {
+                f.set_modified(old).unwrap();
+            }
+        }
+
+        let mut warnings = Vec::new();
+        let since = parse_duration("30d").unwrap();
+        locked.clean(&ctx, Some(since), false, &mut warnings);
+        assert!(warnings.is_empty());
+        assert!(locked_file.exists());
+        assert!(!old_file.exists());
+        assert!(new_file.exists());
+    }
+
+    #[test]
+    fn locked_config_clean_orphans_only() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let used_dir = ctx.clone_dir().join("github.com/owner/used");
+        let orphan_dir = ctx.clone_dir().join("github.com/owner/deleted");
+        let stray_file = ctx.clone_dir().join("github.com/owner/stray.txt");
+        let stray_download = ctx.download_dir().join("stray.zsh");
+        let locked = LockedConfig {
+            plugins: vec![LockedPlugin::External(LockedExternalPlugin {
+                name: "used".to_string(),
+                source_dir: used_dir.clone(),
+                plugin_dir: None,
+                files: vec![used_dir.join("used.plugin.zsh")],
+                apply: vec_into!["source"],
+                local: false,
+                file: None,
+                checksum: None,
+                url: None,
+                revision: None,
+                reference: None,
+                fetched: None,
+                version: None,
+                templates: IndexMap::new(),
+                groups: Vec::new(),
+                tags: Vec::new(),
+            })],
+            ..LockedConfig::empty(&ctx, Shell::Zsh)
+        };
+        for dir in [&used_dir, &orphan_dir] {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(dir.join("test.txt"), "").unwrap();
+        }
+        fs::create_dir_all(stray_download.parent().unwrap()).unwrap();
+        for file in [&stray_file, &stray_download] {
+            fs::write(file, "").unwrap();
+        }
+
+        let mut warnings = Vec::new();
+        locked.clean(&ctx, None, true, &mut warnings);
+        assert!(warnings.is_empty());
+        assert!(used_dir.join("test.txt").exists());
+        assert!(!orphan_dir.exists());
+        assert!(stray_file.exists());
+        assert!(stray_download.exists());
+    }
+
+    #[test]
+    fn locked_config_clean_trash_and_gc() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let used_dir = ctx.clone_dir().join("github.com/owner/used");
+        let orphan_dir = ctx.clone_dir().join("github.com/owner/deleted");
+        let stray_download = ctx.download_dir().join("stray.zsh");
+        let locked = LockedConfig {
+            plugins: vec![LockedPlugin::External(LockedExternalPlugin {
+                name: "used".to_string(),
+                source_dir: used_dir.clone(),
+                plugin_dir: None,
+                files: vec![used_dir.join("used.plugin.zsh")],
+                apply: vec_into!["source"],
+                local: false,
+                file: None,
+                checksum: None,
+                url: None,
+                revision: None,
+                reference: None,
+                fetched: None,
+                version: None,
+                templates: IndexMap::new(),
+                groups: Vec::new(),
+                tags: Vec::new(),
+            })],
+            trash: true,
+            ..LockedConfig::empty(&ctx, Shell::Zsh)
+        };
+        for dir in [&used_dir, &orphan_dir] {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(dir.join("test.txt"), "").unwrap();
+        }
+        fs::create_dir_all(stray_download.parent().unwrap()).unwrap();
+        fs::write(&stray_download, "").unwrap();
+
+        let mut warnings = Vec::new();
+        locked.clean(&ctx, None, false, &mut warnings);
+        assert!(warnings.is_empty());
+        assert!(used_dir.join("test.txt").exists());
+        assert!(!orphan_dir.exists());
+        assert!(!stray_download.exists());
+
+        let trashed: Vec<_> = fs::read_dir(ctx.trash_dir())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(trashed.len(), 1);
+        assert!(trashed[0]
+            .join("repos/github.com/owner/deleted/test.txt")
+            .exists());
+        assert!(trashed[0].join("downloads/stray.zsh").exists());
+
+        assert!(empty_trash(&ctx).unwrap());
+        assert!(!ctx.trash_dir().exists());
+        assert!(!empty_trash(&ctx).unwrap());
+    }
+
+    #[test]
+    fn locked_config_clean_trash_twice() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let locked = LockedConfig {
+            trash: true,
+            ..LockedConfig::empty(&ctx, Shell::Zsh)
+        };
+        let stray_download = ctx.download_dir().join("stray.zsh");
+        fs::create_dir_all(ctx.download_dir()).unwrap();
+
+        // Cleans in quick succession, most likely within the same second, each
+        // get their own directory in the trash.
+        for contents in ["first", "second"] {
+            fs::write(&stray_download, contents).unwrap();
+            let mut warnings = Vec::new();
+            locked.clean(&ctx, None, false, &mut warnings);
+            assert!(warnings.is_empty());
+            assert!(!stray_download.exists());
+        }
+
+        let mut trashed: Vec<_> = fs::read_dir(ctx.trash_dir())
+            .unwrap()
+            .map(|entry| {
+                fs::read_to_string(entry.unwrap().path().join("downloads/stray.zsh")).unwrap()
+            })
+            .collect();
+        trashed.sort();
+        assert_eq!(trashed, ["first", "second"]);
+    }
+
+    #[test]
+    fn parse_duration_units() {
+        for (s, secs) in [
+            ("45s", 45),
+            ("90m", 90 * 60),
+            ("12h", 12 * 60 * 60),
+            ("30d", 30 * 24 * 60 * 60),
+            ("2w", 2 * 7 * 24 * 60 * 60),
+        ] {
+            assert_eq!(parse_duration(s).unwrap(), time::Duration::from_secs(secs));
+        }
+        for s in ["", "30", "d", "-1d", "1.5h", "30y"] {
+            assert_eq!(
+                parse_duration(s).unwrap_err().to_string(),
+                format!(
+                    "invalid duration `{}`, expected a number followed by one of `s`, `m`, \
+                     `h`, `d`, or `w`",
+                    s
+                )
+            );
+        }
+    }
+
+    fn local_config(dir: &Path) -> Config {
+        Config {
+            shell: Shell::Zsh,
+            matches: None,
+            apply: None,
+            templates: IndexMap::new(),
+            remove_templates: None,
+            clean_ignore: None,
+            footer: None,
+            dedupe_paths: None,
+            invalidate_compdump: None,
+            guard: None,
+            profile_startup: None,
+            trash: None,
+            require_clean: None,
+            normalize_line_endings: None,
+            manifest: None,
+            wrap_in_function: None,
+            clone_dir_layout: None,
+            active_profiles: None,
+            imports: Vec::new(),
+            plugins: vec![Plugin::External(ExternalPlugin {
+                name: "test".to_string(),
+                source: Source::Local {
+                    dir: dir.to_path_buf(),
+                },
+                dir: None,
+                uses: None,
+                apply: Some(vec_into!["fpath"]),
+                apply_matching: Vec::new(),
+                apply_globs: Vec::new(),
+                templates: IndexMap::new(),
+                profiles: None,
+                shell: None,
+                follow_symlinks: false,
+                follow_dependencies: false,
+                version_from: None,
+                match_shebang: None,
+                order: Vec::new(),
+                tags: Vec::new(),
+            })],
+            skipped: IndexMap::new(),
+        }
+    }
+
+    /// A config with a plugin that locks and a plugin that fails to lock.
+    fn partially_broken_config(root: &Path) -> Config {
+        let external = |name: &str, uses: Vec<String>| {
+            let dir = root.join(name);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("test.zsh"), "").unwrap();
+            Plugin::External(ExternalPlugin {
+                name: name.to_string(),
+                source: Source::Local { dir },
+                dir: None,
+                uses: Some(uses),
+                apply: None,
+                apply_matching: Vec::new(),
+                apply_globs: Vec::new(),
+                templates: IndexMap::new(),
+                profiles: None,
+                shell: None,
+                follow_symlinks: false,
+                follow_dependencies: false,
+                version_from: None,
+                match_shebang: None,
+                order: Vec::new(),
+                tags: Vec::new(),
+            })
+        };
+        Config {
+            plugins: vec![
+                external("ok", vec_into!["test.zsh"]),
+                external("broken", vec_into!["missing.zsh"]),
+            ],
+            ..local_config(root)
+        }
+    }
+
+    #[test]
+    fn lock_config_version_from() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        fs::write(temp.path().join("VERSION"), "1.2.3\n").unwrap();
+        let mut cfg = local_config(temp.path());
+        cfg.templates.insert(
+            "version".into(),
+            Template::from("# {{ name }} {{ version }}"),
+        );
+        if let Plugin::External(plugin) = &mut cfg.plugins[0] {
+            plugin.apply = Some(vec_into!["version"]);
+            plugin.version_from = Some("VERSION".into());
+        }
+
+        let locked = config(&ctx, cfg).unwrap();
+
+        assert!(locked.errors.is_empty());
+        assert_eq!(locked.script(&ctx, None, &[]).unwrap(), "# test 1.2.3\n");
+    }
+
+    #[test]
+    fn lock_config_remove_templates() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let mut cfg = local_config(temp.path());
+        cfg.remove_templates = Some(vec_into!["fpath"]);
+
+        let plan = plan(&ctx, cfg);
+        assert!(!plan.templates.contains_key("fpath"));
+        assert!(plan.templates.contains_key("PATH"));
+
+        let locked = plan.execute(&ctx).unwrap();
+        assert!(locked.plugins.is_empty());
+        assert_eq!(locked.errors.len(), 1);
+        assert_eq!(
+            format!("{:#}", locked.errors[0]),
+            "failed to install plugin `test`: unknown template `fpath`"
+        );
+    }
+
+    #[test]
+    fn lock_config_for_shell_bash() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        fs::write(temp.path().join("test.plugin.zsh"), "").unwrap();
+        fs::write(temp.path().join("test.bash"), "").unwrap();
+        let zsh_config = || {
+            let mut cfg = local_config(temp.path());
+            if let Plugin::External(plugin) = &mut cfg.plugins[0] {
+                plugin.apply = None;
+            }
+            cfg
+        };
+
+        let locked = config(&ctx, zsh_config()).unwrap();
+        assert_eq!(locked.shell, Shell::Zsh);
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            format!(
+                "source \"{}\"\n",
+                temp.path().join("test.plugin.zsh").display()
+            )
+        );
+
+        let locked = config_for_shell(&ctx, zsh_config(), Shell::Bash).unwrap();
+        assert_eq!(locked.shell, Shell::Bash);
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            format!("source \"{}\"\n", temp.path().join("test.bash").display())
+        );
+    }
+
+    #[test]
+    fn lock_config_for_shell_unavailable_template() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+
+        let err = config_for_shell(&ctx, local_config(temp.path()), Shell::Bash).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "template `fpath` is not available for shell `bash`"
+        );
+    }
+
+    #[test]
+    fn lock_config_for_shell_excludes_other_shell_plugins() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let mixed_config = || {
+            let mut cfg = local_config(temp.path());
+            if let Plugin::External(plugin) = &mut cfg.plugins[0] {
+                plugin.shell = Some(Shell::Zsh);
+            }
+            let inline = |name: &str, shell| {
+                Plugin::Inline(InlinePlugin {
+                    name: name.to_string(),
+                    raw: format!("echo '{}'", name),
+                    profiles: None,
+                    shell,
+                    tags: Vec::new(),
+                })
+            };
+            cfg.plugins.push(inline("zsh-only", Some(Shell::Zsh)));
+            cfg.plugins.push(inline("bash-only", Some(Shell::Bash)));
+            cfg.plugins.push(inline("both", None));
+            cfg
+        };
+        let names = |locked: &LockedConfig| -> Vec<String> {
+            locked
+                .plugins
+                .iter()
+                .map(|plugin| match plugin {
+                    LockedPlugin::External(plugin) => plugin.name.clone(),
+                    LockedPlugin::Inline(plugin) => plugin.name.clone(),
+                })
+                .collect()
+        };
+
+        let locked = config(&ctx, mixed_config()).unwrap();
+        assert_eq!(names(&locked), vec!["test", "zsh-only", "both"]);
+
+        let locked = config_for_shell(&ctx, mixed_config(), Shell::Bash).unwrap();
+        assert_eq!(names(&locked), vec!["bash-only", "both"]);
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "echo 'bash-only'\necho 'both'\n"
+        );
+    }
+
+    #[test]
+    fn lock_config_on_error_skip() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+
+        let locked = config(&ctx, partially_broken_config(temp.path())).unwrap();
+
+        assert_eq!(locked.plugins.len(), 1);
+        assert!(matches!(&locked.plugins[0], LockedPlugin::External(p) if p.name == "ok"));
+        assert_eq!(locked.errors.len(), 1);
+        assert_eq!(
+            locked.errors[0].to_string(),
+            "failed to install plugin `broken`"
+        );
+    }
+
+    #[test]
+    fn lock_config_on_error_abort() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let mut ctx = Context::testing(temp.path());
+        ctx.on_error = OnError::Abort;
+        // Install one source at a time, so that the sources after the broken
+        // one are deterministically not started.
+        ctx.jobs = Some(1);
+        let origin = temp.path().join("origin");
+        testing::git_commit(&origin, "test.zsh", "");
+        let git = |dir: &Path, clone_as: &str| Source::Git {
+            url: Url::from_directory_path(dir).unwrap(),
+            reference: None,
+            archive: false,
+            clone_as: Some(clone_as.into()),
+            no_history: false,
+            verify_signature: None,
+        };
+
+        let mut cfg = partially_broken_config(temp.path());
+        if let Plugin::External(plugin) = &mut cfg.plugins[1] {
+            plugin.source = git(&temp.path().join("missing"), "broken");
+        }
+        for name in ["later-a", "later-b"] {
+            cfg.plugins.push(Plugin::External(ExternalPlugin {
+                name: name.to_string(),
+                source: git(&origin, name),
+                dir: None,
+                uses: Some(vec_into!["test.zsh"]),
+                apply: None,
+                apply_matching: Vec::new(),
+                apply_globs: Vec::new(),
+                templates: IndexMap::new(),
+                profiles: None,
+                shell: None,
+                follow_symlinks: false,
+                follow_dependencies: false,
+                version_from: None,
+                match_shebang: None,
+                order: Vec::new(),
+                tags: Vec::new(),
+            }));
+        }
+
+        let err = config(&ctx, cfg).unwrap_err();
+
+        assert!(err.to_string().starts_with("failed to install source"));
+        assert!(!ctx.clone_dir().join("later-a").exists());
+        assert!(!ctx.clone_dir().join("later-b").exists());
+        assert!(!ctx.lock_file().exists());
+    }
+
+    #[test]
+    fn lock_config_duplicate_basenames() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let mut ctx = Context::testing(temp.path());
+        let other = temp.path().join("other");
+        fs::create_dir_all(&other).unwrap();
+        fs::write(other.join("test.zsh"), "").unwrap();
+        // A config where the second plugin uses the `test.zsh` file in `dir`.
+        let with_other = |dir: &str| {
+            let mut cfg = partially_broken_config(temp.path());
+            if let Plugin::External(plugin) = &mut cfg.plugins[1] {
+                plugin.name = "other".to_string();
+                plugin.source = Source::Local {
+                    dir: temp.path().join(dir),
+                };
+                plugin.uses = Some(vec_into!["test.zsh"]);
+            }
+            cfg
+        };
+        let expected = format!(
+            "file name `test.zsh` is matched by more than one plugin: `ok` ({}), `other` ({})",
+            temp.path().join("ok/test.zsh").display(),
+            temp.path().join("other/test.zsh").display()
+        );
+
+        // Plugins that match the same file do not conflict.
+        let locked = config(&ctx, with_other("ok")).unwrap();
+        assert!(locked.warnings.is_empty());
+
+        let locked = config(&ctx, with_other("other")).unwrap();
+        assert!(locked.errors.is_empty());
+        assert_eq!(locked.warnings.len(), 1);
+        assert_eq!(locked.warnings[0].to_string(), expected);
+
+        ctx.strict = true;
+        let locked = config(&ctx, with_other("other")).unwrap();
+        assert!(locked.warnings.is_empty());
+        assert_eq!(locked.errors.len(), 1);
+        assert_eq!(locked.errors[0].to_string(), expected);
+    }
+
+    #[test]
+    fn on_error_from_str() {
+        assert_eq!("skip".parse::<OnError>().unwrap(), OnError::Skip);
+        assert_eq!("abort".parse::<OnError>().unwrap(), OnError::Abort);
+        assert_eq!(
+            "stop".parse::<OnError>().unwrap_err().to_string(),
+            "expected `skip` or `abort`, got `stop`"
+        );
+    }
+
+    #[test]
+    fn locked_config_verify_local() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let functions = temp.path().join("functions");
+        fs::create_dir_all(&functions).unwrap();
+        fs::write(functions.join("_test"), "").unwrap();
+
+        let locked = config(&ctx, local_config(&functions)).unwrap();
+        assert!(locked.errors.is_empty());
+        assert!(locked.verify(&ctx));
+
+        fs::remove_dir_all(&functions).unwrap();
+        fs::write(&functions, "").unwrap();
+        assert!(!locked.verify(&ctx));
+    }
+
+    #[test]
+    fn locked_config_clean_local() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let functions = ctx.download_dir().join("functions");
+        let stale_dir = ctx.download_dir().join("github.com/stale");
+        for dir in [&functions, &stale_dir] {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(dir.join("_test"), "").unwrap();
+        }
+
+        let locked = config(&ctx, local_config(&functions)).unwrap();
+        assert!(locked.errors.is_empty());
+
+        let mut warnings = Vec::new();
+        locked.clean(&ctx, None, false, &mut warnings);
+        assert!(warnings.is_empty());
+        assert!(functions.join("_test").exists());
+        assert!(!stale_dir.exists());
+    }
+
+    #[test]
+    fn locked_config_prune() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let mut cfg = partially_broken_config(temp.path());
+        if let Plugin::External(plugin) = &mut cfg.plugins[1] {
+            plugin.name = "removed".into();
+            plugin.uses = Some(vec_into!["test.zsh"]);
+        }
+        let mut locked = config(&ctx, cfg).unwrap();
+        assert!(locked.errors.is_empty());
+        assert_eq!(locked.plugins.len(), 2);
+
+        let mut cfg = partially_broken_config(temp.path());
+        cfg.plugins.pop();
+        let plan = plan(&ctx, cfg);
+        assert_eq!(locked.prune(&plan.plugin_names()), vec!["removed"]);
+        assert!(locked.prune(&plan.plugin_names()).is_empty());
+
+        let expected = plan.execute(&ctx).unwrap();
+        assert_eq!(locked.plugins, expected.plugins);
+    }
+
+    #[test]
+    fn locked_config_plugin_revision() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let origin = temp.path().join("origin");
+        let oid = testing::git_commit(&origin, "_test", "");
+
+        let mut cfg = local_config(&origin);
+        if let Plugin::External(plugin) = &mut cfg.plugins[0] {
+            plugin.source = Source::Git {
+                url: Url::from_directory_path(&origin).unwrap(),
+                reference: None,
+                archive: false,
+                clone_as: Some("test".into()),
+                no_history: false,
+                verify_signature: None,
+            };
+            plugin.uses = Some(vec_into!["_test"]);
+        }
+        let locked = config(&ctx, cfg).unwrap();
+        assert!(locked.errors.is_empty());
+
+        assert!(locked.plugin("missing").is_none());
+        let plugin = locked.plugin("test").unwrap();
+        assert_eq!(plugin.name(), "test");
+        assert_eq!(plugin.files().len(), 1);
+        assert!(plugin.files()[0].ends_with("_test"));
+        match plugin {
+            LockedPlugin::External(plugin) => {
+                assert_eq!(plugin.revision(), Some(oid.to_string()));
+            }
+            LockedPlugin::Inline(_) => panic!("expected an external plugin"),
+        }
+    }
+
+    #[test]
+    fn locked_config_clone_as() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let origin = temp.path().join("origin");
+        testing::git_commit(&origin, "_test", "");
+        let stale_dir = ctx.clone_dir().join("github.com/stale");
+        fs::create_dir_all(&stale_dir).unwrap();
+
+        let mut cfg = local_config(&origin);
+        if let Plugin::External(plugin) = &mut cfg.plugins[0] {
+            plugin.source = Source::Git {
+                url: Url::from_directory_path(&origin).unwrap(),
+                reference: None,
+                archive: false,
+                clone_as: Some("short".into()),
+                no_history: false,
+                verify_signature: None,
+            };
+        }
+        let locked = config(&ctx, cfg).unwrap();
+        assert!(locked.errors.is_empty());
+        let short = ctx.clone_dir().join("short");
+        match &locked.plugins[0] {
+            LockedPlugin::External(plugin) => assert_eq!(plugin.source_dir, short),
+            plugin => panic!("unexpected plugin {:?}", plugin),
+        }
+        assert!(short.join("_test").exists());
+        assert!(locked.verify(&ctx));
+
+        let mut warnings = Vec::new();
+        locked.clean(&ctx, None, false, &mut warnings);
+        assert!(warnings.is_empty());
+        assert!(short.join("_test").exists());
+        assert!(!stale_dir.exists());
+
+        fs::remove_dir_all(&short).unwrap();
+        assert!(!locked.verify(&ctx));
+    }
+
+    #[test]
+    fn plan_install_skips_rendering() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let origin = temp.path().join("origin");
+        testing::git_commit(&origin, "_test", "");
+
+        let cfg = || {
+            let mut cfg = local_config(&origin);
+            if let Plugin::External(plugin) = &mut cfg.plugins[0] {
+                plugin.source = Source::Git {
+                    url: Url::from_directory_path(&origin).unwrap(),
+                    reference: None,
+                    archive: false,
+                    clone_as: Some("test".into()),
+                    no_history: false,
+                    verify_signature: None,
+                };
+                // The plugin directory fails to render.
+                plugin.dir = Some("{{ broken".into());
+            }
+            cfg
+        };
+        let errors = plan(&ctx, cfg()).install(&ctx).unwrap();
+        assert!(errors.is_empty());
+        assert!(ctx.clone_dir().join("test/_test").exists());
+
+        let locked = config(&ctx, cfg()).unwrap();
+        assert_eq!(
+            locked.errors[0].to_string(),
+            "failed to install plugin `test`"
+        );
+    }
+
+    #[test]
+    fn config_update_queries_each_remote_once() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let mut ctx = Context::testing(temp.path());
+        let origin = temp.path().join("origin");
+        let first = testing::git_commit(&origin, "first.zsh", "");
+        let repo = git2::Repository::open(&origin).unwrap();
+        let object = repo.find_object(first, None).unwrap();
+        repo.tag_lightweight("v1", &object, false).unwrap();
+
+        // Two plugins from the same repository at different references.
+        let cfg = || {
+            let mut cfg = local_config(&origin);
+            cfg.plugins.clear();
+            for (name, reference) in [
+                ("tagged", Some(GitReference::Tag("v1".into()))),
+                ("latest", None),
+            ] {
+                if let Plugin::External(plugin) = local_config(&origin).plugins.remove(0) {
+                    cfg.plugins.push(Plugin::External(ExternalPlugin {
+                        name: name.into(),
+                        source: Source::Git {
+                            url: Url::from_directory_path(&origin).unwrap(),
+                            reference,
+                            archive: false,
+                            clone_as: Some(name.into()),
+                            no_history: false,
+                            verify_signature: None,
+                        },
+                        ..plugin
+                    }));
+                }
+            }
+            cfg
+        };
+
+        let session = Session::default();
+        let locked = plan(&ctx, cfg()).execute_in(&ctx, &session).unwrap();
+        assert!(locked.errors.is_empty());
+        assert_eq!(session.remote_refs.queries(), 0);
+
+        ctx.lock_mode = Some(LockMode::Update);
+        let locked = plan(&ctx, cfg()).execute_in(&ctx, &session).unwrap();
+        assert!(locked.errors.is_empty());
+        assert_eq!(session.remote_refs.queries(), 1);
+
+        // A new commit upstream is still fetched in the next run.
+        let second = testing::git_commit(&origin, "second.zsh", "");
+        let session = Session::default();
+        let locked = plan(&ctx, cfg()).execute_in(&ctx, &session).unwrap();
+        assert!(locked.errors.is_empty());
+        assert_eq!(session.remote_refs.queries(), 1);
+        let revisions = revisions(&locked);
+        assert_eq!(
+            revisions[&ctx.clone_dir().join("tagged")],
+            first.to_string()
+        );
+        assert_eq!(
+            revisions[&ctx.clone_dir().join("latest")],
+            second.to_string()
+        );
+    }
+
+    #[test]
+    fn summary_counts_mixed_outcomes() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let mut ctx = Context::testing(temp.path());
+        let origin = temp.path().join("origin");
+        testing::git_commit(&origin, "test.plugin.zsh", "");
+        let local = temp.path().join("local");
+        fs::create_dir(&local).unwrap();
+
+        let base = || match local_config(&local).plugins.remove(0) {
+            Plugin::External(plugin) => plugin,
+            Plugin::Inline(_) => unreachable!(),
+        };
+        let cfg = || {
+            let mut cfg = local_config(&local);
+            cfg.plugins.push(Plugin::External(ExternalPlugin {
+                name: "cloned".into(),
+                source: Source::Git {
+                    url: Url::from_directory_path(&origin).unwrap(),
+                    reference: None,
+                    archive: false,
+                    clone_as: Some("cloned".into()),
+                    no_history: false,
+                    verify_signature: None,
+                },
+                ..base()
+            }));
+            cfg.plugins.push(Plugin::External(ExternalPlugin {
+                name: "missing".into(),
+                source: Source::Local {
+                    dir: temp.path().join("missing"),
+                },
+                ..base()
+            }));
+            cfg
+        };
+        let summarize = |ctx: &Context| {
+            let locked = plan(ctx, cfg()).execute(ctx).unwrap();
+            locked.to_path(ctx.lock_file()).unwrap();
+            Summary::new(&locked)
+        };
+
+        let summary = summarize(&ctx);
+        assert_eq!(
+            summary,
+            Summary {
+                plugins: 2,
+                installed: 1,
+                updated: 0,
+                errors: 1,
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            "2 plugins locked, 1 source installed, 0 updated, 1 error"
+        );
+
+        // Updating only counts the sources that moved to another commit.
+        ctx.lock_mode = Some(LockMode::Update);
+        assert_eq!(
+            summarize(&ctx),
+            Summary {
+                plugins: 2,
+                installed: 0,
+                updated: 0,
+                errors: 1,
+            }
+        );
+        testing::git_commit(&origin, "second.zsh", "");
+        assert_eq!(
+            summarize(&ctx),
+            Summary {
+                plugins: 2,
+                installed: 0,
+                updated: 1,
+                errors: 1,
+            }
+        );
+
+        // Reinstalling counts every source that was installed again.
+        ctx.lock_mode = Some(LockMode::Reinstall);
+        assert_eq!(
+            summarize(&ctx),
+            Summary {
+                plugins: 2,
+                installed: 1,
+                updated: 0,
+                errors: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn diff_lists_changed_revision_and_added_plugin() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let origin = temp.path().join("origin");
+        let first = testing::git_commit(&origin, "first.zsh", "");
+        let repo = git2::Repository::open(&origin).unwrap();
+        let object = repo.find_object(first, None).unwrap();
+        repo.tag_lightweight("v1", &object, false).unwrap();
+        let second = testing::git_commit(&origin, "second.zsh", "");
+
+        let cfg = |reference: Option<GitReference>| {
+            let mut cfg = local_config(&origin);
+            if let Plugin::External(plugin) = &mut cfg.plugins[0] {
+                plugin.source = Source::Git {
+                    url: Url::from_directory_path(&origin).unwrap(),
+                    reference,
+                    archive: false,
+                    clone_as: Some("test".into()),
+                    no_history: false,
+                    verify_signature: None,
+                };
+            }
+            cfg
+        };
+
+        let old = config(&ctx, cfg(Some(GitReference::Tag("v1".into())))).unwrap();
+        let revisions = revisions(&old);
+
+        let mut new_cfg = cfg(None);
+        let added = temp.path().join("added");
+        fs::create_dir(&added).unwrap();
+        if let Plugin::External(plugin) = local_config(&added).plugins.remove(0) {
+            new_cfg.plugins.push(Plugin::External(ExternalPlugin {
+                name: "added".into(),
+                ..plugin
+            }));
+        }
+        let plan = plan(&ctx, new_cfg);
+        let checkpoint = plan.checkpoint(&ctx, &revisions);
+        let new = plan.execute(&ctx).unwrap();
+
+        let changes = diff(&ctx, Some(&old), &revisions, &new);
+        assert!(changes.contains(&format!(
+            "~ test: revision {} -> {}",
+            &first.to_string()[..7],
+            &second.to_string()[..7]
+        )));
+        assert!(changes.contains(&"+ added".to_string()));
+        assert!(!changes.iter().any(|change| change.starts_with('-')));
+
+        // The clone is checked out at the old commit again if the new lock is
+        // not written.
+        checkpoint.restore(&ctx, &new).unwrap();
+        let clone = git2::Repository::open(ctx.clone_dir().join("test")).unwrap();
+        assert_eq!(clone.head().unwrap().target(), Some(first));
+    }
+
+    #[test]
+    fn config_migrates_clone_to_new_location() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let origin = temp.path().join("origin");
+        testing::git_commit(&origin, "test.plugin.zsh", "");
+
+        let cfg = |clone_as: &str| {
+            let mut cfg = local_config(&origin);
+            if let Plugin::External(plugin) = &mut cfg.plugins[0] {
+                plugin.source = Source::Git {
+                    url: Url::from_directory_path(&origin).unwrap(),
+                    reference: None,
+                    archive: false,
+                    clone_as: Some(clone_as.into()),
+                    no_history: false,
+                    verify_signature: None,
+                };
+            }
+            cfg
+        };
+        let old_dir = ctx.clone_dir().join("old");
+        let new_dir = ctx.clone_dir().join("new");
+
+        fs::create_dir_all(ctx.data_dir()).unwrap();
+        config(&ctx, cfg("old"))
+            .unwrap()
+            .to_path(ctx.lock_file())
+            .unwrap();
+        // A marker that would not survive cloning again.
+        fs::write(old_dir.join(".git/marker"), "").unwrap();
+
+        // The clone is locked where it is until the lock file is written.
+        let mut locked = config(&ctx, cfg("new")).unwrap();
+        assert!(old_dir.join(".git/marker").exists());
+        assert!(!new_dir.exists());
+        assert_eq!(locked.migrations, vec![(old_dir.clone(), new_dir.clone())]);
+        match &locked.plugins[0] {
+            LockedPlugin::External(plugin) => assert_eq!(plugin.source_dir, old_dir),
+            plugin => panic!("unexpected plugin {:?}", plugin),
+        }
+
+        locked.migrate(&ctx);
+        assert!(!old_dir.exists());
+        assert!(new_dir.join(".git/marker").exists());
+        match &locked.plugins[0] {
+            LockedPlugin::External(plugin) => {
+                assert_eq!(plugin.source_dir, new_dir);
+                assert!(plugin.files.iter().all(|file| file.starts_with(&new_dir)));
+            }
+            plugin => panic!("unexpected plugin {:?}", plugin),
+        }
+    }
+
+    /// A local plugin that follows the dependencies in its source directory.
+    fn dependent_config(dir: &Path) -> Config {
+        let mut config = local_config(dir);
+        if let Plugin::External(plugin) = &mut config.plugins[0] {
+            plugin.name = "a".to_string();
+            plugin.apply = None;
+            plugin.follow_dependencies = true;
+        }
+        config
+    }
+
+    #[test]
+    fn locked_config_follow_dependencies() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let (a, b, c) = (
+            temp.path().join("a"),
+            temp.path().join("b"),
+            temp.path().join("c"),
+        );
+        for (dir, name) in [(&a, "a"), (&b, "b"), (&c, "c")] {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(dir.join(format!("{}.plugin.zsh", name)), "").unwrap();
+        }
+        fs::write(
+            a.join(DEPENDENCIES_FILE),
+            format!(
+                "[plugins.b]\nlocal = '{}'\nfollow_dependencies = true\n",
+                b.display()
+            ),
+        )
+        .unwrap();
+        fs::write(
+            b.join(DEPENDENCIES_FILE),
+            format!("[plugins.c]\nlocal = '{}'\n", c.display()),
+        )
+        .unwrap();
+
+        let locked = config(&ctx, dependent_config(&a)).unwrap();
+        assert!(locked.errors.is_empty());
+        let names: Vec<_> = locked
+            .plugins
+            .iter()
+            .map(|plugin| match plugin {
+                LockedPlugin::External(plugin) => plugin.name.as_str(),
+                LockedPlugin::Inline(plugin) => plugin.name.as_str(),
+            })
+            .collect();
+        assert_eq!(names, ["c", "b", "a"]);
+    }
+
+    #[test]
+    fn locked_config_follow_dependencies_cycle() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let a = temp.path().join("a");
+        fs::create_dir_all(&a).unwrap();
+        fs::write(a.join("a.plugin.zsh"), "").unwrap();
+        fs::write(
+            a.join(DEPENDENCIES_FILE),
+            format!(
+                "[plugins.a]\nlocal = '{}'\nfollow_dependencies = true\n",
+                a.display()
+            ),
+        )
+        .unwrap();
+
+        let locked = config(&ctx, dependent_config(&a)).unwrap();
+        assert_eq!(locked.plugins.len(), 1);
+        assert_eq!(locked.errors.len(), 1);
+        assert_eq!(
+            locked.errors[0].root_cause().to_string(),
+            format!("dependency cycle detected for source `{}`", a.display())
+        );
+    }
+
+    #[test]
+    fn locked_config_follow_dependencies_shared_and_colliding() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let (a, b, c, d) = (
+            temp.path().join("a"),
+            temp.path().join("b"),
+            temp.path().join("c"),
+            temp.path().join("d"),
+        );
+        for (dir, name) in [(&a, "a"), (&b, "b"), (&c, "c"), (&d, "d")] {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(dir.join(format!("{}.plugin.zsh", name)), "").unwrap();
+        }
+        // Both `a` and `b` depend on `c`, and `b` also depends on a plugin
+        // with the same name as `a` but a different source.
+        fs::write(
+            a.join(DEPENDENCIES_FILE),
+            format!("[plugins.c]\nlocal = '{}'\n", c.display()),
+        )
+        .unwrap();
+        fs::write(
+            b.join(DEPENDENCIES_FILE),
+            format!(
+                "[plugins.c]\nlocal = '{}'\n\n[plugins.a]\nlocal = '{}'\n",
+                c.display(),
+                d.display()
+            ),
+        )
+        .unwrap();
+        let mut cfg = dependent_config(&a);
+        if let Plugin::External(plugin) = dependent_config(&b).plugins.remove(0) {
+            cfg.plugins.push(Plugin::External(ExternalPlugin {
+                name: "b".into(),
+                ..plugin
+            }));
+        }
+
+        let locked = config(&ctx, cfg).unwrap();
+        let names: Vec<_> = locked.plugins.iter().map(LockedPlugin::name).collect();
+        assert_eq!(names, ["c", "a", "b"]);
+        assert_eq!(locked.errors.len(), 1);
+        assert_eq!(
+            locked.errors[0].root_cause().to_string(),
+            "dependency `a` has the same name as another plugin"
+        );
+    }
+
+    #[test]
+    fn locked_config_from_path_invalid_utf8() {
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(b"version = \"0.6.6\"\nhome = \"\xff\xfe\"\n")
+            .unwrap();
+        let err = from_path(temp.path()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "locked config contents are not valid UTF-8"
+        );
+    }
+
+    #[test]
+    fn locked_config_to_and_from_path() {
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        let content = r#"version = "<version>"
+home = "<home>"
+config_dir = "<config>"
+data_dir = "<data>"
+config_file = "<config>/plugins.toml"
+lock_file = "<data>/plugins.lock"
+clone_dir = "<data>/repos"
+download_dir = "<data>/downloads"
+shell = "zsh"
+plugins = []
+
+[templates]
+"#;
+        temp.write_all(content.as_bytes()).unwrap();
+        let locked_config = from_path(temp.into_temp_path()).unwrap();
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        let path = temp.into_temp_path();
+        locked_config.to_path(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), content);
+    }
+
+    #[test]
+    fn locked_config_to_and_from_path_with_groups() {
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        let content = r#"version = "<version>"
+home = "<home>"
+config_dir = "<config>"
+data_dir = "<data>"
+config_file = "<config>/plugins.toml"
+lock_file = "<data>/plugins.lock"
+clone_dir = "<data>/repos"
+download_dir = "<data>/downloads"
+shell = "zsh"
+
+[[plugins]]
+name = "test"
+source_dir = "<data>/repos/test"
+files = ["<data>/repos/test/test.plugin.zsh", "<data>/repos/test/_test"]
+apply = ["source"]
+
+[[plugins.groups]]
+files = ["<data>/repos/test/_test"]
+apply = ["fpath"]
+
+[templates]
+"#;
+        temp.write_all(content.as_bytes()).unwrap();
+        let locked_config = from_path(temp.into_temp_path()).unwrap();
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        let path = temp.into_temp_path();
+        locked_config.to_path(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), content);
+    }
+
+    #[test]
+    fn locked_config_to_and_from_path_json() {
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        let content = r#"version = "<version>"
+home = "<home>"
+config_dir = "<config>"
+data_dir = "<data>"
+config_file = "<config>/plugins.toml"
+lock_file = "<data>/plugins.lock"
+clone_dir = "<data>/repos"
+download_dir = "<data>/downloads"
+shell = "zsh"
+dedupe_paths = true
+
+[[plugins]]
+name = "test"
+source_dir = "<data>/repos/test"
+files = ["<data>/repos/test/test.plugin.zsh", "<data>/repos/test/_test"]
+apply = ["source"]
+
+[[plugins.groups]]
+files = ["<data>/repos/test/_test"]
+apply = ["fpath"]
+
+[[plugins]]
+name = "inline"
+raw = "echo 'inline'"
+
+[templates.source]
+value = "source \"{{ file }}\""
+each = true
+"#;
+        temp.write_all(content.as_bytes()).unwrap();
+        let locked_config = from_path(temp.into_temp_path()).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let json = dir.path().join("plugins.lock.json");
+        locked_config.to_path(&json).unwrap();
+        assert!(fs::read_to_string(&json).unwrap().starts_with('{'));
+        let from_json = from_path(&json).unwrap();
+
+        let toml = dir.path().join("plugins.lock");
+        locked_config.to_path(&toml).unwrap();
+        let toml_from_json = dir.path().join("plugins-from-json.lock");
+        from_json.to_path(&toml_from_json).unwrap();
+        assert_eq!(
+            fs::read_to_string(&toml_from_json).unwrap(),
+            fs::read_to_string(&toml).unwrap()
+        );
+        assert_eq!(from_json.plugins, locked_config.plugins);
+        assert_eq!(from_json.templates, locked_config.templates);
+    }
+
+    #[test]
+    fn locked_config_to_string_for_matches_written_file() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let mut locked = LockedConfig::empty(&ctx, Shell::Zsh);
+        locked.plugins.push(LockedPlugin::Inline(InlinePlugin {
+            name: "inline".into(),
+            raw: "echo 'inline'".into(),
+            shell: None,
+            profiles: None,
+            tags: Vec::new(),
+        }));
+
+        for name in ["plugins.lock", "plugins.lock.json"] {
+            let path = temp.path().join(name);
+            let printed = locked.to_string_for(&path).unwrap();
+            assert!(!path.exists());
+            locked.to_path(&path).unwrap();
+            assert_eq!(printed, fs::read_to_string(&path).unwrap());
+        }
+    }
+
+    #[test]
+    fn lock_backup_and_restore() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let path = ctx.lock_file();
+        let backup_file = temp.path().join("config.lock.bak");
+        assert_eq!(backup(path).unwrap(), None);
+
+        let mut locked = LockedConfig::empty(&ctx, Shell::Zsh);
+        locked.to_path(path).unwrap();
+        let previous = fs::read_to_string(path).unwrap();
+        assert_eq!(backup(path).unwrap(), Some(backup_file.clone()));
+        locked.footer = Some("echo 'footer'".into());
+        locked.to_path(path).unwrap();
+        let current = fs::read_to_string(path).unwrap();
+        assert_eq!(fs::read_to_string(&backup_file).unwrap(), previous);
+
+        restore(path).unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), previous);
+        assert_eq!(fs::read_to_string(&backup_file).unwrap(), current);
+
+        restore(path).unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), current);
+        assert_eq!(fs::read_to_string(&backup_file).unwrap(), previous);
+    }
+
+    #[test]
+    fn lock_cached_script() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let path = ctx.lock_file();
+
+        let mut locked = LockedConfig::empty(&ctx, Shell::Zsh);
+        locked.footer = Some("echo 'first'".into());
+        locked.to_path(path).unwrap();
+        assert_eq!(cached_script(path), None);
+
+        // An unchanged lock file uses the cached script.
+        let script = locked.script(&ctx, None, &[]).unwrap();
+        cache_script(path, &script).unwrap();
+        assert_eq!(cached_script(path), Some(script));
+
+        // A changed lock file must be rendered again.
+        locked.footer = Some("echo 'second'".into());
+        locked.to_path(path).unwrap();
+        assert_eq!(cached_script(path), None);
+        let script = locked.script(&ctx, None, &[]).unwrap();
+        assert!(script.contains("echo 'second'"));
+        cache_script(path, &script).unwrap();
+        assert_eq!(cached_script(path), Some(script));
+    }
+
+    #[test]
+    fn locked_config_is_not_cacheable_with_secrets() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let mut locked = LockedConfig::empty(&ctx, Shell::Zsh);
+        assert!(locked.is_cacheable());
+        locked.templates.insert(
+            "token".into(),
+            Template::from("export TOKEN=\"{{ secret \"token\" }}\""),
+        );
+        assert!(!locked.is_cacheable());
+    }
+
+    #[test]
+    fn locked_config_is_not_cacheable_with_plugin_secrets() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let mut locked = LockedConfig::empty(&ctx, Shell::Zsh);
+        locked
+            .plugins
+            .push(LockedPlugin::External(LockedExternalPlugin {
+                name: "test".to_string(),
+                source_dir: temp.path().to_path_buf(),
+                plugin_dir: None,
+                files: Vec::new(),
+                apply: vec_into!["token"],
+                local: true,
+                file: None,
+                checksum: None,
+                url: None,
+                revision: None,
+                reference: None,
+                fetched: None,
+                version: None,
+                tags: Vec::new(),
+                templates: indexmap! {
+                    "token".into() => Template::from("export TOKEN=\"{{ secret \"token\" }}\""),
+                },
+                groups: Vec::new(),
+            }));
+        assert!(!locked.is_cacheable());
+    }
+
+    #[test]
+    fn lock_restore_invalid_backup() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let path = ctx.lock_file();
+
+        let err = restore(path).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "no backup of the lock file found at `{}`",
+                temp.path().join("config.lock.bak").display()
+            )
+        );
+
+        LockedConfig::empty(&ctx, Shell::Zsh).to_path(path).unwrap();
+        let contents = fs::read_to_string(path).unwrap();
+        fs::write(temp.path().join("config.lock.bak"), "not a lock file").unwrap();
+        let err = restore(path).unwrap_err();
+        assert_eq!(err.to_string(), "failed to read the lock file backup");
+        assert_eq!(fs::read_to_string(path).unwrap(), contents);
     }
 }
@@ -1,17 +1,30 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read};
+use std::iter;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use anyhow::{bail, Context as ResultExt, Result};
+use anyhow::{anyhow, bail, Context as ResultExt, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 use indexmap::IndexMap;
 use maplit::hashmap;
+use walkdir::WalkDir;
 
-use crate::config::{ExternalPlugin, Source, Template};
+use crate::config::{ExternalPlugin, Shell, Source, Template};
 use crate::context::Context;
-use crate::lock::file::LockedExternalPlugin;
+use crate::lock::file::{LockedExternalPlugin, LockedFileGroup};
+use crate::lock::render_remote_url;
 use crate::lock::source::LockedSource;
+use crate::util::TempPath;
+
+/// The variables that are always available when rendering a template.
+const VARIABLES: &[&str] = &["data_dir", "name", "dir", "index", "shell"];
 
 /// Consume the [`ExternalPlugin`] and convert it to a [`LockedExternalPlugin`].
 pub fn lock(
     ctx: &Context,
+    shell: Shell,
     templates: &IndexMap<String, Template>,
     locked_source: LockedSource,
     global_matches: &[String],
@@ -24,19 +37,94 @@ pub fn lock(
         dir,
         uses,
         apply,
+        apply_matching,
+        apply_globs,
+        templates: plugin_templates,
         profiles: _,
+        shell: _,
+        follow_symlinks,
+        follow_dependencies: _,
+        version_from,
+        match_shebang,
+        order,
+        tags,
     } = plugin;
 
-    let apply = apply.unwrap_or_else(|| global_apply.to_vec());
+    // The plugin's own templates take precedence over the global templates.
+    let merged;
+    let templates = if plugin_templates.is_empty() {
+        templates
+    } else {
+        merged = templates
+            .iter()
+            .chain(&plugin_templates)
+            .map(|(name, template)| (name.clone(), template.clone()))
+            .collect();
+        &merged
+    };
+
+    let enabled = |name: &String| {
+        templates.get(name).is_none_or(|t| {
+            t.when
+                .as_ref()
+                .is_none_or(|w| w.matches(shell, std::env::consts::OS))
+        })
+    };
+    let apply: Vec<_> = apply
+        .unwrap_or_else(|| global_apply.to_vec())
+        .into_iter()
+        .filter(enabled)
+        .collect();
+    let apply_matching: Vec<_> = apply_matching
+        .into_iter()
+        .map(|(pattern, apply)| (pattern, apply.into_iter().filter(enabled).collect()))
+        .collect();
+    // Without `use`, the patterns of an `apply` table pick which files to use.
+    let uses = uses.or_else(|| {
+        (!apply_globs.is_empty()).then(|| {
+            apply_globs
+                .iter()
+                .map(|(_, pattern)| pattern.clone())
+                .collect()
+        })
+    });
+    let apply_globs: Vec<_> = apply_globs
+        .into_iter()
+        .filter(|(name, _)| enabled(name))
+        .collect();
+    let local = matches!(source, Source::Local { .. });
 
-    Ok(if let Source::Remote { .. } = source {
-        let LockedSource { dir, file } = locked_source;
+    let mut locked = if let Source::Remote {
+        url,
+        version,
+        checksum,
+        ..
+    } = source
+    {
+        let LockedSource { dir, file, .. } = locked_source;
+        let file = file.unwrap();
+        // Record the resolved URL when it depends on the version.
+        let url = match version {
+            Some(version) => Some(render_remote_url(&url, Some(&version))?),
+            None => None,
+        };
         LockedExternalPlugin {
             name,
             source_dir: dir,
             plugin_dir: None,
-            files: vec![file.unwrap()],
+            files: vec![file.clone()],
             apply,
+            local,
+            file: Some(file),
+            checksum,
+            url,
+            revision: None,
+            reference: None,
+            fetched: None,
+            version: None,
+            tags,
+            templates: plugin_templates,
+            groups: Vec::new(),
         }
     } else {
         // Handlebars instance to do the rendering
@@ -44,15 +132,23 @@ pub fn lock(
         hbs.set_strict_mode(true);
 
         // Data to use in template rendering
+        let shell = shell.to_string();
         let mut data = hashmap! {
             "data_dir" => ctx
                 .data_dir()
                 .to_str()
                 .context("data directory is not valid UTF-8")?,
-            "name" => &name
+            "name" => &name,
+            "shell" => &shell
         };
 
-        let source_dir = locked_source.dir;
+        let LockedSource {
+            dir: source_dir,
+            revision,
+            reference,
+            fetched,
+            ..
+        } = locked_source;
         let plugin_dir = if let Some(dir) = dir {
             let rendered = hbs
                 .render_template(&dir, &data)
@@ -78,19 +174,38 @@ pub fn lock(
                         .with_context(s!("failed to render template `{}`", u))
                 })
                 .collect::<Result<Vec<_>>>()?;
-            if !match_globs(dir, &patterns, &mut files)? {
+            let mut matched = match_globs(dir, &patterns, follow_symlinks, &mut files)?;
+            if let Some(prefix) = &match_shebang {
+                matched |= match_shebangs(dir, prefix, follow_symlinks, &mut files)?;
+            }
+            if !matched {
                 bail!("failed to find any files matching any of `{:?}`", patterns);
             }
         // Otherwise we try to figure out which files to use...
         } else {
-            for g in global_matches {
-                let pattern = hbs
-                    .render_template(g, &data)
-                    .with_context(s!("failed to render template `{}`", g))?;
-                if match_globs(dir, &[pattern], &mut files)? {
+            let patterns = global_matches
+                .iter()
+                .map(|g| {
+                    hbs.render_template(g, &data)
+                        .with_context(s!("failed to render template `{}`", g))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for (i, pattern) in patterns.iter().enumerate() {
+                if pattern.starts_with('!') {
+                    continue;
+                }
+                // Exclusions apply to all the patterns before them.
+                let patterns: Vec<_> = iter::once(pattern)
+                    .chain(patterns[i + 1..].iter().filter(|p| p.starts_with('!')))
+                    .cloned()
+                    .collect();
+                if match_globs(dir, &patterns, follow_symlinks, &mut files)? {
                     break;
                 }
             }
+            if let Some(prefix) = &match_shebang {
+                match_shebangs(dir, prefix, follow_symlinks, &mut files)?;
+            }
             if files.is_empty()
                 && templates
                     .iter()
@@ -100,17 +215,318 @@ pub fn lock(
             }
         }
 
+        order_files(dir, &order, &mut files)?;
+
         LockedExternalPlugin {
             name,
             source_dir,
             plugin_dir,
             files,
             apply,
+            local,
+            file: None,
+            checksum: None,
+            url: None,
+            revision,
+            reference,
+            fetched,
+            version: None,
+            tags,
+            templates: plugin_templates,
+            groups: Vec::new(),
         }
-    })
+    };
+    locked.groups = group_files(&locked, &apply_matching)?;
+    locked
+        .groups
+        .extend(group_files_by_template(&locked, &apply_globs)?);
+    if let Some(version_from) = &version_from {
+        locked.version = Some(
+            read_version(locked.dir(), version_from).unwrap_or_else(|err| {
+                error_w!(
+                    ctx,
+                    &err.context(format!("failed to read version of `{}`", locked.name))
+                );
+                String::new()
+            }),
+        );
+    }
+    check_applied_templates(&locked, templates)?;
+
+    for (path, names) in applied_templates(&locked, templates) {
+        status_v!(
+            ctx,
+            "Applied",
+            &format!(
+                "{}: {} to {}",
+                locked.name,
+                names.join(", "),
+                ctx.replace_home(path).display()
+            )
+        );
+    }
+
+    Ok(locked)
 }
 
-fn match_globs(dir: &Path, patterns: &[String], files: &mut Vec<PathBuf>) -> Result<bool> {
+/// Check the matched files of a locked plugin for CRLF line endings.
+///
+/// If `normalize` is set then the files are rewritten with LF line endings,
+/// otherwise a warning is logged for each file. Only files that Sheldon owns
+/// are rewritten, the files of Local sources, of Remote sources with a
+/// checksum, and of Git clones, which would then have uncommitted changes, only
+/// get a warning. Binary files, which contain a NUL byte, are skipped.
+pub fn check_line_endings(
+    ctx: &Context,
+    locked: &LockedExternalPlugin,
+    normalize: bool,
+) -> Result<()> {
+    let owned =
+        !locked.local && locked.checksum.is_none() && !locked.source_dir.join(".git").exists();
+    for file in locked.files.iter().filter(|f| f.is_file()) {
+        let contents =
+            fs::read(file).with_context(s!("failed to read from `{}`", file.display()))?;
+        if contents.contains(&0) || !contents.windows(2).any(|w| w == b"\r\n") {
+            continue;
+        }
+        if normalize && owned {
+            let mut normalized = Vec::with_capacity(contents.len());
+            for (i, byte) in contents.iter().enumerate() {
+                if !(*byte == b'\r' && contents.get(i + 1) == Some(&b'\n')) {
+                    normalized.push(*byte);
+                }
+            }
+            // Plugins that share a source can match the same file, so it is
+            // replaced by renaming a temporary file of its own over it.
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+            let mut name = OsString::from(format!("~{}.", id));
+            name.push(file.file_name().unwrap());
+            let temp = TempPath::new_unchecked(file.with_file_name(name));
+            fs::write(temp.path(), normalized)
+                .with_context(s!("failed to write to `{}`", temp.path().display()))?;
+            fs::rename(temp.path(), file)
+                .with_context(s!("failed to write to `{}`", file.display()))?;
+            status_v!(ctx, "Normalized", file.as_path());
+        } else {
+            error_w!(
+                ctx,
+                &anyhow!(
+                    "`{}` in plugin `{}` has CRLF line endings",
+                    ctx.replace_home(file).display(),
+                    locked.name
+                )
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Move the files listed in `order`, relative to the plugin directory, to the
+/// front in the given order.
+///
+/// The other files keep the order they were matched in. Every listed file must
+/// be one of the matched files.
+fn order_files(dir: &Path, order: &[String], files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut ordered = Vec::with_capacity(files.len());
+    for relative in order {
+        let path = dir.join(relative);
+        match files.iter().position(|file| *file == path) {
+            Some(i) => ordered.push(files.remove(i)),
+            None => bail!(
+                "file `{}` in the `order` field is not one of the matched files",
+                relative
+            ),
+        }
+    }
+    ordered.append(files);
+    *files = ordered;
+    Ok(())
+}
+
+/// Split up the plugin's files by the first `apply_matching` pattern that
+/// matches them.
+///
+/// The patterns are matched against the path relative to the plugin directory.
+fn group_files(
+    plugin: &LockedExternalPlugin,
+    apply_matching: &[(String, Vec<String>)],
+) -> Result<Vec<LockedFileGroup>> {
+    let mut groups: Vec<_> = Vec::with_capacity(apply_matching.len());
+    for (pattern, apply) in apply_matching {
+        groups.push((
+            glob_set("apply_matching", pattern)?,
+            LockedFileGroup {
+                files: Vec::new(),
+                apply: apply.clone(),
+            },
+        ));
+    }
+
+    for file in &plugin.files {
+        let relative = file.strip_prefix(plugin.dir()).unwrap_or(file);
+        if let Some((_, group)) = groups.iter_mut().find(|(set, _)| set.is_match(relative)) {
+            group.files.push(file.clone());
+        }
+    }
+
+    Ok(groups
+        .into_iter()
+        .map(|(_, group)| group)
+        .filter(|group| !group.files.is_empty())
+        .collect())
+}
+
+/// Split up the plugin's files by the templates of an `apply` table whose
+/// patterns match them.
+///
+/// Unlike `apply_matching`, each file gets every template with a pattern that
+/// matches it, and files that get the same templates share a group.
+fn group_files_by_template(
+    plugin: &LockedExternalPlugin,
+    apply_globs: &[(String, String)],
+) -> Result<Vec<LockedFileGroup>> {
+    let sets = apply_globs
+        .iter()
+        .map(|(name, pattern)| Ok((name, glob_set("apply", pattern)?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut groups: IndexMap<Vec<String>, Vec<PathBuf>> = IndexMap::new();
+    for file in &plugin.files {
+        let relative = file.strip_prefix(plugin.dir()).unwrap_or(file);
+        let apply: Vec<_> = sets
+            .iter()
+            .filter(|(_, set)| set.is_match(relative))
+            .map(|(name, _)| name.to_string())
+            .collect();
+        if !apply.is_empty() {
+            groups.entry(apply).or_default().push(file.clone());
+        }
+    }
+
+    Ok(groups
+        .into_iter()
+        .map(|(apply, files)| LockedFileGroup { files, apply })
+        .collect())
+}
+
+/// Build a glob set from a pattern in the given field, expanding any braces.
+fn glob_set(field: &str, pattern: &str) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for p in expand_braces(pattern) {
+        builder.add(
+            GlobBuilder::new(&p)
+                .literal_separator(true)
+                .build()
+                .with_context(s!("failed to parse `{}` pattern `{}`", field, pattern))?,
+        );
+    }
+    builder
+        .build()
+        .with_context(s!("failed to build `{}` patterns", field))
+}
+
+/// Read a plugin's version from the given file in the plugin directory.
+///
+/// If the file is followed by a `#` and a dotted key, then the file is parsed
+/// as JSON and the version is the value at that key.
+fn read_version(dir: &Path, version_from: &str) -> Result<String> {
+    let (file, key) = match version_from.split_once('#') {
+        Some((file, key)) => (file, Some(key)),
+        None => (version_from, None),
+    };
+    let path = dir.join(file);
+    let contents =
+        fs::read_to_string(&path).with_context(s!("failed to read from `{}`", path.display()))?;
+    let key = match key {
+        Some(key) => key,
+        None => return Ok(contents.trim().to_string()),
+    };
+    let json: serde_json::Value = serde_json::from_str(&contents)
+        .with_context(s!("failed to parse `{}` as JSON", path.display()))?;
+    let value = key
+        .split('.')
+        .try_fold(&json, |value, k| value.get(k))
+        .with_context(s!("failed to find `{}` in `{}`", key, path.display()))?;
+    match value {
+        serde_json::Value::String(s) => Ok(s.clone()),
+        serde_json::Value::Number(n) => Ok(n.to_string()),
+        _ => bail!("expected `{}` in `{}` to be a string", key, path.display()),
+    }
+}
+
+/// Check that each applied template exists and that the variables it requires
+/// will be available when it is rendered.
+///
+/// The `file` variable is only available to templates with `each` set, and the
+/// `version` variable only to plugins that have a version.
+fn check_applied_templates(
+    plugin: &LockedExternalPlugin,
+    templates: &IndexMap<String, Template>,
+) -> Result<()> {
+    for bucket in plugin.buckets() {
+        for name in bucket.apply {
+            let template = match templates.get(name) {
+                Some(template) => template,
+                None => bail!("unknown template `{}`", name),
+            };
+            for var in &template.requires {
+                let available = match var.as_str() {
+                    "file" => template.each,
+                    "version" => plugin.version.as_deref().is_some_and(|v| !v.is_empty()),
+                    var => VARIABLES.contains(&var),
+                };
+                if !available {
+                    bail!(
+                        "template `{}` requires variable `{}` which is not available for plugin \
+                         `{}`",
+                        name,
+                        var,
+                        plugin.name
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns each file, or directory, and the names of the templates that will
+/// be applied to it.
+///
+/// Templates with `each` set are applied to each file, the rest are applied to
+/// the directories.
+fn applied_templates<'a>(
+    plugin: &'a LockedExternalPlugin,
+    templates: &IndexMap<String, Template>,
+) -> Vec<(&'a Path, Vec<&'a str>)> {
+    let mut applied = Vec::new();
+    for bucket in plugin.buckets() {
+        let (each, once): (Vec<_>, Vec<_>) = bucket
+            .apply
+            .iter()
+            .map(String::as_str)
+            .partition(|name| matches!(templates.get(*name), Some(t) if t.each));
+        if !once.is_empty() {
+            for dir in bucket.dirs {
+                applied.push((dir, once.clone()));
+            }
+        }
+        if !each.is_empty() {
+            for file in bucket.files {
+                applied.push((file, each.clone()));
+            }
+        }
+    }
+    applied
+}
+
+fn match_globs(
+    dir: &Path,
+    patterns: &[String],
+    follow_symlinks: bool,
+    files: &mut Vec<PathBuf>,
+) -> Result<bool> {
     let debug = || {
         patterns
             .iter()
@@ -118,13 +534,20 @@ fn match_globs(dir: &Path, patterns: &[String], files: &mut Vec<PathBuf>) -> Res
             .collect::<Vec<_>>()
             .join(", ")
     };
+    let patterns: Vec<_> = patterns.iter().flat_map(|p| expand_braces(p)).collect();
     let mut matched = false;
-    for entry in globwalk::GlobWalkerBuilder::from_patterns(dir, patterns)
+    for entry in globwalk::GlobWalkerBuilder::from_patterns(dir, &patterns)
         .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .follow_links(follow_symlinks)
         .build()
         .with_context(s!("failed to parse glob patterns: {}", debug()))?
     {
-        let entry = entry.with_context(s!("failed to match patterns: {}", debug()))?;
+        // When following symlinks a symlink cycle is reported as an error, we
+        // simply don't descend into it.
+        let entry = match entry {
+            Err(err) if err.loop_ancestor().is_some() => continue,
+            entry => entry.with_context(s!("failed to match patterns: {}", debug()))?,
+        };
         if entry.metadata()?.file_type().is_symlink() {
             entry
                 .path()
@@ -138,6 +561,108 @@ fn match_globs(dir: &Path, patterns: &[String], files: &mut Vec<PathBuf>) -> Res
     Ok(matched)
 }
 
+/// Match the files in the directory, and its subdirectories, whose first line
+/// starts with the given prefix, for example `#compdef`.
+///
+/// Git directories are not searched, and files that were already matched are
+/// skipped.
+fn match_shebangs(
+    dir: &Path,
+    prefix: &str,
+    follow_symlinks: bool,
+    files: &mut Vec<PathBuf>,
+) -> Result<bool> {
+    let mut matched = false;
+    let walker = WalkDir::new(dir)
+        .follow_links(follow_symlinks)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| entry.depth() == 0 || entry.file_name() != ".git");
+    for entry in walker {
+        // When following symlinks a symlink cycle is reported as an error, we
+        // simply don't descend into it.
+        let entry = match entry {
+            Err(err) if err.loop_ancestor().is_some() => continue,
+            entry => entry.with_context(s!("failed to read directory `{}`", dir.display()))?,
+        };
+        let path = entry.path();
+        if !path.is_file() || files.iter().any(|file| file == path) {
+            continue;
+        }
+        // Only the start of the first line needs to be read, files that are
+        // shorter than the prefix can not match.
+        let mut start = vec![0; prefix.len()];
+        let result = fs::File::open(path).and_then(|mut file| file.read_exact(&mut start));
+        match result {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => continue,
+            result => result.with_context(s!("failed to read from `{}`", path.display()))?,
+        }
+        if start == prefix.as_bytes() {
+            files.push(entry.into_path());
+            matched = true;
+        }
+    }
+    Ok(matched)
+}
+
+/// Expand the brace groups in a glob pattern into multiple patterns.
+///
+/// For example `*.{zsh,sh}` is expanded to `*.zsh` and `*.sh`. Groups can be
+/// nested and braces escaped with a backslash are left as is, so that they
+/// are matched literally.
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let mut escaped = false;
+    for (open, c) in pattern.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '{' => {
+                if let Some((close, commas)) = find_brace_group(pattern, open) {
+                    if commas.is_empty() {
+                        continue;
+                    }
+                    let prefix = &pattern[..open];
+                    let suffix = &pattern[close + 1..];
+                    let mut start = open + 1;
+                    let mut expanded = Vec::new();
+                    for end in commas.into_iter().chain([close]) {
+                        let alternative = format!("{}{}{}", prefix, &pattern[start..end], suffix);
+                        expanded.extend(expand_braces(&alternative));
+                        start = end + 1;
+                    }
+                    return expanded;
+                }
+            }
+            _ => {}
+        }
+    }
+    vec![pattern.to_string()]
+}
+
+/// Find the closing brace that matches the opening brace at `open`, and the
+/// positions of the commas directly inside the group.
+fn find_brace_group(pattern: &str, open: usize) -> Option<(usize, Vec<usize>)> {
+    let mut depth = 0;
+    let mut escaped = false;
+    let mut commas = Vec::new();
+    for (i, c) in pattern[open..].char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((open + i, commas));
+                }
+            }
+            ',' if depth == 1 => commas.push(open + i),
+            _ => {}
+        }
+    }
+    None
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 // Unit tests
 ////////////////////////////////////////////////////////////////////////////////
@@ -146,10 +671,13 @@ fn match_globs(dir: &Path, patterns: &[String], files: &mut Vec<PathBuf>) -> Res
 mod tests {
     use super::*;
 
+    use std::fs;
+
     use url::Url;
 
-    use crate::config::{GitReference, Shell};
-    use crate::lock::source;
+    use crate::config::{Condition, GitReference};
+    use crate::lock::source::{self, Session};
+    use crate::util::testing;
 
     #[test]
     fn external_plugin_lock_git_with_uses() {
@@ -161,17 +689,39 @@ mod tests {
             source: Source::Git {
                 url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
                 reference: Some(GitReference::Tag("v0.1.0".to_string())),
+                archive: false,
+                clone_as: None,
+                no_history: false,
+                verify_signature: None,
             },
             dir: None,
             uses: Some(vec!["*.md".into(), "{{ name }}.plugin.zsh".into()]),
             apply: None,
+            apply_matching: Vec::new(),
+            apply_globs: Vec::new(),
+            templates: IndexMap::new(),
             profiles: None,
+            shell: None,
+            follow_symlinks: false,
+            follow_dependencies: false,
+            version_from: None,
+            match_shebang: None,
+            order: Vec::new(),
+            tags: Vec::new(),
         };
-        let locked_source = source::lock(&ctx, plugin.source.clone()).unwrap();
+        let locked_source = source::lock(
+            &ctx,
+            &Session::default(),
+            plugin.source.clone(),
+            None,
+            false,
+        )
+        .unwrap();
         let clone_dir = dir.join("repos/github.com/rossmacarthur/sheldon-test");
 
         let locked = lock(
             &ctx,
+            Shell::Zsh,
             &Shell::default().default_templates().clone(),
             locked_source,
             &[],
@@ -202,17 +752,39 @@ mod tests {
             source: Source::Git {
                 url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
                 reference: Some(GitReference::Tag("v0.1.0".to_string())),
+                archive: false,
+                clone_as: None,
+                no_history: false,
+                verify_signature: None,
             },
             dir: None,
             uses: None,
             apply: None,
+            apply_matching: Vec::new(),
+            apply_globs: Vec::new(),
+            templates: IndexMap::new(),
             profiles: None,
+            shell: None,
+            follow_symlinks: false,
+            follow_dependencies: false,
+            version_from: None,
+            match_shebang: None,
+            order: Vec::new(),
+            tags: Vec::new(),
         };
-        let locked_source = source::lock(&ctx, plugin.source.clone()).unwrap();
+        let locked_source = source::lock(
+            &ctx,
+            &Session::default(),
+            plugin.source.clone(),
+            None,
+            false,
+        )
+        .unwrap();
         let clone_dir = dir.join("repos/github.com/rossmacarthur/sheldon-test");
 
         let locked = lock(
             &ctx,
+            Shell::Zsh,
             &Shell::default().default_templates().clone(),
             locked_source,
             &["*.plugin.zsh".to_string()],
@@ -237,16 +809,38 @@ mod tests {
             source: Source::Git {
                 url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
                 reference: Some(GitReference::Tag("v0.1.0".to_string())),
+                archive: false,
+                clone_as: None,
+                no_history: false,
+                verify_signature: None,
             },
             dir: None,
             uses: None,
             apply: None,
+            apply_matching: Vec::new(),
+            apply_globs: Vec::new(),
+            templates: IndexMap::new(),
             profiles: None,
+            shell: None,
+            follow_symlinks: false,
+            follow_dependencies: false,
+            version_from: None,
+            match_shebang: None,
+            order: Vec::new(),
+            tags: Vec::new(),
         };
-        let locked_source = source::lock(&ctx, plugin.source.clone()).unwrap();
+        let locked_source = source::lock(
+            &ctx,
+            &Session::default(),
+            plugin.source.clone(),
+            None,
+            false,
+        )
+        .unwrap();
 
         lock(
             &ctx,
+            Shell::Zsh,
             &Shell::default().default_templates().clone(),
             locked_source,
             &["*doesnotexist*".to_string()],
@@ -266,17 +860,39 @@ mod tests {
             source: Source::Git {
                 url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
                 reference: Some(GitReference::Tag("v0.1.0".to_string())),
+                archive: false,
+                clone_as: None,
+                no_history: false,
+                verify_signature: None,
             },
             dir: None,
             uses: None,
             apply: None,
+            apply_matching: Vec::new(),
+            apply_globs: Vec::new(),
+            templates: IndexMap::new(),
             profiles: None,
+            shell: None,
+            follow_symlinks: false,
+            follow_dependencies: false,
+            version_from: None,
+            match_shebang: None,
+            order: Vec::new(),
+            tags: Vec::new(),
         };
-        let locked_source = source::lock(&ctx, plugin.source.clone()).unwrap();
+        let locked_source = source::lock(
+            &ctx,
+            &Session::default(),
+            plugin.source.clone(),
+            None,
+            false,
+        )
+        .unwrap();
         let clone_dir = dir.join("repos/github.com/rossmacarthur/sheldon-test");
 
         let locked = lock(
             &ctx,
+            Shell::Zsh,
             &Shell::default().default_templates().clone(),
             locked_source,
             &["*doesnotexist*".to_string()],
@@ -291,6 +907,76 @@ mod tests {
         assert_eq!(locked.apply, vec![String::from("PATH")]);
     }
 
+    #[test]
+    fn external_plugin_lock_remote_with_filename() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let ctx = Context::testing(dir);
+
+        // Serve the plugin from a URL without a useful file name.
+        let body = "echo 'hello world'\n";
+        let (url, _) = testing::serve(vec![format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )]);
+
+        let plugin = ExternalPlugin {
+            name: "test".to_string(),
+            source: Source::Remote {
+                url: url.join("download").unwrap(),
+                version: None,
+                checksum: None,
+                filename: Some("test.plugin.zsh".into()),
+            },
+            dir: None,
+            uses: None,
+            apply: None,
+            apply_matching: vec![("*.plugin.zsh".to_string(), vec_into!["source"])],
+            apply_globs: Vec::new(),
+            templates: IndexMap::new(),
+            profiles: None,
+            shell: None,
+            follow_symlinks: false,
+            follow_dependencies: false,
+            version_from: None,
+            match_shebang: None,
+            order: Vec::new(),
+            tags: Vec::new(),
+        };
+        let locked_source = source::lock(
+            &ctx,
+            &Session::default(),
+            plugin.source.clone(),
+            None,
+            false,
+        )
+        .unwrap();
+        let download_dir = dir.join("downloads/127.0.0.1");
+
+        let locked = lock(
+            &ctx,
+            Shell::Zsh,
+            &Shell::default().default_templates().clone(),
+            locked_source,
+            &[],
+            &["source".to_string()],
+            plugin,
+        )
+        .unwrap();
+
+        let file = download_dir.join("test.plugin.zsh");
+        assert_eq!(locked.files, vec![file.clone()]);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "echo 'hello world'\n");
+        assert_eq!(
+            locked.groups,
+            vec![LockedFileGroup {
+                files: vec![file],
+                apply: vec_into!["source"],
+            }]
+        );
+    }
+
     #[test]
     fn external_plugin_lock_remote() {
         let temp = tempfile::tempdir().expect("create temporary directory");
@@ -303,17 +989,38 @@ mod tests {
                     "https://github.com/rossmacarthur/sheldon-test/raw/master/test.plugin.zsh",
                 )
                 .unwrap(),
+                version: None,
+                checksum: None,
+                filename: None,
             },
             dir: None,
             uses: None,
             apply: None,
+            apply_matching: Vec::new(),
+            apply_globs: Vec::new(),
+            templates: IndexMap::new(),
             profiles: None,
+            shell: None,
+            follow_symlinks: false,
+            follow_dependencies: false,
+            version_from: None,
+            match_shebang: None,
+            order: Vec::new(),
+            tags: Vec::new(),
         };
-        let locked_source = source::lock(&ctx, plugin.source.clone()).unwrap();
+        let locked_source = source::lock(
+            &ctx,
+            &Session::default(),
+            plugin.source.clone(),
+            None,
+            false,
+        )
+        .unwrap();
         let download_dir = dir.join("downloads/github.com/rossmacarthur/sheldon-test/raw/master");
 
         let locked = lock(
             &ctx,
+            Shell::Zsh,
             &Shell::default().default_templates().clone(),
             locked_source,
             &[],
@@ -327,4 +1034,800 @@ mod tests {
         assert_eq!(locked.files, vec![download_dir.join("test.plugin.zsh")]);
         assert_eq!(locked.apply, vec![String::from("hello")]);
     }
+
+    fn local_plugin(dir: &Path, uses: Vec<String>, follow_symlinks: bool) -> ExternalPlugin {
+        ExternalPlugin {
+            name: "test".to_string(),
+            source: Source::Local {
+                dir: dir.to_path_buf(),
+            },
+            dir: None,
+            uses: Some(uses),
+            apply: None,
+            apply_matching: Vec::new(),
+            apply_globs: Vec::new(),
+            templates: IndexMap::new(),
+            profiles: None,
+            shell: None,
+            follow_symlinks,
+            follow_dependencies: false,
+            version_from: None,
+            match_shebang: None,
+            order: Vec::new(),
+            tags: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn external_plugin_applied_templates() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.zsh"), "").unwrap();
+        fs::write(dir.join("b.zsh"), "").unwrap();
+        let ctx = Context::testing(temp.path());
+        let mut plugin = local_plugin(&dir, vec_into!["*.zsh"], false);
+        plugin.apply = Some(vec_into!["source", "fpath", "defer"]);
+        let templates = Shell::default().default_templates().clone();
+        let locked_source = source::lock(
+            &ctx,
+            &Session::default(),
+            plugin.source.clone(),
+            None,
+            false,
+        )
+        .unwrap();
+
+        let locked = lock(
+            &ctx,
+            Shell::Zsh,
+            &templates,
+            locked_source,
+            &[],
+            &[],
+            plugin,
+        )
+        .unwrap();
+
+        assert_eq!(
+            applied_templates(&locked, &templates),
+            vec![
+                (dir.as_path(), vec!["fpath"]),
+                (dir.join("a.zsh").as_path(), vec!["source", "defer"]),
+                (dir.join("b.zsh").as_path(), vec!["source", "defer"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn external_plugin_lock_match_shebang() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("test.zsh"), "").unwrap();
+        fs::write(dir.join("_test"), "#compdef test\n_arguments\n").unwrap();
+        fs::write(dir.join("README"), "# test\n").unwrap();
+        fs::write(dir.join("short"), "#").unwrap();
+        fs::create_dir_all(dir.join("completions")).unwrap();
+        fs::write(dir.join("completions/_other"), "#compdef other\n").unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(dir.join(".git/_ignored"), "#compdef ignored\n").unwrap();
+        let ctx = Context::testing(temp.path());
+        let mut plugin = local_plugin(&dir, Vec::new(), false);
+        plugin.uses = None;
+        plugin.match_shebang = Some("#compdef".into());
+        let templates = Shell::Zsh.default_templates().clone();
+        let matches = Shell::Zsh.default_matches().clone();
+        let apply = vec_into!["source"];
+        let locked_source = source::lock(
+            &ctx,
+            &Session::default(),
+            plugin.source.clone(),
+            None,
+            false,
+        )
+        .unwrap();
+
+        let locked = lock(
+            &ctx,
+            Shell::Zsh,
+            &templates,
+            locked_source,
+            &matches,
+            &apply,
+            plugin,
+        )
+        .unwrap();
+
+        assert_eq!(
+            locked.files,
+            vec![
+                dir.join("test.zsh"),
+                dir.join("_test"),
+                dir.join("completions/_other")
+            ]
+        );
+    }
+
+    #[test]
+    fn external_plugin_check_line_endings() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("test.plugin.zsh"),
+            "echo 'first'\r\necho 'second'\r\n",
+        )
+        .unwrap();
+        let ctx = Context::testing(temp.path());
+        let plugin = local_plugin(&dir, vec_into!["*.zsh"], false);
+        let templates = Shell::Zsh.default_templates().clone();
+        let apply = vec_into!["source"];
+        let locked_source = source::lock(
+            &ctx,
+            &Session::default(),
+            plugin.source.clone(),
+            None,
+            false,
+        )
+        .unwrap();
+        let mut locked = lock(
+            &ctx,
+            Shell::Zsh,
+            &templates,
+            locked_source,
+            &[],
+            &apply,
+            plugin,
+        )
+        .unwrap();
+
+        // The files of Local sources are never rewritten.
+        check_line_endings(&ctx, &locked, true).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("test.plugin.zsh")).unwrap(),
+            "echo 'first'\r\necho 'second'\r\n"
+        );
+
+        locked.local = false;
+        check_line_endings(&ctx, &locked, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("test.plugin.zsh")).unwrap(),
+            "echo 'first'\r\necho 'second'\r\n"
+        );
+        check_line_endings(&ctx, &locked, true).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("test.plugin.zsh")).unwrap(),
+            "echo 'first'\necho 'second'\n"
+        );
+
+        // Binary files are never rewritten.
+        fs::write(dir.join("test.plugin.zsh"), b"\0\r\n").unwrap();
+        check_line_endings(&ctx, &locked, true).unwrap();
+        assert_eq!(fs::read(dir.join("test.plugin.zsh")).unwrap(), b"\0\r\n");
+
+        // Neither are the files of Git clones.
+        fs::write(dir.join("test.plugin.zsh"), "echo 'first'\r\n").unwrap();
+        git2::Repository::init(&dir).unwrap();
+        check_line_endings(&ctx, &locked, true).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("test.plugin.zsh")).unwrap(),
+            "echo 'first'\r\n"
+        );
+    }
+
+    #[test]
+    fn external_plugin_lock_order() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        for file in ["a.zsh", "b.zsh", "c.zsh", "lib/d.zsh"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let ctx = Context::testing(temp.path());
+        let mut plugin = local_plugin(&dir, vec_into!["*.zsh", "lib/*.zsh"], false);
+        plugin.order = vec_into!["lib/d.zsh", "c.zsh"];
+        let templates = Shell::Zsh.default_templates().clone();
+        let apply = vec_into!["source"];
+        let locked_source = source::lock(
+            &ctx,
+            &Session::default(),
+            plugin.source.clone(),
+            None,
+            false,
+        )
+        .unwrap();
+
+        let locked = lock(
+            &ctx,
+            Shell::Zsh,
+            &templates,
+            locked_source,
+            &[],
+            &apply,
+            plugin,
+        )
+        .unwrap();
+
+        assert_eq!(
+            locked.files,
+            vec![
+                dir.join("lib/d.zsh"),
+                dir.join("c.zsh"),
+                dir.join("a.zsh"),
+                dir.join("b.zsh"),
+            ]
+        );
+    }
+
+    #[test]
+    fn external_plugin_lock_order_unmatched_file() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.zsh"), "").unwrap();
+        let ctx = Context::testing(temp.path());
+        let mut plugin = local_plugin(&dir, vec_into!["*.zsh"], false);
+        plugin.order = vec_into!["missing.zsh"];
+        let templates = Shell::Zsh.default_templates().clone();
+        let locked_source = source::lock(
+            &ctx,
+            &Session::default(),
+            plugin.source.clone(),
+            None,
+            false,
+        )
+        .unwrap();
+
+        let err = lock(
+            &ctx,
+            Shell::Zsh,
+            &templates,
+            locked_source,
+            &[],
+            &[],
+            plugin,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "file `missing.zsh` in the `order` field is not one of the matched files"
+        );
+    }
+
+    #[test]
+    fn external_plugin_lock_version_from() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.zsh"), "").unwrap();
+        fs::write(dir.join("VERSION"), "1.2.3\n").unwrap();
+        fs::write(
+            dir.join("package.json"),
+            r#"{ "name": "test", "version": "4.5.6", "engines": { "zsh": "5.8" } }"#,
+        )
+        .unwrap();
+        let ctx = Context::testing(temp.path());
+        let templates = Shell::default().default_templates().clone();
+
+        for (version_from, expected) in [
+            ("VERSION", "1.2.3"),
+            ("package.json#version", "4.5.6"),
+            ("package.json#engines.zsh", "5.8"),
+            ("package.json#missing", ""),
+            ("MISSING", ""),
+        ] {
+            let mut plugin = local_plugin(&dir, vec_into!["*.zsh"], false);
+            plugin.version_from = Some(version_from.to_string());
+            let locked_source = source::lock(
+                &ctx,
+                &Session::default(),
+                plugin.source.clone(),
+                None,
+                false,
+            )
+            .unwrap();
+            let locked = lock(
+                &ctx,
+                Shell::Zsh,
+                &templates,
+                locked_source,
+                &[],
+                &[],
+                plugin,
+            )
+            .unwrap();
+            assert_eq!(locked.version.as_deref(), Some(expected));
+        }
+    }
+
+    /// Lock a local plugin with a single `example` template that requires the
+    /// given variables applied to it, and return the error.
+    fn lock_required_variable_missing(requires: Vec<String>) -> String {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.zsh"), "").unwrap();
+        let ctx = Context::testing(temp.path());
+        let mut plugin = local_plugin(&dir, vec_into!["*.zsh"], false);
+        plugin.apply = Some(vec_into!["example"]);
+        let mut templates = Shell::default().default_templates().clone();
+        templates.insert(
+            "example".into(),
+            Template {
+                value: "source {{ dir }}/{{ file }} {{ version }}".into(),
+                each: false,
+                requires,
+                when: None,
+            },
+        );
+        let locked_source = source::lock(
+            &ctx,
+            &Session::default(),
+            plugin.source.clone(),
+            None,
+            false,
+        )
+        .unwrap();
+
+        lock(
+            &ctx,
+            Shell::Zsh,
+            &templates,
+            locked_source,
+            &[],
+            &[],
+            plugin,
+        )
+        .unwrap_err()
+        .to_string()
+    }
+
+    #[test]
+    fn external_plugin_lock_required_variable_missing() {
+        assert_eq!(
+            lock_required_variable_missing(vec_into!["dir", "file"]),
+            "template `example` requires variable `file` which is not available for plugin `test`"
+        );
+    }
+
+    #[test]
+    fn external_plugin_lock_required_version_missing() {
+        assert_eq!(
+            lock_required_variable_missing(vec_into!["dir", "version"]),
+            "template `example` requires variable `version` which is not available for plugin \
+             `test`"
+        );
+    }
+
+    #[test]
+    fn external_plugin_lock_local_apply_matching() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(dir.join("completions")).unwrap();
+        fs::write(dir.join("example.plugin.zsh"), "").unwrap();
+        fs::write(dir.join("completions/_example"), "").unwrap();
+        let ctx = Context::testing(temp.path());
+        let mut plugin = local_plugin(&dir, vec_into!["*.plugin.zsh", "completions/_*"], false);
+        plugin.apply = Some(vec_into!["source"]);
+        plugin.apply_matching = vec![("completions/_*".into(), vec_into!["fpath"])];
+        let templates = Shell::default().default_templates().clone();
+        let locked_source = source::lock(
+            &ctx,
+            &Session::default(),
+            plugin.source.clone(),
+            None,
+            false,
+        )
+        .unwrap();
+
+        let locked = lock(
+            &ctx,
+            Shell::Zsh,
+            &templates,
+            locked_source,
+            &[],
+            &[],
+            plugin,
+        )
+        .unwrap();
+
+        assert_eq!(
+            locked.groups,
+            vec![LockedFileGroup {
+                files: vec![dir.join("completions/_example")],
+                apply: vec_into!["fpath"],
+            }]
+        );
+        assert_eq!(
+            applied_templates(&locked, &templates),
+            vec![
+                (dir.join("completions").as_path(), vec!["fpath"]),
+                (dir.join("example.plugin.zsh").as_path(), vec!["source"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn external_plugin_lock_uses_shell_variable() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("test.zsh"), "").unwrap();
+        fs::write(dir.join("test.bash"), "").unwrap();
+        let ctx = Context::testing(temp.path());
+
+        for (shell, file) in [(Shell::Zsh, "test.zsh"), (Shell::Bash, "test.bash")] {
+            let plugin = local_plugin(&dir, vec_into!["{{ name }}.{{ shell }}"], false);
+            let templates = shell.default_templates().clone();
+            let locked_source = source::lock(
+                &ctx,
+                &Session::default(),
+                plugin.source.clone(),
+                None,
+                false,
+            )
+            .unwrap();
+            let locked = lock(&ctx, shell, &templates, locked_source, &[], &[], plugin).unwrap();
+            assert_eq!(locked.files, vec![dir.join(file)]);
+        }
+    }
+
+    #[test]
+    fn external_plugin_lock_local_apply_globs() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(dir.join("completions")).unwrap();
+        fs::write(dir.join("example.plugin.zsh"), "").unwrap();
+        fs::write(dir.join("completions/_example"), "").unwrap();
+        fs::write(dir.join("README.md"), "").unwrap();
+        let ctx = Context::testing(temp.path());
+        let mut plugin = local_plugin(&dir, Vec::new(), false);
+        plugin.uses = None;
+        plugin.apply = Some(Vec::new());
+        plugin.apply_globs = vec![
+            ("source".into(), "*.zsh".into()),
+            ("fpath".into(), "completions/*".into()),
+        ];
+        let templates = Shell::default().default_templates().clone();
+        let locked_source = source::lock(
+            &ctx,
+            &Session::default(),
+            plugin.source.clone(),
+            None,
+            false,
+        )
+        .unwrap();
+
+        let locked = lock(
+            &ctx,
+            Shell::Zsh,
+            &templates,
+            locked_source,
+            &["*.md".into()],
+            &["defer".into()],
+            plugin,
+        )
+        .unwrap();
+
+        assert_eq!(
+            locked.files,
+            vec![
+                dir.join("completions/_example"),
+                dir.join("example.plugin.zsh")
+            ]
+        );
+        assert_eq!(
+            locked.groups,
+            vec![
+                LockedFileGroup {
+                    files: vec![dir.join("completions/_example")],
+                    apply: vec_into!["fpath"],
+                },
+                LockedFileGroup {
+                    files: vec![dir.join("example.plugin.zsh")],
+                    apply: vec_into!["source"],
+                },
+            ]
+        );
+        assert_eq!(
+            applied_templates(&locked, &templates),
+            vec![
+                (dir.join("completions").as_path(), vec!["fpath"]),
+                (dir.join("example.plugin.zsh").as_path(), vec!["source"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn external_plugin_lock_template_when() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(dir.join("completions")).unwrap();
+        fs::write(dir.join("test.zsh"), "").unwrap();
+        fs::write(dir.join("completions/_test"), "").unwrap();
+        let ctx = Context::testing(temp.path());
+        let mut templates = Shell::default().default_templates().clone();
+        let guarded = |shell, os: &str| Template {
+            value: "echo {{ file }}".into(),
+            each: true,
+            requires: Vec::new(),
+            when: Some(Condition {
+                shell,
+                os: Some(os.into()),
+            }),
+        };
+        templates.insert("this-os".into(), guarded(None, std::env::consts::OS));
+        templates.insert("other-os".into(), guarded(None, "not-an-os"));
+        templates.insert(
+            "zsh-only".into(),
+            guarded(Some(Shell::Zsh), std::env::consts::OS),
+        );
+
+        for (shell, apply) in [
+            (Shell::Zsh, vec!["source", "this-os", "zsh-only"]),
+            (Shell::Bash, vec!["source", "this-os"]),
+        ] {
+            let mut plugin = local_plugin(&dir, vec_into!["*.zsh", "completions/_*"], false);
+            plugin.apply = Some(vec_into!["source", "this-os", "other-os", "zsh-only"]);
+            plugin.apply_matching = vec![(
+                "completions/_*".into(),
+                vec_into!["fpath", "other-os", "zsh-only"],
+            )];
+            let locked_source = source::lock(
+                &ctx,
+                &Session::default(),
+                plugin.source.clone(),
+                None,
+                false,
+            )
+            .unwrap();
+            let locked = lock(&ctx, shell, &templates, locked_source, &[], &[], plugin).unwrap();
+            assert_eq!(locked.apply, apply);
+            let mut group_apply = vec!["fpath"];
+            if shell == Shell::Zsh {
+                group_apply.push("zsh-only");
+            }
+            assert_eq!(locked.groups[0].apply, group_apply);
+        }
+    }
+
+    #[test]
+    fn expand_braces_patterns() {
+        assert_eq!(expand_braces("*.zsh"), vec!["*.zsh"]);
+        assert_eq!(expand_braces("*.{zsh,sh}"), vec!["*.zsh", "*.sh"]);
+        assert_eq!(
+            expand_braces("{a,b}/*.{zsh,sh}"),
+            vec!["a/*.zsh", "a/*.sh", "b/*.zsh", "b/*.sh"]
+        );
+        assert_eq!(expand_braces("*.{z{sh,},sh}"), vec!["*.zsh", "*.z", "*.sh"]);
+        assert_eq!(expand_braces(r"\{a,b\}.zsh"), vec![r"\{a,b\}.zsh"]);
+        assert_eq!(
+            expand_braces(r"{\{a,b\}}.zsh"),
+            vec![r"\{a.zsh", r"b\}.zsh"]
+        );
+        assert_eq!(expand_braces("{a}.zsh"), vec!["{a}.zsh"]);
+        assert_eq!(expand_braces("{a,b.zsh"), vec!["{a,b.zsh"]);
+    }
+
+    #[test]
+    fn external_plugin_lock_local_brace_expansion() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(&dir).unwrap();
+        for file in ["a.zsh", "b.sh", "c.bash", "{a,b}.zsh"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let ctx = Context::testing(temp.path());
+        let templates = Shell::default().default_templates().clone();
+
+        for (pattern, expected) in [
+            ("*.{zsh,sh}", vec!["a.zsh", "b.sh", "{a,b}.zsh"]),
+            (r"\{a,b\}.zsh", vec!["{a,b}.zsh"]),
+        ] {
+            let plugin = local_plugin(&dir, vec![pattern.to_string()], false);
+            let locked_source = source::lock(
+                &ctx,
+                &Session::default(),
+                plugin.source.clone(),
+                None,
+                false,
+            )
+            .unwrap();
+            let locked = lock(
+                &ctx,
+                Shell::Zsh,
+                &templates,
+                locked_source,
+                &[],
+                &[],
+                plugin,
+            )
+            .unwrap();
+            let expected: Vec<_> = expected.into_iter().map(|f| dir.join(f)).collect();
+            assert_eq!(locked.files, expected);
+        }
+    }
+
+    #[test]
+    fn external_plugin_lock_local_uses_exclusions() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(dir.join("test")).unwrap();
+        for file in ["a.zsh", "b.zsh", "test/c.zsh", "test/keep.zsh"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let ctx = Context::testing(temp.path());
+        let templates = Shell::default().default_templates().clone();
+
+        for (patterns, expected) in [
+            (vec!["*.zsh", "!test/*"], vec!["a.zsh", "b.zsh"]),
+            (
+                vec!["*.zsh", "!test/*", "test/keep.zsh"],
+                vec!["a.zsh", "b.zsh", "test/keep.zsh"],
+            ),
+            // Later rules take precedence, so this re-include is excluded again.
+            (
+                vec!["*.zsh", "!test/*", "test/keep.zsh", "!keep.zsh"],
+                vec!["a.zsh", "b.zsh"],
+            ),
+            (
+                vec!["*.zsh", "!{a,test/c}.zsh"],
+                vec!["b.zsh", "test/keep.zsh"],
+            ),
+        ] {
+            let uses = patterns.iter().map(|p| p.to_string()).collect();
+            let plugin = local_plugin(&dir, uses, false);
+            let locked_source = source::lock(
+                &ctx,
+                &Session::default(),
+                plugin.source.clone(),
+                None,
+                false,
+            )
+            .unwrap();
+            let locked = lock(
+                &ctx,
+                Shell::Zsh,
+                &templates,
+                locked_source,
+                &[],
+                &[],
+                plugin,
+            )
+            .unwrap();
+            let expected: Vec<_> = expected.into_iter().map(|f| dir.join(f)).collect();
+            assert_eq!(locked.files, expected, "{:?}", patterns);
+        }
+    }
+
+    #[test]
+    fn external_plugin_lock_local_matches_exclusions() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(dir.join("test")).unwrap();
+        for file in ["a.zsh", "b.zsh", "test.plugin.zsh", "test/c.zsh"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let ctx = Context::testing(temp.path());
+        let templates = Shell::default().default_templates().clone();
+
+        for (matches, expected) in [
+            (
+                vec!["{{ name }}.plugin.zsh", "*.zsh", "!test/*"],
+                vec!["test.plugin.zsh"],
+            ),
+            (
+                vec![
+                    "{{ name }}.plugin.zsh",
+                    "!test.plugin.zsh",
+                    "*.zsh",
+                    "!test/*",
+                ],
+                vec!["a.zsh", "b.zsh", "test.plugin.zsh"],
+            ),
+            // Exclusions only apply to the patterns before them.
+            (
+                vec!["!test.plugin.zsh", "*.plugin.zsh"],
+                vec!["test.plugin.zsh"],
+            ),
+        ] {
+            let matches: Vec<_> = matches.iter().map(|p| p.to_string()).collect();
+            let mut plugin = local_plugin(&dir, Vec::new(), false);
+            plugin.uses = None;
+            let locked_source = source::lock(
+                &ctx,
+                &Session::default(),
+                plugin.source.clone(),
+                None,
+                false,
+            )
+            .unwrap();
+            let locked = lock(
+                &ctx,
+                Shell::Zsh,
+                &templates,
+                locked_source,
+                &matches,
+                &[],
+                plugin,
+            )
+            .unwrap();
+            let expected: Vec<_> = expected.into_iter().map(|f| dir.join(f)).collect();
+            assert_eq!(locked.files, expected, "{:?}", matches);
+        }
+    }
+
+    #[test]
+    fn external_plugin_lock_local_symlink_cycle() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/test.zsh"), "").unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("sub/loop")).unwrap();
+        let ctx = Context::testing(temp.path());
+        let plugin = local_plugin(&dir, vec_into!["**/*.zsh"], true);
+        let locked_source = source::lock(
+            &ctx,
+            &Session::default(),
+            plugin.source.clone(),
+            None,
+            false,
+        )
+        .unwrap();
+
+        let locked = lock(
+            &ctx,
+            Shell::Zsh,
+            &Shell::default().default_templates().clone(),
+            locked_source,
+            &[],
+            &["source".to_string()],
+            plugin,
+        )
+        .unwrap();
+
+        assert_eq!(locked.files, vec![dir.join("sub/test.zsh")]);
+    }
+
+    #[test]
+    fn external_plugin_lock_local_follow_symlinks() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path().join("plugin");
+        let other = temp.path().join("other");
+        fs::create_dir_all(&dir).unwrap();
+        fs::create_dir_all(&other).unwrap();
+        fs::write(other.join("test.zsh"), "").unwrap();
+        std::os::unix::fs::symlink(&other, dir.join("linked")).unwrap();
+        let ctx = Context::testing(temp.path());
+
+        for (follow_symlinks, expected) in
+            [(false, vec![]), (true, vec![dir.join("linked/test.zsh")])]
+        {
+            let plugin = local_plugin(&dir, vec_into!["**/*.zsh"], follow_symlinks);
+            let locked_source = source::lock(
+                &ctx,
+                &Session::default(),
+                plugin.source.clone(),
+                None,
+                false,
+            )
+            .unwrap();
+            let result = lock(
+                &ctx,
+                Shell::Zsh,
+                &Shell::default().default_templates().clone(),
+                locked_source,
+                &[],
+                &["source".to_string()],
+                plugin,
+            );
+            if expected.is_empty() {
+                result.unwrap_err();
+            } else {
+                assert_eq!(result.unwrap().files, expected);
+            }
+        }
+    }
 }
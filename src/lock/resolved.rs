@@ -0,0 +1,282 @@
+//! The fully resolved config that is locked.
+
+use std::path::Path;
+
+use anyhow::{Context as ResultExt, Result};
+use indexmap::IndexMap;
+use itertools::Itertools;
+use serde::{Serialize, Serializer};
+
+use crate::config::{Shell, Template};
+use crate::lock::file::{is_false, is_json};
+use crate::lock::Plan;
+
+/// The config that a [`Plan`] locks.
+///
+/// This is the config after the defaults are applied, the plugins are filtered
+/// by profile and shell, and the source shorthands are expanded. Unlike the
+/// lock file it does not contain anything that is only known after locking.
+#[derive(Debug, Serialize)]
+pub struct ResolvedConfig<'a> {
+    /// What type of shell is being used.
+    shell: Shell,
+    /// The global files to match on.
+    #[serde(rename = "match")]
+    matches: &'a [String],
+    /// The global templates to apply.
+    apply: &'a [String],
+    /// Each plugin that is locked, in the order it is rendered.
+    plugins: Vec<ResolvedPlugin<'a>>,
+    /// The default templates merged with the configured templates.
+    ///
+    /// Note: this field must come last in the struct for it to serialize
+    /// properly.
+    templates: &'a IndexMap<String, Template>,
+}
+
+/// A plugin in a [`ResolvedConfig`].
+///
+/// This contains every option of the configured plugin, except `profiles` and
+/// `shell` which are already applied by filtering the plugins.
+#[derive(Debug, Serialize)]
+struct ResolvedPlugin<'a> {
+    /// The name of this plugin.
+    name: &'a str,
+    /// Where an external plugin is retrieved from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    /// Which directory to use in an external plugin.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dir: Option<&'a str>,
+    /// What files to use in an external plugin's directory.
+    #[serde(rename = "use", skip_serializing_if = "Option::is_none")]
+    uses: Option<&'a [String]>,
+    /// What templates to apply to each of an external plugin's files.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    apply: Option<&'a [String]>,
+    /// The files to put before the other matched files, in this order.
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    order: &'a [String],
+    /// Whether to follow symlinked directories when matching files.
+    #[serde(skip_serializing_if = "is_false")]
+    follow_symlinks: bool,
+    /// Whether to also lock the plugins declared in the plugin's source.
+    #[serde(skip_serializing_if = "is_false")]
+    follow_dependencies: bool,
+    /// A file to read the plugin's version from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version_from: Option<&'a str>,
+    /// Also use the files whose first line starts with this prefix.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    match_shebang: Option<&'a str>,
+    /// The source of an inline plugin.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    inline: Option<&'a str>,
+    /// Labels for selecting this plugin.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: &'a Vec<String>,
+    /// What templates to apply to the matched files that match each pattern.
+    #[serde(
+        skip_serializing_if = "<[_]>::is_empty",
+        serialize_with = "serialize_pairs"
+    )]
+    apply_matching: &'a [(String, Vec<String>)],
+    /// The pattern of files that each template is applied to.
+    #[serde(
+        skip_serializing_if = "<[_]>::is_empty",
+        serialize_with = "serialize_pairs"
+    )]
+    apply_globs: &'a [(String, String)],
+    /// The templates that are only available to this plugin.
+    ///
+    /// Note: this field and the other maps above must come last in the struct
+    /// for it to serialize properly.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    templates: Option<&'a IndexMap<String, Template>>,
+}
+
+/// Serialize key and value pairs as a map, in order.
+fn serialize_pairs<S, K, V>(pairs: &&[(K, V)], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    K: Serialize,
+    V: Serialize,
+{
+    serializer.collect_map(pairs.iter().map(|(k, v)| (k, v)))
+}
+
+impl Plan {
+    /// Returns the config that this plan locks.
+    pub fn resolved(&self) -> ResolvedConfig<'_> {
+        let externals = self.sources.iter().flat_map(|(source, plugins)| {
+            plugins.iter().map(move |(index, plugin)| {
+                let resolved = ResolvedPlugin {
+                    name: &plugin.name,
+                    source: Some(source.to_string()),
+                    dir: plugin.dir.as_deref(),
+                    uses: plugin.uses.as_deref(),
+                    apply: Some(plugin.apply.as_deref().unwrap_or(&self.apply)),
+                    order: &plugin.order,
+                    follow_symlinks: plugin.follow_symlinks,
+                    follow_dependencies: plugin.follow_dependencies,
+                    version_from: plugin.version_from.as_deref(),
+                    match_shebang: plugin.match_shebang.as_deref(),
+                    inline: None,
+                    tags: &plugin.tags,
+                    apply_matching: &plugin.apply_matching,
+                    apply_globs: &plugin.apply_globs,
+                    templates: (!plugin.templates.is_empty()).then_some(&plugin.templates),
+                };
+                (*index, resolved)
+            })
+        });
+        let inlines = self.inlines.iter().map(|(index, plugin)| {
+            let resolved = ResolvedPlugin {
+                name: &plugin.name,
+                source: None,
+                dir: None,
+                uses: None,
+                apply: None,
+                order: &[],
+                follow_symlinks: false,
+                follow_dependencies: false,
+                version_from: None,
+                match_shebang: None,
+                inline: Some(&plugin.raw),
+                tags: &plugin.tags,
+                apply_matching: &[],
+                apply_globs: &[],
+                templates: None,
+            };
+            (*index, resolved)
+        });
+        ResolvedConfig {
+            shell: self.shell,
+            matches: &self.matches,
+            apply: &self.apply,
+            plugins: externals
+                .chain(inlines)
+                .sorted_by_key(|(index, _)| *index)
+                .map(|(_, plugin)| plugin)
+                .collect(),
+            templates: &self.templates,
+        }
+    }
+}
+
+impl ResolvedConfig<'_> {
+    /// Serialize the resolved config as it would be written to the given path.
+    ///
+    /// Like the lock file, the config is serialized as JSON if the path has a
+    /// `.json` extension, otherwise it is serialized as TOML.
+    pub fn to_string_for(&self, path: &Path) -> Result<String> {
+        if is_json(path) {
+            serde_json::to_string_pretty(&self).context("failed to serialize resolved config")
+        } else {
+            toml::to_string(&self).context("failed to serialize resolved config")
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Unit tests
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indexmap::indexmap;
+
+    use crate::config::{Config, ExternalPlugin, InlinePlugin, Plugin, Source};
+    use crate::context::Context;
+    use crate::lock::plan;
+
+    #[test]
+    fn plan_resolved_applies_defaults() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let config = Config {
+            shell: Shell::Zsh,
+            matches: None,
+            apply: None,
+            templates: IndexMap::new(),
+            remove_templates: None,
+            clean_ignore: None,
+            footer: None,
+            dedupe_paths: None,
+            invalidate_compdump: None,
+            guard: None,
+            profile_startup: None,
+            trash: None,
+            require_clean: None,
+            normalize_line_endings: None,
+            manifest: None,
+            wrap_in_function: None,
+            clone_dir_layout: None,
+            active_profiles: None,
+            imports: Vec::new(),
+            plugins: vec![
+                Plugin::External(ExternalPlugin {
+                    name: "test".to_string(),
+                    source: Source::Local {
+                        dir: "/plugins/test".into(),
+                    },
+                    dir: None,
+                    uses: None,
+                    apply: None,
+                    apply_matching: vec![("*.zsh".to_string(), vec_into!["greet"])],
+                    apply_globs: Vec::new(),
+                    templates: indexmap_into! { "greet" => "echo {{ quote file }}" },
+                    profiles: None,
+                    shell: None,
+                    follow_symlinks: true,
+                    follow_dependencies: false,
+                    version_from: None,
+                    match_shebang: None,
+                    order: vec_into!["init.zsh"],
+                    tags: Vec::new(),
+                }),
+                Plugin::Inline(InlinePlugin {
+                    name: "inline".to_string(),
+                    raw: "echo 'inline'".to_string(),
+                    profiles: Some(vec_into!["work"]),
+                    shell: None,
+                    tags: Vec::new(),
+                }),
+            ],
+            skipped: IndexMap::new(),
+        };
+
+        let plan = plan(&ctx, config);
+        let resolved = plan.resolved();
+        let value: serde_json::Value =
+            serde_json::from_str(&resolved.to_string_for(Path::new("resolved.json")).unwrap())
+                .unwrap();
+
+        assert_eq!(value["shell"], "zsh");
+        assert_eq!(
+            value["match"],
+            serde_json::to_value(Shell::Zsh.default_matches()).unwrap()
+        );
+        assert_eq!(value["apply"], serde_json::json!(["source"]));
+        assert_eq!(
+            value["plugins"],
+            serde_json::json!([{
+                "name": "test",
+                "source": "/plugins/test",
+                "apply": ["source"],
+                "order": ["init.zsh"],
+                "follow_symlinks": true,
+                "apply_matching": { "*.zsh": ["greet"] },
+                "templates": {
+                    "greet": { "value": "echo {{ quote file }}", "each": false },
+                },
+            }])
+        );
+        assert!(value["templates"]["source"].is_object());
+        assert!(resolved
+            .to_string_for(Path::new("resolved.toml"))
+            .unwrap()
+            .contains("[[plugins]]\nname = \"test\"\n"));
+    }
+}
@@ -1,63 +1,218 @@
-use anyhow::{Context as ResultExt, Result};
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use anyhow::{bail, Context as ResultExt, Result};
+use handlebars::{HelperDef, HelperResult, RenderError};
+use indexmap::IndexMap;
+use itertools::Itertools;
 use maplit::hashmap;
 
+use crate::config::{is_tagged, Manifest, Shell, Template};
 use crate::context::Context;
 use crate::lock::file::LockedPlugin;
 use crate::lock::LockedConfig;
+use crate::util::keyring::{Keyring, OsKeyring};
+
+/// The built-in templates that add the plugin directory to a path variable.
+const PATH_TEMPLATES: &[&str] = &["PATH", "path", "fpath"];
+
+/// The variable that is set once the generated script has been sourced.
+const GUARD_VARIABLE: &str = "_SHELDON_SOURCED";
+
+/// The variable that holds the time that the current plugin started loading.
+const PROFILE_VARIABLE: &str = "_sheldon_start";
+
+/// Removes the zsh completion dump, and its compiled version, if the lock file
+/// is newer than it, so that `compinit` rebuilds it.
+const INVALIDATE_COMPDUMP: &str = r#"() {
+  local dump="${ZSH_COMPDUMP:-${ZDOTDIR:-$HOME}/.zcompdump}"
+  if [[ {lock_file} -nt "$dump" ]]; then
+    rm -f "$dump" "$dump.zwc"
+  fi
+}"#;
+
+/// The size in bytes of each part of a generated script.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Measurement {
+    /// The size of each plugin, in the order that they are rendered.
+    pub plugins: Vec<(String, usize)>,
+    /// The size of everything else, like accumulated paths and the footer.
+    pub other: usize,
+}
+
+impl Measurement {
+    /// The size of the whole script.
+    pub fn total(&self) -> usize {
+        self.plugins.iter().map(|(_, size)| size).sum::<usize>() + self.other
+    }
+}
 
 impl LockedConfig {
     /// Generate the script.
-    pub fn script(&self, ctx: &Context) -> Result<String> {
+    ///
+    /// If `apply` is given then those templates are applied to every plugin
+    /// instead of the ones in the lock file. If `tags` are given then only the
+    /// plugins with at least one of them are rendered.
+    pub fn script(
+        &self,
+        ctx: &Context,
+        apply: Option<&[String]>,
+        tags: &[String],
+    ) -> Result<String> {
+        self.render(ctx, &OsKeyring, apply, tags)
+            .map(|(script, _)| script)
+    }
+
+    /// Generate the script, and measure how much of it each plugin renders.
+    pub fn measure(
+        &self,
+        ctx: &Context,
+        apply: Option<&[String]>,
+        tags: &[String],
+    ) -> Result<(String, Measurement)> {
+        self.render(ctx, &OsKeyring, apply, tags)
+    }
+
+    /// Generate the script, looking up any secrets in the given keyring.
+    #[cfg(test)]
+    fn script_with(&self, ctx: &Context, keyring: &dyn Keyring) -> Result<String> {
+        self.render(ctx, keyring, None, &[])
+            .map(|(script, _)| script)
+    }
+
+    fn render(
+        &self,
+        ctx: &Context,
+        keyring: &dyn Keyring,
+        apply: Option<&[String]>,
+        tags: &[String],
+    ) -> Result<(String, Measurement)> {
         // Compile the templates
         let mut templates = handlebars::Handlebars::new();
         templates.set_strict_mode(true);
+        templates.register_helper("secret", Box::new(SecretHelper { keyring }));
+        templates.register_helper("quote", Box::new(QuoteHelper { shell: self.shell }));
         for (name, template) in &self.templates {
             templates
                 .register_template_string(name, &template.value)
                 .with_context(s!("failed to compile template `{}`", name))?;
         }
+        for plugin in &self.plugins {
+            if let LockedPlugin::External(plugin) = plugin {
+                for (name, template) in &plugin.templates {
+                    templates
+                        .register_template_string(
+                            &plugin_template_key(&plugin.name, name),
+                            &template.value,
+                        )
+                        .with_context(s!("failed to compile template `{}`", name))?;
+                }
+            }
+        }
 
+        let shell = self.shell.to_string();
         let mut script = String::new();
+        let mut measurement = Measurement::default();
 
-        for plugin in &self.plugins {
+        // The plugin directories for each path template, if they are being
+        // accumulated instead of rendered for each plugin.
+        let mut paths: IndexMap<&str, Vec<&str>> = IndexMap::new();
+
+        if self.profile_startup && self.shell == Shell::Zsh {
+            script.push_str("zmodload zsh/datetime\n");
+        }
+
+        for (index, plugin) in self.selected(tags).enumerate() {
+            // The position of the plugin in the generated script.
+            let index = index.to_string();
+            let start = script.len();
+            let name = plugin.name();
+            if self.profile_startup {
+                script.push_str(&profile_start(self.shell));
+                script.push('\n');
+            }
             match plugin {
                 LockedPlugin::External(plugin) => {
-                    for name in &plugin.apply {
-                        let dir_as_str = plugin
-                            .dir()
-                            .to_str()
-                            .context("plugin directory is not valid UTF-8")?;
+                    let data_dir = self
+                        .ctx
+                        .data_dir()
+                        .to_str()
+                        .context("data directory is not valid UTF-8")?;
+                    let plugin_dir = plugin
+                        .dir()
+                        .to_str()
+                        .context("plugin directory is not valid UTF-8")?;
+
+                    // The plugin's own templates take precedence over the
+                    // global templates.
+                    let template = |name: &str| {
+                        plugin
+                            .templates
+                            .get(name)
+                            .or_else(|| self.templates.get(name))
+                            .unwrap()
+                    };
+                    if let Some(apply) = apply {
+                        if let Some(name) = apply.iter().find(|name| {
+                            !plugin.templates.contains_key(*name)
+                                && !self.templates.contains_key(*name)
+                        }) {
+                            bail!("unknown template `{}`", name);
+                        }
+                    }
 
+                    let render = |name: &str, dir: &str, file: Option<&str>| {
                         // Data to use in template rendering
                         let mut data = hashmap! {
-                            "data_dir" => self
-                                .ctx
-                                .data_dir()
-                                .to_str()
-                                .context("data directory is not valid UTF-8")?,
+                            "data_dir" => data_dir,
                             "name" => &plugin.name,
-                            "dir" => dir_as_str,
+                            "dir" => dir,
+                            "index" => &index,
+                            "shell" => &shell,
+                            "version" => plugin.version.as_deref().unwrap_or(""),
+                        };
+                        if let Some(file) = file {
+                            data.insert("file", file);
+                        }
+                        let key = if plugin.templates.contains_key(name) {
+                            plugin_template_key(&plugin.name, name)
+                        } else {
+                            name.to_string()
                         };
+                        templates
+                            .render(&key, &data)
+                            .map(|rendered| normalize_line_endings(&rendered))
+                            .with_context(s!("failed to render template `{}`", name))
+                    };
 
-                        if self.templates.get(name.as_str()).unwrap().each {
-                            for file in &plugin.files {
-                                let as_str =
-                                    file.to_str().context("plugin file is not valid UTF-8")?;
-                                data.insert("file", as_str);
-                                script.push_str(
-                                    &templates
-                                        .render(name, &data)
-                                        .with_context(s!("failed to render template `{}`", name))?,
-                                );
+                    for bucket in plugin.buckets() {
+                        for name in apply.unwrap_or(bucket.apply) {
+                            if template(name).each {
+                                for file in &bucket.files {
+                                    let as_str =
+                                        file.to_str().context("plugin file is not valid UTF-8")?;
+                                    script.push_str(&render(name, plugin_dir, Some(as_str))?);
+                                    script.push('\n');
+                                }
+                                continue;
+                            }
+
+                            for dir in &bucket.dirs {
+                                let dir_as_str = dir
+                                    .to_str()
+                                    .context("plugin directory is not valid UTF-8")?;
+
+                                if self.dedupe_paths
+                                    && is_path_template(self.shell, name, template(name))
+                                {
+                                    paths.entry(name).or_default().push(dir_as_str);
+                                    continue;
+                                }
+
+                                script.push_str(&render(name, dir_as_str, None)?);
                                 script.push('\n');
                             }
-                        } else {
-                            script.push_str(
-                                &templates
-                                    .render(name, &data)
-                                    .with_context(s!("failed to render template `{}`", name))?,
-                            );
-                            script.push('\n');
                         }
                     }
                     status_v!(ctx, "Rendered", &plugin.name);
@@ -70,21 +225,1158 @@ impl LockedConfig {
                             .to_str()
                             .context("data directory is not valid UTF-8")?,
                         "name" => &plugin.name,
+                        "index" => &index,
+                        "shell" => &shell,
                     };
-                    script.push_str(
-                        &templates
-                            .render_template(&plugin.raw, &data)
-                            .with_context(s!(
-                                "failed to render inline plugin `{}`",
-                                &plugin.name
-                            ))?,
-                    );
+                    let rendered = templates
+                        .render_template(&plugin.raw, &data)
+                        .with_context(s!("failed to render inline plugin `{}`", &plugin.name))?;
+                    script.push_str(&normalize_line_endings(&rendered));
                     script.push('\n');
                     status_v!(ctx, "Inlined", &plugin.name);
                 }
             }
+            if self.profile_startup {
+                script.push_str(&profile_end(self.shell, name));
+                script.push('\n');
+            }
+            measurement
+                .plugins
+                .push((name.to_string(), script.len() - start));
+        }
+
+        if self.profile_startup {
+            script.push_str(&format!("unset {}\n", PROFILE_VARIABLE));
+        }
+
+        for (name, dirs) in paths {
+            // Each rendered path template prepends to the variable, so later
+            // plugins take precedence. Keep this order and only the first
+            // occurrence of each directory.
+            let dirs: Vec<_> = dirs.into_iter().rev().unique().collect();
+            let mut quoted = dirs.iter().map(|d| self.shell.quote(d));
+            let line = if name == "PATH" {
+                format!("export PATH={}:\"$PATH\"", quoted.join(":"))
+            } else {
+                format!("{name}=( {} ${name} )", quoted.join(" "), name = name)
+            };
+            script.push_str(&line);
+            script.push('\n');
+        }
+
+        // Only zsh has a completion dump.
+        if self.invalidate_compdump && self.shell == Shell::Zsh {
+            let lock_file = self
+                .ctx
+                .lock_file()
+                .to_str()
+                .context("lock file is not valid UTF-8")?;
+            script.push_str(
+                &INVALIDATE_COMPDUMP.replace("{lock_file}", &self.shell.quote(lock_file)),
+            );
+            script.push('\n');
         }
 
-        Ok(script)
+        if let Some(manifest) = &self.manifest {
+            script.push_str(&self.manifest(manifest, tags)?);
+            script.push('\n');
+        }
+
+        if let Some(footer) = &self.footer {
+            script.push_str(&normalize_line_endings(footer));
+            script.push('\n');
+        }
+
+        if self.guard {
+            script = format!(
+                "if {}; then\n{}=1\n{}fi\n",
+                guard_condition(self.shell),
+                GUARD_VARIABLE,
+                script
+            );
+        }
+
+        if let Some(name) = &self.wrap_in_function {
+            // A function body can not be empty.
+            if script.is_empty() {
+                script.push_str(":\n");
+            }
+            script = format!("{}() {{\n{}}}\n", name, script);
+        }
+
+        measurement.other = script.len() - measurement.total();
+        Ok((script, measurement))
+    }
+}
+
+impl LockedConfig {
+    /// Returns the plugins that have at least one of the given tags, or every
+    /// plugin if no tags are given.
+    fn selected<'a>(&'a self, tags: &'a [String]) -> impl Iterator<Item = &'a LockedPlugin> {
+        self.plugins
+            .iter()
+            .filter(move |plugin| is_tagged(plugin.tags(), tags))
+    }
+
+    /// Returns the assignment of the environment variable that lists the
+    /// plugin names, or the plugin files.
+    fn manifest(&self, manifest: &Manifest, tags: &[String]) -> Result<String> {
+        let mut entries = Vec::new();
+        for plugin in self.selected(tags) {
+            match plugin {
+                LockedPlugin::External(plugin) if manifest.files => {
+                    for file in &plugin.files {
+                        entries.push(file.to_str().context("plugin file is not valid UTF-8")?);
+                    }
+                }
+                LockedPlugin::External(plugin) => entries.push(&plugin.name),
+                LockedPlugin::Inline(plugin) if !manifest.files => entries.push(&plugin.name),
+                LockedPlugin::Inline(_) => {}
+            }
+        }
+        Ok(format!(
+            "export {}={}",
+            manifest.variable,
+            self.shell.quote(&entries.join(&manifest.separator))
+        ))
+    }
+}
+
+/// Check that a generated script parses using the given shell, without running
+/// it.
+pub fn check_syntax(shell: Shell, script: &str) -> Result<()> {
+    let program = shell.to_string();
+    let mut child = Command::new(&program)
+        .arg("-n")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(s!("failed to run `{}`", program))?;
+    // Write the script from another thread. The shell stops reading at the
+    // first syntax error, so writing has to be able to fail without hiding it.
+    let mut stdin = child.stdin.take().unwrap();
+    let contents = script.as_bytes().to_vec();
+    let writer = thread::spawn(move || stdin.write_all(&contents));
+    let output = child
+        .wait_with_output()
+        .with_context(s!("failed to run `{}`", program))?;
+    let written = writer.join().expect("writer thread panicked");
+    if !output.status.success() {
+        bail!(
+            "script does not parse with `{} -n`: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    match written {
+        Err(err) if err.kind() != io::ErrorKind::BrokenPipe => {
+            Err(err).with_context(s!("failed to write script to `{}`", program))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// A template helper that renders a secret from the keyring.
+///
+/// Secrets are only ever rendered into the generated script, they are never
+/// written to the lock file.
+struct SecretHelper<'a> {
+    keyring: &'a dyn Keyring,
+}
+
+impl HelperDef for SecretHelper<'_> {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &handlebars::Helper<'reg, 'rc>,
+        _: &'reg handlebars::Handlebars<'reg>,
+        _: &'rc handlebars::Context,
+        _: &mut handlebars::RenderContext<'reg, 'rc>,
+        out: &mut dyn handlebars::Output,
+    ) -> HelperResult {
+        let name = h
+            .param(0)
+            .and_then(|p| p.value().as_str())
+            .ok_or_else(|| RenderError::new("the `secret` helper requires a secret name"))?;
+        match self.keyring.get(name) {
+            Ok(Some(secret)) => out.write(&secret).map_err(RenderError::from),
+            Ok(None) => Err(RenderError::new(format!(
+                "secret `{}` not found in the keyring",
+                name
+            ))),
+            Err(err) => Err(RenderError::new(format!("{:#}", err))),
+        }
+    }
+}
+
+/// Quoting of strings, like paths, that are emitted into the generated script.
+///
+/// Each shell has its own rules for which characters are special, so a new
+/// shell must also define how it quotes.
+pub trait ShellQuote {
+    /// Escape the given string so that it is taken literally inside double
+    /// quotes.
+    fn escape(&self, s: &str) -> String;
+
+    /// Quote the given string so that it is taken literally as a single word.
+    fn quote(&self, s: &str) -> String {
+        format!("\"{}\"", self.escape(s))
+    }
+}
+
+impl ShellQuote for Shell {
+    fn escape(&self, s: &str) -> String {
+        // Inside double quotes both Bash and Zsh only treat these characters
+        // specially. History expansion with `!` never happens in a script that
+        // is sourced or evaluated.
+        let special: &[char] = match self {
+            Self::Bash | Self::Zsh => &['"', '\\', '$', '`'],
+        };
+        let mut escaped = String::with_capacity(s.len());
+        for c in s.chars() {
+            if special.contains(&c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+}
+
+/// A template helper that quotes a value for the shell that the script is
+/// generated for.
+struct QuoteHelper {
+    shell: Shell,
+}
+
+impl HelperDef for QuoteHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &handlebars::Helper<'reg, 'rc>,
+        _: &'reg handlebars::Handlebars<'reg>,
+        _: &'rc handlebars::Context,
+        _: &mut handlebars::RenderContext<'reg, 'rc>,
+        out: &mut dyn handlebars::Output,
+    ) -> HelperResult {
+        let value = h
+            .param(0)
+            .and_then(|p| p.value().as_str())
+            .ok_or_else(|| RenderError::new("the `quote` helper requires a string"))?;
+        out.write(&self.shell.quote(value))
+            .map_err(RenderError::from)
+    }
+}
+
+/// Returns the condition that checks the guard variable is not set yet.
+fn guard_condition(shell: Shell) -> String {
+    match shell {
+        Shell::Bash => format!("[[ -z ${{{}+x}} ]]", GUARD_VARIABLE),
+        Shell::Zsh => format!("(( ! ${{+{}}} ))", GUARD_VARIABLE),
+    }
+}
+
+/// Returns the command that records the time that a plugin started loading.
+fn profile_start(shell: Shell) -> String {
+    match shell {
+        Shell::Bash => format!("{}=${{EPOCHREALTIME/[.,]/}}", PROFILE_VARIABLE),
+        Shell::Zsh => format!("{}=$EPOCHREALTIME", PROFILE_VARIABLE),
+    }
+}
+
+/// Returns the command that prints how long the plugin took to load.
+///
+/// Bash only supports integer arithmetic so the time is measured in whole
+/// microseconds and printed in milliseconds.
+fn profile_end(shell: Shell, name: &str) -> String {
+    let name = name.replace('\'', "'\\''");
+    match shell {
+        Shell::Bash => format!(
+            "printf '%s: %dms\\n' '{}' $(( (${{EPOCHREALTIME/[.,]/}} - {}) / 1000 )) >&2",
+            name, PROFILE_VARIABLE
+        ),
+        Shell::Zsh => format!(
+            "printf '%s: %.3fms\\n' '{}' $(( (EPOCHREALTIME - {}) * 1000 )) >&2",
+            name, PROFILE_VARIABLE
+        ),
+    }
+}
+
+/// Replace CRLF line endings with LF line endings, so that the generated script
+/// is consistent regardless of the line endings of the templates and files.
+fn normalize_line_endings(s: &str) -> String {
+    s.replace("\r\n", "\n")
+}
+
+/// The name that a plugin's own template is registered under, so that it does
+/// not clash with the global templates or the templates of other plugins.
+fn plugin_template_key(plugin: &str, name: &str) -> String {
+    format!("plugins.{}.{}", plugin, name)
+}
+
+/// Whether the given template is an unmodified built-in path template of the
+/// given shell.
+fn is_path_template(shell: Shell, name: &str, template: &Template) -> bool {
+    PATH_TEMPLATES.contains(&name) && shell.default_templates().get(name) == Some(template)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Unit tests
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    use pretty_assertions::assert_eq;
+
+    use crate::config::{InlinePlugin, Shell, Template};
+    use crate::lock::file::{LockedExternalPlugin, LockedFileGroup};
+
+    fn locked_config(ctx: &Context, plugins: Vec<LockedPlugin>) -> LockedConfig {
+        LockedConfig {
+            ctx: ctx.clone(),
+            shell: Shell::Zsh,
+            clean_ignore: Vec::new(),
+            footer: None,
+            dedupe_paths: false,
+            invalidate_compdump: false,
+            guard: false,
+            profile_startup: false,
+            trash: false,
+            imports: Vec::new(),
+            manifest: None,
+            wrap_in_function: None,
+            plugins,
+            templates: Shell::Zsh.default_templates().clone(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            migrations: Vec::new(),
+            installed: Vec::new(),
+            updated: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn locked_config_script_defer() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let locked = locked_config(
+            &ctx,
+            vec![LockedPlugin::External(LockedExternalPlugin {
+                name: "test".to_string(),
+                source_dir: "/sheldon/repos/test".into(),
+                plugin_dir: None,
+                files: vec!["/sheldon/repos/test/test.plugin.zsh".into()],
+                apply: vec_into!["defer"],
+                local: false,
+                file: None,
+                checksum: None,
+                url: None,
+                revision: None,
+                reference: None,
+                fetched: None,
+                version: None,
+                templates: IndexMap::new(),
+                groups: Vec::new(),
+                tags: Vec::new(),
+            })],
+        );
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "if (( $+functions[zsh-defer] )); then zsh-defer source \
+             \"/sheldon/repos/test/test.plugin.zsh\"; else source \
+             \"/sheldon/repos/test/test.plugin.zsh\"; fi\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_script_groups() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let locked = locked_config(
+            &ctx,
+            vec![LockedPlugin::External(LockedExternalPlugin {
+                name: "test".to_string(),
+                source_dir: "/sheldon/repos/test".into(),
+                plugin_dir: None,
+                files: vec![
+                    "/sheldon/repos/test/test.plugin.zsh".into(),
+                    "/sheldon/repos/test/completions/_test".into(),
+                ],
+                apply: vec_into!["source"],
+                local: false,
+                file: None,
+                checksum: None,
+                url: None,
+                revision: None,
+                reference: None,
+                fetched: None,
+                version: None,
+                templates: IndexMap::new(),
+                groups: vec![LockedFileGroup {
+                    files: vec!["/sheldon/repos/test/completions/_test".into()],
+                    apply: vec_into!["fpath"],
+                }],
+                tags: Vec::new(),
+            })],
+        );
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "fpath=( \"/sheldon/repos/test/completions\" $fpath )\n\
+             source \"/sheldon/repos/test/test.plugin.zsh\"\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_script_index() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let external = |name: &str| {
+            LockedPlugin::External(LockedExternalPlugin {
+                name: name.to_string(),
+                source_dir: format!("/sheldon/repos/{}", name).into(),
+                plugin_dir: None,
+                files: Vec::new(),
+                apply: vec_into!["header"],
+                local: false,
+                file: None,
+                checksum: None,
+                url: None,
+                revision: None,
+                reference: None,
+                fetched: None,
+                version: None,
+                templates: IndexMap::new(),
+                groups: Vec::new(),
+                tags: Vec::new(),
+            })
+        };
+        let mut locked = locked_config(
+            &ctx,
+            vec![
+                external("first"),
+                LockedPlugin::Inline(InlinePlugin {
+                    name: "second".to_string(),
+                    raw: "# plugin #{{ index }}: {{ name }}".to_string(),
+                    profiles: None,
+                    shell: None,
+                    tags: Vec::new(),
+                }),
+                external("third"),
+            ],
+        );
+        locked.templates.insert(
+            "header".to_string(),
+            Template::from("# plugin #{{ index }}: {{ name }}"),
+        );
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "# plugin #0: first\n# plugin #1: second\n# plugin #2: third\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_script_plugin_templates() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let external = |name: &str, templates| {
+            LockedPlugin::External(LockedExternalPlugin {
+                name: name.to_string(),
+                source_dir: format!("/sheldon/repos/{}", name).into(),
+                plugin_dir: None,
+                files: Vec::new(),
+                apply: vec_into!["header"],
+                local: false,
+                file: None,
+                checksum: None,
+                url: None,
+                revision: None,
+                reference: None,
+                fetched: None,
+                version: None,
+                templates,
+                groups: Vec::new(),
+                tags: Vec::new(),
+            })
+        };
+        let mut locked = locked_config(
+            &ctx,
+            vec![
+                external(
+                    "first",
+                    indexmap::indexmap! {
+                        "header".to_string() => Template::from("# local: {{ name }}"),
+                    },
+                ),
+                external("second", IndexMap::new()),
+            ],
+        );
+        locked
+            .templates
+            .insert("header".to_string(), Template::from("# global: {{ name }}"));
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "# local: first\n# global: second\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_script_shell() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let mut locked = locked_config(
+            &ctx,
+            vec![LockedPlugin::External(LockedExternalPlugin {
+                name: "test".to_string(),
+                source_dir: "/sheldon/repos/test".into(),
+                plugin_dir: None,
+                files: Vec::new(),
+                apply: vec_into!["complete"],
+                local: false,
+                file: None,
+                checksum: None,
+                url: None,
+                revision: None,
+                reference: None,
+                fetched: None,
+                version: None,
+                templates: IndexMap::new(),
+                groups: Vec::new(),
+                tags: Vec::new(),
+            })],
+        );
+        locked.templates.insert(
+            "complete".to_string(),
+            Template::from(
+                "{{#if (eq shell \"zsh\")}}fpath+=( \"{{ dir }}\" ){{else}}source \"{{ dir \
+                 }}/{{ name }}.bash\"{{/if}}",
+            ),
+        );
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "fpath+=( \"/sheldon/repos/test\" )\n"
+        );
+        locked.shell = Shell::Bash;
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "source \"/sheldon/repos/test/test.bash\"\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_script_footer() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let mut locked = locked_config(
+            &ctx,
+            vec![
+                LockedPlugin::External(LockedExternalPlugin {
+                    name: "test".to_string(),
+                    source_dir: "/sheldon/repos/test".into(),
+                    plugin_dir: None,
+                    files: Vec::new(),
+                    apply: vec_into!["fpath"],
+                    local: false,
+                    file: None,
+                    checksum: None,
+                    url: None,
+                    revision: None,
+                    reference: None,
+                    fetched: None,
+                    version: None,
+                    templates: IndexMap::new(),
+                    groups: Vec::new(),
+                    tags: Vec::new(),
+                }),
+                LockedPlugin::Inline(InlinePlugin {
+                    name: "inline".to_string(),
+                    raw: "echo 'inline'".to_string(),
+                    profiles: None,
+                    shell: None,
+                    tags: Vec::new(),
+                }),
+            ],
+        );
+        locked.footer = Some("autoload -Uz compinit && compinit".to_string());
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "fpath=( \"/sheldon/repos/test\" $fpath )\necho 'inline'\nautoload -Uz compinit && \
+             compinit\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_script_crlf_line_endings() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let mut locked = locked_config(
+            &ctx,
+            vec![LockedPlugin::Inline(InlinePlugin {
+                name: "inline".to_string(),
+                raw: "echo 'first'\r\necho 'second'\r\n".to_string(),
+                profiles: None,
+                shell: None,
+                tags: Vec::new(),
+            })],
+        );
+        locked.footer = Some("autoload -Uz compinit\r\ncompinit".to_string());
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "echo 'first'\necho 'second'\n\nautoload -Uz compinit\ncompinit\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_script_apply_override() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let locked = locked_config(
+            &ctx,
+            vec![LockedPlugin::External(LockedExternalPlugin {
+                name: "test".to_string(),
+                source_dir: "/sheldon/repos/test".into(),
+                plugin_dir: None,
+                files: vec!["/sheldon/repos/test/test.plugin.zsh".into()],
+                apply: vec_into!["source"],
+                local: false,
+                file: None,
+                checksum: None,
+                url: None,
+                revision: None,
+                reference: None,
+                fetched: None,
+                version: None,
+                templates: IndexMap::new(),
+                groups: Vec::new(),
+                tags: Vec::new(),
+            })],
+        );
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "source \"/sheldon/repos/test/test.plugin.zsh\"\n"
+        );
+        assert_eq!(
+            locked
+                .script(
+                    &ctx,
+                    Some(&["fpath".to_string(), "source".to_string()]),
+                    &[],
+                )
+                .unwrap(),
+            "fpath=( \"/sheldon/repos/test\" $fpath )\nsource \"/sheldon/repos/test/test.plugin.zsh\"\n"
+        );
+        let err = locked
+            .script(&ctx, Some(&["unknown".to_string()]), &[])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "unknown template `unknown`");
+    }
+
+    #[test]
+    fn locked_config_measure() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let mut locked = locked_config(
+            &ctx,
+            vec![
+                LockedPlugin::External(LockedExternalPlugin {
+                    name: "test".to_string(),
+                    source_dir: "/sheldon/repos/test".into(),
+                    plugin_dir: None,
+                    files: vec!["/sheldon/repos/test/test.plugin.zsh".into()],
+                    apply: vec_into!["source", "PATH"],
+                    local: false,
+                    file: None,
+                    checksum: None,
+                    url: None,
+                    revision: None,
+                    reference: None,
+                    fetched: None,
+                    version: None,
+                    templates: IndexMap::new(),
+                    groups: Vec::new(),
+                    tags: Vec::new(),
+                }),
+                LockedPlugin::Inline(InlinePlugin {
+                    name: "inline".to_string(),
+                    raw: "echo 'inline'".to_string(),
+                    profiles: None,
+                    shell: None,
+                    tags: Vec::new(),
+                }),
+            ],
+        );
+        locked.footer = Some("autoload -Uz compinit && compinit".to_string());
+        locked.dedupe_paths = true;
+        locked.guard = true;
+
+        let (script, measurement) = locked.measure(&ctx, None, &[]).unwrap();
+        assert_eq!(script, locked.script(&ctx, None, &[]).unwrap());
+        assert_eq!(
+            measurement.plugins,
+            vec![
+                (
+                    "test".to_string(),
+                    "source \"/sheldon/repos/test/test.plugin.zsh\"\n".len()
+                ),
+                ("inline".to_string(), "echo 'inline'\n".len()),
+            ]
+        );
+        assert!(measurement.other > 0);
+        assert_eq!(measurement.total(), script.len());
+    }
+
+    #[test]
+    fn locked_config_script_manifest() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let mut locked = locked_config(
+            &ctx,
+            vec![
+                LockedPlugin::External(LockedExternalPlugin {
+                    name: "test".to_string(),
+                    source_dir: "/sheldon/repos/test".into(),
+                    plugin_dir: None,
+                    files: vec_into![
+                        "/sheldon/repos/test/a.plugin.zsh",
+                        "/sheldon/repos/test/b.plugin.zsh"
+                    ],
+                    apply: Vec::new(),
+                    local: false,
+                    file: None,
+                    checksum: None,
+                    url: None,
+                    revision: None,
+                    reference: None,
+                    fetched: None,
+                    version: None,
+                    templates: IndexMap::new(),
+                    groups: Vec::new(),
+                    tags: Vec::new(),
+                }),
+                LockedPlugin::Inline(InlinePlugin {
+                    name: "inline".to_string(),
+                    raw: "echo 'inline'".to_string(),
+                    profiles: None,
+                    shell: None,
+                    tags: Vec::new(),
+                }),
+            ],
+        );
+        locked.footer = Some("echo 'footer'".to_string());
+        locked.manifest = Some(Manifest::default());
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "echo 'inline'\nexport SHELDON_PLUGINS=\"test:inline\"\necho 'footer'\n"
+        );
+
+        locked.manifest = Some(Manifest {
+            variable: "PLUGIN_FILES".into(),
+            separator: " ".into(),
+            files: true,
+        });
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "echo 'inline'\nexport PLUGIN_FILES=\"/sheldon/repos/test/a.plugin.zsh \
+             /sheldon/repos/test/b.plugin.zsh\"\necho 'footer'\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_script_dedupe_paths() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let external = |name: &str, dir: &str| {
+            LockedPlugin::External(LockedExternalPlugin {
+                name: name.to_string(),
+                source_dir: format!("/sheldon/repos/{}", dir).into(),
+                plugin_dir: None,
+                files: Vec::new(),
+                apply: vec_into!["PATH", "fpath"],
+                local: false,
+                file: None,
+                checksum: None,
+                url: None,
+                revision: None,
+                reference: None,
+                fetched: None,
+                version: None,
+                templates: IndexMap::new(),
+                groups: Vec::new(),
+                tags: Vec::new(),
+            })
+        };
+        let mut locked = locked_config(
+            &ctx,
+            vec![
+                external("first", "a"),
+                external("second", "b"),
+                external("third", "a"),
+            ],
+        );
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "export PATH=\"/sheldon/repos/a\":\"$PATH\"\n\
+             fpath=( \"/sheldon/repos/a\" $fpath )\n\
+             export PATH=\"/sheldon/repos/b\":\"$PATH\"\n\
+             fpath=( \"/sheldon/repos/b\" $fpath )\n\
+             export PATH=\"/sheldon/repos/a\":\"$PATH\"\n\
+             fpath=( \"/sheldon/repos/a\" $fpath )\n"
+        );
+
+        locked.dedupe_paths = true;
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "export PATH=\"/sheldon/repos/a\":\"/sheldon/repos/b\":\"$PATH\"\n\
+             fpath=( \"/sheldon/repos/a\" \"/sheldon/repos/b\" $fpath )\n"
+        );
+
+        // Bash has no built-in `fpath` template, so one that is configured is
+        // rendered for each plugin.
+        locked.shell = Shell::Bash;
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "fpath=( \"/sheldon/repos/a\" $fpath )\n\
+             fpath=( \"/sheldon/repos/b\" $fpath )\n\
+             fpath=( \"/sheldon/repos/a\" $fpath )\n\
+             export PATH=\"/sheldon/repos/a\":\"/sheldon/repos/b\":\"$PATH\"\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_script_invalidate_compdump() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let mut locked = locked_config(&ctx, Vec::new());
+        locked.footer = Some("compinit".to_string());
+        assert_eq!(locked.script(&ctx, None, &[]).unwrap(), "compinit\n");
+
+        locked.invalidate_compdump = true;
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            r#"() {
+  local dump="${ZSH_COMPDUMP:-${ZDOTDIR:-$HOME}/.zcompdump}"
+  if [[ "/sheldon/config.lock" -nt "$dump" ]]; then
+    rm -f "$dump" "$dump.zwc"
+  fi
+}
+compinit
+"#
+        );
+
+        locked.shell = Shell::Bash;
+        assert_eq!(locked.script(&ctx, None, &[]).unwrap(), "compinit\n");
+    }
+
+    #[test]
+    fn locked_config_script_guard() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let mut locked = locked_config(
+            &ctx,
+            vec![LockedPlugin::Inline(InlinePlugin {
+                name: "test".to_string(),
+                raw: "echo 'test'".to_string(),
+                profiles: None,
+                shell: None,
+                tags: Vec::new(),
+            })],
+        );
+        locked.guard = true;
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "if (( ! ${+_SHELDON_SOURCED} )); then\n\
+             _SHELDON_SOURCED=1\n\
+             echo 'test'\n\
+             fi\n"
+        );
+
+        locked.shell = Shell::Bash;
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "if [[ -z ${_SHELDON_SOURCED+x} ]]; then\n\
+             _SHELDON_SOURCED=1\n\
+             echo 'test'\n\
+             fi\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_script_wrap_in_function() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let mut locked = locked_config(
+            &ctx,
+            vec![LockedPlugin::Inline(InlinePlugin {
+                name: "test".to_string(),
+                raw: "echo 'test'".to_string(),
+                profiles: None,
+                shell: None,
+                tags: Vec::new(),
+            })],
+        );
+        locked.wrap_in_function = Some("sheldon_load".into());
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "sheldon_load() {\n\
+             echo 'test'\n\
+             }\n"
+        );
+
+        locked.shell = Shell::Bash;
+        locked.guard = true;
+        locked.wrap_in_function = Some("load_plugins".into());
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "load_plugins() {\n\
+             if [[ -z ${_SHELDON_SOURCED+x} ]]; then\n\
+             _SHELDON_SOURCED=1\n\
+             echo 'test'\n\
+             fi\n\
+             }\n"
+        );
+
+        locked.guard = false;
+        locked.plugins.clear();
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "load_plugins() {\n:\n}\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_script_profile_startup() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let mut locked = locked_config(
+            &ctx,
+            vec![
+                LockedPlugin::External(LockedExternalPlugin {
+                    name: "test".to_string(),
+                    source_dir: "/sheldon/repos/test".into(),
+                    plugin_dir: None,
+                    files: vec!["/sheldon/repos/test/test.plugin.zsh".into()],
+                    apply: vec_into!["source"],
+                    local: false,
+                    file: None,
+                    checksum: None,
+                    url: None,
+                    revision: None,
+                    reference: None,
+                    fetched: None,
+                    version: None,
+                    templates: IndexMap::new(),
+                    groups: Vec::new(),
+                    tags: Vec::new(),
+                }),
+                LockedPlugin::Inline(InlinePlugin {
+                    name: "inline".to_string(),
+                    raw: "echo 'inline'".to_string(),
+                    profiles: None,
+                    shell: None,
+                    tags: Vec::new(),
+                }),
+            ],
+        );
+        locked.profile_startup = true;
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            r#"zmodload zsh/datetime
+_sheldon_start=$EPOCHREALTIME
+source "/sheldon/repos/test/test.plugin.zsh"
+printf '%s: %.3fms\n' 'test' $(( (EPOCHREALTIME - _sheldon_start) * 1000 )) >&2
+_sheldon_start=$EPOCHREALTIME
+echo 'inline'
+printf '%s: %.3fms\n' 'inline' $(( (EPOCHREALTIME - _sheldon_start) * 1000 )) >&2
+unset _sheldon_start
+"#
+        );
+
+        locked.shell = Shell::Bash;
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            r#"_sheldon_start=${EPOCHREALTIME/[.,]/}
+source "/sheldon/repos/test/test.plugin.zsh"
+printf '%s: %dms\n' 'test' $(( (${EPOCHREALTIME/[.,]/} - _sheldon_start) / 1000 )) >&2
+_sheldon_start=${EPOCHREALTIME/[.,]/}
+echo 'inline'
+printf '%s: %dms\n' 'inline' $(( (${EPOCHREALTIME/[.,]/} - _sheldon_start) / 1000 )) >&2
+unset _sheldon_start
+"#
+        );
+    }
+
+    /// A keyring that stores its secrets in memory.
+    struct MockKeyring(Vec<(&'static str, &'static str)>);
+
+    impl Keyring for MockKeyring {
+        fn get(&self, name: &str) -> Result<Option<String>> {
+            Ok(self
+                .0
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, secret)| secret.to_string()))
+        }
+    }
+
+    fn secret_config(ctx: &Context) -> LockedConfig {
+        let mut locked = locked_config(
+            ctx,
+            vec![LockedPlugin::External(LockedExternalPlugin {
+                name: "test".to_string(),
+                source_dir: "/sheldon/repos/test".into(),
+                plugin_dir: None,
+                files: Vec::new(),
+                apply: vec_into!["token"],
+                local: false,
+                file: None,
+                checksum: None,
+                url: None,
+                revision: None,
+                reference: None,
+                fetched: None,
+                version: None,
+                templates: IndexMap::new(),
+                groups: Vec::new(),
+                tags: Vec::new(),
+            })],
+        );
+        locked.templates.insert(
+            "token".to_string(),
+            Template::from("export TEST_TOKEN=\"{{ secret \"test-token\" }}\""),
+        );
+        locked
+    }
+
+    #[test]
+    fn locked_config_script_secret() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let locked = secret_config(&ctx);
+        let keyring = MockKeyring(vec![("test-token", "hunter2")]);
+        assert_eq!(
+            locked.script_with(&ctx, &keyring).unwrap(),
+            "export TEST_TOKEN=\"hunter2\"\n"
+        );
+        assert!(!toml::to_string(&locked).unwrap().contains("hunter2"));
+    }
+
+    #[test]
+    fn locked_config_script_secret_missing() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let locked = secret_config(&ctx);
+        let keyring = MockKeyring(Vec::new());
+        let err = locked.script_with(&ctx, &keyring).unwrap_err();
+        assert_eq!(err.to_string(), "failed to render template `token`");
+        assert!(format!("{:#}", err).contains("secret `test-token` not found in the keyring"));
+    }
+
+    #[test]
+    fn shell_quote_tricky_path() {
+        let path = r#"/my "plugins"/$HOME/`id`/back\slash/it's!"#;
+        for shell in [Shell::Bash, Shell::Zsh] {
+            assert_eq!(
+                shell.quote(path),
+                r#""/my \"plugins\"/\$HOME/\`id\`/back\\slash/it's!""#,
+                "{}",
+                shell
+            );
+        }
+    }
+
+    #[test]
+    fn locked_config_script_quotes_tricky_path() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        let mut locked = locked_config(
+            &ctx,
+            vec![LockedPlugin::External(LockedExternalPlugin {
+                name: "test".to_string(),
+                source_dir: "/sheldon/repos/te\"$t".into(),
+                plugin_dir: None,
+                files: vec!["/sheldon/repos/te\"$t/te`s`t.zsh".into()],
+                apply: vec_into!["source", "defer"],
+                local: false,
+                file: None,
+                checksum: None,
+                url: None,
+                revision: None,
+                reference: None,
+                fetched: None,
+                version: None,
+                templates: IndexMap::new(),
+                groups: Vec::new(),
+                tags: Vec::new(),
+            })],
+        );
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "source \"/sheldon/repos/te\\\"\\$t/te\\`s\\`t.zsh\"\n\
+             if (( $+functions[zsh-defer] )); then zsh-defer source \
+             \"/sheldon/repos/te\\\"\\$t/te\\`s\\`t.zsh\"; else source \
+             \"/sheldon/repos/te\\\"\\$t/te\\`s\\`t.zsh\"; fi\n"
+        );
+        locked.shell = Shell::Bash;
+        locked.templates = Shell::Bash.default_templates().clone();
+        if let LockedPlugin::External(plugin) = &mut locked.plugins[0] {
+            plugin.apply = vec_into!["source"];
+        }
+        assert_eq!(
+            locked.script(&ctx, None, &[]).unwrap(),
+            "source \"/sheldon/repos/te\\\"\\$t/te\\`s\\`t.zsh\"\n"
+        );
+    }
+
+    #[test]
+    fn check_syntax_broken_template() {
+        let ctx = Context::testing(Path::new("/sheldon"));
+        for shell in [Shell::Bash, Shell::Zsh] {
+            if Command::new(shell.to_string())
+                .arg("--version")
+                .output()
+                .is_err()
+            {
+                continue;
+            }
+            let mut locked = locked_config(
+                &ctx,
+                vec![LockedPlugin::External(LockedExternalPlugin {
+                    name: "test".to_string(),
+                    source_dir: "/sheldon/repos/test".into(),
+                    plugin_dir: None,
+                    files: vec!["/sheldon/repos/test/test.plugin.zsh".into()],
+                    apply: vec_into!["source"],
+                    local: false,
+                    file: None,
+                    checksum: None,
+                    url: None,
+                    revision: None,
+                    reference: None,
+                    fetched: None,
+                    version: None,
+                    templates: IndexMap::new(),
+                    groups: Vec::new(),
+                    tags: Vec::new(),
+                })],
+            );
+            locked.shell = shell;
+            let script = locked.script(&ctx, None, &[]).unwrap();
+            check_syntax(shell, &script).unwrap();
+
+            locked.templates.insert(
+                "source".to_string(),
+                Template::from("if [[ -f {{ quote file }} ]]; then source {{ quote file }}")
+                    .each(true),
+            );
+            let script = locked.script(&ctx, None, &[]).unwrap();
+            let err = check_syntax(shell, &script).unwrap_err();
+            assert!(err
+                .to_string()
+                .starts_with(&format!("script does not parse with `{} -n`", shell)));
+        }
+    }
+
+    #[test]
+    fn check_syntax_large_broken_script() {
+        for shell in [Shell::Bash, Shell::Zsh] {
+            if Command::new(shell.to_string())
+                .arg("--version")
+                .output()
+                .is_err()
+            {
+                continue;
+            }
+            // Much larger than a pipe buffer, so the shell exits before all of
+            // it has been written.
+            let script = format!("fi\n{}", "echo test\n".repeat(100_000));
+            let err = check_syntax(shell, &script).unwrap_err();
+            assert!(err
+                .to_string()
+                .starts_with(&format!("script does not parse with `{} -n`", shell)));
+        }
     }
 }
@@ -1,12 +1,18 @@
 use std::fmt;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::{Context as ResultExt, Result};
+use anyhow::{anyhow, bail, Context as ResultExt, Result};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use url::Url;
+use walkdir::WalkDir;
 
 use crate::config::GitReference;
 use crate::context::Context;
-use crate::lock::source::LockedSource;
+use crate::lock::source::{LockedSource, Session};
 use crate::lock::LockMode;
 use crate::util::git;
 use crate::util::TempPath;
@@ -15,86 +21,619 @@ use crate::util::TempPath;
 pub enum GitCheckout {
     /// Checkout the latest of the default branch (HEAD).
     DefaultBranch,
+    /// Checkout the latest of the default branch, explicitly configured using
+    /// `branch = "HEAD"`.
+    Head,
     /// Checkout the tip of a branch.
     Branch(String),
     /// Checkout a specific revision.
     Rev(String),
     /// Checkout a tag.
     Tag(String),
+    /// Checkout the first of the given checkouts that resolves.
+    Fallback(Vec<GitCheckout>),
+    /// Checkout the newest commit on a branch, or the default branch, from
+    /// before a date.
+    Before {
+        branch: Option<String>,
+        date: String,
+    },
 }
 
 /// Clones a Git repository and checks it out at a particular revision.
-pub fn lock(ctx: &Context, dir: PathBuf, url: &Url, c: GitCheckout) -> Result<LockedSource> {
+///
+/// If `trusted_keys` is given then the signature of the tag or commit is
+/// verified before it is checked out, see [`verify_signature`]. A revision
+/// that fails verification is never checked out, and a new clone that fails
+/// verification is never moved into place.
+pub fn lock(
+    ctx: &Context,
+    session: &Session,
+    dir: PathBuf,
+    url: &Url,
+    c: GitCheckout,
+    trusted_keys: Option<&[String]>,
+) -> Result<LockedSource> {
     match ctx.lock_mode() {
-        LockMode::Normal => match git::open(&dir) {
-            Ok(repo) => {
-                if checkout(ctx, &repo, url, c.clone()).is_err() {
-                    git::fetch(&repo)?;
-                    checkout(ctx, &repo, url, c)?;
+        LockMode::Normal => match open_existing(ctx, &dir) {
+            Some(repo) => {
+                let previous = session.previous.iter().find(|p| p.dir == dir);
+                if let Some(checkout) =
+                    checkout_pinned(ctx, &repo, url, &c, previous, trusted_keys)?
+                {
+                    return locked(dir, &repo, &checkout);
                 }
-                Ok(LockedSource { dir, file: None })
+                // Fetch if the revision is missing, but not if it is untrusted.
+                let checkout = match checkout(ctx, &repo, url, c.clone(), trusted_keys) {
+                    Ok(checkout) => checkout,
+                    Err(err) if err.is::<Untrusted>() => return Err(err),
+                    Err(_) => {
+                        fetch(ctx, &repo)?;
+                        checkout(ctx, &repo, url, c, trusted_keys)?
+                    }
+                };
+                locked(dir, &repo, &checkout)
             }
-            Err(_) => install(ctx, dir, url, c),
+            None => install(ctx, dir, url, c, trusted_keys),
         },
-        LockMode::Update => match git::open(&dir) {
-            Ok(repo) => {
-                git::fetch(&repo)?;
-                checkout(ctx, &repo, url, c)?;
-                Ok(LockedSource { dir, file: None })
+        LockMode::Update => match open_existing(ctx, &dir) {
+            Some(repo) => {
+                // Only fetch if the remote has changed, this can be checked with
+                // a single cached query for all sources with the same URL.
+                let up_to_date =
+                    ls_remote(ctx, session, url).is_ok_and(|refs| git::is_up_to_date(&repo, &refs));
+                if !up_to_date {
+                    fetch(ctx, &repo)?;
+                }
+                let checkout = match ctx.update_max_age() {
+                    Some(age) => checkout_older_than(ctx, &repo, url, c, age, trusted_keys)?,
+                    None => checkout(ctx, &repo, url, c, trusted_keys)?,
+                };
+                locked(dir, &repo, &checkout)
             }
-            Err(_) => install(ctx, dir, url, c),
+            None => install(ctx, dir, url, c, trusted_keys),
         },
-        LockMode::Reinstall => install(ctx, dir, url, c),
+        LockMode::Reinstall => install(ctx, dir, url, c, trusted_keys),
+    }
+}
+
+/// Returns the locked source for a clone, recording the commit that is checked
+/// out and the reference it was resolved from.
+fn locked(dir: PathBuf, repo: &git2::Repository, checkout: &GitCheckout) -> Result<LockedSource> {
+    let oid = repo.head()?.target().context("current HEAD as no target")?;
+    Ok(LockedSource {
+        dir,
+        file: None,
+        revision: Some(oid.to_string()),
+        reference: checkout.reference(repo),
+        fetched: None,
+    })
+}
+
+/// Produced when a tag or commit does not have a trusted signature.
+#[derive(Debug, Error)]
+#[error("{0:#}")]
+struct Untrusted(anyhow::Error);
+
+/// Verify that a tag or commit in a repository is signed by a key that Git
+/// trusts, and if any keys are given, by one of those keys.
+///
+/// Tags are verified using the tag object, other checkouts verify the commit.
+/// Nothing is verified if `trusted_keys` is not given.
+fn verify_signature(
+    ctx: &Context,
+    repo: &git2::Repository,
+    checkout: &GitCheckout,
+    oid: git2::Oid,
+    trusted_keys: Option<&[String]>,
+) -> Result<()> {
+    let keys = match trusted_keys {
+        Some(keys) => keys,
+        None => return Ok(()),
+    };
+    let dir = repo.workdir().unwrap_or_else(|| repo.path());
+    let tag = match checkout {
+        GitCheckout::Tag(tag) => Some(tag.as_str()),
+        _ => None,
+    };
+    let args = match tag {
+        Some(tag) => format!("verify-tag --raw {}", tag),
+        None => format!("verify-commit --raw {}", oid),
+    };
+    trace_in(ctx, repo, &args);
+    let fingerprints = git::verify_signature(dir, tag, oid, ctx.gnupg_home()).map_err(Untrusted)?;
+    // Key IDs are the end of the fingerprint.
+    if !keys.is_empty()
+        && !fingerprints
+            .iter()
+            .any(|f| keys.iter().any(|k| f.ends_with(k.as_str())))
+    {
+        return Err(Untrusted(anyhow!(
+            "the signature of `{}` was made by key `{}`, which is not one of the trusted keys",
+            &oid.to_string()[..7],
+            fingerprints[0]
+        ))
+        .into());
+    }
+    status_v!(
+        ctx,
+        "Verified",
+        &format!("{} signed by {}", &oid.to_string()[..7], fingerprints[0])
+    );
+    Ok(())
+}
+
+/// Whether the given directory contains a complete clone.
+///
+/// A clone that was interrupted can leave behind a directory that is not a
+/// repository, or a repository without a checked out commit.
+pub fn is_installed(dir: &Path) -> bool {
+    git::open(dir).is_ok_and(|repo| git::has_head(&repo))
+}
+
+/// Open the existing clone in the given directory.
+///
+/// Returns `None` if there is no clone, or if the clone is incomplete, in which
+/// case it needs to be cloned again.
+fn open_existing(ctx: &Context, dir: &Path) -> Option<git2::Repository> {
+    match git::open(dir) {
+        Ok(repo) if git::has_head(&repo) => Some(repo),
+        _ if dir.exists() => {
+            warning!(
+                ctx,
+                "Repairing",
+                &format!("incomplete clone {}", ctx.replace_home(dir).display())
+            );
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Checks out an existing clone at a revision that it was locked at before.
+///
+/// The revision is not verified again.
+pub fn restore(ctx: &Context, dir: &Path, url: &Url, c: GitCheckout) -> Result<()> {
+    let repo = git::open(dir)?;
+    checkout(ctx, &repo, url, c, None)?;
+    Ok(())
+}
+
+/// Fails if the existing clone in the given directory has uncommitted changes.
+///
+/// Reinstalling replaces the clone, so it is never checked.
+pub fn check_clean(ctx: &Context, dir: &Path) -> Result<()> {
+    if matches!(ctx.lock_mode(), LockMode::Reinstall) {
+        return Ok(());
+    }
+    if let Ok(repo) = git::open(dir) {
+        if git::is_dirty(&repo)? {
+            bail!(
+                "the working tree of `{}` has uncommitted changes and `require_clean` is set",
+                ctx.replace_home(dir).display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a Git repository exists and has the given reference, without
+/// cloning it.
+///
+/// Revisions can not be checked without fetching them, so only the repository
+/// is checked for them.
+pub fn check_remote(ctx: &Context, session: &Session, url: &Url, c: GitCheckout) -> Result<()> {
+    let refs: Vec<_> = ls_remote(ctx, session, url)?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    if c.is_advertised(&refs) {
+        return Ok(());
+    }
+    let available = |prefix: &str| {
+        let names: Vec<_> = refs
+            .iter()
+            .filter_map(|r| r.strip_prefix(prefix))
+            .filter(|r| !r.ends_with("^{}"))
+            .map(|r| format!("`{}`", r))
+            .collect();
+        if names.is_empty() {
+            "none".to_string()
+        } else {
+            names.join(", ")
+        }
+    };
+    bail!(
+        "failed to find `{}` in `{}`, available branches: {}; available tags: {}",
+        c.to_string().trim_start_matches('@'),
+        url,
+        available("refs/heads/"),
+        available("refs/tags/")
+    );
+}
+
+/// List the references of a remote repository, at most once per run.
+fn ls_remote(ctx: &Context, session: &Session, url: &Url) -> Result<git::RemoteRefs> {
+    session.remote_refs.get_or_query(url, || {
+        trace!(
+            ctx,
+            "Running",
+            &format!("git ls-remote {}", git::redact_url(url))
+        );
+        git::ls_remote(url, ctx.credential_helper())
+    })
+}
+
+/// Exports a Git repository at a particular revision, without the `.git`
+/// directory.
+///
+/// Since there is no repository to check, an existing export is compared with
+/// the reference recorded in the previous lock file, and is only replaced if it
+/// was exported at a different reference, or when updating or reinstalling. If
+/// `no_history` is set then only the tree is fetched using `git archive`,
+/// falling back to cloning if that fails.
+pub fn export(
+    ctx: &Context,
+    session: &Session,
+    dir: PathBuf,
+    url: &Url,
+    c: GitCheckout,
+    no_history: bool,
+) -> Result<LockedSource> {
+    let exported = dir.is_dir() && !dir.join(".git").exists();
+    if matches!(ctx.lock_mode(), LockMode::Normal) && exported {
+        let previous = session.previous.iter().find(|p| p.dir == dir);
+        match previous.and_then(|p| Some((p, p.reference.as_deref()?))) {
+            Some((previous, reference)) if c.is_exported_at(ctx, session, url, reference) => {
+                status!(ctx, "Checked", &format!("{}{}", url, c));
+                return Ok(previous.clone());
+            }
+            _ => status_v!(
+                ctx,
+                "Replacing",
+                &format!(
+                    "{}, it was not exported at {}{}",
+                    ctx.replace_home(&dir).display(),
+                    url,
+                    c
+                )
+            ),
+        }
+    }
+    let temp_dir =
+        TempPath::new_force(&dir).context("failed to prepare temporary clone directory")?;
+    let (mut locked, description) = export_tree(ctx, url, c, temp_dir.path(), no_history)?;
+    rename(ctx, temp_dir, &dir)?;
+    match locked.fetched {
+        Some(Fetched::Archive) => status!(ctx, "Archived", &description),
+        _ => status!(ctx, "Exported", &description),
     }
+    locked.dir = dir;
+    Ok(locked)
+}
+
+/// How the tree of an exported Git source was fetched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fetched {
+    /// Only the tree was fetched using `git archive`.
+    Archive,
+    /// The repository was cloned and then its `.git` directories removed.
+    Clone,
+}
+
+/// Fetch the tree of a Git repository at a particular revision into the given
+/// directory.
+///
+/// Returns the locked source for the directory, which records how the tree was
+/// fetched, and a description of the checkout for displaying. The commit is
+/// only known if the repository was cloned.
+fn export_tree(
+    ctx: &Context,
+    url: &Url,
+    c: GitCheckout,
+    dir: &Path,
+    no_history: bool,
+) -> Result<(LockedSource, String)> {
+    if let Some(treeish) = c.treeish().filter(|_| no_history) {
+        trace!(
+            ctx,
+            "Running",
+            &format!(
+                "git archive --format=tar --remote={} -- {}",
+                git::redact_url(url),
+                treeish
+            )
+        );
+        match git::archive_remote(url, treeish, dir) {
+            Ok(()) => {
+                let locked = LockedSource {
+                    dir: dir.to_path_buf(),
+                    file: None,
+                    revision: None,
+                    reference: Some(treeish.to_string()),
+                    fetched: Some(Fetched::Archive),
+                };
+                return Ok((locked, format!("{}{}", url, c)));
+            }
+            Err(err) => {
+                status_v!(ctx, "Falling back", &format!("to cloning {}, {}", url, err));
+                if dir.exists() {
+                    fs::remove_dir_all(dir)
+                        .with_context(s!("failed to remove `{}`", dir.display()))?;
+                    trace!(ctx, "Removed", &ctx.replace_home(dir).display());
+                }
+            }
+        }
+    }
+    let (mut locked, description) = {
+        let repo = clone(ctx, url, dir)?;
+        let (checkout, oid) = c.resolve(&repo)?;
+        update(ctx, &repo, oid)?;
+        let locked = locked(dir.to_path_buf(), &repo, checkout)?;
+        (locked, describe(&repo, url, checkout, oid))
+    };
+    remove_git_dirs(ctx, dir).context("failed to remove `.git` directories")?;
+    locked.fetched = Some(Fetched::Clone);
+    Ok((locked, description))
+}
+
+/// Remove the `.git` directory, and those of any submodules, from a clone.
+fn remove_git_dirs(ctx: &Context, dir: &Path) -> Result<()> {
+    let git_dirs: Vec<_> = WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || e.file_name() != ".git")
+        .filter_map(result::Result::ok)
+        .filter(|e| e.file_type().is_dir())
+        .map(|e| e.path().join(".git"))
+        .filter(|p| p.symlink_metadata().is_ok())
+        .collect();
+    for path in git_dirs {
+        if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        }
+        .with_context(s!("failed to remove `{}`", path.display()))?;
+        trace!(ctx, "Removed", &ctx.replace_home(&path).display());
+    }
+    Ok(())
 }
 
 /// Checks if a repository is correctly checked out, if not checks it out.
+///
+/// Returns the checkout that was actually used.
 fn checkout(
     ctx: &Context,
     repo: &git2::Repository,
     url: &Url,
     checkout: GitCheckout,
-) -> Result<()> {
+    trusted_keys: Option<&[String]>,
+) -> Result<GitCheckout> {
     let current_oid = repo.head()?.target().context("current HEAD as no target")?;
-    let expected_oid = checkout.resolve(repo)?;
+    let (checkout, expected_oid) = checkout.resolve(repo)?;
+    verify_signature(ctx, repo, checkout, expected_oid, trusted_keys)?;
     if current_oid == expected_oid {
-        status!(ctx, "Checked", &format!("{}{}", url, checkout))
+        status!(ctx, "Checked", &describe(repo, url, checkout, expected_oid))
     } else {
-        git::checkout(repo, expected_oid)?;
-        git::submodule_update(repo).context("failed to recursively update")?;
+        update(ctx, repo, expected_oid)?;
         status!(
             ctx,
             "Updated",
             &format!(
-                "{}{} ({} to {})",
-                url,
-                checkout,
+                "{} ({} to {})",
+                describe(repo, url, checkout, expected_oid),
                 &current_oid.to_string()[..7],
                 &expected_oid.to_string()[..7]
             )
         );
     }
-    Ok(())
+    Ok(checkout.clone())
+}
+
+/// Checks if a repository is still at the commit recorded in the previous lock
+/// file while the tip of its branch has moved on.
+///
+/// This happens after updating with a maximum age, when the fetched tip was too
+/// recent to check out. Only updating moves a branch forward, so the recorded
+/// commit is kept. Returns `None` if the repository should be checked out as
+/// usual.
+fn checkout_pinned(
+    ctx: &Context,
+    repo: &git2::Repository,
+    url: &Url,
+    c: &GitCheckout,
+    previous: Option<&LockedSource>,
+    trusted_keys: Option<&[String]>,
+) -> Result<Option<GitCheckout>> {
+    let current_oid = repo.head()?.target().context("current HEAD as no target")?;
+    let previous = match previous {
+        Some(previous) if previous.revision == Some(current_oid.to_string()) => previous,
+        _ => return Ok(None),
+    };
+    let (resolved, tip) = match c.resolve(repo) {
+        Ok(resolved) => resolved,
+        Err(_) => return Ok(None),
+    };
+    let is_branch = matches!(
+        resolved,
+        GitCheckout::DefaultBranch | GitCheckout::Head | GitCheckout::Branch(_)
+    );
+    if !is_branch
+        || tip == current_oid
+        || previous.reference != resolved.reference(repo)
+        || !git::is_descendant_of(repo, tip, current_oid)
+    {
+        return Ok(None);
+    }
+    verify_signature(ctx, repo, resolved, current_oid, trusted_keys)?;
+    status!(ctx, "Checked", &describe(repo, url, resolved, current_oid));
+    Ok(Some(resolved.clone()))
+}
+
+/// Checks out the newest commit of a reference that is at least the given age,
+/// if it is newer than the current commit.
+///
+/// If there is no such commit then the repository stays at the current commit.
+/// A reference that does not contain the current commit, for example because
+/// the configured branch changed, is checked out as usual.
+fn checkout_older_than(
+    ctx: &Context,
+    repo: &git2::Repository,
+    url: &Url,
+    c: GitCheckout,
+    age: Duration,
+    trusted_keys: Option<&[String]>,
+) -> Result<GitCheckout> {
+    let current_oid = repo.head()?.target().context("current HEAD as no target")?;
+    let (resolved, tip) = c.resolve(repo)?;
+    if tip == current_oid || !git::is_descendant_of(repo, tip, current_oid) {
+        return checkout(ctx, repo, url, c.clone(), trusted_keys);
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("system time is before the Unix epoch")?;
+    let cutoff = now.saturating_sub(age).as_secs() as i64;
+    let expected_oid = git::resolve_before(repo, tip, cutoff)
+        .ok()
+        .filter(|oid| git::is_descendant_of(repo, *oid, current_oid));
+    match expected_oid {
+        Some(expected_oid) => {
+            verify_signature(ctx, repo, resolved, expected_oid, trusted_keys)?;
+            update(ctx, repo, expected_oid)?;
+            status!(
+                ctx,
+                "Updated",
+                &format!(
+                    "{} ({} to {})",
+                    describe(repo, url, resolved, expected_oid),
+                    &current_oid.to_string()[..7],
+                    &expected_oid.to_string()[..7]
+                )
+            );
+        }
+        None => status!(
+            ctx,
+            "Pinned",
+            &format!(
+                "{} ({}, newer commits are too recent)",
+                describe(repo, url, resolved, current_oid),
+                &current_oid.to_string()[..7]
+            )
+        ),
+    }
+    Ok(resolved.clone())
 }
 
-fn install(ctx: &Context, dir: PathBuf, url: &Url, checkout: GitCheckout) -> Result<LockedSource> {
+fn install(
+    ctx: &Context,
+    dir: PathBuf,
+    url: &Url,
+    checkout: GitCheckout,
+    trusted_keys: Option<&[String]>,
+) -> Result<LockedSource> {
     let temp_dir =
         TempPath::new_force(&dir).context("failed to prepare temporary clone directory")?;
-    let repo = git::clone(url, temp_dir.path())?;
-    git::checkout(&repo, checkout.resolve(&repo)?)?;
-    git::submodule_update(&repo).context("failed to recursively update")?;
+    let repo = clone(ctx, url, temp_dir.path())?;
+    let (checkout, oid) = checkout.resolve(&repo)?;
+    verify_signature(ctx, &repo, checkout, oid, trusted_keys)?;
+    update(ctx, &repo, oid)?;
+    let reference = checkout.reference(&repo);
+    rename(ctx, temp_dir, &dir)?;
+    status!(ctx, "Cloned", &describe(&repo, url, checkout, oid));
+    Ok(LockedSource {
+        dir,
+        file: None,
+        revision: Some(oid.to_string()),
+        reference,
+        fetched: None,
+    })
+}
+
+/// Clone a repository into the given directory.
+fn clone(ctx: &Context, url: &Url, dir: &Path) -> Result<git2::Repository> {
+    trace!(
+        ctx,
+        "Running",
+        &format!(
+            "git clone {} {}",
+            git::redact_url(url),
+            ctx.replace_home(dir).display()
+        )
+    );
+    git::clone(url, dir, ctx.credential_helper())
+}
+
+/// Fetch the latest changes for a repository from its remote.
+fn fetch(ctx: &Context, repo: &git2::Repository) -> Result<()> {
+    trace_in(ctx, repo, "fetch origin");
+    git::fetch(repo, ctx.credential_helper())
+}
+
+/// Checkout a commit in a repository and update its submodules.
+fn update(ctx: &Context, repo: &git2::Repository, oid: git2::Oid) -> Result<()> {
+    trace_in(ctx, repo, &format!("checkout --force {}", oid));
+    git::checkout(repo, oid)?;
+    trace_in(ctx, repo, "submodule update --init --recursive");
+    git::submodule_update(repo).context("failed to recursively update")?;
+    Ok(())
+}
+
+/// Move a temporary clone directory into place.
+fn rename(ctx: &Context, temp_dir: TempPath, dir: &Path) -> Result<()> {
+    let from = ctx.replace_home(temp_dir.path());
     temp_dir
-        .rename(&dir)
+        .rename(dir)
         .context("failed to rename temporary clone directory")?;
-    status!(ctx, "Cloned", &format!("{}{}", url, checkout));
-    Ok(LockedSource { dir, file: None })
+    trace!(
+        ctx,
+        "Renamed",
+        &format!("{} to {}", from.display(), ctx.replace_home(dir).display())
+    );
+    Ok(())
+}
+
+/// Log a Git operation on a repository as the equivalent command.
+fn trace_in(ctx: &Context, repo: &git2::Repository, args: &str) {
+    let dir = repo.workdir().unwrap_or_else(|| repo.path());
+    trace!(
+        ctx,
+        "Running",
+        &format!("git -C {} {}", ctx.replace_home(dir).display(), args)
+    );
+}
+
+/// Describe a checkout of a repository for displaying.
+///
+/// Checkouts that are pinned to a date also include the resolved commit, and
+/// checkouts of `HEAD` include the resolved default branch.
+fn describe(repo: &git2::Repository, url: &Url, checkout: &GitCheckout, oid: git2::Oid) -> String {
+    match checkout {
+        GitCheckout::Before { .. } => format!("{}{} ({})", url, checkout, &oid.to_string()[..7]),
+        GitCheckout::Head => match git::head_branch(repo) {
+            Some(branch) => format!("{}{} ({})", url, checkout, branch),
+            None => format!("{}{}", url, checkout),
+        },
+        _ => format!("{}{}", url, checkout),
+    }
 }
 
 impl fmt::Display for GitCheckout {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::DefaultBranch => write!(f, ""),
+            Self::Head => write!(f, "@HEAD"),
             Self::Branch(s) | Self::Rev(s) | Self::Tag(s) => write!(f, "@{}", s),
+            Self::Fallback(checkouts) => {
+                let candidates: Vec<_> = checkouts
+                    .iter()
+                    .map(|c| c.to_string().trim_start_matches('@').to_string())
+                    .collect();
+                write!(f, "@{}", candidates.join("|"))
+            }
+            Self::Before { branch, date } => {
+                write!(f, "@{}<{}", branch.as_deref().unwrap_or("HEAD"), date)
+            }
         }
     }
 }
@@ -103,23 +642,121 @@ impl From<Option<GitReference>> for GitCheckout {
     fn from(reference: Option<GitReference>) -> Self {
         match reference {
             None => Self::DefaultBranch,
+            Some(GitReference::Branch(s)) if s == "HEAD" => Self::Head,
             Some(GitReference::Branch(s)) => Self::Branch(s),
             Some(GitReference::Rev(s)) => Self::Rev(s),
             Some(GitReference::Tag(s)) => Self::Tag(s),
+            Some(GitReference::Before { branch, date }) => Self::Before { branch, date },
+            Some(GitReference::Fallback(references)) => Self::Fallback(
+                references
+                    .into_iter()
+                    .map(|reference| Some(reference).into())
+                    .collect(),
+            ),
         }
     }
 }
 
 impl GitCheckout {
-    /// Resolve `GitCheckout` to a Git object identifier.
-    fn resolve(&self, repo: &git2::Repository) -> Result<git2::Oid> {
+    /// The name to request with `git archive`, if the checkout can be resolved
+    /// by the remote.
+    fn treeish(&self) -> Option<&str> {
+        match self {
+            Self::DefaultBranch | Self::Head => Some("HEAD"),
+            Self::Branch(s) | Self::Rev(s) | Self::Tag(s) => Some(s),
+            Self::Fallback(_) | Self::Before { .. } => None,
+        }
+    }
+
+    /// Whether the checkout could resolve to one of the given remote
+    /// references.
+    fn is_advertised(&self, refs: &[String]) -> bool {
+        let has_branch = |b: &str| refs.contains(&format!("refs/heads/{}", b));
+        match self {
+            Self::DefaultBranch | Self::Head | Self::Rev(_) => true,
+            Self::Branch(s) => has_branch(s),
+            Self::Tag(s) => refs.contains(&format!("refs/tags/{}", s)),
+            Self::Fallback(checkouts) => checkouts.iter().any(|c| c.is_advertised(refs)),
+            Self::Before { branch, .. } => branch.as_deref().is_none_or(has_branch),
+        }
+    }
+
+    /// The name of the reference that a resolved checkout uses.
+    ///
+    /// Checkouts of the default branch use the name of the remote's default
+    /// branch, or `HEAD` if it is not known.
+    fn reference(&self, repo: &git2::Repository) -> Option<String> {
         match self {
-            Self::DefaultBranch => git::resolve_head(repo),
-            Self::Branch(s) => git::resolve_branch(repo, s),
-            Self::Rev(s) => git::resolve_rev(repo, s),
-            Self::Tag(s) => git::resolve_tag(repo, s),
+            Self::DefaultBranch | Self::Head | Self::Before { branch: None, .. } => {
+                Some(git::head_branch(repo).unwrap_or_else(|| "HEAD".to_string()))
+            }
+            Self::Branch(s) | Self::Rev(s) | Self::Tag(s) => Some(s.clone()),
+            Self::Before {
+                branch: Some(branch),
+                ..
+            } => Some(branch.clone()),
+            Self::Fallback(_) => None,
+        }
+    }
+
+    /// Whether an export that recorded the given reference was exported at
+    /// this checkout.
+    ///
+    /// Exports of the default branch record the name of the branch, which is
+    /// looked up in the remote repository. If that fails the export is assumed
+    /// to be correct.
+    fn is_exported_at(&self, ctx: &Context, session: &Session, url: &Url, reference: &str) -> bool {
+        match self {
+            Self::Branch(s) | Self::Rev(s) | Self::Tag(s) => reference == s,
+            Self::Before {
+                branch: Some(branch),
+                ..
+            } => reference == branch,
+            Self::DefaultBranch | Self::Head | Self::Before { branch: None, .. } => {
+                reference == "HEAD"
+                    || ls_remote(ctx, session, url)
+                        .map_or(true, |refs| git::is_default_branch(&refs, reference))
+            }
+            Self::Fallback(checkouts) => checkouts
+                .iter()
+                .any(|c| c.is_exported_at(ctx, session, url, reference)),
         }
     }
+
+    /// Resolve `GitCheckout` to a Git object identifier, also returning the
+    /// checkout that was actually used.
+    ///
+    /// This is only different to `self` for fallback checkouts, where the
+    /// first candidate that resolves is used.
+    fn resolve(&self, repo: &git2::Repository) -> Result<(&Self, git2::Oid)> {
+        let oid = match self {
+            Self::DefaultBranch | Self::Head => git::resolve_head(repo)?,
+            Self::Branch(s) => git::resolve_branch(repo, s)?,
+            Self::Rev(s) => git::resolve_rev(repo, s)?,
+            Self::Tag(s) => git::resolve_tag(repo, s)?,
+            Self::Before { branch, date } => {
+                let tip = match branch {
+                    Some(branch) => git::resolve_branch(repo, branch)?,
+                    None => git::resolve_head(repo)?,
+                };
+                git::resolve_before(repo, tip, git::parse_date(date)?)
+                    .with_context(s!("failed to find commit before `{}`", date))?
+            }
+            Self::Fallback(checkouts) => {
+                for checkout in checkouts {
+                    if let Ok(resolved) = checkout.resolve(repo) {
+                        return Ok(resolved);
+                    }
+                }
+                let candidates: Vec<_> = checkouts
+                    .iter()
+                    .map(|c| format!("`{}`", c.to_string().trim_start_matches('@')))
+                    .collect();
+                bail!("failed to find any of {}", candidates.join(", "));
+            }
+        };
+        Ok((self, oid))
+    }
 }
 
 #[cfg(test)]
@@ -127,7 +764,9 @@ mod tests {
     use super::*;
 
     use std::process::Command;
-    use std::{fs, thread, time};
+    use std::{thread, time};
+
+    use crate::util::testing;
 
     #[test]
     fn git_checkout_to_string() {
@@ -148,7 +787,7 @@ mod tests {
         let repo = git_clone_sheldon_test(&temp);
 
         let checkout = GitCheckout::Branch("feature".to_string());
-        let oid = checkout.resolve(&repo).expect("lock git checkout");
+        let (_, oid) = checkout.resolve(&repo).expect("lock git checkout");
         assert_eq!(oid.to_string(), "09ead574b20bb573ae0a53c1a5c546181cfa41c8");
 
         let checkout = GitCheckout::Branch("not-a-branch".to_string());
@@ -162,7 +801,7 @@ mod tests {
         let repo = git_clone_sheldon_test(&temp);
 
         let checkout = GitCheckout::Rev("ad149784a".to_string());
-        let oid = checkout.resolve(&repo).unwrap();
+        let (_, oid) = checkout.resolve(&repo).unwrap();
         assert_eq!(oid.to_string(), "ad149784a1538291f2477fb774eeeed4f4d29e45");
 
         let checkout = GitCheckout::Rev("2c4ed7710".to_string());
@@ -176,7 +815,7 @@ mod tests {
         let repo = git_clone_sheldon_test(&temp);
 
         let checkout = GitCheckout::Tag("v0.1.0".to_string());
-        let oid = checkout.resolve(&repo).unwrap();
+        let (_, oid) = checkout.resolve(&repo).unwrap();
         assert_eq!(oid.to_string(), "be8fde277e76f35efbe46848fb352cee68549962");
 
         let checkout = GitCheckout::Tag("v0.2.0".to_string());
@@ -184,12 +823,163 @@ mod tests {
         assert_eq!(error.to_string(), "failed to find tag `v0.2.0`");
     }
 
+    /// Create a repository with a single commit on the given default branch.
+    fn git_init_with_default_branch(dir: &Path, branch: &str) -> git2::Oid {
+        let repo = git2::Repository::init(dir).unwrap();
+        repo.set_head(&format!("refs/heads/{}", branch)).unwrap();
+        testing::git_commit(dir, "test.plugin.zsh", "echo 'test'\n")
+    }
+
+    #[test]
+    fn lock_git_head_follows_default_branch() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let origin = temp.path().join("origin");
+        let dir = temp.path().join("clone");
+        let oid = git_init_with_default_branch(&origin, "trunk");
+        let mut ctx = Context::testing(temp.path());
+        let url = Url::from_directory_path(&origin).unwrap();
+        let checkout: GitCheckout = Some(GitReference::Branch("HEAD".to_string())).into();
+        assert_eq!(checkout.to_string(), "@HEAD");
+
+        let locked = lock(
+            &ctx,
+            &Session::default(),
+            dir.clone(),
+            &url,
+            checkout.clone(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(locked.revision, Some(oid.to_string()));
+        assert_eq!(locked.reference.as_deref(), Some("trunk"));
+
+        let repo = git2::Repository::open(&dir).unwrap();
+        assert_eq!(git::head_branch(&repo).as_deref(), Some("trunk"));
+        assert_eq!(repo.head().unwrap().target(), Some(oid));
+
+        // Rename the default branch of the remote.
+        git2::Repository::open(&origin)
+            .unwrap()
+            .find_branch("trunk", git2::BranchType::Local)
+            .unwrap()
+            .rename("main", false)
+            .unwrap();
+        ctx.lock_mode = Some(LockMode::Update);
+
+        let locked = lock(&ctx, &Session::default(), dir.clone(), &url, checkout, None).unwrap();
+        assert_eq!(locked.reference.as_deref(), Some("main"));
+
+        let repo = git2::Repository::open(&dir).unwrap();
+        assert_eq!(git::head_branch(&repo).as_deref(), Some("main"));
+        assert_eq!(repo.head().unwrap().target(), Some(oid));
+    }
+
+    /// Add a commit to the current branch of a repository, committed the given
+    /// number of days ago.
+    fn git_commit_days_ago(dir: &Path, days: i64) -> git2::Oid {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let time = git2::Time::new(now.as_secs() as i64 - days * 24 * 60 * 60, 0);
+        testing::git_commit_at(dir, &format!("{}.zsh", days), "echo 'test'\n", time)
+    }
+
+    #[test]
+    fn lock_git_update_max_age() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let origin = temp.path().join("origin");
+        let dir = temp.path().join("clone");
+        git_init_with_default_branch(&origin, "main");
+        let initial = git_commit_days_ago(&origin, 60);
+        let mut ctx = Context::testing(temp.path());
+        let url = Url::from_directory_path(&origin).unwrap();
+
+        lock(
+            &ctx,
+            &Session::default(),
+            dir.clone(),
+            &url,
+            GitCheckout::DefaultBranch,
+            None,
+        )
+        .unwrap();
+        let head = || {
+            git2::Repository::open(&dir)
+                .unwrap()
+                .head()
+                .unwrap()
+                .target()
+                .unwrap()
+        };
+        assert_eq!(head(), initial);
+
+        let older = git_commit_days_ago(&origin, 20);
+        let recent = git_commit_days_ago(&origin, 1);
+        ctx.lock_mode = Some(LockMode::Update);
+
+        // There are no new commits older than 30 days.
+        ctx.update_max_age = Some(Duration::from_secs(30 * 24 * 60 * 60));
+        lock(
+            &ctx,
+            &Session::default(),
+            dir.clone(),
+            &url,
+            GitCheckout::DefaultBranch,
+            None,
+        )
+        .unwrap();
+        assert_eq!(head(), initial);
+
+        // Only the commit from 20 days ago is older than 7 days.
+        ctx.update_max_age = Some(Duration::from_secs(7 * 24 * 60 * 60));
+        let locked = lock(
+            &ctx,
+            &Session::default(),
+            dir.clone(),
+            &url,
+            GitCheckout::DefaultBranch,
+            None,
+        )
+        .unwrap();
+        assert_eq!(head(), older);
+        assert_eq!(locked.revision, Some(older.to_string()));
+
+        // A normal lock keeps the commit recorded in the lock file.
+        ctx.lock_mode = Some(LockMode::Normal);
+        ctx.update_max_age = None;
+        let session = Session {
+            previous: vec![locked.clone()],
+            ..Default::default()
+        };
+        let relocked = lock(
+            &ctx,
+            &session,
+            dir.clone(),
+            &url,
+            GitCheckout::DefaultBranch,
+            None,
+        )
+        .unwrap();
+        assert_eq!(head(), older);
+        assert_eq!(relocked, locked);
+
+        ctx.lock_mode = Some(LockMode::Update);
+        lock(
+            &ctx,
+            &Session::default(),
+            dir.clone(),
+            &url,
+            GitCheckout::DefaultBranch,
+            None,
+        )
+        .unwrap();
+        assert_eq!(head(), recent);
+    }
+
     fn git_clone_sheldon_test(temp: &tempfile::TempDir) -> git2::Repository {
         let dir = temp.path();
         Command::new("git")
             .arg("clone")
             .arg("https://github.com/rossmacarthur/sheldon-test")
-            .arg(&dir)
+            .arg(dir)
             .output()
             .expect("git clone rossmacarthur/sheldon-test");
         git2::Repository::open(dir).expect("open sheldon-test git repository")
@@ -202,28 +992,571 @@ mod tests {
         let mut ctx = Context::testing(dir);
         let url = Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap();
 
-        let locked = lock(&ctx, dir.to_path_buf(), &url, GitCheckout::DefaultBranch).unwrap();
+        let locked = lock(
+            &ctx,
+            &Session::default(),
+            dir.to_path_buf(),
+            &url,
+            GitCheckout::DefaultBranch,
+            None,
+        )
+        .unwrap();
 
         assert_eq!(locked.dir, dir);
         assert_eq!(locked.file, None);
-        let repo = git2::Repository::open(&dir).unwrap();
+        let repo = git2::Repository::open(dir).unwrap();
         assert_eq!(
             repo.head().unwrap().target().unwrap().to_string(),
             "be8fde277e76f35efbe46848fb352cee68549962"
         );
 
-        let modified = fs::metadata(&dir).unwrap().modified().unwrap();
+        let modified = fs::metadata(dir).unwrap().modified().unwrap();
         thread::sleep(time::Duration::from_secs(1));
         ctx.lock_mode = Some(LockMode::Reinstall);
-        let locked = lock(&ctx, dir.to_path_buf(), &url, GitCheckout::DefaultBranch).unwrap();
+        let locked = lock(
+            &ctx,
+            &Session::default(),
+            dir.to_path_buf(),
+            &url,
+            GitCheckout::DefaultBranch,
+            None,
+        )
+        .unwrap();
         assert_eq!(locked.dir, dir);
         assert_eq!(locked.file, None);
-        let repo = git2::Repository::open(&dir).unwrap();
+        let repo = git2::Repository::open(dir).unwrap();
         assert_eq!(
             repo.head().unwrap().target().unwrap().to_string(),
             "be8fde277e76f35efbe46848fb352cee68549962"
         );
-        assert!(fs::metadata(&dir).unwrap().modified().unwrap() > modified);
+        assert!(fs::metadata(dir).unwrap().modified().unwrap() > modified);
+    }
+
+    /// Create a local Git repository with a single commit to use as a source.
+    fn git_init_fixture(dir: &Path) -> Url {
+        testing::git_commit(dir, "test.plugin.zsh", "echo 'testing'\n");
+        Url::from_directory_path(dir).unwrap()
+    }
+
+    #[test]
+    fn lock_git_verify_signature() {
+        // Signing the fixture requires `gpg`.
+        if Command::new("gpg").arg("--version").output().is_err() {
+            return;
+        }
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let gnupg = temp.path().join("gnupg");
+        fs::create_dir(&gnupg).unwrap();
+        let status = Command::new("gpg")
+            .env("GNUPGHOME", &gnupg)
+            .args(["--batch", "--pinentry-mode", "loopback", "--passphrase", ""])
+            .args(["--quick-gen-key", "Sheldon <sheldon@example.com>"])
+            .args(["ed25519", "sign", "never"])
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success());
+        let output = Command::new("gpg")
+            .env("GNUPGHOME", &gnupg)
+            .args(["--list-keys", "--with-colons"])
+            .output()
+            .unwrap();
+        let fingerprint = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .find_map(|line| {
+                line.strip_prefix("fpr:")
+                    .map(|l| l.trim_matches(':').to_string())
+            })
+            .unwrap();
+
+        // The commit is unsigned, but one of the tags is signed.
+        let fixture = temp.path().join("fixture");
+        let url = git_init_fixture(&fixture);
+        for args in [
+            &["tag", "-s", "-m", "signed", "signed"][..],
+            &["tag", "unsigned"],
+        ] {
+            let status = Command::new("git")
+                .env("GNUPGHOME", &gnupg)
+                .arg("-C")
+                .arg(&fixture)
+                .args([
+                    "-c",
+                    "user.name=Sheldon",
+                    "-c",
+                    "user.email=sheldon@example.com",
+                ])
+                .args(["-c", "user.signingkey=sheldon@example.com"])
+                .args(args)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success());
+        }
+        let dir = temp.path().join("repos/fixture");
+        let mut ctx = Context::testing(temp.path());
+        ctx.gnupg_home = Some(gnupg);
+        let tag = |name: &str| GitCheckout::Tag(name.to_string());
+
+        // A clone that fails verification is never moved into place.
+        let err = lock(
+            &ctx,
+            &Session::default(),
+            dir.clone(),
+            &url,
+            GitCheckout::DefaultBranch,
+            Some(&[]),
+        )
+        .unwrap_err();
+        assert!(err.to_string().ends_with("does not have a valid signature"));
+        assert!(!dir.exists());
+        let err = lock(
+            &ctx,
+            &Session::default(),
+            dir.clone(),
+            &url,
+            tag("unsigned"),
+            Some(&[]),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "tag `unsigned` does not have a valid signature"
+        );
+        assert!(!dir.exists());
+
+        lock(
+            &ctx,
+            &Session::default(),
+            dir.clone(),
+            &url,
+            tag("signed"),
+            Some(&[]),
+        )
+        .unwrap();
+        lock(
+            &ctx,
+            &Session::default(),
+            dir.clone(),
+            &url,
+            tag("signed"),
+            Some(&[fingerprint[24..].to_string()]),
+        )
+        .unwrap();
+        let signed = git2::Repository::open(&dir)
+            .unwrap()
+            .head()
+            .unwrap()
+            .target()
+            .unwrap();
+
+        // An existing clone stays at the trusted commit when a fetched tag is
+        // not signed.
+        for args in [
+            &["commit", "--allow-empty", "-m", "next"][..],
+            &["tag", "next"],
+        ] {
+            let status = Command::new("git")
+                .arg("-C")
+                .arg(&fixture)
+                .args([
+                    "-c",
+                    "user.name=Sheldon",
+                    "-c",
+                    "user.email=sheldon@example.com",
+                ])
+                .args(args)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success());
+        }
+        let err = lock(
+            &ctx,
+            &Session::default(),
+            dir.clone(),
+            &url,
+            tag("next"),
+            Some(&[]),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "tag `next` does not have a valid signature"
+        );
+        let repo = git2::Repository::open(&dir).unwrap();
+        assert_eq!(repo.head().unwrap().target().unwrap(), signed);
+
+        let err = lock(
+            &ctx,
+            &Session::default(),
+            dir,
+            &url,
+            tag("signed"),
+            Some(&["0123456789ABCDEF".to_string()]),
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("which is not one of the trusted keys"));
+    }
+
+    #[test]
+    fn export_git_and_update() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let fixture = temp.path().join("fixture");
+        let url = git_init_fixture(&fixture);
+        let dir = temp.path().join("repos/fixture");
+        let mut ctx = Context::testing(temp.path());
+
+        let locked = export(
+            &ctx,
+            &Session::default(),
+            dir.clone(),
+            &url,
+            GitCheckout::DefaultBranch,
+            false,
+        )
+        .unwrap();
+        assert_eq!(locked.dir, dir);
+        assert_eq!(locked.fetched, Some(Fetched::Clone));
+        assert!(!dir.join(".git").exists());
+        assert_eq!(
+            fs::read_to_string(dir.join("test.plugin.zsh")).unwrap(),
+            "echo 'testing'\n"
+        );
+
+        // An existing export is only checked, not re-exported.
+        testing::git_commit(&fixture, "test.plugin.zsh", "echo 'updated'\n");
+        let session = Session {
+            previous: vec![locked.clone()],
+            ..Default::default()
+        };
+        let relocked = export(
+            &ctx,
+            &session,
+            dir.clone(),
+            &url,
+            GitCheckout::DefaultBranch,
+            false,
+        )
+        .unwrap();
+        assert_eq!(relocked, locked);
+        assert_eq!(
+            fs::read_to_string(dir.join("test.plugin.zsh")).unwrap(),
+            "echo 'testing'\n"
+        );
+
+        // An existing export at a different reference is exported again.
+        let fixture_repo = git2::Repository::open(&fixture).unwrap();
+        let head = fixture_repo.head().unwrap().peel_to_commit().unwrap();
+        fixture_repo.branch("feature", &head, false).unwrap();
+        let checkout = GitCheckout::Branch("feature".to_string());
+        let locked = export(&ctx, &session, dir.clone(), &url, checkout, false).unwrap();
+        assert_eq!(locked.reference.as_deref(), Some("feature"));
+        assert_eq!(
+            fs::read_to_string(dir.join("test.plugin.zsh")).unwrap(),
+            "echo 'updated'\n"
+        );
+        fs::write(dir.join("test.plugin.zsh"), "echo 'testing'\n").unwrap();
+
+        ctx.lock_mode = Some(LockMode::Update);
+        export(
+            &ctx,
+            &Session::default(),
+            dir.clone(),
+            &url,
+            GitCheckout::DefaultBranch,
+            false,
+        )
+        .unwrap();
+        assert!(!dir.join(".git").exists());
+        assert_eq!(
+            fs::read_to_string(dir.join("test.plugin.zsh")).unwrap(),
+            "echo 'updated'\n"
+        );
+    }
+
+    #[test]
+    fn export_no_history_with_git_archive() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let fixture = temp.path().join("fixture");
+        let url = git_init_fixture(&fixture);
+        let dir = temp.path().join("export");
+        let ctx = Context::testing(temp.path());
+
+        let (locked, _) = export_tree(&ctx, &url, GitCheckout::DefaultBranch, &dir, true).unwrap();
+        assert_eq!(locked.fetched, Some(Fetched::Archive));
+        assert_eq!(locked.reference.as_deref(), Some("HEAD"));
+        assert!(!dir.join(".git").exists());
+        assert_eq!(
+            fs::read_to_string(dir.join("test.plugin.zsh")).unwrap(),
+            "echo 'testing'\n"
+        );
+    }
+
+    #[test]
+    fn export_no_history_falls_back_to_clone() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let fixture = temp.path().join("fixture");
+        let url = git_init_fixture(&fixture);
+        let dir = temp.path().join("export");
+        let ctx = Context::testing(temp.path());
+
+        // Servers only allow archiving references, not arbitrary commits.
+        let oid = git2::Repository::open(&fixture)
+            .unwrap()
+            .refname_to_id("HEAD")
+            .unwrap();
+        let checkout = GitCheckout::Rev(oid.to_string());
+        let (locked, _) = export_tree(&ctx, &url, checkout, &dir, true).unwrap();
+        assert_eq!(locked.fetched, Some(Fetched::Clone));
+        assert_eq!(locked.revision, Some(oid.to_string()));
+        assert!(!dir.join(".git").exists());
+        assert_eq!(
+            fs::read_to_string(dir.join("test.plugin.zsh")).unwrap(),
+            "echo 'testing'\n"
+        );
+    }
+
+    #[test]
+    fn export_no_history_with_submodules_falls_back_to_clone() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let submodule = git_init_fixture(&temp.path().join("submodule"));
+        let fixture = temp.path().join("fixture");
+        let url = git_init_fixture(&fixture);
+        for args in [
+            &["submodule", "add", submodule.as_str(), "sub"][..],
+            &["commit", "-m", "Add submodule"],
+        ] {
+            let status = Command::new("git")
+                .arg("-C")
+                .arg(&fixture)
+                .args([
+                    "-c",
+                    "protocol.file.allow=always",
+                    "-c",
+                    "user.name=Sheldon",
+                    "-c",
+                    "user.email=sheldon@example.com",
+                ])
+                .args(args)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success());
+        }
+        let dir = temp.path().join("export");
+        let ctx = Context::testing(temp.path());
+
+        // The archive would not include the submodule.
+        let (locked, _) = export_tree(&ctx, &url, GitCheckout::DefaultBranch, &dir, true).unwrap();
+        assert_eq!(locked.fetched, Some(Fetched::Clone));
+        assert!(!dir.join("sub/.git").exists());
+        assert_eq!(
+            fs::read_to_string(dir.join("sub/test.plugin.zsh")).unwrap(),
+            "echo 'testing'\n"
+        );
+    }
+
+    #[test]
+    fn lock_git_fallback_checkout() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let fixture = temp.path().join("fixture");
+        let url = git_init_fixture(&fixture);
+        let fixture_repo = git2::Repository::open(&fixture).unwrap();
+        let head = fixture_repo.head().unwrap().peel_to_commit().unwrap();
+        fixture_repo.branch("feature", &head, false).unwrap();
+        let dir = temp.path().join("repos/fixture");
+        let ctx = Context::testing(temp.path());
+
+        let checkout = GitCheckout::Fallback(vec![
+            GitCheckout::Tag("v0.1.0".to_string()),
+            GitCheckout::Branch("feature".to_string()),
+        ]);
+        assert_eq!(checkout.to_string(), "@v0.1.0|feature");
+        let locked = lock(
+            &ctx,
+            &Session::default(),
+            dir.clone(),
+            &url,
+            checkout.clone(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(locked.revision, Some(head.id().to_string()));
+        assert_eq!(locked.reference.as_deref(), Some("feature"));
+
+        let repo = git2::Repository::open(&dir).unwrap();
+        assert_eq!(repo.head().unwrap().target().unwrap(), head.id());
+        let (used, _) = checkout.resolve(&repo).unwrap();
+        assert_eq!(used.to_string(), "@feature");
+    }
+
+    #[test]
+    fn check_remote_good_references() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let fixture = temp.path().join("fixture");
+        let url = git_init_fixture(&fixture);
+        let fixture_repo = git2::Repository::open(&fixture).unwrap();
+        let head = fixture_repo.head().unwrap().peel_to_commit().unwrap();
+        fixture_repo.branch("feature", &head, false).unwrap();
+        fixture_repo
+            .tag_lightweight("v0.1.0", head.as_object(), false)
+            .unwrap();
+        let ctx = Context::testing(temp.path());
+
+        for checkout in [
+            GitCheckout::DefaultBranch,
+            GitCheckout::Branch("feature".to_string()),
+            GitCheckout::Tag("v0.1.0".to_string()),
+            GitCheckout::Rev(head.id().to_string()),
+            GitCheckout::Fallback(vec![
+                GitCheckout::Tag("v0.2.0".to_string()),
+                GitCheckout::Branch("feature".to_string()),
+            ]),
+        ] {
+            check_remote(&ctx, &Session::default(), &url, checkout).unwrap();
+        }
+        assert!(!temp.path().join("repos").exists());
+    }
+
+    #[test]
+    fn check_remote_bad_reference() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let fixture = temp.path().join("fixture");
+        let url = git_init_fixture(&fixture);
+        let fixture_repo = git2::Repository::open(&fixture).unwrap();
+        let head = fixture_repo.head().unwrap().peel_to_commit().unwrap();
+        fixture_repo.branch("feature", &head, false).unwrap();
+        let branch = fixture_repo
+            .head()
+            .unwrap()
+            .shorthand()
+            .unwrap()
+            .to_string();
+        let ctx = Context::testing(temp.path());
+
+        let err = check_remote(
+            &ctx,
+            &Session::default(),
+            &url,
+            GitCheckout::Tag("v0.1.0".to_string()),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "failed to find `v0.1.0` in `{}`, available branches: `feature`, `{}`; \
+                 available tags: none",
+                url, branch
+            )
+        );
+    }
+
+    #[test]
+    fn lock_git_fallback_checkout_unresolvable() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let url = git_init_fixture(&temp.path().join("fixture"));
+        let dir = temp.path().join("repos/fixture");
+        let ctx = Context::testing(temp.path());
+
+        let checkout = GitCheckout::Fallback(vec![
+            GitCheckout::Tag("v0.1.0".to_string()),
+            GitCheckout::Branch("feature".to_string()),
+        ]);
+        let err = lock(&ctx, &Session::default(), dir.clone(), &url, checkout, None).unwrap_err();
+        assert_eq!(err.to_string(), "failed to find any of `v0.1.0`, `feature`");
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn lock_git_before_checkout() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let fixture = temp.path().join("fixture");
+        let mut commits = Vec::new();
+        for (i, date) in ["2022-06-01", "2022-12-31", "2023-02-01"]
+            .iter()
+            .enumerate()
+        {
+            let time = git2::Time::new(git::parse_date(date).unwrap() + 3600, 0);
+            let contents = format!("echo {}\n", i);
+            commits.push(testing::git_commit_at(
+                &fixture,
+                "test.plugin.zsh",
+                &contents,
+                time,
+            ));
+        }
+        let url = Url::from_directory_path(&fixture).unwrap();
+        let dir = temp.path().join("repos/fixture");
+        let ctx = Context::testing(temp.path());
+
+        let checkout = GitCheckout::Before {
+            branch: None,
+            date: "2023-01-01".to_string(),
+        };
+        assert_eq!(checkout.to_string(), "@HEAD<2023-01-01");
+        let locked = lock(&ctx, &Session::default(), dir.clone(), &url, checkout, None).unwrap();
+        assert_eq!(locked.revision, Some(commits[1].to_string()));
+        let repo = git2::Repository::open(&dir).unwrap();
+        assert_eq!(repo.head().unwrap().target().unwrap(), commits[1]);
+        assert_eq!(
+            fs::read_to_string(dir.join("test.plugin.zsh")).unwrap(),
+            "echo 1\n"
+        );
+
+        let checkout = GitCheckout::Before {
+            branch: None,
+            date: "2022-01-01".to_string(),
+        };
+        let err = checkout.resolve(&repo).unwrap_err();
+        assert_eq!(err.to_string(), "failed to find commit before `2022-01-01`");
+    }
+
+    #[test]
+    fn lock_git_reinstall_corrupted() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let url = git_init_fixture(&temp.path().join("fixture"));
+        let dir = temp.path().join("repos/fixture");
+        let mut ctx = Context::testing(temp.path());
+
+        lock(
+            &ctx,
+            &Session::default(),
+            dir.clone(),
+            &url,
+            GitCheckout::DefaultBranch,
+            None,
+        )
+        .unwrap();
+
+        // Corrupt the clone.
+        fs::remove_file(dir.join("test.plugin.zsh")).unwrap();
+        fs::write(dir.join("untracked.txt"), "garbage").unwrap();
+        fs::remove_dir_all(dir.join(".git/refs")).unwrap();
+        assert!(git::open(&dir).is_err());
+
+        ctx.lock_mode = Some(LockMode::Reinstall);
+        let locked = lock(
+            &ctx,
+            &Session::default(),
+            dir.clone(),
+            &url,
+            GitCheckout::DefaultBranch,
+            None,
+        )
+        .unwrap();
+        assert_eq!(locked.dir, dir);
+
+        let repo = git2::Repository::open(&dir).unwrap();
+        let statuses = repo
+            .statuses(Some(git2::StatusOptions::new().include_untracked(true)))
+            .unwrap();
+        assert!(statuses.is_empty());
+        assert!(dir.join("test.plugin.zsh").exists());
+        assert!(!dir.join("untracked.txt").exists());
     }
 
     #[test]
@@ -233,15 +1566,17 @@ mod tests {
 
         let locked = lock(
             &Context::testing(dir),
+            &Session::default(),
             dir.to_path_buf(),
             &Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
             GitCheckout::Rev("ad149784a1538291f2477fb774eeeed4f4d29e45".to_string()),
+            None,
         )
         .unwrap();
 
         assert_eq!(locked.dir, dir);
         assert_eq!(locked.file, None);
-        let repo = git2::Repository::open(&dir).unwrap();
+        let repo = git2::Repository::open(dir).unwrap();
         let head = repo.head().unwrap();
         assert_eq!(
             head.target().unwrap().to_string(),
@@ -257,15 +1592,17 @@ mod tests {
 
         let locked = lock(
             &Context::testing(dir),
+            &Session::default(),
             dir.to_path_buf(),
             &Url::parse("git://github.com/rossmacarthur/sheldon-test").unwrap(),
             GitCheckout::Rev("ad149784a1538291f2477fb774eeeed4f4d29e45".to_string()),
+            None,
         )
         .unwrap();
 
         assert_eq!(locked.dir, dir);
         assert_eq!(locked.file, None);
-        let repo = git2::Repository::open(&dir).unwrap();
+        let repo = git2::Repository::open(dir).unwrap();
         let head = repo.head().unwrap();
         assert_eq!(
             head.target().unwrap().to_string(),
@@ -11,7 +11,13 @@ pub fn lock(ctx: &Context, dir: PathBuf) -> Result<LockedSource> {
 
     if dir.exists() && dir.is_dir() {
         status!(ctx, "Checked", dir.as_path());
-        Ok(LockedSource { dir, file: None })
+        Ok(LockedSource {
+            dir,
+            file: None,
+            revision: None,
+            reference: None,
+            fetched: None,
+        })
     } else if let Ok(walker) = globwalk::glob(dir.to_string_lossy()) {
         let mut directories: Vec<_> = walker
             .filter_map(|result| match result {
@@ -23,7 +29,13 @@ pub fn lock(ctx: &Context, dir: PathBuf) -> Result<LockedSource> {
         if directories.len() == 1 {
             let dir = directories.remove(0);
             status!(ctx, "Checked", dir.as_path());
-            Ok(LockedSource { dir, file: None })
+            Ok(LockedSource {
+                dir,
+                file: None,
+                revision: None,
+                reference: None,
+                fetched: None,
+            })
         } else {
             Err(anyhow!(
                 "`{}` matches {} directories",
@@ -59,7 +71,7 @@ mod tests {
         Command::new("git")
             .arg("clone")
             .arg("https://github.com/rossmacarthur/sheldon-test")
-            .arg(&dir)
+            .arg(dir)
             .output()
             .expect("git clone rossmacarthur/sheldon-test");
         git2::Repository::open(dir).expect("open sheldon-test git repository")
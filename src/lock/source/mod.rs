@@ -2,14 +2,23 @@ mod git;
 mod local;
 mod remote;
 
+use std::collections::HashSet;
 use std::fmt;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
 
-use anyhow::{Context as ResultExt, Result};
+use anyhow::{bail, Context as ResultExt, Result};
+use maplit::hashmap;
+use url::Url;
 
 use crate::config::Source;
 use crate::context::Context;
+pub use crate::lock::source::git::Fetched;
 use crate::lock::source::git::GitCheckout;
+pub use crate::lock::source::remote::render_url;
+use crate::lock::LockMode;
+use crate::util::git as git_util;
+use crate::util::git::RemoteRefsCache;
 
 /// A locked `Source`.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -18,22 +27,127 @@ pub struct LockedSource {
     pub dir: PathBuf,
     /// The downloaded file.
     pub file: Option<PathBuf>,
+    /// The commit that a Git source was checked out at.
+    pub revision: Option<String>,
+    /// The reference that a Git source was resolved from.
+    pub reference: Option<String>,
+    /// How the tree of an exported Git source was fetched.
+    pub fetched: Option<Fetched>,
 }
 
-// Install a source.
-pub fn lock(ctx: &Context, src: Source) -> Result<LockedSource> {
+/// State that is shared by the sources that are locked in a single run.
+#[derive(Debug, Default)]
+pub struct Session {
+    /// The references of remote Git repositories, so that each repository is
+    /// only queried once.
+    pub remote_refs: RemoteRefsCache,
+    /// The sources as they were recorded in the previous lock file.
+    pub previous: Vec<LockedSource>,
+    /// The existing clones that are locked where they are, and the locations
+    /// that they are moved to when the lock file is written.
+    pub migrations: Vec<(PathBuf, PathBuf)>,
+}
+
+/// An operation that would be performed when locking a `Source`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// The source is not installed and would be installed.
+    Install,
+    /// The source is installed and would only be checked.
+    Check,
+    /// The source is installed and would be updated.
+    Update,
+    /// The source is installed and would be reinstalled.
+    Reinstall,
+}
+
+impl Operation {
+    /// A short description of the operation for displaying.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Install => "Install",
+            Self::Check => "Check",
+            Self::Update => "Update",
+            Self::Reinstall => "Reinstall",
+        }
+    }
+}
+
+/// Determine which operation locking a source would perform.
+///
+/// This only checks whether the source is already installed, it does not
+/// install anything.
+pub fn plan(ctx: &Context, src: &Source, clone_dir_layout: Option<&str>) -> Result<Operation> {
+    let installed = match location(ctx, src, clone_dir_layout)? {
+        // Incomplete clones are installed again.
+        Some(LockedSource { dir, .. })
+            if matches!(
+                src,
+                Source::Git {
+                    archive: false,
+                    no_history: false,
+                    verify_signature: None,
+                    ..
+                }
+            ) =>
+        {
+            git::is_installed(&dir)
+        }
+        Some(LockedSource { dir, file, .. }) => file.as_ref().unwrap_or(&dir).exists(),
+        // Local sources are never installed, only checked.
+        None => return Ok(Operation::Check),
+    };
+    Ok(match (installed, ctx.lock_mode()) {
+        (false, _) => Operation::Install,
+        (true, LockMode::Normal) => Operation::Check,
+        (true, LockMode::Update) => Operation::Update,
+        (true, LockMode::Reinstall) => Operation::Reinstall,
+    })
+}
+
+/// The default template for the directory that Git sources are cloned to.
+const DEFAULT_CLONE_DIR_LAYOUT: &str = "{{ host }}/{{ path }}";
+
+/// Returns the location that a Git or Remote source is installed to.
+///
+/// Git sources are cloned to the directory rendered from the clone directory
+/// layout, unless they are given an explicit `clone_as` directory.
+pub fn location(
+    ctx: &Context,
+    src: &Source,
+    clone_dir_layout: Option<&str>,
+) -> Result<Option<LockedSource>> {
     match src {
-        Source::Git { url, reference } => {
-            let mut dir = ctx.clone_dir().to_path_buf();
-            dir.push(
-                url.host_str()
-                    .with_context(s!("URL `{}` has no host", url))?,
-            );
-            dir.push(url.path().trim_start_matches('/'));
-            git::lock(ctx, dir, &url, reference.into())
+        Source::Git {
+            clone_as: Some(name),
+            ..
+        } => Ok(Some(LockedSource {
+            dir: ctx.clone_dir().join(name),
+            file: None,
+            revision: None,
+            reference: None,
+            fetched: None,
+        })),
+
+        Source::Git { url, .. } => {
+            let layout = clone_dir_layout.unwrap_or(DEFAULT_CLONE_DIR_LAYOUT);
+            let dir = ctx.clone_dir().join(render_clone_dir(layout, url)?);
+            Ok(Some(LockedSource {
+                dir,
+                file: None,
+                revision: None,
+                reference: None,
+                fetched: None,
+            }))
         }
 
-        Source::Remote { url } => {
+        Source::Remote {
+            url,
+            version,
+            filename,
+            ..
+        } => {
+            let url = &remote::render_url(url, version.as_deref())?;
             let mut dir = ctx.download_dir().to_path_buf();
             dir.push(
                 url.host_str()
@@ -45,25 +159,247 @@ pub fn lock(ctx: &Context, src: Source) -> Result<LockedSource> {
                 .with_context(s!("URL `{}` is cannot-be-a-base", url))?
                 .collect();
             let (base, rest) = segments.split_last().unwrap();
-            let base = if base.is_empty() { "index" } else { *base };
+            let base = match filename {
+                Some(filename) => filename.as_str(),
+                None if base.is_empty() => "index",
+                None => base,
+            };
             dir.push(rest.iter().collect::<PathBuf>());
             let file = dir.join(base);
+            Ok(Some(LockedSource {
+                dir,
+                file: Some(file),
+                revision: None,
+                reference: None,
+                fetched: None,
+            }))
+        }
 
-            remote::lock(ctx, dir, file, &url)
+        Source::Local { .. } => Ok(None),
+    }
+}
+
+/// Find the existing clones of Git sources that are not at their location, for
+/// example after the clone directory layout changed, so that they can be moved
+/// instead of cloned again.
+///
+/// Clones are looked for at the location under the default layout, and in the
+/// `previous` directories, which are the source directories of the previous
+/// lock file. A clone is only used if its `origin` remote has the same URL as
+/// the source, and no other source is located there. Returns the directory of
+/// each clone and the location that it should be moved to.
+pub fn migrations<'a, I>(
+    ctx: &Context,
+    sources: I,
+    clone_dir_layout: Option<&str>,
+    previous: &[PathBuf],
+) -> Vec<(PathBuf, PathBuf)>
+where
+    I: IntoIterator<Item = &'a Source>,
+{
+    if matches!(ctx.lock_mode(), LockMode::Reinstall) {
+        return Vec::new();
+    }
+    let mut used = HashSet::new();
+    let mut missing = Vec::new();
+    for src in sources {
+        if let Ok(Some(LockedSource { dir, .. })) = location(ctx, src, clone_dir_layout) {
+            if let Source::Git {
+                url,
+                archive: false,
+                no_history: false,
+                ..
+            } = src
+            {
+                if !dir.exists() {
+                    missing.push((url, dir.clone()));
+                }
+            }
+            used.insert(dir);
+        }
+    }
+
+    let mut migrations: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for (url, dir) in missing {
+        // Sources with the same URL can share a location.
+        if migrations.iter().any(|(_, to)| *to == dir) {
+            continue;
+        }
+        let default = render_clone_dir(DEFAULT_CLONE_DIR_LAYOUT, url)
+            .ok()
+            .map(|d| ctx.clone_dir().join(d));
+        let candidate = default.iter().chain(previous).find(|candidate| {
+            candidate.starts_with(ctx.clone_dir())
+                && !used.contains(*candidate)
+                && git_util::open(candidate)
+                    .is_ok_and(|repo| git_util::origin_url(&repo).as_deref() == Some(url.as_str()))
+        });
+        if let Some(from) = candidate {
+            used.insert(from.clone());
+            migrations.push((from.clone(), dir));
+        }
+    }
+    migrations
+}
+
+/// Move a clone to a new directory, creating its parent directories.
+pub fn move_clone(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)
+            .with_context(s!("failed to create directory `{}`", parent.display()))?;
+    }
+    fs::rename(from, to).with_context(s!(
+        "failed to move clone `{}` to `{}`",
+        from.display(),
+        to.display()
+    ))
+}
+
+/// Render the clone directory layout for a Git source.
+///
+/// The `host` and `path` of the URL are available, along with the `owner` and
+/// `repo` which are the last component of the path, without any `.git`
+/// extension, and the rest of the path before it.
+pub fn render_clone_dir(layout: &str, url: &Url) -> Result<PathBuf> {
+    let host = url
+        .host_str()
+        .with_context(s!("URL `{}` has no host", url))?;
+    let path = url.path().trim_matches('/');
+    let (owner, repo) = path.rsplit_once('/').unwrap_or(("", path));
+    let repo = repo.strip_suffix(".git").unwrap_or(repo);
+    let data = hashmap! {
+        "host" => host,
+        "path" => path,
+        "owner" => owner,
+        "repo" => repo,
+    };
+    let mut hbs = handlebars::Handlebars::new();
+    hbs.set_strict_mode(true);
+    hbs.register_escape_fn(handlebars::no_escape);
+    let rendered = hbs
+        .render_template(layout, &data)
+        .context("failed to render the `clone_dir_layout` template")?;
+    let dir = PathBuf::from(&rendered);
+    if dir.components().next().is_none()
+        || !dir.components().all(|c| matches!(c, Component::Normal(_)))
+    {
+        bail!(
+            "the `clone_dir_layout` template rendered an invalid directory `{}` for `{}`",
+            rendered,
+            url
+        );
+    }
+    Ok(dir)
+}
+
+// Install a source.
+//
+// If `require_clean` is set then this fails if an existing clone of a Git
+// source has uncommitted changes.
+pub fn lock(
+    ctx: &Context,
+    session: &Session,
+    src: Source,
+    clone_dir_layout: Option<&str>,
+    require_clean: bool,
+) -> Result<LockedSource> {
+    let location = location(ctx, &src, clone_dir_layout)?;
+    match src {
+        Source::Git {
+            url,
+            reference,
+            archive,
+            no_history,
+            verify_signature,
+            ..
+        } => {
+            let LockedSource { dir, .. } = location.unwrap();
+            if archive || no_history {
+                git::export(ctx, session, dir, &url, reference.into(), no_history)
+            } else {
+                // A clone that is migrated is locked where it is, it is only
+                // moved when the lock file is written.
+                let dir = session
+                    .migrations
+                    .iter()
+                    .find(|(_, to)| *to == dir)
+                    .map_or(dir, |(from, _)| from.clone());
+                if require_clean {
+                    git::check_clean(ctx, &dir)?;
+                }
+                git::lock(
+                    ctx,
+                    session,
+                    dir,
+                    &url,
+                    reference.into(),
+                    verify_signature.as_deref(),
+                )
+            }
+        }
+
+        Source::Remote {
+            url,
+            version,
+            checksum,
+            ..
+        } => {
+            let LockedSource { dir, file, .. } = location.unwrap();
+            let url = remote::render_url(&url, version.as_deref())?;
+            remote::lock(ctx, dir, file.unwrap(), &url, checksum.as_deref())
         }
 
         Source::Local { dir } => local::lock(ctx, dir),
     }
 }
 
+/// Put a Git source back at a commit that it was locked at before.
+///
+/// Clones are checked out at the commit, exported sources are exported again.
+/// Other sources are left as they are.
+pub fn restore(ctx: &Context, src: &Source, dir: PathBuf, revision: &str) -> Result<()> {
+    match src {
+        Source::Git {
+            url,
+            archive,
+            no_history,
+            ..
+        } => {
+            let c = GitCheckout::Rev(revision.to_string());
+            if *archive || *no_history {
+                git::export(ctx, &Session::default(), dir, url, c, *no_history).map(drop)
+            } else {
+                git::restore(ctx, &dir, url, c)
+            }
+        }
+        Source::Remote { .. } | Source::Local { .. } => Ok(()),
+    }
+}
+
+/// Check that a Git source exists, and has the configured reference, without
+/// installing it. Other sources are not checked.
+pub fn check(ctx: &Context, src: &Source) -> Result<()> {
+    match src {
+        Source::Git { url, reference, .. } => {
+            git::check_remote(ctx, &Session::default(), url, reference.clone().into())
+        }
+        Source::Remote { .. } | Source::Local { .. } => Ok(()),
+    }
+}
+
 impl fmt::Display for Source {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Git { url, reference } => {
+            Self::Git { url, reference, .. } => {
                 let checkout: GitCheckout = reference.clone().into();
                 write!(f, "{}{}", url, checkout)
             }
-            Self::Remote { url, .. } => write!(f, "{}", url),
+            Self::Remote { url, version, .. } => {
+                match remote::render_url(url, version.as_deref()) {
+                    Ok(url) => write!(f, "{}", url),
+                    Err(_) => write!(f, "{}", url),
+                }
+            }
             Self::Local { dir } => write!(f, "{}", dir.display()),
         }
     }
@@ -77,9 +413,11 @@ impl fmt::Display for Source {
 mod tests {
     use super::*;
 
-    use url::Url;
+    use std::fs;
+    use std::path::Path;
 
     use crate::config::GitReference;
+    use crate::util::testing;
 
     #[test]
     fn source_to_string() {
@@ -87,6 +425,10 @@ mod tests {
             Source::Git {
                 url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
                 reference: Some(GitReference::Tag("v0.3.0".to_string())),
+                archive: false,
+                clone_as: None,
+                no_history: false,
+                verify_signature: None,
             }
             .to_string(),
             "https://github.com/rossmacarthur/sheldon-test@v0.3.0"
@@ -95,6 +437,10 @@ mod tests {
             Source::Git {
                 url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
                 reference: None,
+                archive: false,
+                clone_as: None,
+                no_history: false,
+                verify_signature: None,
             }
             .to_string(),
             "https://github.com/rossmacarthur/sheldon-test"
@@ -103,6 +449,9 @@ mod tests {
             Source::Remote {
                 url: Url::parse("https://github.com/rossmacarthur/sheldon/raw/0.3.0/LICENSE-MIT")
                     .unwrap(),
+                version: None,
+                checksum: None,
+                filename: None,
             }
             .to_string(),
             "https://github.com/rossmacarthur/sheldon/raw/0.3.0/LICENSE-MIT"
@@ -116,6 +465,71 @@ mod tests {
         );
     }
 
+    fn git_source(url: &str) -> Source {
+        Source::Git {
+            url: Url::parse(url).unwrap(),
+            reference: None,
+            archive: false,
+            clone_as: None,
+            no_history: false,
+            verify_signature: None,
+        }
+    }
+
+    #[test]
+    fn location_clone_dir_layout_default() {
+        let ctx = Context::testing(Path::new("/"));
+        for (url, expected) in [
+            (
+                "https://github.com/rossmacarthur/sheldon-test",
+                "github.com/rossmacarthur/sheldon-test",
+            ),
+            (
+                "https://gist.github.com/579d02802b1cc17baed07753d09f5009.git",
+                "gist.github.com/579d02802b1cc17baed07753d09f5009.git",
+            ),
+        ] {
+            let locked = location(&ctx, &git_source(url), None).unwrap().unwrap();
+            assert_eq!(locked.dir, ctx.clone_dir().join(expected));
+        }
+    }
+
+    #[test]
+    fn location_clone_dir_layout_flat() {
+        let ctx = Context::testing(Path::new("/"));
+        let layout = Some("{{ owner }}-{{ repo }}");
+        for (url, expected) in [
+            (
+                "https://github.com/rossmacarthur/sheldon-test",
+                "rossmacarthur-sheldon-test",
+            ),
+            (
+                "https://gitlab.com/group/subgroup/repo.git",
+                "group/subgroup-repo",
+            ),
+        ] {
+            let locked = location(&ctx, &git_source(url), layout).unwrap().unwrap();
+            assert_eq!(locked.dir, ctx.clone_dir().join(expected));
+        }
+    }
+
+    #[test]
+    fn location_clone_dir_layout_invalid() {
+        let ctx = Context::testing(Path::new("/"));
+        let source = git_source("https://github.com/rossmacarthur/sheldon-test");
+        let err = location(&ctx, &source, Some("../{{ repo }}")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the `clone_dir_layout` template rendered an invalid directory `../sheldon-test` \
+             for `https://github.com/rossmacarthur/sheldon-test`"
+        );
+        let err = location(&ctx, &source, Some("{{ name }}")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to render the `clone_dir_layout` template"
+        );
+    }
+
     #[test]
     fn lock_with_git() {
         let temp = tempfile::tempdir().expect("create temporary directory");
@@ -125,18 +539,92 @@ mod tests {
         let source = Source::Git {
             url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
             reference: None,
+            archive: false,
+            clone_as: None,
+            no_history: false,
+            verify_signature: None,
         };
-        let locked = lock(&ctx, source).unwrap();
+        let locked = lock(&ctx, &Session::default(), source, None, false).unwrap();
 
         assert_eq!(
             locked,
             LockedSource {
                 dir: dir.join("repos/github.com/rossmacarthur/sheldon-test"),
                 file: None,
+                revision: Some("be8fde277e76f35efbe46848fb352cee68549962".to_string()),
+                reference: Some("master".to_string()),
+                fetched: None,
             }
         );
     }
 
+    #[test]
+    fn lock_with_git_require_clean() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+
+        // Seed the clone directory so that locking does not need the network.
+        let origin = temp.path().join("origin");
+        testing::git_commit(&origin, "test.plugin.zsh", "echo 'test'\n");
+        let clone_dir = ctx.clone_dir().join("example.com/test");
+        let url = Url::from_directory_path(&origin).unwrap();
+        git2::Repository::clone(url.as_str(), &clone_dir).unwrap();
+
+        let source = Source::Git {
+            url: Url::parse("https://example.com/test").unwrap(),
+            reference: None,
+            archive: false,
+            clone_as: None,
+            no_history: false,
+            verify_signature: None,
+        };
+        lock(&ctx, &Session::default(), source.clone(), None, true).unwrap();
+
+        fs::write(clone_dir.join("test.plugin.zsh"), "echo 'dirty'\n").unwrap();
+        lock(&ctx, &Session::default(), source.clone(), None, false).unwrap();
+        let err = lock(&ctx, &Session::default(), source, None, true).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "the working tree of `{}` has uncommitted changes and `require_clean` is set",
+                ctx.replace_home(&clone_dir).display()
+            )
+        );
+    }
+
+    #[test]
+    fn lock_with_git_repairs_interrupted_clone() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let origin = temp.path().join("origin");
+        testing::git_commit(&origin, "test.plugin.zsh", "echo 'test'\n");
+
+        let source = Source::Git {
+            url: Url::from_directory_path(&origin).unwrap(),
+            reference: None,
+            archive: false,
+            clone_as: Some("test".into()),
+            no_history: false,
+            verify_signature: None,
+        };
+        let clone_dir = ctx.clone_dir().join("test");
+
+        // An empty directory, and a repository without any commits checked
+        // out, are both left behind by an interrupted clone.
+        for interrupt in [
+            |dir: &Path| fs::create_dir_all(dir).unwrap(),
+            |dir: &Path| drop(git2::Repository::init(dir).unwrap()),
+        ] {
+            interrupt(&clone_dir);
+            assert_eq!(plan(&ctx, &source, None).unwrap(), Operation::Install);
+            let locked = lock(&ctx, &Session::default(), source.clone(), None, false).unwrap();
+            assert_eq!(locked.dir, clone_dir);
+            assert!(clone_dir.join("test.plugin.zsh").exists());
+            assert_eq!(plan(&ctx, &source, None).unwrap(), Operation::Check);
+            fs::remove_dir_all(&clone_dir).unwrap();
+        }
+    }
+
     #[test]
     fn lock_with_remote() {
         let temp = tempfile::tempdir().expect("create temporary directory");
@@ -146,8 +634,11 @@ mod tests {
         let source = Source::Remote {
             url: Url::parse("https://github.com/rossmacarthur/sheldon/raw/0.3.0/LICENSE-MIT")
                 .unwrap(),
+            version: None,
+            checksum: None,
+            filename: None,
         };
-        let locked = lock(&ctx, source).unwrap();
+        let locked = lock(&ctx, &Session::default(), source, None, false).unwrap();
 
         assert_eq!(
             locked.dir,
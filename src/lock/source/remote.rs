@@ -1,51 +1,435 @@
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
 
-use anyhow::{Context as ResultExt, Result};
+use anyhow::{bail, Context as ResultExt, Result};
+use maplit::hashmap;
+use regex_macro::regex;
 use url::Url;
 
 use crate::context::Context;
 use crate::lock::source::LockedSource;
 use crate::lock::LockMode;
 use crate::util;
-use crate::util::TempPath;
 
-pub fn lock(ctx: &Context, dir: PathBuf, file: PathBuf, url: &Url) -> Result<LockedSource> {
-    if matches!(ctx.lock_mode(), LockMode::Normal) && file.exists() {
+/// Render the `version` into the URL of a remote source.
+///
+/// The braces and spaces of a template are percent-encoded when the URL is
+/// parsed, so they are decoded again before rendering. URLs without a template
+/// are returned as is.
+pub fn render_url(url: &Url, version: Option<&str>) -> Result<Url> {
+    let encoded = url.as_str();
+    let pattern = regex!(r"(?:%7B|\{)(?:%7B|\{).*?(?:%7D|\})(?:%7D|\})");
+    if !pattern.is_match(encoded) {
+        return Ok(url.clone());
+    }
+    let mut template = String::with_capacity(encoded.len());
+    let mut last = 0;
+    for m in pattern.find_iter(encoded) {
+        template.push_str(&encoded[last..m.start()]);
+        template.push_str(
+            &m.as_str()
+                .replace("%7B", "{")
+                .replace("%7D", "}")
+                .replace("%20", " "),
+        );
+        last = m.end();
+    }
+    template.push_str(&encoded[last..]);
+
+    let mut data = hashmap! {};
+    if let Some(version) = version {
+        data.insert("version", version);
+    }
+    let mut hbs = handlebars::Handlebars::new();
+    hbs.set_strict_mode(true);
+    hbs.register_escape_fn(handlebars::no_escape);
+    let rendered = hbs
+        .render_template(&template, &data)
+        .with_context(s!("failed to render URL `{}`", template))?;
+    Url::parse(&rendered).with_context(s!("failed to parse rendered URL `{}`", rendered))
+}
+
+pub fn lock(
+    ctx: &Context,
+    dir: PathBuf,
+    file: PathBuf,
+    url: &Url,
+    checksum: Option<&str>,
+) -> Result<LockedSource> {
+    if matches!(ctx.lock_mode(), LockMode::Normal)
+        && file.exists()
+        && verify(&file, url, checksum).is_ok()
+    {
         status!(ctx, "Checked", &url);
         return Ok(LockedSource {
             dir,
             file: Some(file),
+            revision: None,
+            reference: None,
+            fetched: None,
         });
     }
 
-    let temp_file =
-        TempPath::new_force(&file).context("failed to prepare temporary download directory")?;
-    {
-        let path = temp_file.path();
-        fs::create_dir_all(&dir).with_context(s!("failed to create dir `{}`", dir.display()))?;
-        let temp_file_handle =
-            fs::File::create(path).with_context(s!("failed to create `{}`", path.display()))?;
-        util::download(url.as_ref(), temp_file_handle)
-            .with_context(s!("failed to download `{}`", url))?;
-    }
-    temp_file
-        .rename(&file)
-        .context("failed to rename temporary download file")?;
+    fs::create_dir_all(&dir).with_context(s!("failed to create dir `{}`", dir.display()))?;
+    let part = part_path(&file);
+    if matches!(ctx.lock_mode(), LockMode::Reinstall) {
+        remove_part(&part)?;
+    }
+    download(ctx, url, &part)?;
+    if let Err(err) = verify(&part, url, checksum) {
+        remove_part(&part)?;
+        return Err(err);
+    }
+    fs::rename(&part, &file).context("failed to rename partial download file")?;
+    remove_part(&part)?;
+    trace!(
+        ctx,
+        "Renamed",
+        &format!(
+            "{} to {}",
+            ctx.replace_home(&part).display(),
+            ctx.replace_home(&file).display()
+        )
+    );
     status!(ctx, "Fetched", &url);
 
     Ok(LockedSource {
         dir,
         file: Some(file),
+        revision: None,
+        reference: None,
+        fetched: None,
+    })
+}
+
+/// Returns the path that a remote file is downloaded to until it is complete.
+fn part_path(file: &Path) -> PathBuf {
+    let mut name = file.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    file.with_file_name(name)
+}
+
+/// Returns the path that the validator of a partial download is stored at.
+///
+/// This is the `ETag` or `Last-Modified` header of the response, which is used
+/// to check that the remote file has not changed when resuming the download.
+fn validator_path(part: &Path) -> PathBuf {
+    let mut name = part.file_name().unwrap_or_default().to_os_string();
+    name.push(".validator");
+    part.with_file_name(name)
+}
+
+/// Remove a partial download and its validator, if there are any.
+fn remove_part(part: &Path) -> Result<()> {
+    for path in [part.to_path_buf(), validator_path(part)] {
+        match fs::remove_file(&path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                return Err(err).with_context(s!("failed to remove `{}`", path.display()))
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Download the file at the URL to the partial download path.
+///
+/// If a previous download was interrupted then it is resumed using a range
+/// request that only succeeds if the remote file still matches the stored
+/// validator, falling back to downloading the whole file if the server does
+/// not support ranges or the file has changed. If this download is interrupted
+/// then the partial download is kept so that the next one can resume it.
+fn download(ctx: &Context, url: &Url, part: &Path) -> Result<()> {
+    let offset = fs::metadata(part).map_or(0, |m| m.len());
+    let validator_path = validator_path(part);
+    if offset > 0 {
+        // Without a validator there is no way to tell whether the remote file
+        // has changed since, so the partial download is discarded.
+        if let Ok(validator) = fs::read_to_string(&validator_path) {
+            let handle = fs::OpenOptions::new()
+                .append(true)
+                .open(part)
+                .with_context(s!("failed to open `{}`", part.display()))?;
+            let resume = Some((offset, validator.as_str()));
+            match util::download(
+                url.as_ref(),
+                handle,
+                ctx.max_download_size(),
+                resume,
+                &mut None,
+            ) {
+                Ok(()) => {
+                    trace!(ctx, "Resumed", &format!("{} from byte {}", url, offset));
+                    return Ok(());
+                }
+                Err(err) if !is_unsupported_range(&err) => {
+                    return Err(err).with_context(s!("failed to download `{}`", url))
+                }
+                Err(_) => {}
+            }
+        }
+        trace!(ctx, "Restarted", &url);
+    }
+    remove_part(part)?;
+    let handle =
+        fs::File::create(part).with_context(s!("failed to create `{}`", part.display()))?;
+    let mut validator = None;
+    util::download(
+        url.as_ref(),
+        handle,
+        ctx.max_download_size(),
+        None,
+        &mut validator,
+    )
+    .map_err(|err| {
+        // Only an interrupted transfer is worth resuming, and only if the
+        // remote file can be checked for changes when it is.
+        let resumable = err.downcast_ref::<curl::Error>().is_some()
+            && fs::metadata(part).is_ok_and(|m| m.len() > 0)
+            && validator.is_some_and(|validator| fs::write(&validator_path, validator).is_ok());
+        if !resumable {
+            fs::remove_file(part).ok();
+        }
+        err.context(format!("failed to download `{}`", url))
     })
 }
 
+/// Whether resuming a download failed because the server does not support
+/// range requests, or does not accept the requested range.
+fn is_unsupported_range(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<curl::Error>()
+        .is_some_and(|err| err.is_range_error() || err.is_http_returned_error())
+}
+
+/// Verify that the file at the given path matches the expected checksum.
+fn verify(path: &Path, url: &Url, checksum: Option<&str>) -> Result<()> {
+    let expected = match checksum {
+        Some(checksum) => checksum,
+        None => return Ok(()),
+    };
+    let actual = format!(
+        "sha256:{}",
+        util::sha256::digest_file(path).with_context(s!("failed to read `{}`", path.display()))?
+    );
+    if actual != expected {
+        bail!(
+            "checksum mismatch for `{}`, expected `{}` but got `{}`",
+            url,
+            expected,
+            actual
+        );
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use std::thread;
     use std::time;
 
     use super::*;
+    use crate::util::testing;
+
+    fn lock_max_download_size(content_length: bool, max_size: u64) -> Result<()> {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let mut ctx = Context::testing(dir);
+        ctx.max_download_size = Some(max_size);
+        let response = if content_length {
+            "HTTP/1.1 200 OK\r\nContent-Length: 19\r\n\r\necho 'hello world'\n"
+        } else {
+            "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\necho 'hello world'\n"
+        };
+        let (url, _) = testing::serve(vec![response.to_string()]);
+        let url = url.join("plugin.zsh").unwrap();
+        let file = dir.join("plugin.zsh");
+        let result = lock(&ctx, dir.to_path_buf(), file.clone(), &url, None);
+        match result {
+            Ok(_) => assert_eq!(fs::read_to_string(&file).unwrap(), "echo 'hello world'\n"),
+            Err(_) => assert!(!file.exists()),
+        }
+        result.map(drop)
+    }
+
+    #[test]
+    fn lock_remote_max_download_size_respected() {
+        lock_max_download_size(true, 19).unwrap();
+        lock_max_download_size(false, 19).unwrap();
+    }
+
+    #[test]
+    fn lock_remote_max_download_size_exceeded() {
+        for content_length in [true, false] {
+            let err = lock_max_download_size(content_length, 18).unwrap_err();
+            assert_eq!(
+                err.root_cause().to_string(),
+                "download exceeds the maximum size of 18 bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn lock_remote_checksum_matches() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let ctx = Context::testing(dir);
+        let (url, _) = testing::serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 19\r\n\r\necho 'hello world'\n".to_string(),
+        ]);
+        let url = url.join("plugin.zsh").unwrap();
+        let file = dir.join("plugin.zsh");
+        let checksum = format!("sha256:{}", util::sha256::digest(b"echo 'hello world'\n"));
+
+        lock(&ctx, dir.to_path_buf(), file.clone(), &url, Some(&checksum)).unwrap();
+
+        assert_eq!(fs::read_to_string(&file).unwrap(), "echo 'hello world'\n");
+    }
+
+    #[test]
+    fn lock_remote_checksum_mismatch() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let ctx = Context::testing(dir);
+        let (url, _) = testing::serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 19\r\n\r\necho 'hello world'\n".to_string(),
+        ]);
+        let url = url.join("plugin.zsh").unwrap();
+        let file = dir.join("plugin.zsh");
+        let checksum = format!("sha256:{}", "0".repeat(64));
+
+        let err = lock(&ctx, dir.to_path_buf(), file.clone(), &url, Some(&checksum)).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "checksum mismatch for `{}`, expected `{}` but got `sha256:{}`",
+                url,
+                checksum,
+                util::sha256::digest(b"echo 'hello world'\n")
+            )
+        );
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn lock_remote_resumes_interrupted_download() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let ctx = Context::testing(dir);
+        let file = dir.join("plugin.zsh");
+        let (url, _) = testing::serve(vec![
+            "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 19\r\n\
+             Connection: close\r\n\r\necho 'hel"
+                .to_string(),
+        ]);
+
+        lock(
+            &ctx,
+            dir.to_path_buf(),
+            file.clone(),
+            &url.join("plugin.zsh").unwrap(),
+            None,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            fs::read_to_string(dir.join("plugin.zsh.part")).unwrap(),
+            "echo 'hel"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("plugin.zsh.part.validator")).unwrap(),
+            "\"v1\""
+        );
+
+        let (url, server) = testing::serve(vec![
+            "HTTP/1.1 206 Partial Content\r\nAccept-Ranges: bytes\r\n\
+             Content-Range: bytes 9-18/19\r\nETag: \"v1\"\r\nContent-Length: 10\r\n\
+             Connection: close\r\n\r\nlo world'\n"
+                .to_string(),
+        ]);
+
+        lock(
+            &ctx,
+            dir.to_path_buf(),
+            file.clone(),
+            &url.join("plugin.zsh").unwrap(),
+            None,
+        )
+        .unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].header("Range"), Some("bytes=9-"));
+        assert_eq!(requests[0].header("If-Range"), Some("\"v1\""));
+        assert_eq!(fs::read_to_string(&file).unwrap(), "echo 'hello world'\n");
+        assert!(!dir.join("plugin.zsh.part").exists());
+        assert!(!dir.join("plugin.zsh.part.validator").exists());
+    }
+
+    #[test]
+    fn lock_remote_restarts_changed_partial_download() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let ctx = Context::testing(dir);
+        let file = dir.join("plugin.zsh");
+        fs::write(dir.join("plugin.zsh.part"), "stale").unwrap();
+        fs::write(dir.join("plugin.zsh.part.validator"), "\"v1\"").unwrap();
+        // The server does not match the validator, or does not support ranges,
+        // so it responds with the whole file.
+        let response = "HTTP/1.1 200 OK\r\nETag: \"v2\"\r\nContent-Length: 19\r\n\
+                        Connection: close\r\n\r\necho 'hello world'\n"
+            .to_string();
+        let (url, server) = testing::serve(vec![response.clone(), response]);
+
+        lock(
+            &ctx,
+            dir.to_path_buf(),
+            file.clone(),
+            &url.join("plugin.zsh").unwrap(),
+            None,
+        )
+        .unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].header("Range"), Some("bytes=5-"));
+        assert_eq!(requests[0].header("If-Range"), Some("\"v1\""));
+        assert_eq!(requests[1].header("Range"), None);
+        assert_eq!(requests[1].header("If-Range"), None);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "echo 'hello world'\n");
+        assert!(!dir.join("plugin.zsh.part").exists());
+        assert!(!dir.join("plugin.zsh.part.validator").exists());
+    }
+
+    #[test]
+    fn lock_remote_discards_partial_download_without_validator() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let ctx = Context::testing(dir);
+        let file = dir.join("plugin.zsh");
+        fs::write(dir.join("plugin.zsh.part"), "echo 'hel").unwrap();
+        let (url, server) = testing::serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 19\r\nConnection: close\r\n\r\n\
+             echo 'hello world'\n"
+                .to_string(),
+        ]);
+
+        lock(
+            &ctx,
+            dir.to_path_buf(),
+            file.clone(),
+            &url.join("plugin.zsh").unwrap(),
+            None,
+        )
+        .unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].header("Range"), None);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "echo 'hello world'\n");
+        assert!(!dir.join("plugin.zsh.part").exists());
+    }
 
     #[test]
     fn lock_remote_and_reinstall() {
@@ -57,26 +441,49 @@ mod tests {
         let url =
             Url::parse("https://github.com/rossmacarthur/sheldon/raw/0.3.0/LICENSE-MIT").unwrap();
 
-        let locked = lock(&ctx, dir.to_path_buf(), file.clone(), &url).unwrap();
+        let locked = lock(&ctx, dir.to_path_buf(), file.clone(), &url, None).unwrap();
 
         assert_eq!(locked.dir, dir);
         assert_eq!(locked.file, Some(file.clone()));
         assert_eq!(
             fs::read_to_string(&file).unwrap(),
-            fs::read_to_string(&manifest_dir.join("LICENSE-MIT")).unwrap()
+            fs::read_to_string(manifest_dir.join("LICENSE-MIT")).unwrap()
         );
 
         let modified = fs::metadata(&file).unwrap().modified().unwrap();
         thread::sleep(time::Duration::from_secs(1));
         ctx.lock_mode = Some(LockMode::Reinstall);
-        let locked = lock(&ctx, dir.to_path_buf(), file.clone(), &url).unwrap();
+        let locked = lock(&ctx, dir.to_path_buf(), file.clone(), &url, None).unwrap();
 
         assert_eq!(locked.dir, dir);
         assert_eq!(locked.file, Some(file.clone()));
         assert_eq!(
             fs::read_to_string(&file).unwrap(),
-            fs::read_to_string(&manifest_dir.join("LICENSE-MIT")).unwrap()
+            fs::read_to_string(manifest_dir.join("LICENSE-MIT")).unwrap()
         );
         assert!(fs::metadata(&file).unwrap().modified().unwrap() > modified)
     }
+
+    #[test]
+    fn render_url_with_version() {
+        let url =
+            Url::parse("https://example.com/{{ version }}/completions-{{version}}.sh").unwrap();
+        assert_eq!(
+            render_url(&url, Some("1.2.3")).unwrap().as_str(),
+            "https://example.com/1.2.3/completions-1.2.3.sh"
+        );
+    }
+
+    #[test]
+    fn render_url_without_template() {
+        let url = Url::parse("https://example.com/completions.sh").unwrap();
+        assert_eq!(render_url(&url, Some("1.2.3")).unwrap(), url);
+        assert_eq!(render_url(&url, None).unwrap(), url);
+    }
+
+    #[test]
+    fn render_url_missing_version() {
+        let url = Url::parse("https://example.com/{{ version }}/completions.sh").unwrap();
+        assert!(render_url(&url, None).is_err());
+    }
 }
@@ -61,6 +61,11 @@ macro_rules! warning_v {
     ($($arg:tt)*) => { _status!(crate::context::Verbosity::Verbose, crate::context::Color::Yellow, $($arg)*) }
 }
 
+/// Log a Git operation or filesystem change, only in trace mode.
+macro_rules! trace {
+    ($($arg:tt)*) => { _status!(crate::context::Verbosity::Trace, crate::context::Color::Blue, $($arg)*) }
+}
+
 /// Log an error.
 macro_rules! error {
     ($ctx:expr, $err:expr) => {
@@ -4,21 +4,26 @@ mod cli;
 mod config;
 mod context;
 mod editor;
+mod health;
 mod lock;
 mod util;
 
+use std::cmp::Reverse;
+use std::env;
 use std::fs;
 use std::io;
+use std::io::Write;
 use std::panic;
 use std::path::Path;
 use std::process;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context as ResultExt, Error, Result};
 
 use crate::cli::{Command, Opt};
-use crate::config::{EditConfig, EditPlugin, Shell};
+use crate::config::{Config, EditConfig, EditPlugin, Plugin, Shell};
 use crate::context::Context;
-use crate::lock::LockedConfig;
+use crate::lock::{LockedConfig, LockedPlugin, Measurement};
 use crate::util::{underlying_io_error_kind, PathExt};
 
 fn main() {
@@ -44,7 +49,19 @@ pub fn run_command(ctx: &Context, command: Command) -> Result<()> {
     // the lock and source commands.
     let _guard = match acquire_mutex(ctx, ctx.config_dir()) {
         Ok(g) => Some(g),
-        Err(_) if !matches!(command, Command::Lock | Command::Source) => None,
+        Err(_)
+            if !matches!(
+                command,
+                Command::Lock { .. }
+                    | Command::Source { .. }
+                    | Command::Clean { .. }
+                    | Command::Gc
+                    | Command::Import { .. }
+                    | Command::ImportBundle
+            ) =>
+        {
+            None
+        }
         Err(err) => {
             return Err(err).context("failed to acquire lock on config directory");
         }
@@ -52,11 +69,76 @@ pub fn run_command(ctx: &Context, command: Command) -> Result<()> {
     let mut warnings = Vec::new();
     let result = match command {
         Command::Init { shell } => init(ctx, shell),
-        Command::Add { name, plugin } => add(ctx, name, &plugin),
+        Command::Add {
+            name,
+            plugin,
+            verify,
+        } => add(ctx, name, &plugin, verify),
         Command::Edit => edit(ctx),
         Command::Remove { name } => remove(ctx, name),
-        Command::Lock => lock(ctx, &mut warnings),
-        Command::Source => source(ctx, &mut warnings),
+        Command::List { tags } => list(ctx, &mut warnings, &tags),
+        Command::Why { name } => why(ctx, &mut warnings, &name),
+        Command::Lock { restore: true, .. } => restore(ctx),
+        Command::Lock {
+            prune: true,
+            clean,
+            backup,
+            ..
+        } => prune(ctx, &mut warnings, clean, backup),
+        Command::Lock {
+            install_only: true, ..
+        } => install(ctx, &mut warnings),
+        Command::Lock {
+            dry_run,
+            clean,
+            backup,
+            print,
+            diff,
+            summary,
+            dump_resolved,
+            ..
+        } => lock(
+            ctx,
+            &mut warnings,
+            dry_run,
+            clean,
+            backup,
+            print,
+            diff,
+            summary,
+            dump_resolved.as_deref(),
+        ),
+        Command::Source {
+            shells,
+            output_dir,
+            measure,
+            apply,
+            no_lock,
+            tags,
+            eval_safe,
+        } => source(
+            ctx,
+            &mut warnings,
+            &shells,
+            output_dir.as_deref(),
+            measure,
+            apply.as_deref(),
+            no_lock,
+            &tags,
+            eval_safe,
+        ),
+        Command::Clean {
+            since,
+            orphans_only,
+        } => clean(ctx, &mut warnings, since, orphans_only),
+        Command::Gc => gc(ctx),
+        Command::Verify { checksums } => verify(ctx, checksums),
+        Command::Health => health(ctx, &mut warnings),
+        Command::Export { output } => export(ctx, output.as_deref()),
+        Command::Import { path } => import(ctx, &mut warnings, &path),
+        Command::ExportBundle => lock::export_bundle(ctx),
+        Command::ImportBundle => lock::import_bundle(ctx),
+        Command::Paths { json } => paths(ctx, json),
     };
     for err in &warnings {
         error_w!(ctx, err);
@@ -103,8 +185,16 @@ fn init(ctx: &Context, shell: Option<Shell>) -> Result<()> {
 
 /// Executes the `add` subcommand.
 ///
-/// Add a new plugin to the config file.
-fn add(ctx: &Context, name: String, plugin: &EditPlugin) -> Result<()> {
+/// Add a new plugin to the config file. If `verify` is set then Git sources are
+/// checked to exist, along with their reference, before they are added.
+fn add(ctx: &Context, name: String, plugin: &EditPlugin, verify: bool) -> Result<()> {
+    if verify {
+        if let Some(source) = plugin.git_source()? {
+            lock::check_source(ctx, &source)
+                .with_context(s!("failed to verify source `{}`", source))?;
+            status!(ctx, "Verified", &source);
+        }
+    }
     let path = ctx.config_file();
     let mut config = match EditConfig::from_path(path) {
         Ok(config) => {
@@ -140,10 +230,21 @@ fn edit(ctx: &Context) -> Result<()> {
             config.to_string()
         }
     };
-    let handle = editor::Editor::default()?.edit(path, &original_contents)?;
-    status!(ctx, "Opened", &"config in temporary file for editing");
-    let config = handle.wait_and_update(&original_contents)?;
-    config.to_path(&path)?;
+    status!(ctx, "Opening", &"config in temporary file for editing");
+    let config =
+        editor::Editor::default()?.edit_until_valid(ctx, path, &original_contents, |err| {
+            error_w!(ctx, err);
+            casual::confirm("Edited config is invalid, reopen it for editing?")
+        })?;
+
+    // Keep a backup of the previous config, and restore it if we fail to write
+    // the new one.
+    let backup = editor::backup_path(path);
+    fs::copy(path, &backup).with_context(s!("failed to back up `{}`", path.display()))?;
+    if let Err(err) = config.to_path(path) {
+        fs::copy(&backup, path).ok();
+        return Err(err);
+    }
     header!(ctx, "Updated", path);
     Ok(())
 }
@@ -162,6 +263,37 @@ fn remove(ctx: &Context, name: String) -> Result<()> {
     Ok(())
 }
 
+/// Executes the `list` subcommand.
+///
+/// Print out the names of the plugins in the config file, one per line. If
+/// `tags` are given then only the plugins with at least one of them are listed.
+fn list(ctx: &Context, warnings: &mut Vec<Error>, tags: &[String]) -> Result<()> {
+    let config = loaded(ctx, warnings)?;
+    for plugin in &config.plugins {
+        let (name, plugin_tags) = match plugin {
+            Plugin::External(plugin) => (&plugin.name, &plugin.tags),
+            Plugin::Inline(plugin) => (&plugin.name, &plugin.tags),
+        };
+        if config::is_tagged(plugin_tags, tags) {
+            println!("{}", name);
+        }
+    }
+    Ok(())
+}
+
+/// Executes the `why` subcommand.
+///
+/// Print out whether the plugin with the given name is locked, and if not which
+/// part of the config excludes it.
+fn why(ctx: &Context, warnings: &mut Vec<Error>, name: &str) -> Result<()> {
+    let config = loaded(ctx, warnings)?;
+    match lock::plan(ctx, config).skip_reason(name)? {
+        Some(reason) => println!("{} is skipped, {}", name, reason),
+        None => println!("{} is included", name),
+    }
+    Ok(())
+}
+
 /// Generic function to initialize the config file.
 fn init_config(ctx: &Context, shell: Option<Shell>, path: &Path, err: Error) -> Result<EditConfig> {
     if underlying_io_error_kind(&err) == Some(io::ErrorKind::NotFound) {
@@ -185,41 +317,353 @@ fn init_config(ctx: &Context, shell: Option<Shell>, path: &Path, err: Error) ->
 
 /// Execute the `lock` subcommand.
 ///
-/// Install the plugins sources and generate the lock file.
-fn lock(ctx: &Context, warnings: &mut Vec<Error>) -> Result<()> {
-    let mut locked = locked(ctx, warnings)?;
+/// Install the plugins sources and generate the lock file. If `dry_run` is set
+/// then only the planned operations are shown. If `clean` is set then the
+/// unused files and directories are removed afterwards. If `backup` is set then
+/// the previous lock file is backed up before it is overwritten. If `print` is
+/// set then the lock file is printed out instead of written. If `diff` is set
+/// then the changes to the lock file are shown and it is only written if
+/// confirmed. If `summary` is set then the counts of what was locked are shown
+/// afterwards. If `dump_resolved` is set then the resolved config is also
+/// written to that path.
+#[allow(clippy::too_many_arguments)]
+fn lock(
+    ctx: &Context,
+    warnings: &mut Vec<Error>,
+    dry_run: bool,
+    clean: bool,
+    backup: bool,
+    print: bool,
+    diff: bool,
+    summary: bool,
+    dump_resolved: Option<&Path>,
+) -> Result<()> {
+    if dry_run {
+        let plan = lock::plan(ctx, loaded(ctx, warnings)?);
+        if let Some(path) = dump_resolved {
+            write_resolved(ctx, &plan, path)?;
+        }
+        return plan.print(ctx);
+    }
+
+    // The commits are read before locking because the sources are updated in
+    // place.
+    let previous = if diff {
+        let old = lock::from_path(ctx.lock_file()).ok();
+        let revisions = old.as_ref().map(lock::revisions).unwrap_or_default();
+        Some((old, revisions))
+    } else {
+        None
+    };
+
+    let started = Instant::now();
+    let plan = lock::plan(ctx, loaded(ctx, warnings)?);
+    if let Some(path) = dump_resolved {
+        write_resolved(ctx, &plan, path)?;
+    }
+    let checkpoint = previous
+        .as_ref()
+        .map(|(_, revisions)| plan.checkpoint(ctx, revisions));
+    let mut locked = plan.execute(ctx)?;
+    warnings.append(&mut locked.warnings);
+    let summary = if summary {
+        Some(lock::Summary::new(&locked))
+    } else {
+        None
+    };
+    let log_summary = || {
+        if let Some(summary) = &summary {
+            header!(
+                ctx,
+                "Summary",
+                &format!("{} in {:.2}s", summary, started.elapsed().as_secs_f64())
+            );
+        }
+    };
 
     if let Some(last) = locked.errors.pop() {
         for err in locked.errors {
             error!(ctx, &err);
         }
+        log_summary();
         Err(last)
     } else {
-        locked.clean(ctx, warnings);
         let path = ctx.lock_file();
+        if print {
+            print!("{}", locked.to_string_for(path)?);
+            log_summary();
+            return Ok(());
+        }
+        if let Some((old, revisions)) = previous {
+            let mut changes = lock::diff(ctx, old.as_ref(), &revisions, &locked);
+            if changes.is_empty() {
+                let contents = fs::read_to_string(path).unwrap_or_default();
+                if contents == locked.to_string_for(path)? {
+                    header!(ctx, "Unchanged", path);
+                    log_summary();
+                    return Ok(());
+                }
+                changes.push("~ settings changed".to_string());
+            }
+            for change in changes {
+                println!("{}", change);
+            }
+            if !atty::is(atty::Stream::Stdin) || !casual::confirm("Write the lock file?") {
+                // The sources were already updated, put them back at the
+                // commits that the existing lock file uses.
+                if let Some(checkpoint) = checkpoint {
+                    checkpoint.restore(ctx, &locked)?;
+                }
+                log_summary();
+                return Ok(());
+            }
+        }
+        // Back up the lock file before anything it points at is moved or
+        // removed.
+        if backup {
+            if let Some(backup) = lock::backup(path)? {
+                header!(ctx, "Backed up", backup.as_path());
+            }
+        }
+        locked.migrate(ctx);
+        if clean {
+            locked.clean(ctx, None, false, warnings);
+        }
         locked.to_path(path).context("failed to write lock file")?;
         header!(ctx, "Locked", path);
+        log_summary();
+        Ok(())
+    }
+}
+
+/// Write the config that the plan locks to the given path.
+fn write_resolved(ctx: &Context, plan: &lock::Plan, path: &Path) -> Result<()> {
+    let contents = plan.resolved().to_string_for(path)?;
+    fs::write(path, contents).with_context(s!(
+        "failed to write resolved config to `{}`",
+        path.display()
+    ))?;
+    header!(ctx, "Resolved", path);
+    Ok(())
+}
+
+/// Execute the `lock --install-only` subcommand.
+///
+/// Install the plugin sources without rendering the plugins, so template errors
+/// are not reported and the lock file is not written.
+fn install(ctx: &Context, warnings: &mut Vec<Error>) -> Result<()> {
+    let config = loaded(ctx, warnings)?;
+    let mut errors = lock::plan(ctx, config).install(ctx)?;
+    if let Some(last) = errors.pop() {
+        for err in errors {
+            error!(ctx, &err);
+        }
+        Err(last)
+    } else {
         Ok(())
     }
 }
 
+/// Execute the `lock --restore` subcommand.
+///
+/// Swap the lock file with its backup.
+fn restore(ctx: &Context) -> Result<()> {
+    let path = ctx.lock_file();
+    lock::restore(path)?;
+    header!(ctx, "Restored", path);
+    Ok(())
+}
+
+/// Execute the `lock --prune-lockfile` subcommand.
+///
+/// Remove the plugins that are no longer in the config from the lock file,
+/// without installing or rendering anything. If `clean` is set then the unused
+/// files and directories are removed afterwards. If `backup` is set then the
+/// previous lock file is backed up before it is overwritten.
+fn prune(ctx: &Context, warnings: &mut Vec<Error>, clean: bool, backup: bool) -> Result<()> {
+    let path = ctx.lock_file();
+    let mut locked_config = lock::from_path(path)?;
+    header!(ctx, "Loaded", path);
+
+    if !locked_config.is_current(ctx) {
+        bail!("lock file is out of date, try running `sheldon lock`");
+    }
+
+    let plan = lock::plan(ctx, loaded(ctx, warnings)?);
+    for name in locked_config.prune(&plan.plugin_names()) {
+        status!(ctx, "Pruned", &name);
+    }
+    if backup {
+        if let Some(backup) = lock::backup(path)? {
+            header!(ctx, "Backed up", backup.as_path());
+        }
+    }
+    if clean {
+        locked_config.clean(ctx, None, false, warnings);
+    }
+    // Other changes to the config are only applied by locking, so the lock
+    // file keeps its modification time to stay out of date if it was.
+    let modified = path.metadata_modified();
+    locked_config
+        .to_path(path)
+        .context("failed to write lock file")?;
+    if let Some(modified) = modified {
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_modified(modified))
+            .context("failed to set the modification time of the lock file")?;
+    }
+    header!(ctx, "Locked", path);
+    Ok(())
+}
+
+/// Render the script for the given shell instead of the configured one.
+fn shell_script(
+    ctx: &Context,
+    warnings: &mut Vec<Error>,
+    config: Config,
+    shell: Shell,
+    measure: bool,
+    apply: Option<&[String]>,
+    tags: &[String],
+) -> Result<String> {
+    let mut locked_config = lock::config_for_shell(ctx, config, shell)?;
+    warnings.append(&mut locked_config.warnings);
+    let script = if measure {
+        locked_config
+            .measure(ctx, apply, tags)
+            .map(|(_, measurement)| breakdown(&measurement))
+    } else {
+        locked_config.script(ctx, apply, tags)
+    }
+    .context("failed to render source")?;
+    for err in &locked_config.errors {
+        error!(ctx, err);
+    }
+    Ok(script)
+}
+
+/// Format the size of each plugin in a script, largest first, followed by the
+/// size of everything else and the total.
+fn breakdown(measurement: &Measurement) -> String {
+    let total = measurement.total();
+    let width = total.to_string().len();
+    let mut plugins: Vec<_> = measurement
+        .plugins
+        .iter()
+        .map(|(name, size)| (name.as_str(), *size))
+        .collect();
+    plugins.sort_by_key(|(_, size)| Reverse(*size));
+    plugins
+        .into_iter()
+        .chain([("(other)", measurement.other), ("total", total)])
+        .map(|(name, size)| format!("{:>width$}  {}\n", size, name, width = width))
+        .collect()
+}
+
 /// Execute the `source` subcommand.
 ///
-/// Generate and print out the shell script.
-fn source(ctx: &Context, warnings: &mut Vec<Error>) -> Result<()> {
+/// Generate and print out the shell script. If `shells` are given then the
+/// script is rendered for those shells instead, without touching the lock file.
+/// If `output_dir` is set then the script for each shell is written to
+/// `<shell>.sh` in that directory instead of printed out. If `measure` is set
+/// then the size of each plugin in the script is printed out instead. If
+/// `apply` is set then those templates are applied to every plugin instead of
+/// the configured ones. If `no_lock` is set then the plugins are always locked
+/// in memory and the lock file is not written. If `tags` are given then only
+/// the plugins with at least one of them are rendered. If `eval_safe` is set
+/// then the script must parse using the shell's syntax check before it is
+/// printed out.
+#[allow(clippy::too_many_arguments)]
+fn source(
+    ctx: &Context,
+    warnings: &mut Vec<Error>,
+    shells: &[Shell],
+    output_dir: Option<&Path>,
+    measure: bool,
+    apply: Option<&[String]>,
+    no_lock: bool,
+    tags: &[String],
+    eval_safe: bool,
+) -> Result<()> {
+    let check = |shell: Shell, script: &str| {
+        if eval_safe {
+            lock::check_syntax(shell, script)?;
+            status_v!(ctx, "Checked", &format!("{} -n", shell));
+        }
+        Ok::<_, Error>(())
+    };
+
+    if let Some(output_dir) = output_dir {
+        fs::create_dir_all(output_dir)
+            .with_context(s!("failed to create directory `{}`", output_dir.display()))?;
+        for (i, shell) in shells.iter().enumerate() {
+            // The config is loaded again for each shell, only report its warnings
+            // once.
+            let config = if i == 0 {
+                loaded(ctx, warnings)?
+            } else {
+                loaded(ctx, &mut Vec::new())?
+            };
+            let script = shell_script(ctx, warnings, config, *shell, false, apply, tags)?;
+            check(*shell, &script)?;
+            let path = output_dir.join(format!("{}.sh", shell));
+            write_private(&path, &script)
+                .with_context(s!("failed to write `{}`", path.display()))?;
+            header!(ctx, "Rendered", path.as_path());
+        }
+        return Ok(());
+    }
+    match shells {
+        [] => {}
+        [shell] => {
+            let config = loaded(ctx, warnings)?;
+            let script = shell_script(ctx, warnings, config, *shell, measure, apply, tags)?;
+            check(*shell, &script)?;
+            print!("{}", script);
+            return Ok(());
+        }
+        _ => bail!("rendering for more than one shell requires `--output-dir`"),
+    }
+
     let config_path = ctx.config_file();
     let lock_path = ctx.lock_file();
 
-    let mut to_path = true;
+    let mut to_path = !no_lock;
 
-    let locked_config = if ctx.lock_mode.is_some() || config_path.newer_than(lock_path) {
+    let locked_config = if no_lock || ctx.lock_mode.is_some() || config_path.newer_than(lock_path) {
         locked(ctx, warnings)?
     } else {
         match lock::from_path(lock_path) {
-            Ok(locked_config) => {
-                if locked_config.verify(ctx) {
+            Ok(mut locked_config) => {
+                // The lock file is also out of date if any of the imported
+                // config files have changed.
+                if locked_config.verify(ctx)
+                    && !locked_config
+                        .imports
+                        .iter()
+                        .any(|import| import.newer_than(lock_path))
+                {
                     to_path = false;
                     header_v!(ctx, "Unlocked", lock_path);
+                    // The lock file was possibly written without strict mode.
+                    if ctx.strict() {
+                        locked_config.check_duplicates(ctx);
+                    }
+                    // Skip rendering entirely if the lock file has not
+                    // changed since the script was last generated.
+                    if let Some(script) = lock::cached_script(lock_path).filter(|_| {
+                        !measure
+                            && apply.is_none()
+                            && tags.is_empty()
+                            && locked_config.errors.is_empty()
+                    }) {
+                        header_v!(ctx, "Cached", lock_path);
+                        check(locked_config.shell, &script)?;
+                        print!("{}", script);
+                        return Ok(());
+                    }
                     locked_config
                 } else {
                     locked(ctx, warnings)?
@@ -229,12 +673,39 @@ fn source(ctx: &Context, warnings: &mut Vec<Error>) -> Result<()> {
         }
     };
 
-    let script = locked_config
-        .script(ctx)
-        .context("failed to render source")?;
+    // Migrated clones are only moved if the lock file is written, the script
+    // must use their new location then.
+    let to_path = to_path && locked_config.errors.is_empty();
+    let mut locked_config = locked_config;
+    // In strict mode errors fail the command, instead of generating the script
+    // from the other plugins.
+    if ctx.strict() {
+        if let Some(last) = locked_config.errors.pop() {
+            for err in &locked_config.errors {
+                error!(ctx, err);
+            }
+            return Err(last);
+        }
+    }
+    if to_path {
+        locked_config.migrate(ctx);
+    }
+
+    let (script, measurement) = if measure {
+        let (script, measurement) = locked_config
+            .measure(ctx, apply, tags)
+            .context("failed to render source")?;
+        (script, Some(measurement))
+    } else {
+        let script = locked_config
+            .script(ctx, apply, tags)
+            .context("failed to render source")?;
+        check(locked_config.shell, &script)?;
+        (script, None)
+    };
 
-    if to_path && locked_config.errors.is_empty() {
-        locked_config.clean(ctx, warnings);
+    if to_path {
+        locked_config.clean(ctx, None, false, warnings);
         locked_config
             .to_path(lock_path)
             .context("failed to write lock file")?;
@@ -245,15 +716,278 @@ fn source(ctx: &Context, warnings: &mut Vec<Error>) -> Result<()> {
         }
     }
 
-    print!("{}", script);
+    if !no_lock
+        && locked_config.errors.is_empty()
+        && locked_config.is_cacheable()
+        && apply.is_none()
+        && tags.is_empty()
+    {
+        if let Err(err) = lock::cache_script(lock_path, &script) {
+            warnings.push(err.context("failed to cache source"));
+        }
+    }
+
+    match measurement {
+        Some(measurement) => print!("{}", breakdown(&measurement)),
+        None => print!("{}", script),
+    }
+    Ok(())
+}
+
+/// Write a rendered script so that only the current user can read it.
+///
+/// The script can contain the values of secrets, so the file is created with
+/// mode `0600`, and an existing file is restricted to it as well.
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents.as_bytes())
+}
+
+/// Execute the `clean` subcommand.
+///
+/// Remove the files and directories in the clone and download directories that
+/// are not used by the lock file. If `since` is set then only downloads that
+/// have not been modified for that long are removed. If `orphans_only` is set
+/// then only directories that are not used by any plugin are removed.
+fn clean(
+    ctx: &Context,
+    warnings: &mut Vec<Error>,
+    since: Option<Duration>,
+    orphans_only: bool,
+) -> Result<()> {
+    let path = ctx.lock_file();
+    let locked_config = lock::from_path(path)?;
+    header!(ctx, "Loaded", path);
+
+    if !locked_config.is_current(ctx) {
+        bail!("lock file is out of date, try running `sheldon lock`");
+    }
+
+    locked_config.clean(ctx, since, orphans_only, warnings);
+    Ok(())
+}
+
+/// Execute the `gc` subcommand.
+///
+/// Permanently remove the files and directories that were moved to the trash
+/// when cleaning with the `trash` option.
+fn gc(ctx: &Context) -> Result<()> {
+    let trash_dir = ctx.trash_dir();
+    if lock::empty_trash(ctx)? {
+        header!(ctx, "Emptied", trash_dir.as_path());
+    } else {
+        status!(ctx, "Empty", trash_dir.as_path());
+    }
+    Ok(())
+}
+
+/// Execute the `verify` subcommand.
+///
+/// Check that the plugins in the lock file are installed and report any that
+/// are missing files. If `checksums` is set then the downloaded files are also
+/// hashed again and any that do not match their checksum are reported.
+fn verify(ctx: &Context, checksums: bool) -> Result<()> {
+    let path = ctx.lock_file();
+    let locked_config = lock::from_path(path)?;
+    header!(ctx, "Loaded", path);
+
+    if !locked_config.is_current(ctx) {
+        bail!("lock file is out of date, try running `sheldon lock`");
+    }
+
+    let mut broken = 0;
+    let mut mismatched = 0;
+    for plugin in &locked_config.plugins {
+        match plugin {
+            LockedPlugin::External(plugin) => {
+                let missing = plugin.missing();
+                if missing.is_empty() {
+                    let mismatch = if checksums {
+                        plugin.checksum_mismatch()?
+                    } else {
+                        None
+                    };
+                    match mismatch {
+                        None => status!(ctx, "Ok", &plugin.name),
+                        Some(actual) => {
+                            mismatched += 1;
+                            warning!(
+                                ctx,
+                                "Mismatch",
+                                &format!(
+                                    "{} (expected `{}` but got `{}`)",
+                                    plugin.name,
+                                    plugin.checksum.as_deref().unwrap_or_default(),
+                                    actual
+                                )
+                            );
+                        }
+                    }
+                } else {
+                    broken += 1;
+                    let paths = missing
+                        .iter()
+                        .map(|path| ctx.replace_home(path).display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    warning!(ctx, "Missing", &format!("{} ({})", plugin.name, paths));
+                }
+            }
+            LockedPlugin::Inline(plugin) => {
+                status!(ctx, "Ok", &plugin.name);
+            }
+        }
+    }
+
+    if broken > 0 {
+        bail!(
+            "{} of {} plugins are missing files, try running `sheldon lock`",
+            broken,
+            locked_config.plugins.len()
+        );
+    }
+    if mismatched > 0 {
+        bail!(
+            "{} of {} plugins have downloads that do not match their checksum, try running \
+             `sheldon lock --reinstall`",
+            mismatched,
+            locked_config.plugins.len()
+        );
+    }
+    Ok(())
+}
+
+/// Execute the `health` subcommand.
+///
+/// Report which of the external tools that the config relies on are installed
+/// and which are missing.
+fn health(ctx: &Context, warnings: &mut Vec<Error>) -> Result<()> {
+    let config = loaded(ctx, warnings)?;
+    let cwd = env::current_dir().context("failed to determine the current directory")?;
+    let tools = health::check(ctx, &config, env::var_os("PATH").as_deref(), &cwd);
+
+    let mut missing = 0;
+    for tool in &tools {
+        match &tool.path {
+            Some(path) => {
+                let path = ctx.replace_home(path);
+                status!(ctx, "Found", &format!("{} ({})", tool.name, path.display()));
+            }
+            None => {
+                missing += 1;
+                warning!(
+                    ctx,
+                    "Missing",
+                    &format!("{}, required {}", tool.name, tool.reason)
+                );
+            }
+        }
+    }
+
+    if missing > 0 {
+        bail!(
+            "{} required tool{} missing",
+            missing,
+            if missing == 1 { " is" } else { "s are" }
+        );
+    }
+    Ok(())
+}
+
+/// Execute the `export` subcommand.
+///
+/// Print out the config with every plugin pinned to the version in the lock
+/// file, or write it to `output` if given.
+fn export(ctx: &Context, output: Option<&Path>) -> Result<()> {
+    let path = ctx.lock_file();
+    let locked_config = lock::from_path(path)?;
+    header!(ctx, "Loaded", path);
+
+    if !locked_config.is_current(ctx) {
+        bail!("lock file is out of date, try running `sheldon lock`");
+    }
+
+    let snapshot = config::snapshot(ctx, ctx.config_file(), &locked_config)
+        .context("failed to export snapshot")?;
+    match output {
+        Some(output) => {
+            fs::write(output, snapshot)
+                .with_context(s!("failed to write to `{}`", output.display()))?;
+            header!(ctx, "Exported", output);
+        }
+        None => print!("{}", snapshot),
+    }
+    Ok(())
+}
+
+/// Execute the `import` subcommand.
+///
+/// Replace the config file with the snapshot at the given path, and then lock
+/// it. The previous config file is backed up next to the config file.
+fn import(ctx: &Context, warnings: &mut Vec<Error>, path: &Path) -> Result<()> {
+    let contents =
+        fs::read_to_string(path).with_context(s!("failed to read from `{}`", path.display()))?;
+    config::from_path(ctx, path, warnings).context("failed to load snapshot")?;
+    header!(ctx, "Loaded", path);
+
+    let config_path = ctx.config_file();
+    if config_path.exists() {
+        let backup = editor::backup_path(config_path);
+        fs::copy(config_path, &backup)
+            .with_context(s!("failed to back up `{}`", config_path.display()))?;
+    } else if let Some(parent) = config_path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(s!("failed to create directory `{}`", parent.display()))?;
+    }
+    fs::write(config_path, contents)
+        .with_context(s!("failed to write to `{}`", config_path.display()))?;
+    header!(ctx, "Imported", config_path);
+
+    lock(ctx, warnings, false, true, false, false, false, false, None)
+}
+
+/// Execute the `paths` subcommand.
+///
+/// Print out the resolved config and data paths, one per line or as JSON.
+fn paths(ctx: &Context, json: bool) -> Result<()> {
+    let paths = ctx.paths();
+    if json {
+        let json =
+            serde_json::to_string_pretty(&paths).context("failed to serialize paths as JSON")?;
+        println!("{}", json);
+    } else {
+        for (name, path) in paths {
+            println!("{}\t{}", name, path.display());
+        }
+    }
     Ok(())
 }
 
 /// Reads the config from the config file path, locks it, and returns the
 /// locked config.
 fn locked(ctx: &Context, warnings: &mut Vec<Error>) -> Result<LockedConfig> {
+    let config = loaded(ctx, warnings)?;
+    let mut locked = lock::config(ctx, config)?;
+    warnings.append(&mut locked.warnings);
+    Ok(locked)
+}
+
+/// Reads the config from the config file path.
+fn loaded(ctx: &Context, warnings: &mut Vec<Error>) -> Result<Config> {
     let path = ctx.config_file();
-    let config = config::from_path(path, warnings).context("failed to load config file")?;
+    let config = config::from_path(ctx, path, warnings).context("failed to load config file")?;
     header!(ctx, "Loaded", path);
-    lock::config(ctx, config)
+    Ok(config)
 }
@@ -0,0 +1,79 @@
+//! Look up secrets in the operating system's keyring.
+
+use std::process::{Command, Output};
+
+use anyhow::{bail, Context as ResultExt, Result};
+
+/// The keyring service that Sheldon's secrets are stored under.
+const SERVICE: &str = "sheldon";
+
+/// A store of named secrets.
+pub trait Keyring: Sync {
+    /// Returns the secret with the given name, or `None` if it does not exist.
+    fn get(&self, name: &str) -> Result<Option<String>>;
+}
+
+/// Returns the program used to look up secrets on this platform.
+pub fn program() -> Option<&'static str> {
+    if cfg!(target_os = "macos") {
+        Some("security")
+    } else if cfg!(unix) {
+        Some("secret-tool")
+    } else {
+        None
+    }
+}
+
+/// The operating system's keyring.
+///
+/// On macOS this is the login Keychain, and on other Unix systems it is the
+/// Secret Service, for example GNOME Keyring or KWallet. Secrets are stored
+/// with the service `sheldon` and the secret name as the account.
+#[derive(Debug)]
+pub struct OsKeyring;
+
+impl Keyring for OsKeyring {
+    fn get(&self, name: &str) -> Result<Option<String>> {
+        let program = match program() {
+            Some(program) => program,
+            None => bail!("keyring secrets are not supported on this platform"),
+        };
+        let args = if program == "security" {
+            vec!["find-generic-password", "-s", SERVICE, "-a", name, "-w"]
+        } else {
+            vec!["lookup", "service", SERVICE, "account", name]
+        };
+        let output = Command::new(program)
+            .args(&args)
+            .output()
+            .with_context(s!("failed to run `{}`", program))?;
+        if !output.status.success() {
+            if is_not_found(program, &output) {
+                return Ok(None);
+            }
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            bail!(
+                "failed to look up secret `{}` with `{}`: {}",
+                name,
+                program,
+                stderr.trim()
+            );
+        }
+        let secret = String::from_utf8(output.stdout)
+            .with_context(s!("secret `{}` is not valid UTF-8", name))?;
+        Ok(Some(secret.trim_end_matches('\n').to_string()))
+    }
+}
+
+/// Whether a failed lookup failed only because the secret does not exist.
+///
+/// `security` exits with `errSecItemNotFound` (44) if the item is missing,
+/// while `secret-tool` exits without printing anything. Any other failure, for
+/// example a locked keyring or denied access, is an error of the backend.
+fn is_not_found(program: &str, output: &Output) -> bool {
+    if program == "security" {
+        output.status.code() == Some(44)
+    } else {
+        output.stderr.iter().all(u8::is_ascii_whitespace)
+    }
+}
@@ -1,14 +1,21 @@
 pub mod build;
 pub mod git;
+pub mod keyring;
 mod path_ext;
+pub mod sha256;
 mod temp;
+#[cfg(test)]
+pub mod testing;
 
+use std::cell::{Cell, RefCell};
 use std::fs::File;
 use std::io;
 use std::io::Write;
 use std::result;
+use std::sync::Mutex;
 
-use anyhow::Error;
+use anyhow::{anyhow, Error, Result};
+use once_cell::sync::Lazy;
 
 pub use crate::util::path_ext::PathExt;
 pub use crate::util::temp::TempPath;
@@ -23,19 +30,163 @@ pub fn underlying_io_error_kind(error: &Error) -> Option<io::ErrorKind> {
     None
 }
 
+/// The cURL handles that are shared by all downloads.
+///
+/// Each handle keeps its connections and TLS sessions, so taking one from this
+/// pool instead of creating a new one avoids connecting to the same host again.
+/// The pool outlives the threads that download in parallel, so the handles are
+/// reused by every thread pool that downloads.
+static HANDLES: Lazy<Mutex<Vec<curl::easy::Easy>>> = Lazy::new(Default::default);
+
+/// Call the given function with a cURL handle from the given pool, and return
+/// the handle to the pool afterwards.
+fn with_handle<F, T>(handles: &Mutex<Vec<curl::easy::Easy>>, f: F) -> T
+where
+    F: FnOnce(&mut curl::easy::Easy) -> T,
+{
+    let handle = handles.lock().unwrap().pop();
+    let mut easy = handle.unwrap_or_else(curl::easy::Easy::new);
+    let result = f(&mut easy);
+    handles.lock().unwrap().push(easy);
+    result
+}
+
 /// Download a remote file.
-pub fn download(url: &str, mut file: File) -> result::Result<(), curl::Error> {
-    let mut easy = curl::easy::Easy::new();
-    easy.fail_on_error(true)?; // -f
-    easy.follow_location(true)?; // -L
-    easy.url(url.as_ref())?;
-    let mut transfer = easy.transfer();
-    transfer.write_function(move |data| {
-        match file.write_all(data) {
-            Ok(()) => Ok(data.len()),
-            Err(_) => Ok(0), // signals to cURL that the writing failed
+///
+/// If a maximum size is given then the download is aborted as soon as it is
+/// known to exceed it, either from the `Content-Length` header or while
+/// streaming the response body. If `resume` is given then only the bytes from
+/// its offset are requested and appended to the file, provided that the remote
+/// file still matches its validator. This fails with a range error if the
+/// server does not support ranges or the file has changed.
+///
+/// The `validator` is set to the strong `ETag` of the response, or else its
+/// `Last-Modified` date, even if the download fails.
+pub fn download(
+    url: &str,
+    file: File,
+    max_size: Option<u64>,
+    resume: Option<(u64, &str)>,
+    validator: &mut Option<String>,
+) -> Result<()> {
+    download_with(&HANDLES, url, file, max_size, resume, validator)
+}
+
+/// Download a remote file like [`download`], using a handle from the given
+/// pool.
+fn download_with(
+    handles: &Mutex<Vec<curl::easy::Easy>>,
+    url: &str,
+    mut file: File,
+    max_size: Option<u64>,
+    resume: Option<(u64, &str)>,
+    validator: &mut Option<String>,
+) -> Result<()> {
+    let exceeded = Cell::new(false);
+    let etag = RefCell::new(None);
+    let last_modified = RefCell::new(None);
+    let result = with_handle(handles, |easy| -> result::Result<(), curl::Error> {
+        // Resetting the handle clears the options from the previous download
+        // but keeps the connection cache.
+        easy.reset();
+        easy.fail_on_error(true)?; // -f
+        easy.follow_location(true)?; // -L
+        easy.url(url.as_ref())?;
+        if let Some(max_size) = max_size {
+            easy.max_filesize(max_size)?; // --max-filesize
+        }
+        let mut headers = curl::easy::List::new();
+        if let Some((offset, validator)) = resume {
+            easy.resume_from(offset)?; // -C
+            headers.append(&format!("If-Range: {}", validator))?;
         }
-    })?;
-    transfer.perform()?;
-    Ok(())
+        if let Some(token) = git::github_token(url) {
+            headers.append(&format!("Authorization: token {}", token.secret()))?;
+        }
+        easy.http_headers(headers)?;
+        let mut written = resume.map_or(0, |(offset, _)| offset);
+        let mut transfer = easy.transfer();
+        transfer.header_function(|header| {
+            let header = String::from_utf8_lossy(header);
+            if header.starts_with("HTTP/") {
+                // The start of another response, for example after a redirect.
+                etag.replace(None);
+                last_modified.replace(None);
+            } else if let Some((name, value)) = header.split_once(':') {
+                let value = value.trim();
+                // Weak entity tags can not be used to resume a download.
+                if name.eq_ignore_ascii_case("ETag") && !value.starts_with("W/") {
+                    etag.replace(Some(value.to_string()));
+                } else if name.eq_ignore_ascii_case("Last-Modified") {
+                    last_modified.replace(Some(value.to_string()));
+                }
+            }
+            true
+        })?;
+        transfer.write_function(|data| {
+            written += data.len() as u64;
+            if matches!(max_size, Some(max_size) if written > max_size) {
+                exceeded.set(true);
+                return Ok(0); // signals to cURL that the writing failed
+            }
+            match file.write_all(data) {
+                Ok(()) => Ok(data.len()),
+                Err(_) => Ok(0), // signals to cURL that the writing failed
+            }
+        })?;
+        transfer.perform()
+    });
+    *validator = etag.into_inner().or_else(|| last_modified.into_inner());
+    match result {
+        Err(err) if exceeded.get() || err.is_filesize_exceeded() => Err(anyhow!(
+            "download exceeds the maximum size of {} bytes",
+            max_size.unwrap()
+        )),
+        result => result.map_err(Error::from),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::thread;
+
+    #[test]
+    fn download_reuses_connection_across_threads() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let handles = Mutex::new(Vec::new());
+        let (url, server) = testing::serve(
+            ["first\n", "second\n"]
+                .iter()
+                .map(|body| {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                })
+                .collect(),
+        );
+
+        // Each download is made on a new thread, like the threads of separate
+        // thread pools.
+        for name in ["first", "second"] {
+            let url = url.join(&format!("{}.zsh", name)).unwrap();
+            let path = temp.path().join(format!("{}.zsh", name));
+            let file = File::create(&path).unwrap();
+            thread::scope(|s| {
+                s.spawn(|| download_with(&handles, url.as_ref(), file, None, None, &mut None))
+                    .join()
+                    .unwrap()
+                    .unwrap();
+            });
+            assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", name));
+        }
+        // The second download only reused the connection of the first one if
+        // both requests were made on the same connection.
+        let requests = server.join().unwrap();
+        assert!(requests.iter().all(|request| request.connection == 0));
+    }
 }
@@ -0,0 +1,53 @@
+//! SHA-256 digests for verifying downloads.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+/// Returns the SHA-256 digest of the given bytes as a lowercase hex string.
+#[cfg(test)]
+pub fn digest(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Returns the SHA-256 digest of the file at the given path as a lowercase hex
+/// string.
+pub fn digest_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Unit tests
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    #[test]
+    fn sha256_digest_known_vectors() {
+        assert_eq!(
+            digest(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            digest(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn sha256_digest_file() {
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        let data = vec![b'x'; 10_000];
+        temp.write_all(&data).unwrap();
+        assert_eq!(digest_file(temp.path()).unwrap(), digest(&data));
+    }
+}
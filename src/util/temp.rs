@@ -20,10 +20,7 @@ impl TempPath {
     ///
     /// If the temporary path already exists.
     pub fn new(original_path: &Path) -> result::Result<Self, PathBuf> {
-        let mut path = original_path.parent().unwrap().to_path_buf();
-        let mut file_name = ffi::OsString::from("~");
-        file_name.push(original_path.file_name().unwrap());
-        path.push(file_name);
+        let path = sibling_path(original_path, "~");
         if path.exists() {
             Err(path)
         } else {
@@ -54,16 +51,23 @@ impl TempPath {
     }
 
     /// Move the temporary path to a new location.
+    ///
+    /// Anything that exists at the new location is first moved aside and only
+    /// removed once the rename has succeeded. This means that a failure to
+    /// remove it never leaves a partially removed directory in its place. Such
+    /// a failure is not an error, the leftover is removed on the next rename.
     pub fn rename(mut self, new_path: &Path) -> io::Result<()> {
-        if let Err(err) = nuke_path(new_path) {
-            if err.kind() != io::ErrorKind::NotFound {
+        if let Some(path) = &self.path {
+            let backup = sibling_path(new_path, "~~");
+            ignore_not_found(nuke_path(&backup))?;
+            ignore_not_found(fs::rename(new_path, &backup))?;
+            if let Err(err) = fs::rename(path, new_path) {
+                fs::rename(&backup, new_path).ok();
                 return Err(err);
             }
-        };
-        if let Some(path) = &self.path {
-            fs::rename(path, new_path)?;
             // This is so that the Drop impl doesn't try delete a non-existent file.
             self.path = None;
+            nuke_path(&backup).ok();
         }
         Ok(())
     }
@@ -77,6 +81,24 @@ impl Drop for TempPath {
     }
 }
 
+/// Returns a path in the same directory as the given one with a prefixed file
+/// name.
+fn sibling_path(original_path: &Path, prefix: &str) -> PathBuf {
+    let mut path = original_path.parent().unwrap().to_path_buf();
+    let mut file_name = ffi::OsString::from(prefix);
+    file_name.push(original_path.file_name().unwrap());
+    path.push(file_name);
+    path
+}
+
+/// Converts a "not found" error into a success.
+fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
 /// Remove a file or directory.
 fn nuke_path(path: &Path) -> io::Result<()> {
     if path.is_dir() {
@@ -85,3 +107,26 @@ fn nuke_path(path: &Path) -> io::Result<()> {
         fs::remove_file(path)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temp_path_rename_replaces_existing_dir() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(dir.join("stale")).unwrap();
+        fs::write(dir.join("stale/file.txt"), "stale").unwrap();
+
+        let temp_path = TempPath::new_force(&dir).unwrap();
+        fs::create_dir_all(temp_path.path()).unwrap();
+        fs::write(temp_path.path().join("file.txt"), "fresh").unwrap();
+        temp_path.rename(&dir).unwrap();
+
+        assert_eq!(fs::read_to_string(dir.join("file.txt")).unwrap(), "fresh");
+        assert!(!dir.join("stale").exists());
+        assert!(!temp.path().join("~plugin").exists());
+        assert!(!temp.path().join("~~plugin").exists());
+    }
+}
@@ -0,0 +1,110 @@
+//! Helpers that are shared between unit tests.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use url::Url;
+
+/// Commit a file with the given contents to the Git repository in the given
+/// directory, creating the repository if there is none yet.
+///
+/// The commit is made on top of `HEAD`, if there is one, and its message is
+/// the name of the file. Returns the new commit.
+pub fn git_commit(dir: &Path, file: &str, contents: &str) -> git2::Oid {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    git_commit_at(
+        dir,
+        file,
+        contents,
+        git2::Time::new(now.as_secs() as i64, 0),
+    )
+}
+
+/// Commit a file to a Git repository like [`git_commit`], at the given time.
+pub fn git_commit_at(dir: &Path, file: &str, contents: &str, time: git2::Time) -> git2::Oid {
+    let repo = git2::Repository::open(dir)
+        .or_else(|_| git2::Repository::init(dir))
+        .unwrap();
+    fs::write(dir.join(file), contents).unwrap();
+    let mut index = repo.index().unwrap();
+    index.add_path(Path::new(file)).unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let sig = git2::Signature::new("Sheldon", "sheldon@example.com", &time).unwrap();
+    let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+    let parents: Vec<_> = parent.iter().collect();
+    repo.commit(Some("HEAD"), &sig, &sig, file, &tree, &parents)
+        .unwrap()
+}
+
+/// A request received by a server started with [`serve`].
+#[derive(Debug)]
+pub struct Request {
+    /// The index of the connection that the request was made on.
+    pub connection: usize,
+    /// The request line and headers.
+    pub head: String,
+}
+
+impl Request {
+    /// Returns the value of the header with the given name, if it was sent.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.head.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+}
+
+/// Serve the given raw HTTP responses in order from a local server.
+///
+/// Each response is written after reading the head of a request. Connections
+/// are kept open for the next request unless the response contains
+/// `Connection: close`. Returns the base URL of the server and a handle that
+/// returns the requests once every response has been served.
+pub fn serve(responses: Vec<String>) -> (Url, thread::JoinHandle<Vec<Request>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let mut requests = Vec::new();
+        let mut responses = responses.into_iter().peekable();
+        let mut connection = 0;
+        while responses.peek().is_some() {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            while let Some(head) = read_head(&mut reader) {
+                let response = responses.next().unwrap();
+                requests.push(Request { connection, head });
+                let _ = stream.write_all(response.as_bytes());
+                if response.contains("Connection: close") || responses.peek().is_none() {
+                    break;
+                }
+            }
+            connection += 1;
+        }
+        requests
+    });
+    let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+    (url, server)
+}
+
+/// Read the request line and headers of the next request on a connection.
+///
+/// Returns `None` if the connection was closed.
+fn read_head<R: BufRead>(reader: &mut R) -> Option<String> {
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return (!head.is_empty()).then_some(head);
+        }
+        if line == "\r\n" {
+            return Some(head);
+        }
+        head.push_str(&line);
+    }
+}
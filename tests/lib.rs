@@ -3,9 +3,9 @@ use std::env;
 use std::ffi;
 use std::fmt;
 use std::fs;
-use std::io;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::rc::Rc;
 
 use itertools::Itertools;
@@ -76,7 +76,7 @@ impl TestCommand {
 
         command
             .env_clear()
-            .env("HOME", &dirs.home.path())
+            .env("HOME", dirs.home.path())
             .env("SHELDON_CONFIG_DIR", &dirs.config)
             .env("SHELDON_DATA_DIR", &dirs.data)
             .args(&params)
@@ -223,11 +223,22 @@ impl TestCase {
         fs::write(self.dirs.config.join(name), self.get(name))
     }
 
+    /// Assert that a file in the data directory has the expected contents.
+    ///
+    /// Any `<revision>` is replaced with the commit that sheldon-test is
+    /// checked out at.
     fn assert_contents(&self, name: &str) -> io::Result<()> {
-        assert_eq!(
-            &fs::read_to_string(self.dirs.data.join(name))?,
-            &self.get(name)
-        );
+        let mut expected = self.get(name);
+        if expected.contains("<revision>") {
+            let dir = self
+                .dirs
+                .data
+                .join("repos/github.com/rossmacarthur/sheldon-test");
+            let repo = git2::Repository::open(dir).unwrap();
+            let revision = repo.revparse_commit("HEAD").unwrap().id().to_string();
+            expected = expected.replace("<revision>", &revision);
+        }
+        assert_eq!(&fs::read_to_string(self.dirs.data.join(name))?, &expected);
         Ok(())
     }
 
@@ -305,6 +316,20 @@ fn check_sheldon_test(data: &Path) -> Result<(), git2::Error> {
     Ok(())
 }
 
+/// Create a Git repository with a single plugin to use as a local source.
+fn git_init_origin(dir: &Path) -> io::Result<url::Url> {
+    fs::create_dir_all(dir)?;
+    let repo = git2::Repository::init(dir).unwrap();
+    fs::write(dir.join("test.plugin.zsh"), "echo 'testing'\n")?;
+    let mut index = repo.index().unwrap();
+    index.add_path(Path::new("test.plugin.zsh")).unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let sig = git2::Signature::now("Sheldon", "sheldon@example.com").unwrap();
+    repo.commit(Some("HEAD"), &sig, &sig, "Initial commit", &tree, &[])
+        .unwrap();
+    Ok(url::Url::from_directory_path(dir).unwrap())
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 // Tests
 ////////////////////////////////////////////////////////////////////////////////
@@ -317,8 +342,9 @@ fn lock_and_source_clean() -> io::Result<()> {
     {
         fs::OpenOptions::new()
             .create(true)
+            .truncate(true)
             .write(true)
-            .open(&data.join("repos/test.com/test.txt"))?;
+            .open(data.join("repos/test.com/test.txt"))?;
     }
 
     case.run()?;
@@ -326,6 +352,33 @@ fn lock_and_source_clean() -> io::Result<()> {
     Ok(())
 }
 
+#[test]
+fn lock_clean_flags() -> io::Result<()> {
+    let case = TestCase::load("clean_flags")?;
+    let test_dir = case.dirs.data.join("repos/test.com");
+    fs::create_dir_all(&test_dir)?;
+    fs::write(test_dir.join("test.txt"), "")?;
+    case.write_config_file("plugins.toml")?;
+
+    TestCommand::new(&case.dirs)
+        .expect_exit_code(0)
+        .expect_stdout(String::new())
+        .expect_stderr(case.get("lock.kept.stderr"))
+        .args(["lock", "--no-clean"])
+        .run()?;
+    assert!(test_dir.join("test.txt").exists());
+
+    TestCommand::new(&case.dirs)
+        .expect_exit_code(0)
+        .expect_stdout(String::new())
+        .expect_stderr(case.get("lock.stderr"))
+        .args(["lock", "--clean"])
+        .run()?;
+    assert!(!test_dir.exists());
+
+    Ok(())
+}
+
 #[test]
 fn lock_and_source_clean_permission_denied() -> io::Result<()> {
     use std::os::unix::fs::PermissionsExt;
@@ -336,18 +389,19 @@ fn lock_and_source_clean_permission_denied() -> io::Result<()> {
     {
         fs::OpenOptions::new()
             .create(true)
+            .truncate(true)
             .write(true)
             .open(data.join("repos/test.com/test.txt"))?;
     }
     fs::set_permissions(
-        &data.join("repos/test.com"),
+        data.join("repos/test.com"),
         fs::Permissions::from_mode(0o000),
     )?;
 
     case.run()?;
 
     fs::set_permissions(
-        &data.join("repos/test.com"),
+        data.join("repos/test.com"),
         fs::Permissions::from_mode(0o777),
     )?;
 
@@ -526,7 +580,7 @@ fn lock_and_source_override_config_file() -> io::Result<()> {
         .expect_exit_code(0)
         .expect_stdout(case.get("lock.stdout"))
         .expect_stderr(case.get("lock.stderr"))
-        .args(&args)
+        .args(args)
         .arg("lock")
         .run()?;
 
@@ -534,7 +588,7 @@ fn lock_and_source_override_config_file() -> io::Result<()> {
         .expect_exit_code(0)
         .expect_stdout(case.get("source.stdout"))
         .expect_stderr(case.get("source.stderr"))
-        .args(&args)
+        .args(args)
         .arg("source")
         .run()?;
 
@@ -551,7 +605,7 @@ fn lock_and_source_override_config_file_missing() -> io::Result<()> {
         .expect_exit_code(2)
         .expect_stdout(case.get("stdout"))
         .expect_stderr(case.get("stderr"))
-        .args(&args)
+        .args(args)
         .arg("lock")
         .run()?;
 
@@ -559,7 +613,7 @@ fn lock_and_source_override_config_file_missing() -> io::Result<()> {
         .expect_exit_code(2)
         .expect_stdout(case.get("stdout"))
         .expect_stderr(case.get("stderr"))
-        .args(&args)
+        .args(args)
         .arg("source")
         .run()
 }
@@ -576,7 +630,7 @@ fn lock_and_source_override_lock_file() -> io::Result<()> {
         .expect_exit_code(0)
         .expect_stdout(case.get("lock.stdout"))
         .expect_stderr(case.get("lock.stderr"))
-        .args(&args)
+        .args(args)
         .arg("lock")
         .run()?;
 
@@ -586,11 +640,394 @@ fn lock_and_source_override_lock_file() -> io::Result<()> {
         .expect_exit_code(0)
         .expect_stdout(case.get("source.stdout"))
         .expect_stderr(case.get("source.stderr"))
-        .args(&args)
+        .args(args)
         .arg("source")
         .run()
 }
 
+#[test]
+fn verify_missing_file() -> io::Result<()> {
+    let case = TestCase::load("verify")?;
+    let plugin = case.dirs.home.path().join("plugin");
+    fs::create_dir(&plugin)?;
+    fs::write(plugin.join("test.plugin.zsh"), "")?;
+    case.write_config_file("plugins.toml")?;
+    case.run_command("lock")?;
+    case.run_command("verify")?;
+
+    fs::remove_file(plugin.join("test.plugin.zsh"))?;
+    TestCommand::new(&case.dirs)
+        .expect_exit_code(2)
+        .expect_stdout(String::new())
+        .expect_stderr(case.get("verify.missing.stderr"))
+        .arg("verify")
+        .run()
+}
+
+#[test]
+fn lock_progress_format_json() -> io::Result<()> {
+    let dirs = Directories::default()?;
+    let mut config = String::new();
+    for name in ["one", "two", "broken"] {
+        let plugin = dirs.home.path().join(name);
+        fs::create_dir(&plugin)?;
+        if name != "broken" {
+            fs::write(plugin.join(format!("{}.plugin.zsh", name)), "")?;
+        }
+        config.push_str(&format!(
+            "[plugins.{}]\nlocal = '{}'\n\n",
+            name,
+            plugin.display()
+        ));
+    }
+    fs::create_dir_all(&dirs.config)?;
+    fs::write(dirs.config.join("plugins.toml"), config)?;
+
+    let output = TestCommand::new(&dirs)
+        .args(["--progress-format", "json", "lock"])
+        .command
+        .output()?;
+    assert_eq!(output.status.code(), Some(2));
+
+    let events: Vec<serde_json::Value> = String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .filter(|line| line.starts_with('{'))
+        .map(|line| serde_json::from_str(line).expect("event is valid JSON"))
+        .collect();
+    for name in ["one", "two", "broken"] {
+        let source = dirs.home.path().join(name).display().to_string();
+        let kinds: Vec<_> = events
+            .iter()
+            .filter(|event| event["source"] == source.as_str())
+            .map(|event| event["event"].as_str().unwrap())
+            .collect();
+        let last = if name == "broken" {
+            "failed"
+        } else {
+            "finished"
+        };
+        assert_eq!(kinds, ["started", last]);
+    }
+    let failed = events.iter().find(|e| e["event"] == "failed").unwrap();
+    assert!(failed["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("failed to install plugin `broken`"));
+    Ok(())
+}
+
+#[test]
+fn lock_on_error_abort_and_keep_going() -> io::Result<()> {
+    let dirs = Directories::default()?;
+    let mut config = String::new();
+    for name in ["one", "broken1", "broken2"] {
+        let plugin = dirs.home.path().join(name);
+        fs::create_dir(&plugin)?;
+        if name == "one" {
+            fs::write(plugin.join("one.plugin.zsh"), "")?;
+        }
+        config.push_str(&format!(
+            "[plugins.{}]\nlocal = '{}'\n\n",
+            name,
+            plugin.display()
+        ));
+    }
+    fs::create_dir_all(&dirs.config)?;
+    fs::write(dirs.config.join("plugins.toml"), config)?;
+
+    // Aborting stops at the first failure, keeping going reports them all. The
+    // lock file is not written in either case.
+    for (args, failures) in [
+        (&["--on-error", "abort", "lock"][..], 1),
+        (&["--on-error", "abort", "--keep-going", "lock"][..], 2),
+    ] {
+        let output = TestCommand::new(&dirs).args(args).command.output()?;
+        assert_eq!(output.status.code(), Some(2));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(
+            stderr.matches("failed to install plugin `broken").count(),
+            failures,
+            "unexpected failures in:\n{}",
+            stderr
+        );
+        assert!(!dirs.data.join("plugins.lock").exists());
+    }
+    Ok(())
+}
+
+#[test]
+fn source_strict_fails_with_up_to_date_lock() -> io::Result<()> {
+    let dirs = Directories::default()?;
+    let mut config = String::new();
+    for name in ["one", "two"] {
+        let plugin = dirs.home.path().join(name);
+        fs::create_dir(&plugin)?;
+        fs::write(plugin.join("functions.zsh"), "")?;
+        config.push_str(&format!(
+            "[plugins.{}]\nlocal = '{}'\nuse = ['functions.zsh']\n\n",
+            name,
+            plugin.display()
+        ));
+    }
+    fs::create_dir_all(&dirs.config)?;
+    fs::write(dirs.config.join("plugins.toml"), config)?;
+
+    // Without strict mode the duplicate file name is only a warning, so the
+    // lock file is written and is up to date afterwards.
+    let output = TestCommand::new(&dirs).args(["source"]).command.output()?;
+    assert_eq!(output.status.code(), Some(0));
+    assert!(dirs.data.join("plugins.lock").exists());
+
+    let output = TestCommand::new(&dirs)
+        .args(["--strict", "source"])
+        .command
+        .output()?;
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("file name `functions.zsh` is matched by more than one plugin"));
+    Ok(())
+}
+
+#[test]
+fn lock_trace_logs_git_clone() -> io::Result<()> {
+    let dirs = Directories::default()?;
+    let url = git_init_origin(&dirs.home.path().join("origin"))?;
+    fs::create_dir_all(&dirs.config)?;
+    fs::write(
+        dirs.config.join("plugins.toml"),
+        format!("[plugins.test]\ngit = '{}'\nclone_as = 'test'\n", url),
+    )?;
+
+    let output = TestCommand::new(&dirs)
+        .args(["--trace", "lock"])
+        .command
+        .output()?;
+    assert_eq!(output.status.code(), Some(0));
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let clone = format!("git clone {} ", url);
+    assert!(
+        stderr.lines().any(|line| line.contains(&clone)),
+        "no clone command in:\n{}",
+        stderr
+    );
+    Ok(())
+}
+
+#[test]
+fn lock_and_source_are_locale_and_timezone_independent() -> io::Result<()> {
+    let dirs = Directories::default()?;
+    let mut config = String::from("shell = 'zsh'\n\n");
+    for name in ["ünïcode", "Zebra", "alpha"] {
+        let plugin = dirs.home.path().join(name);
+        fs::create_dir(&plugin)?;
+        fs::write(plugin.join(format!("{}.plugin.zsh", name)), "")?;
+        fs::write(plugin.join("_completion"), "")?;
+        config.push_str(&format!(
+            "[plugins.{:?}]\nlocal = '{}'\napply = ['source', 'fpath']\n\n",
+            name,
+            plugin.display()
+        ));
+    }
+    fs::create_dir_all(&dirs.config)?;
+    fs::write(dirs.config.join("plugins.toml"), config)?;
+
+    let run = |locale: &str, tz: &str| -> io::Result<(String, String)> {
+        let mut cmd = TestCommand::new(&dirs).args(["--quiet", "source", "--relock"]);
+        cmd.command
+            .env("LANG", locale)
+            .env("LC_ALL", locale)
+            .env("LC_COLLATE", locale)
+            .env("LC_TIME", locale)
+            .env("TZ", tz);
+        let output = cmd.command.output()?;
+        assert_eq!(output.status.code(), Some(0));
+        let lock = fs::read_to_string(dirs.data.join("plugins.lock"))?;
+        Ok((lock, String::from_utf8_lossy(&output.stdout).into_owned()))
+    };
+    let (lock_c, source_c) = run("C", "UTC")?;
+    let (lock_de, source_de) = run("de_DE.UTF-8", "Pacific/Kiritimati")?;
+    assert_eq!(lock_c, lock_de);
+    assert_eq!(source_c, source_de);
+    Ok(())
+}
+
+#[test]
+fn source_multiple_shells_to_output_dir() -> io::Result<()> {
+    let dirs = Directories::default()?;
+    fs::create_dir_all(&dirs.config)?;
+    fs::write(
+        dirs.config.join("plugins.toml"),
+        "[plugins.common]\ninline = 'echo common'\n\n\
+         [plugins.only-zsh]\ninline = 'echo zsh'\nshell = 'zsh'\n\n\
+         [plugins.only-bash]\ninline = 'echo bash'\nshell = 'bash'\n",
+    )?;
+    let output_dir = dirs.home.path().join("out");
+
+    let output = TestCommand::new(&dirs)
+        .args([
+            "source",
+            "--shell",
+            "zsh",
+            "--shell",
+            "bash",
+            "--output-dir",
+        ])
+        .arg(&output_dir)
+        .command
+        .output()?;
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stdout.is_empty());
+
+    let zsh = fs::read_to_string(output_dir.join("zsh.sh"))?;
+    assert!(zsh.contains("echo common"));
+    assert!(zsh.contains("echo zsh"));
+    assert!(!zsh.contains("echo bash"));
+    let bash = fs::read_to_string(output_dir.join("bash.sh"))?;
+    assert!(bash.contains("echo common"));
+    assert!(bash.contains("echo bash"));
+    assert!(!bash.contains("echo zsh"));
+    assert!(!dirs.data.join("plugins.lock").exists());
+    Ok(())
+}
+
+#[test]
+fn source_no_lock() -> io::Result<()> {
+    let dirs = Directories::default()?;
+    fs::create_dir_all(&dirs.config)?;
+    fs::write(
+        dirs.config.join("plugins.toml"),
+        "[plugins.test]\ninline = 'echo testing'\n",
+    )?;
+
+    for _ in 0..2 {
+        let output = TestCommand::new(&dirs)
+            .args(["source", "--no-lock"])
+            .command
+            .output()?;
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "echo testing\n");
+        assert!(!dirs.data.join("plugins.lock").exists());
+        assert!(!dirs.data.join("plugins.lock.cache").exists());
+    }
+    Ok(())
+}
+
+#[test]
+fn list_and_source_tag() -> io::Result<()> {
+    let dirs = Directories::default()?;
+    fs::create_dir_all(&dirs.config)?;
+    fs::write(
+        dirs.config.join("plugins.toml"),
+        "[plugins.a]\ninline = 'echo a'\ntags = ['prompt']\n\n\
+         [plugins.b]\ninline = 'echo b'\ntags = ['slow']\n",
+    )?;
+
+    let output = TestCommand::new(&dirs)
+        .args(["list", "--tag", "slow"])
+        .command
+        .output()?;
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "b\n");
+
+    let output = TestCommand::new(&dirs)
+        .args(["source", "--tag", "prompt"])
+        .command
+        .output()?;
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "echo a\n");
+    Ok(())
+}
+
+#[test]
+fn why_plugin_skipped() -> io::Result<()> {
+    let dirs = Directories::default()?;
+    fs::create_dir_all(&dirs.config)?;
+    fs::write(
+        dirs.config.join("plugins.toml"),
+        "[plugins.a]\ninline = 'echo a'\n\n\
+         [plugins.b]\ninline = 'echo b'\nshell = 'bash'\n\n\
+         [groups.extras]\nenabled = false\n\n\
+         [groups.extras.plugins.c]\ninline = 'echo c'\n",
+    )?;
+
+    let why = |name: &str| {
+        TestCommand::new(&dirs)
+            .args(["why", name])
+            .command
+            .output()
+    };
+    let output = why("a")?;
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "a is included\n");
+
+    let output = why("b")?;
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "b is skipped, it is only for the `bash` shell\n"
+    );
+
+    let output = why("c")?;
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "c is skipped, it is in the disabled group `extras`\n"
+    );
+
+    let output = why("d")?;
+    assert_eq!(output.status.code(), Some(2));
+    Ok(())
+}
+
+#[test]
+fn export_bundle_and_import_bundle() -> io::Result<()> {
+    let origin = tempfile::tempdir()?;
+    let url = git_init_origin(origin.path())?;
+    let config = format!("[plugins.test]\ngit = '{}'\nclone_as = 'test'\n", url);
+
+    let dirs = Directories::default()?;
+    fs::create_dir_all(&dirs.config)?;
+    fs::write(dirs.config.join("plugins.toml"), &config)?;
+    let output = TestCommand::new(&dirs).args(["lock"]).command.output()?;
+    assert_eq!(output.status.code(), Some(0));
+    let download = dirs.data.join("downloads/example.com/plugin.zsh");
+    fs::create_dir_all(download.parent().unwrap())?;
+    fs::write(&download, "echo 'downloaded'\n")?;
+
+    let bundle = TestCommand::new(&dirs)
+        .args(["export-bundle"])
+        .command
+        .output()?;
+    assert_eq!(bundle.status.code(), Some(0));
+
+    // The other machine has no access to the source.
+    drop(origin);
+    let other = Directories::default()?;
+    fs::create_dir_all(&other.config)?;
+    fs::write(other.config.join("plugins.toml"), &config)?;
+    let mut child = TestCommand::new(&other)
+        .args(["import-bundle"])
+        .command
+        .stdin(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(&bundle.stdout)?;
+    assert_eq!(child.wait()?.code(), Some(0));
+
+    assert_eq!(
+        fs::read_to_string(other.data.join("downloads/example.com/plugin.zsh"))?,
+        "echo 'downloaded'\n"
+    );
+    let output = TestCommand::new(&other).args(["lock"]).command.output()?;
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        fs::read_to_string(other.data.join("repos/test/test.plugin.zsh"))?,
+        "echo 'testing'\n"
+    );
+    Ok(())
+}
+
 #[test]
 fn dirs_default() -> io::Result<()> {
     let dirs = Directories::default()?;
@@ -661,6 +1098,18 @@ fn dirs_xdg_from_env() -> io::Result<()> {
     Ok(())
 }
 
+#[test]
+fn paths() -> io::Result<()> {
+    let case = TestCase::load("paths")?;
+    case.run_command("paths")?;
+    TestCommand::new(&case.dirs)
+        .expect_exit_code(0)
+        .expect_stdout(case.get("paths.json.stdout"))
+        .expect_stderr(String::new())
+        .args(["paths", "--json"])
+        .run()
+}
+
 #[test]
 fn version() -> io::Result<()> {
     let dirs = Directories::default()?;